@@ -0,0 +1,151 @@
+//! Policies for clearing a contact alert.
+//!
+//! By default an alert clears itself once the signal has recovered. In pathology use, some teams
+//! prefer the alert to latch until the operator acknowledges it.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{fmt, str::FromStr};
+
+use crate::command::ParseError;
+
+/// Determines what is required before an alert returns to normal operation
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AlertPolicy {
+    /// Clear as soon as the detector reports the end of contact. The acknowledge input is ignored.
+    #[default]
+    AutoClear,
+    /// Stay in alert until the operator acknowledges it, even if contact continues
+    LatchUntilAck,
+    /// Stay in alert until the operator has acknowledged it _and_ the detector has reported the
+    /// end of contact, in either order
+    LatchUntilAckAndClear,
+}
+
+impl AlertPolicy {
+    /// Every policy, in declaration order
+    pub const ALL: [AlertPolicy; 3] = [
+        AlertPolicy::AutoClear,
+        AlertPolicy::LatchUntilAck,
+        AlertPolicy::LatchUntilAckAndClear,
+    ];
+
+    /// Name used on the console
+    pub const fn name(&self) -> &'static str {
+        match self {
+            AlertPolicy::AutoClear => "auto_clear",
+            AlertPolicy::LatchUntilAck => "latch_until_ack",
+            AlertPolicy::LatchUntilAckAndClear => "latch_until_ack_and_clear",
+        }
+    }
+
+    /// Whether the operator must acknowledge alerts under this policy
+    pub const fn latches(&self) -> bool {
+        !matches!(self, AlertPolicy::AutoClear)
+    }
+}
+
+impl FromStr for AlertPolicy {
+    type Err = ParseError;
+
+    /// Parse the [`AlertPolicy::name`] of a policy
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{alert::AlertPolicy, command::ParseError};
+    ///
+    /// assert_eq!("Latch_Until_Ack".parse(), Ok(AlertPolicy::LatchUntilAck));
+    /// assert_eq!("latch".parse::<AlertPolicy>(), Err(ParseError::InvalidValue));
+    /// ```
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        AlertPolicy::ALL
+            .into_iter()
+            .find(|policy| name.eq_ignore_ascii_case(policy.name()))
+            .ok_or(ParseError::InvalidValue)
+    }
+}
+
+impl fmt::Display for AlertPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Tracks the conditions needed to clear the current alert under an [`AlertPolicy`].
+///
+/// ```
+/// use aps490_pfpu2_core::alert::{AlertLatch, AlertPolicy};
+///
+/// let policy = AlertPolicy::LatchUntilAckAndClear;
+/// let mut latch = AlertLatch::new();
+/// latch.raise();
+/// assert!(!latch.acknowledge(policy));
+/// assert!(latch.signal_clear(policy));
+///
+/// // A new contact must be acknowledged again
+/// latch.raise();
+/// assert!(!latch.signal_clear(policy));
+/// assert!(latch.acknowledge(policy));
+///
+/// // Auto-clear ignores the operator
+/// latch.raise();
+/// assert!(!latch.acknowledge(AlertPolicy::AutoClear));
+/// assert!(latch.signal_clear(AlertPolicy::AutoClear));
+/// ```
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AlertLatch {
+    /// The operator has acknowledged the current alert
+    acknowledged: bool,
+    /// The detector has reported the end of contact for the current alert
+    signal_cleared: bool,
+}
+
+impl AlertLatch {
+    /// Create a latch with no conditions met
+    pub const fn new() -> Self {
+        Self {
+            acknowledged: false,
+            signal_cleared: false,
+        }
+    }
+
+    /// Reset the latch for a new alert
+    pub fn raise(&mut self) {
+        self.acknowledged = false;
+        self.signal_cleared = false;
+    }
+
+    /// Record the end of contact. Returns `true` if the alert should now be cleared.
+    pub fn signal_clear(&mut self, policy: AlertPolicy) -> bool {
+        self.signal_cleared = true;
+        self.should_clear(policy)
+    }
+
+    /// Record an operator acknowledgement. Returns `true` if the alert should now be cleared.
+    pub fn acknowledge(&mut self, policy: AlertPolicy) -> bool {
+        self.acknowledged = true;
+        self.should_clear(policy)
+    }
+
+    /// Check whether the recorded conditions satisfy `policy`
+    pub fn should_clear(&self, policy: AlertPolicy) -> bool {
+        match policy {
+            AlertPolicy::AutoClear => self.signal_cleared,
+            AlertPolicy::LatchUntilAck => self.acknowledged,
+            AlertPolicy::LatchUntilAckAndClear => self.acknowledged && self.signal_cleared,
+        }
+    }
+}
@@ -0,0 +1,386 @@
+//! Exact clock and divider planning for the excitation signal and ADC.
+//!
+//! The system clock is generated by `PLL_SYS` from the crystal, and the excitation PWM counts
+//! system clock cycles through an 8.4 fractional divider, wrapping after `top + 1` counts.
+//!
+//! ADC conversions are not free-running. A second pacing PWM slice, with the same divider and a
+//! whole fraction of the excitation's `top`, wraps once per sample, and each wrap starts a single
+//! conversion (see [`PacingPlan`]). Both slices are enabled together, so every sample is taken at a
+//! known phase of the excitation, and the two can never drift apart.
+//!
+//! A [`ClockPlan`] chooses the PLL settings for an exact system clock, then the pacing divider
+//! closest to the requested sample rate, checking that each fits its register and that the ADC
+//! (on its own 48 MHz clock from `PLL_USB`) can keep up.
+//!
+//! ```
+//! use aps490_pfpu2_core::clock::ClockPlan;
+//!
+//! let plan = ClockPlan::new(12_000_000, 24_000_000, 48_000_000, 100_000, 2).unwrap();
+//! assert_eq!(plan.sys_hz(), 24_000_000);
+//! assert_eq!((plan.pwm.div_int, plan.pwm.div_frac, plan.pwm.top), (1, 0, 239));
+//! assert_eq!(plan.pacing.pwm.top, 119);
+//! assert_eq!(plan.excitation_millihz(), 100_000_000);
+//! assert_eq!(plan.sample_millihz(), 200_000_000);
+//!
+//! // Samples fall in the middle of the high and low halves of each cycle
+//! assert_eq!(plan.pacing.trigger_count(0), 60);
+//! assert_eq!(plan.pacing.trigger_count(1), 180);
+//!
+//! // 70 kHz does not divide the clock exactly, so the fractional divider gets as close as possible,
+//! // but sampling still runs at exactly twice the excitation
+//! let plan = ClockPlan::new(12_000_000, 24_000_000, 48_000_000, 70_000, 2).unwrap();
+//! assert!(plan.excitation_millihz().abs_diff(70_000_000) < 10_000);
+//! assert_eq!(plan.sample_millihz(), plan.excitation_millihz() * 2);
+//!
+//! // Conversions take 96 ADC clock cycles
+//! assert!(ClockPlan::new(12_000_000, 24_000_000, 48_000_000, 300_000, 2).is_err());
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt;
+
+/// Settings for a PLL, with the reference divider fixed at 1
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PllPlan {
+    /// Feedback divider, multiplying the crystal frequency up to the VCO frequency
+    pub fbdiv: u16,
+    /// First post divider, at least `post_div2`
+    pub post_div1: u8,
+    /// Second post divider
+    pub post_div2: u8,
+}
+
+impl PllPlan {
+    /// Allowed range of the feedback divider
+    pub const FBDIV: (u16, u16) = (16, 320);
+    /// Allowed range of the VCO frequency
+    pub const VCO_HZ: (u32, u32) = (750_000_000, 1_600_000_000);
+    /// Largest post divider
+    pub const MAX_POST_DIV: u8 = 7;
+
+    /// Find the settings with the lowest VCO frequency, to save power, which produce exactly
+    /// `target_hz` from `xosc_hz`
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::clock::PllPlan;
+    ///
+    /// let pll = PllPlan::find(12_000_000, 125_000_000).unwrap();
+    /// assert_eq!(pll.output_hz(12_000_000), 125_000_000);
+    /// assert_eq!(pll.vco_hz(12_000_000), 1_500_000_000);
+    /// assert!(PllPlan::find(12_000_000, 1_000_000).is_err());
+    /// ```
+    pub fn find(xosc_hz: u32, target_hz: u32) -> Result<Self, ClockError> {
+        let mut best: Option<Self> = None;
+        for post_div1 in 1..=Self::MAX_POST_DIV {
+            for post_div2 in 1..=post_div1 {
+                let vco_hz = target_hz as u64 * post_div1 as u64 * post_div2 as u64;
+                if !vco_hz.is_multiple_of(xosc_hz as u64)
+                    || !(Self::VCO_HZ.0 as u64..=Self::VCO_HZ.1 as u64).contains(&vco_hz)
+                {
+                    continue;
+                }
+                let fbdiv = vco_hz / xosc_hz as u64;
+                if !(Self::FBDIV.0 as u64..=Self::FBDIV.1 as u64).contains(&fbdiv) {
+                    continue;
+                }
+                let plan = Self {
+                    fbdiv: fbdiv as u16,
+                    post_div1,
+                    post_div2,
+                };
+                if best.is_none_or(|best| plan.fbdiv < best.fbdiv) {
+                    best = Some(plan);
+                }
+            }
+        }
+        best.ok_or(ClockError::NoPllConfig { target_hz })
+    }
+
+    /// VCO frequency with a crystal of `xosc_hz`
+    pub fn vco_hz(&self, xosc_hz: u32) -> u32 {
+        xosc_hz * self.fbdiv as u32
+    }
+
+    /// Output frequency with a crystal of `xosc_hz`
+    pub fn output_hz(&self, xosc_hz: u32) -> u32 {
+        self.vco_hz(xosc_hz) / (self.post_div1 as u32 * self.post_div2 as u32)
+    }
+}
+
+/// Divider and wrap value for a PWM slice
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PwmPlan {
+    /// Integer part of the clock divider, from 1
+    pub div_int: u8,
+    /// Fractional part of the clock divider, in 16ths
+    pub div_frac: u8,
+    /// Counter wraps after `top + 1` counts
+    pub top: u16,
+}
+
+impl PwmPlan {
+    /// Find the divider and `top` giving the frequency closest to `target_hz` from a clock of
+    /// `clock_hz`. Among equally close settings, the smallest divider is used, so that the duty
+    /// cycle has the finest resolution.
+    pub fn find(clock_hz: u32, target_hz: u32) -> Result<Self, ClockError> {
+        if target_hz == 0 {
+            return Err(ClockError::PwmOutOfRange { target_hz });
+        }
+        let clock16 = clock_hz as u64 * 16;
+        // Best so far, with its error as a fraction
+        let mut best: Option<(Self, u64, u64)> = None;
+        for div16 in 16..=(u8::MAX as u64 * 16 + 15) {
+            let per_div = target_hz as u64 * div16;
+            let wrap = (clock16 + per_div / 2) / per_div;
+            if !(2..=u16::MAX as u64 + 1).contains(&wrap) {
+                continue;
+            }
+            let period = div16 * wrap;
+            let error = clock16.abs_diff(target_hz as u64 * period);
+            let better = best.is_none_or(|(_, best_error, best_period)| {
+                (error as u128) * (best_period as u128) < (best_error as u128) * (period as u128)
+            });
+            if better {
+                let plan = Self {
+                    div_int: (div16 / 16) as u8,
+                    div_frac: (div16 % 16) as u8,
+                    top: (wrap - 1) as u16,
+                };
+                best = Some((plan, error, period));
+                if error == 0 {
+                    break;
+                }
+            }
+        }
+        best.map(|(plan, _, _)| plan)
+            .ok_or(ClockError::PwmOutOfRange { target_hz })
+    }
+
+    /// Clock cycles per PWM period, in 16ths
+    pub fn period16(&self) -> u64 {
+        (self.div_int as u64 * 16 + self.div_frac as u64) * (self.top as u64 + 1)
+    }
+}
+
+/// Pacing PWM slice, which wraps once per ADC sample to start each conversion
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PacingPlan {
+    /// Divider and wrap value of the pacing slice
+    pub pwm: PwmPlan,
+    /// Counter value to preset on the pacing slice before it is enabled with the excitation, so
+    /// that each wrap falls in the middle of a sample period rather than on an edge
+    pub counter: u16,
+    /// Conversions may only be armed while the excitation counter is below this, so that the first
+    /// conversion is the first of a cycle. Leaves a quarter of a sample period to start the DMA.
+    pub arm_before: u16,
+}
+
+impl PacingPlan {
+    /// Fewest counts per sample, so there is time to arm conversions before the first trigger
+    pub const MIN_WRAP: u32 = 4;
+
+    /// Pace samples with the slice settings in `pwm`, which must have the same divider as the
+    /// excitation
+    pub fn new(pwm: PwmPlan) -> Option<Self> {
+        let wrap = pwm.top as u32 + 1;
+        if wrap < Self::MIN_WRAP {
+            return None;
+        }
+        Some(Self {
+            pwm,
+            counter: (wrap - wrap / 2) as u16,
+            arm_before: (wrap / 4) as u16,
+        })
+    }
+
+    /// Excitation counter value at which conversion `n` of each cycle is started
+    pub fn trigger_count(&self, n: u32) -> u32 {
+        let wrap = self.pwm.top as u32 + 1;
+        n * wrap + wrap / 2
+    }
+}
+
+/// Complete clock configuration for detection
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ClockPlan {
+    /// Crystal frequency
+    pub xosc_hz: u32,
+    /// `PLL_SYS` settings, which drive the system clock directly
+    pub pll_sys: PllPlan,
+    /// ADC clock frequency
+    pub adc_clock_hz: u32,
+    /// Excitation PWM settings, counting system clock cycles
+    pub pwm: PwmPlan,
+    /// Pacing PWM settings, which trigger the ADC
+    pub pacing: PacingPlan,
+    /// ADC samples per excitation cycle
+    pub samples_per_cycle: u32,
+}
+
+impl ClockPlan {
+    /// Fewest ADC clock cycles per conversion
+    pub const MIN_CONVERSION_CYCLES: u64 = 96;
+
+    /// Plan a system clock of exactly `sys_hz`, with an excitation of `excitation_hz` sampled
+    /// `samples_per_cycle` times per cycle
+    pub fn new(
+        xosc_hz: u32,
+        sys_hz: u32,
+        adc_clock_hz: u32,
+        excitation_hz: u32,
+        samples_per_cycle: u32,
+    ) -> Result<Self, ClockError> {
+        let pll_sys = PllPlan::find(xosc_hz, sys_hz)?;
+        let sample_hz = excitation_hz.saturating_mul(samples_per_cycle);
+        // The excitation wraps after exactly `samples_per_cycle` pacing periods
+        let pacing = PwmPlan::find(sys_hz, sample_hz)?;
+        let top = (pacing.top as u32 + 1)
+            .checked_mul(samples_per_cycle)
+            .and_then(|wrap| u16::try_from(wrap - 1).ok())
+            .ok_or(ClockError::PwmOutOfRange {
+                target_hz: excitation_hz,
+            })?;
+        let pacing = PacingPlan::new(pacing).ok_or(ClockError::AdcTooFast {
+            target_hz: sample_hz,
+        })?;
+        if pacing.pwm.period16() * (adc_clock_hz as u64)
+            < Self::MIN_CONVERSION_CYCLES * 16 * sys_hz as u64
+        {
+            return Err(ClockError::AdcTooFast {
+                target_hz: sample_hz,
+            });
+        }
+        Ok(Self {
+            xosc_hz,
+            pll_sys,
+            adc_clock_hz,
+            pwm: PwmPlan { top, ..pacing.pwm },
+            pacing,
+            samples_per_cycle,
+        })
+    }
+
+    /// Plan the same clocks with the excitation changed to `excitation_hz`
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::clock::ClockPlan;
+    ///
+    /// let plan = ClockPlan::new(12_000_000, 24_000_000, 48_000_000, 100_000, 2).unwrap();
+    /// let retuned = plan.with_excitation(50_000).unwrap();
+    /// assert_eq!(retuned.excitation_millihz(), 50_000_000);
+    /// assert_eq!(retuned.pll_sys, plan.pll_sys);
+    /// assert!(plan.with_excitation(1_000_000).is_err());
+    /// ```
+    pub fn with_excitation(&self, excitation_hz: u32) -> Result<Self, ClockError> {
+        Self::new(
+            self.xosc_hz,
+            self.sys_hz(),
+            self.adc_clock_hz,
+            excitation_hz,
+            self.samples_per_cycle,
+        )
+    }
+
+    /// System clock frequency
+    pub fn sys_hz(&self) -> u32 {
+        self.pll_sys.output_hz(self.xosc_hz)
+    }
+
+    /// Achieved excitation frequency, in millihertz
+    pub fn excitation_millihz(&self) -> u64 {
+        self.sys_hz() as u64 * 16_000 / self.pwm.period16()
+    }
+
+    /// Achieved sample rate, in millihertz
+    pub fn sample_millihz(&self) -> u64 {
+        self.sys_hz() as u64 * 16_000 / self.pacing.pwm.period16()
+    }
+
+    /// Time taken to record a window of `samples` readings, in microseconds
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::clock::ClockPlan;
+    ///
+    /// let plan = ClockPlan::new(12_000_000, 125_000_000, 48_000_000, 100_000, 2).unwrap();
+    /// assert_eq!(plan.window_us(4000), 20_000);
+    /// ```
+    pub fn window_us(&self, samples: usize) -> u32 {
+        (samples as u64 * 1_000_000_000 / self.sample_millihz()) as u32
+    }
+
+    /// Phase gained by the achieved excitation on an ideal `excitation_hz` signal over a window of
+    /// `samples` readings, in millidegrees. Negative if the achieved excitation is slower.
+    ///
+    /// Sampling is locked to the achieved excitation, so this is the drift against the requested
+    /// frequency, not between the excitation and the samples.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::clock::ClockPlan;
+    ///
+    /// let plan = ClockPlan::new(12_000_000, 125_000_000, 48_000_000, 100_000, 2).unwrap();
+    /// assert_eq!(plan.drift_millidegrees(100_000, 4000), 0);
+    ///
+    /// // 70 kHz is slightly slow, which builds up over the 2000 cycles in a window
+    /// let plan = plan.with_excitation(70_000).unwrap();
+    /// let drift = plan.drift_millidegrees(70_000, 4000);
+    /// assert!(drift < 0 && drift > -360_000);
+    /// ```
+    pub fn drift_millidegrees(&self, excitation_hz: u32, samples: usize) -> i64 {
+        let error_millihz = self.excitation_millihz() as i64 - excitation_hz as i64 * 1000;
+        error_millihz * samples as i64 * 360_000 / self.sample_millihz() as i64
+    }
+}
+
+/// Reasons a clock plan could not be made
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClockError {
+    /// No PLL settings produce exactly this frequency from the crystal
+    NoPllConfig {
+        /// Requested frequency
+        target_hz: u32,
+    },
+    /// The excitation frequency cannot be reached with a 16-bit `top` and 8.4 divider
+    PwmOutOfRange {
+        /// Requested frequency
+        target_hz: u32,
+    },
+    /// The sample rate is faster than the ADC can convert
+    AdcTooFast {
+        /// Requested sample rate
+        target_hz: u32,
+    },
+}
+
+impl fmt::Display for ClockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClockError::NoPllConfig { target_hz } => {
+                write!(f, "no PLL settings produce {target_hz} Hz")
+            }
+            ClockError::PwmOutOfRange { target_hz } => {
+                write!(f, "PWM cannot produce {target_hz} Hz")
+            }
+            ClockError::AdcTooFast { target_hz } => {
+                write!(f, "ADC cannot sample at {target_hz} Hz")
+            }
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! Line-based command set for the serial console.
+//!
+//! Bytes received from the host are collected by a [`LineBuffer`], and each complete line is
+//! parsed into a [`Command`]:
+//!
+//! ```
+//! use aps490_pfpu2_core::command::{Command, LineBuffer, Threshold};
+//!
+//! let mut line = LineBuffer::<32>::new();
+//! let mut commands = Vec::new();
+//! for byte in b"set trigger_delta 3\r\ndump 250\n" {
+//!     if let Some(Ok(text)) = line.push(*byte) {
+//!         commands.push(text.parse::<Command>());
+//!     }
+//! }
+//! assert_eq!(
+//!     commands,
+//!     [Ok(Command::Set(Threshold::TriggerDelta, 3)), Ok(Command::Dump(250))]
+//! );
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{fmt, str::FromStr};
+
+use crate::{
+    alert::AlertPolicy, config::Thresholds, gain::GainSetting, impedance::MAX_TONES,
+    prbs::ExcitationMode, spectrum::FrequencyList,
+};
+
+/// A single console command
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// `status`: Report the system state and detection progress
+    Status,
+    /// `get [threshold]`: Report one threshold, or all of them along with the alert policy
+    Get(Option<Threshold>),
+    /// `set <threshold> <value>`: Change a threshold
+    Set(Threshold, u8),
+    /// `get alert_policy` or `set alert_policy <policy>`: Report or change the policy for
+    /// clearing contact alerts
+    AlertPolicy(Option<AlertPolicy>),
+    /// `events`: List recent detection events
+    Events,
+    /// `dump <n>`: Send the `n` most recent averaged samples, oldest first, each with the gain of
+    /// the excitation it was taken at
+    Dump(usize),
+    /// `calibrate`: Record a new idle signal level
+    Calibrate,
+    /// `freq [hz]`: Report the excitation frequency, or change it and recalibrate
+    Frequency(Option<u32>),
+    /// `sweep [hz...]`: Report the results of the last frequency sweep, or start a new one through
+    /// the given frequencies. Each step recalibrates, so detection stays in
+    /// [`Calibrating`](crate::state::StatusLedStates::Calibrating) until the whole sweep is done.
+    Sweep(Option<FrequencyList>),
+    /// `multi [hz hz [hz]]`: Report the latest multi-frequency features, or start alternating the
+    /// excitation between two or three frequencies and recalibrate. `freq <hz>` returns to a single
+    /// frequency.
+    Multi(Option<FrequencyList>),
+    /// `excitation [square|prbs]`: Report the excitation waveform, or change it and recalibrate
+    Excitation(Option<ExcitationMode>),
+    /// `gain [auto|level]`: Report the drive level, or hold it at an index into
+    /// [`DRIVE_LEVELS`](crate::gain::DRIVE_LEVELS) or return it to automatic control
+    Gain(Option<GainSetting>),
+    /// `reset`: Restart the system
+    Reset,
+    /// `help`: List the available commands
+    Help,
+}
+
+impl Command {
+    /// Name of the alert policy setting for `get` and `set`
+    pub const ALERT_POLICY: &'static str = "alert_policy";
+
+    /// Usage for every command, one per line
+    pub const HELP: &'static str = "status\n\
+        get [trigger_delta|confirm_delta|restore_delta|proximity_delta|proximity_hysteresis|\
+        dispersion_delta|alert_policy]\n\
+        set <threshold> <value>\n\
+        set alert_policy <auto_clear|latch_until_ack|latch_until_ack_and_clear>\n\
+        events\n\
+        dump <n>\n\
+        calibrate\n\
+        freq [hz]\n\
+        sweep [hz...] (no detection until done: calibrates at every step)\n\
+        multi [hz hz [hz]]\n\
+        excitation [square|prbs]\n\
+        gain [auto|level]\n\
+        reset\n\
+        help";
+}
+
+impl FromStr for Command {
+    type Err = ParseError;
+
+    /// Parse a single line. Words are separated by any whitespace, and commands are
+    /// case-insensitive.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{
+    ///     alert::AlertPolicy,
+    ///     command::{Command, ParseError, Threshold},
+    ///     gain::GainSetting,
+    ///     prbs::ExcitationMode,
+    ///     spectrum::FrequencyList,
+    /// };
+    ///
+    /// assert_eq!("  STATUS ".parse(), Ok(Command::Status));
+    /// assert_eq!("get".parse(), Ok(Command::Get(None)));
+    /// assert_eq!(
+    ///     "get proximity_delta".parse(),
+    ///     Ok(Command::Get(Some(Threshold::ProximityDelta)))
+    /// );
+    /// assert_eq!("set restore_delta".parse::<Command>(), Err(ParseError::MissingArgument));
+    /// assert_eq!("get alert_policy".parse(), Ok(Command::AlertPolicy(None)));
+    /// assert_eq!(
+    ///     "set alert_policy latch_until_ack".parse(),
+    ///     Ok(Command::AlertPolicy(Some(AlertPolicy::LatchUntilAck)))
+    /// );
+    /// assert_eq!("set restore_delta 300".parse::<Command>(), Err(ParseError::InvalidValue));
+    /// assert_eq!("dump 10 20".parse::<Command>(), Err(ParseError::TooManyArguments));
+    /// assert_eq!("freq 50000".parse(), Ok(Command::Frequency(Some(50_000))));
+    /// assert_eq!(
+    ///     "sweep 20000 40000".parse(),
+    ///     Ok(Command::Sweep(FrequencyList::new(&[20_000, 40_000])))
+    /// );
+    /// assert_eq!("multi 20000".parse::<Command>(), Err(ParseError::MissingArgument));
+    /// assert_eq!("multi 1 2 3 4".parse::<Command>(), Err(ParseError::TooManyArguments));
+    /// assert_eq!(
+    ///     "excitation PRBS".parse(),
+    ///     Ok(Command::Excitation(Some(ExcitationMode::Prbs)))
+    /// );
+    /// assert_eq!("gain 3".parse(), Ok(Command::Gain(Some(GainSetting::Fixed(3)))));
+    /// assert_eq!("retract".parse::<Command>(), Err(ParseError::UnknownCommand));
+    /// assert_eq!("".parse::<Command>(), Err(ParseError::Empty));
+    /// ```
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or(ParseError::Empty)?;
+
+        let command = if name.eq_ignore_ascii_case("status") {
+            Command::Status
+        } else if name.eq_ignore_ascii_case("get") {
+            match words.next() {
+                Some(setting) if setting.eq_ignore_ascii_case(Self::ALERT_POLICY) => {
+                    Command::AlertPolicy(None)
+                }
+                setting => Command::Get(setting.map(str::parse).transpose()?),
+            }
+        } else if name.eq_ignore_ascii_case("set") {
+            let setting = words.next().ok_or(ParseError::MissingArgument)?;
+            let value = words.next().ok_or(ParseError::MissingArgument)?;
+            if setting.eq_ignore_ascii_case(Self::ALERT_POLICY) {
+                Command::AlertPolicy(Some(value.parse()?))
+            } else {
+                Command::Set(
+                    setting.parse()?,
+                    value.parse().or(Err(ParseError::InvalidValue))?,
+                )
+            }
+        } else if name.eq_ignore_ascii_case("events") {
+            Command::Events
+        } else if name.eq_ignore_ascii_case("dump") {
+            let count = words.next().ok_or(ParseError::MissingArgument)?;
+            Command::Dump(count.parse().or(Err(ParseError::InvalidValue))?)
+        } else if name.eq_ignore_ascii_case("calibrate") {
+            Command::Calibrate
+        } else if name.eq_ignore_ascii_case("freq") {
+            let frequency = words.next().map(str::parse).transpose();
+            Command::Frequency(frequency.or(Err(ParseError::InvalidValue))?)
+        } else if name.eq_ignore_ascii_case("sweep") {
+            match FrequencyList::from_words(&mut words) {
+                Ok(list) => Command::Sweep(Some(list)),
+                Err(ParseError::MissingArgument) => Command::Sweep(None),
+                Err(err) => return Err(err),
+            }
+        } else if name.eq_ignore_ascii_case("multi") {
+            match FrequencyList::from_words(&mut words) {
+                Ok(list) if list.as_slice().len() > MAX_TONES => {
+                    return Err(ParseError::TooManyArguments)
+                }
+                Ok(list) if list.as_slice().len() < 2 => return Err(ParseError::MissingArgument),
+                Ok(list) => Command::Multi(Some(list)),
+                Err(ParseError::MissingArgument) => Command::Multi(None),
+                Err(err) => return Err(err),
+            }
+        } else if name.eq_ignore_ascii_case("excitation") {
+            Command::Excitation(words.next().map(str::parse).transpose()?)
+        } else if name.eq_ignore_ascii_case("gain") {
+            Command::Gain(words.next().map(str::parse).transpose()?)
+        } else if name.eq_ignore_ascii_case("reset") {
+            Command::Reset
+        } else if name.eq_ignore_ascii_case("help") {
+            Command::Help
+        } else {
+            return Err(ParseError::UnknownCommand);
+        };
+
+        match words.next() {
+            Some(_) => Err(ParseError::TooManyArguments),
+            None => Ok(command),
+        }
+    }
+}
+
+/// A detection threshold which can be read or changed from the console (see [`Thresholds`])
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Threshold {
+    /// [`Thresholds::trigger_delta`]
+    TriggerDelta,
+    /// [`Thresholds::confirm_delta`]
+    ConfirmDelta,
+    /// [`Thresholds::restore_delta`]
+    RestoreDelta,
+    /// [`Thresholds::proximity_delta`]
+    ProximityDelta,
+    /// [`Thresholds::proximity_hysteresis`]
+    ProximityHysteresis,
+    /// [`Thresholds::dispersion_delta`]
+    DispersionDelta,
+}
+
+impl Threshold {
+    /// Every threshold, in declaration order
+    pub const ALL: [Threshold; 6] = [
+        Threshold::TriggerDelta,
+        Threshold::ConfirmDelta,
+        Threshold::RestoreDelta,
+        Threshold::ProximityDelta,
+        Threshold::ProximityHysteresis,
+        Threshold::DispersionDelta,
+    ];
+
+    /// Name used on the console
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Threshold::TriggerDelta => "trigger_delta",
+            Threshold::ConfirmDelta => "confirm_delta",
+            Threshold::RestoreDelta => "restore_delta",
+            Threshold::ProximityDelta => "proximity_delta",
+            Threshold::ProximityHysteresis => "proximity_hysteresis",
+            Threshold::DispersionDelta => "dispersion_delta",
+        }
+    }
+
+    /// Read this threshold from `thresholds`
+    pub const fn get(&self, thresholds: &Thresholds) -> u8 {
+        match self {
+            Threshold::TriggerDelta => thresholds.trigger_delta,
+            Threshold::ConfirmDelta => thresholds.confirm_delta,
+            Threshold::RestoreDelta => thresholds.restore_delta,
+            Threshold::ProximityDelta => thresholds.proximity_delta,
+            Threshold::ProximityHysteresis => thresholds.proximity_hysteresis,
+            Threshold::DispersionDelta => thresholds.dispersion_delta,
+        }
+    }
+
+    /// Change this threshold in `thresholds`
+    pub fn set(&self, thresholds: &mut Thresholds, value: u8) {
+        match self {
+            Threshold::TriggerDelta => thresholds.trigger_delta = value,
+            Threshold::ConfirmDelta => thresholds.confirm_delta = value,
+            Threshold::RestoreDelta => thresholds.restore_delta = value,
+            Threshold::ProximityDelta => thresholds.proximity_delta = value,
+            Threshold::ProximityHysteresis => thresholds.proximity_hysteresis = value,
+            Threshold::DispersionDelta => thresholds.dispersion_delta = value,
+        }
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = ParseError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Threshold::ALL
+            .into_iter()
+            .find(|threshold| name.eq_ignore_ascii_case(threshold.name()))
+            .ok_or(ParseError::UnknownThreshold)
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Reasons a line could not be parsed into a [`Command`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    /// The line has no command
+    Empty,
+    /// The first word is not a known command
+    UnknownCommand,
+    /// The threshold name is not known
+    UnknownThreshold,
+    /// A required argument is missing
+    MissingArgument,
+    /// A numeric argument is out of range or not a number
+    InvalidValue,
+    /// More arguments were given than the command accepts
+    TooManyArguments,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseError::Empty => "empty command",
+            ParseError::UnknownCommand => "unknown command, try `help`",
+            ParseError::UnknownThreshold => "unknown threshold",
+            ParseError::MissingArgument => "missing argument",
+            ParseError::InvalidValue => "invalid value",
+            ParseError::TooManyArguments => "too many arguments",
+        })
+    }
+}
+
+/// A line received by [`LineBuffer`] could not be used
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LineError {
+    /// The line was longer than the buffer, and has been discarded
+    TooLong,
+    /// The line was not valid UTF-8
+    InvalidUtf8,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LineError::TooLong => "line too long",
+            LineError::InvalidUtf8 => "invalid UTF-8",
+        })
+    }
+}
+
+/// Collects received bytes until a line ending (`\r` or `\n`) is found
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct LineBuffer<const N: usize> {
+    /// Bytes received since the last line ending
+    buf: [u8; N],
+    /// Number of bytes in `buf`
+    len: usize,
+    /// More than `N` bytes have been received since the last line ending
+    overflow: bool,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    /// Create an empty buffer
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Add a received byte. Returns the completed line once a line ending is received.
+    ///
+    /// Empty lines are skipped, so `\r\n` endings only produce one line.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::command::{LineBuffer, LineError};
+    ///
+    /// let mut line = LineBuffer::<4>::new();
+    /// let results: Vec<_> = b"calibrate\nhelp\r\n"
+    ///     .iter()
+    ///     .filter_map(|byte| line.push(*byte).map(|res| res.map(str::to_owned)))
+    ///     .collect();
+    /// assert_eq!(results, [Err(LineError::TooLong), Ok("help".to_owned())]);
+    /// ```
+    pub fn push(&mut self, byte: u8) -> Option<Result<&str, LineError>> {
+        if byte != b'\r' && byte != b'\n' {
+            match self.buf.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                }
+                None => self.overflow = true,
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return Some(Err(LineError::TooLong));
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(core::str::from_utf8(&self.buf[..len]).or(Err(LineError::InvalidUtf8)))
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,146 @@
+//! Runtime configuration for the detection system.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{alert::AlertPolicy, gain::GainSetting, prbs::ExcitationMode};
+
+/// Settings which can be changed without rebuilding the firmware
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// When a contact alert may return to normal operation
+    pub alert_policy: AlertPolicy,
+    /// Detection thresholds
+    pub thresholds: Thresholds,
+    /// Frequency of the excitation signal, in hertz. Detection recalibrates whenever it changes.
+    pub excitation_hz: u32,
+    /// Waveform of the excitation signal. Detection recalibrates whenever it changes.
+    pub excitation_mode: ExcitationMode,
+    /// How the drive level of the excitation is chosen. Detection recalibrates whenever it changes.
+    /// Held at full drive by default, as automatic control is not yet validated on the hardware.
+    pub gain: GainSetting,
+}
+
+impl Config {
+    /// Default excitation frequency
+    pub const EXCITATION_HZ: u32 = 100_000;
+
+    /// Default configuration, usable in `static` initializers
+    pub const fn new() -> Self {
+        Self {
+            alert_policy: AlertPolicy::AutoClear,
+            thresholds: Thresholds::new(),
+            excitation_hz: Self::EXCITATION_HZ,
+            excitation_mode: ExcitationMode::Square,
+            gain: GainSetting::Fixed(0),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Changes in averaged voltage which drive detection
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Thresholds {
+    /// Averaged difference used for detecting contact.
+    ///
+    /// Ex. a trigger delta of 128 on a 3.3V signal requires that the average voltage range has
+    /// decreased by approximately 1.65V. The default is based on experimental data and accounts for
+    /// signal drift.
+    pub trigger_delta: u8,
+    /// Change in averaged difference across the last three windows which confirms a contact, after
+    /// the window following a [`Thresholds::trigger_delta`] change.
+    pub confirm_delta: u8,
+    /// Averaged difference to return to normal operation after contact.
+    ///
+    /// This is the increase in voltage relative to the last detection event. The default is based
+    /// on experimental data and accounts for signal drift.
+    pub restore_delta: u8,
+    /// Increase in average voltage over the calibrated level which indicates the blade is close to
+    /// a conductive surface.
+    ///
+    /// The voltage increases significantly as the blade approaches highly conductive surfaces, even
+    /// without contact.
+    pub proximity_delta: u8,
+    /// Proximity clears once the average voltage falls this far below the proximity threshold
+    pub proximity_hysteresis: u8,
+    /// Change in the [dispersion](crate::impedance::ImpedanceFeatures::dispersion_x100) from its
+    /// calibrated value, in percent, which must accompany a contact while alternating between
+    /// frequencies. Zero reports contact on the amplitude alone.
+    ///
+    /// Wet bone and saline respond almost equally at every frequency, so a contact which does not
+    /// change the shape of the spectrum is not tissue.
+    pub dispersion_delta: u8,
+}
+
+impl Thresholds {
+    /// Default thresholds, usable in `static` initializers
+    pub const fn new() -> Self {
+        Self {
+            trigger_delta: 2,
+            confirm_delta: 1,
+            restore_delta: 2,
+            proximity_delta: 8,
+            proximity_hysteresis: 2,
+            dispersion_delta: 10,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Scale every threshold on the averaged voltage to a drive level of `gain_percent`, so that
+    /// windows taken at a reduced drive are judged as they would be at full drive. Thresholds are
+    /// rounded to the nearest count, but never reduced to zero.
+    ///
+    /// [`Thresholds::dispersion_delta`] is a ratio, and so is unchanged.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::config::Thresholds;
+    ///
+    /// let thresholds = Thresholds::new().at_gain(37);
+    /// assert_eq!(thresholds.trigger_delta, 1);
+    /// assert_eq!(thresholds.proximity_delta, 3);
+    /// assert_eq!(thresholds.dispersion_delta, Thresholds::new().dispersion_delta);
+    /// assert_eq!(Thresholds::new().at_gain(100), Thresholds::new());
+    /// ```
+    pub fn at_gain(&self, gain_percent: u8) -> Self {
+        let scale = |threshold: u8| match threshold {
+            0 => 0,
+            threshold => {
+                let scaled = (threshold as u32 * gain_percent as u32 + 50) / 100;
+                scaled.clamp(1, u8::MAX as u32) as u8
+            }
+        };
+        Self {
+            trigger_delta: scale(self.trigger_delta),
+            confirm_delta: scale(self.confirm_delta),
+            restore_delta: scale(self.restore_delta),
+            proximity_delta: scale(self.proximity_delta),
+            proximity_hysteresis: scale(self.proximity_hysteresis),
+            dispersion_delta: self.dispersion_delta,
+        }
+    }
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,163 @@
+//! Software debouncing for mechanical inputs, such as the disable switch.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use embedded_hal::digital::InputPin;
+
+/// A confirmed change in the level of a debounced input
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Edge {
+    /// Input is now high
+    Rising,
+    /// Input is now low
+    Falling,
+}
+
+/// Counts consecutive samples which disagree with the stable level, and only accepts a new level
+/// once `threshold` samples in a row agree.
+///
+/// ```
+/// use aps490_pfpu2_core::debounce::{Debouncer, Edge};
+///
+/// let mut debouncer = Debouncer::new(false, 3);
+/// // Contact bounce is ignored
+/// assert_eq!(debouncer.update(true), None);
+/// assert_eq!(debouncer.update(false), None);
+/// assert!(debouncer.is_settled());
+/// // A held level is accepted once
+/// assert_eq!(debouncer.update(true), None);
+/// assert_eq!(debouncer.update(true), None);
+/// assert_eq!(debouncer.update(true), Some(Edge::Rising));
+/// assert_eq!(debouncer.update(true), None);
+/// assert!(debouncer.is_high());
+/// ```
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Debouncer {
+    /// Last accepted level
+    stable: bool,
+    /// Consecutive samples which disagree with `stable`
+    pending: u8,
+    /// Samples required to accept a new level
+    threshold: u8,
+}
+
+impl Debouncer {
+    /// Create a debouncer starting at the `initial` level. A `threshold` of 0 is treated as 1.
+    pub const fn new(initial: bool, threshold: u8) -> Self {
+        Self {
+            stable: initial,
+            pending: 0,
+            threshold: if threshold == 0 { 1 } else { threshold },
+        }
+    }
+
+    /// Add a new sample, returning an [`Edge`] only when the accepted level changes
+    pub fn update(&mut self, level: bool) -> Option<Edge> {
+        if level == self.stable {
+            self.pending = 0;
+            return None;
+        }
+
+        self.pending += 1;
+        if self.pending < self.threshold {
+            return None;
+        }
+
+        self.pending = 0;
+        self.stable = level;
+        Some(if level { Edge::Rising } else { Edge::Falling })
+    }
+
+    /// Last accepted level
+    pub fn is_high(&self) -> bool {
+        self.stable
+    }
+
+    /// `true` if no change is currently being confirmed. Sampling can stop until the next edge.
+    pub fn is_settled(&self) -> bool {
+        self.pending == 0
+    }
+}
+
+/// Pairs an [`InputPin`] with a [`Debouncer`].
+///
+/// The pin is only read when [`DebouncedInput::poll`] is called, so the caller decides the sample
+/// rate (ex. from a timer started by a GPIO edge interrupt).
+///
+/// ```
+/// use aps490_pfpu2_core::debounce::{DebouncedInput, Edge};
+/// use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction};
+///
+/// let mut pin = PinMock::new(&[
+///     Transaction::get(State::Low), // Initial level
+///     Transaction::get(State::High),
+///     Transaction::get(State::Low),
+///     Transaction::get(State::High),
+///     Transaction::get(State::High),
+/// ]);
+/// let mut switch = DebouncedInput::new(pin.clone(), 2).unwrap();
+/// assert_eq!(switch.poll().unwrap(), None);
+/// assert_eq!(switch.poll().unwrap(), None);
+/// assert_eq!(switch.poll().unwrap(), None);
+/// assert_eq!(switch.poll().unwrap(), Some(Edge::Rising));
+/// assert!(switch.is_high());
+/// pin.done();
+/// ```
+#[derive(Debug)]
+pub struct DebouncedInput<P: InputPin> {
+    /// Pin being sampled
+    pin: P,
+    /// Debounce state for `pin`
+    debouncer: Debouncer,
+}
+
+impl<P: InputPin> DebouncedInput<P> {
+    /// Wrap `pin`, taking its current level as the initial stable level
+    pub fn new(mut pin: P, threshold: u8) -> Result<Self, P::Error> {
+        let initial = pin.is_high()?;
+        Ok(Self {
+            pin,
+            debouncer: Debouncer::new(initial, threshold),
+        })
+    }
+
+    /// Sample the pin once, returning an [`Edge`] only when the accepted level changes
+    pub fn poll(&mut self) -> Result<Option<Edge>, P::Error> {
+        let level = self.pin.is_high()?;
+        Ok(self.debouncer.update(level))
+    }
+
+    /// Last accepted level
+    pub fn is_high(&self) -> bool {
+        self.debouncer.is_high()
+    }
+
+    /// See [`Debouncer::is_settled`]
+    pub fn is_settled(&self) -> bool {
+        self.debouncer.is_settled()
+    }
+
+    /// Access the underlying pin (ex. to clear interrupts)
+    pub fn pin_mut(&mut self) -> &mut P {
+        &mut self.pin
+    }
+
+    /// Release the underlying pin
+    pub fn free(self) -> P {
+        self.pin
+    }
+}
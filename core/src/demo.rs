@@ -0,0 +1,178 @@
+//! Synthetic ADC windows following a scripted contact scenario, for demonstrations without the
+//! analog front end.
+//!
+//! A script is a list of [`Step`]s, each moving the high and low readings linearly to new values
+//! over a number of windows. Scripts loop, starting from the end of the last step. Readings
+//! alternate between the high and low values, as the ADC samples at twice the excitation
+//! frequency, with up to 1 count of noise. The noise is only ever added, so that the averages of
+//! a steady signal are not truncated to different values from one window to the next.
+//!
+//! ```
+//! use aps490_pfpu2_core::{
+//!     demo::{DemoSignal, Step},
+//!     signal::{AlignedAverages, WINDOW_SIZE},
+//! };
+//!
+//! static SCRIPT: [Step; 2] = [
+//!     Step { windows: 2, high: 150, low: 100 },
+//!     Step { windows: 1, high: 120, low: 100 },
+//! ];
+//! let mut demo = DemoSignal::new(&SCRIPT);
+//! let mut window = [0; WINDOW_SIZE];
+//! let mut deltas = [0; 4];
+//! for delta in deltas.iter_mut() {
+//!     demo.fill(&mut window, true);
+//!     *delta = AlignedAverages::from_window(&window).get_delta();
+//! }
+//! // The first step ramps from the end of the script, then the script loops
+//! for (delta, expected) in deltas.into_iter().zip([35, 50, 20, 35]) {
+//!     assert!(delta.abs_diff(expected) <= 1);
+//! }
+//!
+//! // Without excitation, readings fall to near 0
+//! demo.fill(&mut window, false);
+//! assert!(AlignedAverages::from_window(&window).get_level() <= 2);
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::signal::WINDOW_SIZE;
+
+/// Part of a demo script
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Step {
+    /// Windows taken to reach `high` and `low`. The values are held for the last window.
+    pub windows: u16,
+    /// Reading in the high half of the signal at the end of the step
+    pub high: u8,
+    /// Reading in the low half of the signal at the end of the step
+    pub low: u8,
+}
+
+/// Script used by the firmware's `demo_mode`, lasting about 30 s with 20 ms windows:
+///
+/// 1. Idle in air, long enough to calibrate
+/// 2. Voltage rises as the blade approaches, reporting proximity
+/// 3. Contact, with a sudden drop in the range of the signal
+/// 4. Blade withdraws slowly enough not to trigger another contact
+pub static DEMO_SCRIPT: [Step; 8] = [
+    Step {
+        windows: 500,
+        high: 148,
+        low: 105,
+    },
+    Step {
+        windows: 24,
+        high: 160,
+        low: 117,
+    },
+    Step {
+        windows: 100,
+        high: 160,
+        low: 117,
+    },
+    Step {
+        windows: 1,
+        high: 140,
+        low: 117,
+    },
+    Step {
+        windows: 250,
+        high: 140,
+        low: 117,
+    },
+    Step {
+        windows: 24,
+        high: 140,
+        low: 105,
+    },
+    Step {
+        windows: 16,
+        high: 148,
+        low: 105,
+    },
+    Step {
+        windows: 500,
+        high: 148,
+        low: 105,
+    },
+];
+
+/// Generates windows from a looping script
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DemoSignal {
+    /// Steps to follow
+    script: &'static [Step],
+    /// Index of the current step
+    step: usize,
+    /// Windows generated so far in the current step
+    window: u16,
+    /// State of the noise generator
+    noise: u32,
+}
+
+impl DemoSignal {
+    /// Start at the beginning of `script`, which must not be empty. Usable in `static`
+    /// initializers.
+    pub const fn new(script: &'static [Step]) -> Self {
+        assert!(
+            !script.is_empty(),
+            "Demo script must have at least one step"
+        );
+        Self {
+            script,
+            step: 0,
+            window: 0,
+            noise: 0x2545_f491,
+        }
+    }
+
+    /// High and low readings for the next window
+    pub fn levels(&self) -> (u8, u8) {
+        let step = &self.script[self.step];
+        let prev = &self.script[(self.step + self.script.len() - 1) % self.script.len()];
+        let done = (self.window as i32 + 1).min(step.windows.max(1) as i32);
+        let ramp = |from: u8, to: u8| {
+            (from as i32 + (to as i32 - from as i32) * done / step.windows.max(1) as i32) as u8
+        };
+        (ramp(prev.high, step.high), ramp(prev.low, step.low))
+    }
+
+    /// Fill a window with the next readings of the script. If `excitation` is `false`, such as
+    /// during a self-test, the readings are only noise near 0.
+    pub fn fill(&mut self, window: &mut [u8; WINDOW_SIZE], excitation: bool) {
+        let (high, low) = if excitation { self.levels() } else { (1, 1) };
+        for (idx, reading) in window.iter_mut().enumerate() {
+            let level = if idx % 2 == 0 { high } else { low };
+            *reading = level.saturating_add(self.next_noise());
+        }
+
+        self.window += 1;
+        if self.window >= self.script[self.step].windows {
+            self.window = 0;
+            self.step = (self.step + 1) % self.script.len();
+        }
+    }
+
+    /// Pseudo-random 0 or 1, using xorshift
+    fn next_noise(&mut self) -> u8 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        (self.noise & 1) as u8
+    }
+}
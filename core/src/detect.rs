@@ -0,0 +1,517 @@
+//! Contact, proximity, and calibration logic, run once per analysis window.
+//!
+//! The firmware feeds every window into a [`Detector`] from `DMA_IRQ_0`, and host tools run the
+//! same detector over recordings to measure accuracy.
+//!
+//! ```
+//! use aps490_pfpu2_core::{config::Thresholds, detect::Detector, state::StatusLedStates};
+//!
+//! let thresholds = Thresholds::new();
+//! let mut detector = Detector::new();
+//! // Fill the history before detecting, as the firmware does while calibrating
+//! detector.insert(45);
+//! detector.insert(45);
+//!
+//! let mut state = StatusLedStates::Armed;
+//! let mut alerts = Vec::new();
+//! for (window, delta) in [45, 40, 39, 39, 39].into_iter().enumerate() {
+//!     if let Some(next) = detector.update(state, delta, 100, &thresholds) {
+//!         if next == StatusLedStates::Contact {
+//!             alerts.push(window);
+//!         }
+//!         state = next;
+//!     }
+//! }
+//! assert_eq!(alerts, [2]);
+//! assert_eq!(state, StatusLedStates::Contact);
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{config::Thresholds, impedance::ImpedanceFeatures, state::StatusLedStates};
+
+/// Tracks recent windows and decides when the detection state should change
+///
+/// Contact detection matches the checks which `Buffers` once made on its long-term buffer. Those
+/// indexed the buffer with the unwrapped sample counter, and panicked once it first wrapped after
+/// 45000 windows (15 minutes at 100 kHz), so the detector keeps its own history instead.
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Detector {
+    /// Averaged differences of the three most recent windows, most recent first
+    recent: [u8; Self::HISTORY],
+    /// Time covered by each window recorded, in microseconds
+    window_us: u32,
+    /// Time recorded so far, used to time contact events. Wraps after about 71 minutes.
+    elapsed_us: u32,
+    /// A potential detection event or event clear has been recorded, and the detector is awaiting a
+    /// second window
+    await_confirm: bool,
+    /// Time and averaged difference of the most recent contact
+    last_contact: Option<(u32, u8)>,
+    /// Idle average voltage, recorded during [`StatusLedStates::Calibrating`]
+    baseline_level: Option<u8>,
+    /// Sum of average voltages recorded so far during calibration
+    calibration_sum: u32,
+    /// Number of windows recorded so far during calibration
+    calibration_windows: u16,
+    /// Time recorded so far during calibration, in microseconds
+    calibration_us: u32,
+    /// Idle [dispersion](ImpedanceFeatures::dispersion_x100), if calibrated while alternating
+    /// between frequencies
+    baseline_dispersion: Option<i32>,
+    /// Sum of dispersions recorded so far during calibration
+    dispersion_sum: i32,
+    /// Number of dispersions recorded so far during calibration
+    dispersion_windows: u16,
+}
+
+impl Detector {
+    /// Time averaged to find the idle signal level, in microseconds.
+    ///
+    /// Currently set to 5 s (250 windows at 100 kHz, 50 windows at 20 kHz)
+    pub const CALIBRATION_US: u32 = 5_000_000;
+    /// A contact will not clear until this much time has been recorded, in microseconds (3 s, or
+    /// 150 windows at 100 kHz). This ensures the operator will see the LED light up.
+    pub const MIN_CONTACT_US: u32 = 3_000_000;
+    /// Length of a window at the startup excitation of 100 kHz, sampled twice per cycle, in
+    /// microseconds. Used until [`Detector::set_window_us`] is called.
+    pub const DEFAULT_WINDOW_US: u32 = 20_000;
+    /// Number of recent windows compared by [`Detector::detect_contact`]
+    pub const HISTORY: usize = 3;
+
+    /// Create a detector which has not seen any windows or been calibrated. Usable in `static`
+    /// initializers.
+    pub const fn new() -> Self {
+        Self {
+            recent: [0; Self::HISTORY],
+            window_us: Self::DEFAULT_WINDOW_US,
+            elapsed_us: 0,
+            await_confirm: false,
+            last_contact: None,
+            baseline_level: None,
+            calibration_sum: 0,
+            calibration_windows: 0,
+            calibration_us: 0,
+            baseline_dispersion: None,
+            dispersion_sum: 0,
+            dispersion_windows: 0,
+        }
+    }
+
+    /// Set the time covered by each window recorded from now on, in microseconds, such as from
+    /// `ClockPlan::window_us`. Windows hold a fixed number of readings, so they lengthen as the
+    /// excitation slows, and calibration and contacts are timed by the time recorded rather than
+    /// by the number of windows.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{config::Thresholds, detect::Detector, state::StatusLedStates};
+    ///
+    /// let thresholds = Thresholds::new();
+    /// let mut detector = Detector::new();
+    /// assert_eq!(detector.calibration_windows(), 250);
+    ///
+    /// // Windows at 20 kHz are five times as long, so calibration takes the same 5 s
+    /// detector.set_window_us(100_000);
+    /// assert_eq!(detector.calibration_windows(), 50);
+    /// assert_eq!(detector.min_contact_windows(), 30);
+    /// let windows = (1..)
+    ///     .find(|_| detector.update(StatusLedStates::Calibrating, 45, 50, &thresholds).is_some());
+    /// assert_eq!(windows, Some(50));
+    /// ```
+    pub fn set_window_us(&mut self, window_us: u32) {
+        self.window_us = window_us.max(1);
+    }
+
+    /// Time covered by each window, in microseconds
+    pub fn window_us(&self) -> u32 {
+        self.window_us
+    }
+
+    /// Number of windows of the current length in [`Detector::CALIBRATION_US`]
+    pub fn calibration_windows(&self) -> u32 {
+        Self::CALIBRATION_US.div_ceil(self.window_us)
+    }
+
+    /// Number of windows of the current length in [`Detector::MIN_CONTACT_US`]
+    pub fn min_contact_windows(&self) -> u32 {
+        Self::MIN_CONTACT_US.div_ceil(self.window_us)
+    }
+
+    /// Record a window and run the check for the current `state`, returning the next state if it
+    /// should change.
+    ///
+    /// `delta` is the averaged difference between the high and low halves of the window, and `level`
+    /// is the average voltage across both halves.
+    pub fn update(
+        &mut self,
+        state: StatusLedStates,
+        delta: u8,
+        level: u8,
+        thresholds: &Thresholds,
+    ) -> Option<StatusLedStates> {
+        self.insert(delta);
+        match state {
+            StatusLedStates::Calibrating => self.calibrate(level).then_some(StatusLedStates::Armed),
+            StatusLedStates::Armed | StatusLedStates::Proximity => {
+                let in_proximity = state == StatusLedStates::Proximity;
+                if self.detect_contact(thresholds) {
+                    Some(StatusLedStates::Contact)
+                } else if self.detect_proximity(level, in_proximity, thresholds) != in_proximity {
+                    Some(if in_proximity {
+                        StatusLedStates::Armed
+                    } else {
+                        StatusLedStates::Proximity
+                    })
+                } else {
+                    None
+                }
+            }
+            StatusLedStates::Contact => self
+                .detect_end_contact(thresholds)
+                .then_some(StatusLedStates::Armed),
+            StatusLedStates::Booting
+            | StatusLedStates::SelfTest
+            | StatusLedStates::Fault
+            | StatusLedStates::Disabled => None,
+        }
+    }
+
+    /// Record a complete cycle of a [`MultiFrequency`](crate::impedance::MultiFrequency) and run
+    /// the check for the current `state`, returning the next state if it should change.
+    ///
+    /// The first tone drives [`Detector::update`], and a contact is only reported if
+    /// [`Detector::detect_tissue`] agrees. Each cycle covers the
+    /// [`cycle_us`](ImpedanceFeatures::cycle_us) of its tones, which replaces
+    /// [`Detector::window_us`].
+    ///
+    /// The excitation keeps moving between tones during a contact. Only complete cycles reach the
+    /// detector, and only the first tone enters the history, so the other tones and any window
+    /// left at the previous tone while retuning cannot raise a second contact or clear one early.
+    /// A contact clears once [`Detector::MIN_CONTACT_US`] of whole cycles have been recorded.
+    ///
+    /// Below, touching bone lowers the response evenly across both tones and is ignored, while
+    /// brain also changes the spectrum and is reported:
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{
+    ///     config::Thresholds,
+    ///     detect::Detector,
+    ///     impedance::{MultiFrequency, ToneFeatures},
+    ///     signal::WINDOW_SIZE,
+    ///     spectrum::FrequencyList,
+    ///     state::StatusLedStates,
+    /// };
+    ///
+    /// let quarter = (WINDOW_SIZE / 4) as i32;
+    /// let tone = |excitation_hz, amplitude| {
+    ///     let partial_sums = [100 + amplitude, 100, 100 + amplitude, 100];
+    ///     let partial_sums = partial_sums.map(|sum| sum * quarter);
+    ///     ToneFeatures::from_partial_sums(excitation_hz, &partial_sums, 2)
+    /// };
+    /// let list: FrequencyList = "20000 100000".parse().unwrap();
+    /// let mut multi = MultiFrequency::new(list).unwrap();
+    /// let mut detector = Detector::new();
+    /// let thresholds = Thresholds::new();
+    /// let mut state = StatusLedStates::Calibrating;
+    /// // Amplitude at 20 kHz and 100 kHz of each cycle: wet bone, bone, then brain until the blade
+    /// // is lifted back to wet bone
+    /// let cycles = [(45, 45); 260]
+    ///     .into_iter()
+    ///     .chain([(40, 40); 5])
+    ///     .chain([(30, 38); 40])
+    ///     .chain([(45, 45); 10]);
+    /// let mut transitions = Vec::new();
+    /// for (cycle, amplitudes) in cycles.enumerate() {
+    ///     // Left at the last tone of the previous cycle while retuning
+    ///     assert_eq!(multi.record(tone(100_000, 0)), None);
+    ///     assert_eq!(multi.record(tone(20_000, amplitudes.0)), None);
+    ///     let features = multi.record(tone(100_000, amplitudes.1)).unwrap();
+    ///     assert_eq!(features.cycle_us(), 120_000);
+    ///     if let Some(next) = detector.update_features(state, &features, &thresholds) {
+    ///         transitions.push((cycle, next));
+    ///         state = next;
+    ///     }
+    /// }
+    /// // Calibrated after 5 s of 120 ms cycles. The bone is ignored, and the brain is reported
+    /// // once and clears after 3 s of cycles.
+    /// assert_eq!(
+    ///     transitions,
+    ///     [
+    ///         (41, StatusLedStates::Armed),
+    ///         (266, StatusLedStates::Contact),
+    ///         (291, StatusLedStates::Armed),
+    ///     ]
+    /// );
+    /// ```
+    pub fn update_features(
+        &mut self,
+        state: StatusLedStates,
+        features: &ImpedanceFeatures,
+        thresholds: &Thresholds,
+    ) -> Option<StatusLedStates> {
+        self.set_window_us(features.cycle_us());
+        let dispersion = features.dispersion_x100();
+        if state == StatusLedStates::Calibrating && !self.is_calibrated() {
+            if let Some(dispersion) = dispersion {
+                self.dispersion_sum += dispersion;
+                self.dispersion_windows += 1;
+            }
+        }
+
+        let primary = features.primary();
+        match self.update(state, primary.delta(), primary.level(), thresholds) {
+            Some(StatusLedStates::Contact) if !self.detect_tissue(dispersion, thresholds) => None,
+            Some(StatusLedStates::Armed) if state == StatusLedStates::Calibrating => {
+                self.baseline_dispersion = (self.dispersion_windows > 0)
+                    .then(|| self.dispersion_sum / self.dispersion_windows as i32);
+                Some(StatusLedStates::Armed)
+            }
+            next_state => next_state,
+        }
+    }
+
+    /// Record the averaged difference of the latest window
+    pub fn insert(&mut self, delta: u8) {
+        self.recent.rotate_right(1);
+        self.recent[0] = delta;
+        self.elapsed_us = self.elapsed_us.wrapping_add(self.window_us);
+    }
+
+    /// Determine if a contact event has occurred, using [`Thresholds::trigger_delta`].
+    ///
+    /// A change of at least `trigger_delta` between the last two windows must be confirmed in the next
+    /// window by a change of at least [`Thresholds::confirm_delta`] across the last three windows.
+    pub fn detect_contact(&mut self, thresholds: &Thresholds) -> bool {
+        if !self.await_confirm {
+            // First contact check
+            if self.recent[1].abs_diff(self.recent[0]) >= thresholds.trigger_delta {
+                self.await_confirm = true;
+            }
+            false
+        } else {
+            // Validation contact check
+            self.await_confirm = false; // Always reset on validation check
+            if self.recent[2].abs_diff(self.recent[0]) >= thresholds.confirm_delta {
+                // Contact detected!
+                self.last_contact = Some((self.elapsed_us, self.recent[0]));
+                return true;
+            }
+            false
+        }
+    }
+
+    /// Determine when contact ends, using [`Thresholds::restore_delta`].
+    ///
+    /// A contact will not clear until at least [`Detector::MIN_CONTACT_US`] have been recorded.
+    pub fn detect_end_contact(&mut self, thresholds: &Thresholds) -> bool {
+        let Some((contact_us, delta)) = self.last_contact else {
+            return false;
+        };
+        if self.elapsed_us.wrapping_sub(contact_us) >= Self::MIN_CONTACT_US {
+            self.await_confirm = false;
+            return true;
+        } else if !self.await_confirm && self.recent[0].abs_diff(delta) >= thresholds.restore_delta
+        {
+            // First clear check
+            self.await_confirm = true;
+        }
+        false
+    }
+
+    /// Discard the current idle level and begin a new calibration
+    pub fn start_calibration(&mut self) {
+        self.baseline_level = None;
+        self.calibration_sum = 0;
+        self.calibration_windows = 0;
+        self.calibration_us = 0;
+        self.baseline_dispersion = None;
+        self.dispersion_sum = 0;
+        self.dispersion_windows = 0;
+    }
+
+    /// Record the average voltage of a window during calibration.
+    ///
+    /// Returns `true` once [`Detector::CALIBRATION_US`] have been recorded and the idle level is
+    /// set.
+    pub fn calibrate(&mut self, level: u8) -> bool {
+        if self.baseline_level.is_some() {
+            return true;
+        }
+
+        self.calibration_sum += level as u32;
+        self.calibration_windows += 1;
+        self.calibration_us = self.calibration_us.saturating_add(self.window_us);
+        if self.calibration_us >= Self::CALIBRATION_US {
+            self.baseline_level =
+                Some((self.calibration_sum / self.calibration_windows as u32) as u8);
+            return true;
+        }
+        false
+    }
+
+    /// `true` if an idle level has been recorded since the last [`Detector::start_calibration`]
+    pub fn is_calibrated(&self) -> bool {
+        self.baseline_level.is_some()
+    }
+
+    /// Idle average voltage from the last calibration, if complete
+    pub fn baseline_level(&self) -> Option<u8> {
+        self.baseline_level
+    }
+
+    /// Idle dispersion from the last calibration, if calibrated while alternating between
+    /// frequencies
+    pub fn baseline_dispersion(&self) -> Option<i32> {
+        self.baseline_dispersion
+    }
+
+    /// Check whether `dispersion_x100` has moved [`Thresholds::dispersion_delta`] percent away from
+    /// the calibrated dispersion, indicating the blade is touching tissue rather than bone or
+    /// saline.
+    ///
+    /// Contact is never suppressed without both dispersions, or when the threshold is zero.
+    pub fn detect_tissue(&self, dispersion_x100: Option<i32>, thresholds: &Thresholds) -> bool {
+        let (Some(baseline), Some(dispersion)) = (self.baseline_dispersion, dispersion_x100) else {
+            return true;
+        };
+        if thresholds.dispersion_delta == 0 {
+            return true;
+        }
+        dispersion.abs_diff(baseline) * 100
+            >= baseline.unsigned_abs().max(1) * thresholds.dispersion_delta as u32
+    }
+
+    /// Check whether the average voltage of a window is [`Thresholds::proximity_delta`] above the
+    /// calibrated level, indicating the blade is close to a conductive surface.
+    ///
+    /// `in_proximity` should be `true` if proximity is currently reported, so that it is not
+    /// cleared until the voltage falls below the threshold by
+    /// [`Thresholds::proximity_hysteresis`].
+    pub fn detect_proximity(&self, level: u8, in_proximity: bool, thresholds: &Thresholds) -> bool {
+        let Some(baseline) = self.baseline_level else {
+            return false;
+        };
+        let threshold = baseline.saturating_add(thresholds.proximity_delta);
+        if in_proximity {
+            level >= threshold.saturating_sub(thresholds.proximity_hysteresis)
+        } else {
+            level >= threshold
+        }
+    }
+}
+
+impl Default for Detector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `detect_contact` and `detect_end_contact` as they were in `Buffers`
+    struct Legacy {
+        buffer: [u8; 45_000],
+        counter: usize,
+        await_confirm: bool,
+        last_detection: Option<(usize, u8)>,
+    }
+
+    impl Legacy {
+        fn insert(&mut self, sample: u8) {
+            let head = (self.counter % self.buffer.len() + 1) % self.buffer.len();
+            self.buffer[head] = sample;
+            self.counter += 1;
+        }
+
+        fn detect_contact(&mut self, thresholds: &Thresholds) -> bool {
+            let current = self.buffer[self.counter] as i16;
+            if !self.await_confirm {
+                let prev = self.buffer[self.counter - 1] as i16;
+                self.await_confirm = (prev - current).abs() >= thresholds.trigger_delta as i16;
+                return false;
+            }
+            self.await_confirm = false;
+            let prev_high = self.buffer[self.counter - 2] as i16;
+            if (prev_high - current).abs() >= thresholds.confirm_delta as i16 {
+                self.last_detection = Some((self.counter, self.buffer[self.counter]));
+                return true;
+            }
+            false
+        }
+
+        fn detect_end_contact(&mut self, thresholds: &Thresholds) -> bool {
+            let (counter, sample) = self.last_detection.unwrap();
+            if self.counter - counter >= 150 {
+                self.await_confirm = false;
+                return true;
+            } else if !self.await_confirm
+                && (self.buffer[self.counter] as i16 - sample as i16).abs()
+                    >= thresholds.restore_delta as i16
+            {
+                self.await_confirm = true;
+            }
+            false
+        }
+    }
+
+    /// The detector raises and clears the same alerts as the old checks, for as long as those
+    /// could run
+    #[test]
+    fn matches_legacy_buffer_checks() {
+        let thresholds = Thresholds::new();
+        let mut legacy = Legacy {
+            buffer: [0; 45_000],
+            counter: 0,
+            await_confirm: false,
+            last_detection: None,
+        };
+        let mut detector = Detector::new();
+        for _ in 0..2 {
+            legacy.insert(45);
+            detector.insert(45);
+        }
+
+        // Noisy idle signal, with a contact every 1000 windows
+        let mut noise = 1u32;
+        let (mut legacy_state, mut state) = (StatusLedStates::Armed, StatusLedStates::Armed);
+        let mut alerts = 0;
+        for window in 0..40_000 {
+            noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let base = if window % 1000 < 200 { 45 } else { 38 };
+            let delta = base + (noise >> 16) as u8 % 2;
+
+            legacy.insert(delta);
+            let legacy_next = match legacy_state {
+                StatusLedStates::Armed => legacy
+                    .detect_contact(&thresholds)
+                    .then_some(StatusLedStates::Contact),
+                _ => legacy
+                    .detect_end_contact(&thresholds)
+                    .then_some(StatusLedStates::Armed),
+            };
+            // Proximity is never reported without calibration
+            let next = detector.update(state, delta, 0, &thresholds);
+            assert_eq!(legacy_next, next, "window {window}");
+
+            alerts += (next == Some(StatusLedStates::Contact)) as u32;
+            legacy_state = legacy_next.unwrap_or(legacy_state);
+            state = next.unwrap_or(state);
+        }
+        assert!(alerts >= 40);
+    }
+}
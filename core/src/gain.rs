@@ -0,0 +1,381 @@
+//! Automatic gain control of the excitation drive level.
+//!
+//! The response at the ADC varies widely between setups, from readings which clip at both ends of
+//! the range to swings of a few dozen counts. The drive is reduced by narrowing the pulses of the
+//! excitation through a fixed set of [`DRIVE_LEVELS`]. A [`GainControl`] watches the
+//! [`WindowRange`] of every window, and steps the drive down while readings clip, or back up while
+//! the response is small enough to stay in range at the stronger drive.
+//!
+//! The drive is held at full by default, and [`GainSetting::Auto`] must be selected to adjust it.
+//! Narrower pulses are not yet known to give a smaller response on the hardware (see below), so
+//! stepping the drive down may not bring the readings back into range.
+//!
+//! Every window is recorded with the [`DriveLevel::gain_percent`] it was taken at, and thresholds
+//! are scaled to match with [`Thresholds::at_gain`](crate::config::Thresholds::at_gain), so
+//! detection behaves the same at any drive level.
+//!
+//! The delta is the difference between two readings in each cycle, taken at fixed points whatever
+//! the duty cycle, so a narrow pulse has often ended before the high reading and only its decay is
+//! seen. How much of the response remains depends on the front end and what the blade touches,
+//! not just the duty cycle. The gain of each level is therefore measured, as the ratio of the
+//! delta after each change of level to the delta before it, and the nominal gain is only used
+//! until then. This was checked against the host simulator with phase-locked readings
+//! (`Simulator::phase_locked`): with the default circuit, the deltas at each level relative to full
+//! drive were 100, 375, 337, 200 and 112% in air and 100, 500, 433, 266 and 133% on thin bone,
+//! against the nominal 100, 81, 59, 37 and 22%, and the measured gains follow the simulated
+//! deltas. It has not been repeated on the hardware.
+//!
+//! ```
+//! use aps490_pfpu2_core::gain::{GainControl, WindowRange, DRIVE_LEVELS};
+//!
+//! let mut gain = GainControl::new();
+//! // Readings swing across the whole range, clipping at both ends
+//! let clipped = WindowRange { min: 0, max: 255, clipped: 800 };
+//! let steps = (0..150).filter(|_| gain.update(&clipped, 60)).count();
+//! assert_eq!(steps, 4);
+//! // Clipped windows do not show the full response, so no gain has been measured
+//! assert_eq!(gain.drive(), DRIVE_LEVELS[4]);
+//!
+//! // At the weakest drive, the swing is small enough to step back up
+//! let small = WindowRange { min: 100, max: 140, clipped: 0 };
+//! while !gain.update(&small, 10) {}
+//! assert_eq!(gain.level(), 3);
+//! // The delta doubles at the stronger level, so its gain is twice the nominal 22%
+//! for _ in 0..GainControl::MEASURE_WINDOWS {
+//!     gain.update(&small, 20);
+//! }
+//! assert_eq!(gain.drive().gain_percent, 44);
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{fmt, str::FromStr};
+
+use crate::{command::ParseError, signal::WINDOW_SIZE};
+
+/// Duty cycle of the excitation, and the response it produces
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DriveLevel {
+    /// Duty cycle of the square wave excitation, in percent
+    pub duty_percent: u8,
+    /// Delta relative to full drive, in percent. In [`DRIVE_LEVELS`], the nominal gain: the
+    /// fundamental of a pulse of duty cycle `d` is proportional to sin(π<i>d</i>). From
+    /// [`GainControl::drive`], the gain measured at that level once known.
+    pub gain_percent: u8,
+}
+
+/// Drive levels available, from full drive to the weakest, with their nominal gains
+pub const DRIVE_LEVELS: [DriveLevel; 5] = [
+    DriveLevel {
+        duty_percent: 50,
+        gain_percent: 100,
+    },
+    DriveLevel {
+        duty_percent: 30,
+        gain_percent: 81,
+    },
+    DriveLevel {
+        duty_percent: 20,
+        gain_percent: 59,
+    },
+    DriveLevel {
+        duty_percent: 12,
+        gain_percent: 37,
+    },
+    DriveLevel {
+        duty_percent: 7,
+        gain_percent: 22,
+    },
+];
+
+/// Extremes of the readings in one window
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WindowRange {
+    /// Lowest reading
+    pub min: u8,
+    /// Highest reading
+    pub max: u8,
+    /// Number of readings at either end of the ADC range
+    pub clipped: u16,
+}
+
+impl WindowRange {
+    /// Find the extremes of a window of readings
+    pub fn from_window(window: &[u8; WINDOW_SIZE]) -> Self {
+        window.iter().fold(
+            Self {
+                min: u8::MAX,
+                max: u8::MIN,
+                clipped: 0,
+            },
+            |range, reading| Self {
+                min: range.min.min(*reading),
+                max: range.max.max(*reading),
+                clipped: range.clipped + matches!(*reading, u8::MIN | u8::MAX) as u16,
+            },
+        )
+    }
+
+    /// Difference between the highest and lowest readings
+    pub fn span(&self) -> u8 {
+        self.max.saturating_sub(self.min)
+    }
+}
+
+/// How the drive level is chosen. Defaults to full drive.
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GainSetting {
+    /// Adjusted by a [`GainControl`]
+    Auto,
+    /// Held at an index into [`DRIVE_LEVELS`]
+    Fixed(u8),
+}
+
+impl Default for GainSetting {
+    fn default() -> Self {
+        GainSetting::Fixed(0)
+    }
+}
+
+impl FromStr for GainSetting {
+    type Err = ParseError;
+
+    /// Parse `auto`, or an index into [`DRIVE_LEVELS`]
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{command::ParseError, gain::GainSetting};
+    ///
+    /// assert_eq!("AUTO".parse(), Ok(GainSetting::Auto));
+    /// assert_eq!("2".parse(), Ok(GainSetting::Fixed(2)));
+    /// assert_eq!("5".parse::<GainSetting>(), Err(ParseError::InvalidValue));
+    /// ```
+    fn from_str(word: &str) -> Result<Self, Self::Err> {
+        if word.eq_ignore_ascii_case("auto") {
+            return Ok(GainSetting::Auto);
+        }
+        match word.parse::<u8>() {
+            Ok(level) if (level as usize) < DRIVE_LEVELS.len() => Ok(GainSetting::Fixed(level)),
+            _ => Err(ParseError::InvalidValue),
+        }
+    }
+}
+
+impl fmt::Display for GainSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GainSetting::Auto => f.write_str("auto"),
+            GainSetting::Fixed(level) => write!(f, "{}", level),
+        }
+    }
+}
+
+/// Chooses the drive level from the range of recent windows, and measures the gain of each level
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GainControl {
+    /// Index into [`DRIVE_LEVELS`]
+    level: usize,
+    /// Level which recent windows have asked for, if different
+    pending: Option<usize>,
+    /// Number of consecutive windows which have asked for `pending`
+    windows: u16,
+    /// Gain measured at each level, in percent, where known. Not limited to `u8`, so a large gain
+    /// at one level does not distort those measured against it.
+    measured: [Option<u16>; DRIVE_LEVELS.len()],
+    /// Sum of the deltas of consecutive windows at the current level which were not clipped
+    delta_sum: u32,
+    /// Number of windows in `delta_sum`
+    delta_windows: u16,
+    /// Mean delta of the last [`GainControl::MEASURE_WINDOWS`] unclipped windows at the current
+    /// level, in hundredths of an ADC count
+    mean_x100: Option<u32>,
+    /// Previous level and its mean delta, while the gain at the current level is measured
+    previous: Option<(usize, u32)>,
+    /// For each level, the span at the next stronger level which made the drive step down to it
+    /// last, and the span of the first window after the change
+    step_spans: [Option<(u8, u8)>; DRIVE_LEVELS.len()],
+    /// Span which made the drive step down, until the first window at the weaker level
+    stepped_from: Option<u8>,
+}
+
+impl GainControl {
+    /// Readings at the ends of the ADC range allowed in a window before the drive is reduced
+    /// (1% of a window)
+    pub const MAX_CLIPPED: u16 = (WINDOW_SIZE / 100) as u16;
+    /// Widest span of readings before the drive is reduced, leaving headroom for contact
+    pub const MAX_SPAN: u8 = 224;
+    /// Narrowest span of readings before the drive is increased
+    pub const MIN_SPAN: u8 = 64;
+    /// Consecutive windows which must ask for the same change before it is made
+    pub const ADJUST_WINDOWS: u16 = 25;
+    /// Consecutive unclipped windows averaged for the delta at each level when measuring gain
+    pub const MEASURE_WINDOWS: u16 = 8;
+    /// Smallest mean delta at the previous level which a gain is measured against, in ADC counts
+    pub const MIN_MEASURE_DELTA: u8 = 8;
+
+    /// Start at full drive, with no gains measured
+    pub const fn new() -> Self {
+        Self {
+            level: 0,
+            pending: None,
+            windows: 0,
+            measured: [None; DRIVE_LEVELS.len()],
+            delta_sum: 0,
+            delta_windows: 0,
+            mean_x100: None,
+            previous: None,
+            step_spans: [None; DRIVE_LEVELS.len()],
+            stepped_from: None,
+        }
+    }
+
+    /// Index of the current level in [`DRIVE_LEVELS`]
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Current drive level, with its measured gain once known. Gains above 255% are reported as
+    /// 255%.
+    pub fn drive(&self) -> DriveLevel {
+        DriveLevel {
+            duty_percent: DRIVE_LEVELS[self.level].duty_percent,
+            gain_percent: self.gain_percent(self.level).min(u8::MAX as u32) as u8,
+        }
+    }
+
+    /// Gain at `level`, in percent: measured if known, otherwise nominal
+    fn gain_percent(&self, level: usize) -> u32 {
+        self.measured[level].map_or(DRIVE_LEVELS[level].gain_percent as u32, u32::from)
+    }
+
+    /// Move to `level`, limited to the weakest drive, and forget any pending change. The gain at
+    /// the new level is measured against the recent delta at the current level, if there is one.
+    pub fn set_level(&mut self, level: usize) {
+        let level = level.min(DRIVE_LEVELS.len() - 1);
+        if level != self.level {
+            self.previous = self
+                .mean_x100
+                .filter(|mean_x100| *mean_x100 >= Self::MIN_MEASURE_DELTA as u32 * 100)
+                .map(|mean_x100| (self.level, mean_x100));
+            self.mean_x100 = None;
+            self.delta_sum = 0;
+            self.delta_windows = 0;
+        }
+        self.level = level;
+        self.pending = None;
+        self.windows = 0;
+        self.stepped_from = None;
+    }
+
+    /// Record the `delta` of the latest window at the current level. Once
+    /// [`GainControl::MEASURE_WINDOWS`] unclipped windows follow a change of level, the gain at
+    /// the new level is measured from the ratio of their mean delta to the one before the change.
+    /// Windows with more than [`GainControl::MAX_CLIPPED`] readings clipped do not show the whole
+    /// response, and restart the average.
+    pub fn record(&mut self, range: &WindowRange, delta: u8) {
+        if range.clipped > Self::MAX_CLIPPED {
+            self.delta_sum = 0;
+            self.delta_windows = 0;
+            return;
+        }
+        self.delta_sum += delta as u32;
+        self.delta_windows += 1;
+        if self.delta_windows < Self::MEASURE_WINDOWS {
+            return;
+        }
+
+        let mean_x100 = self.delta_sum * 100 / self.delta_windows as u32;
+        self.mean_x100 = Some(mean_x100);
+        self.delta_sum = 0;
+        self.delta_windows = 0;
+        if let Some((level, previous_x100)) = self.previous.take() {
+            let gain = self.gain_percent(level) * mean_x100 / previous_x100;
+            self.measured[self.level] = Some(gain.clamp(1, u16::MAX as u32) as u16);
+        }
+    }
+
+    /// Record the range and `delta` of the latest window, returning `true` if the drive level
+    /// changed.
+    ///
+    /// The span includes the level and noise of the readings, so it does not follow the gain of
+    /// the delta. Instead, the span which made the drive step down from each level is kept, and
+    /// the drive is only increased again if that span, scaled by how much the span has changed
+    /// since the step, stays below [`GainControl::MAX_SPAN`]. A level which has never been
+    /// stepped down from is always tried.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::gain::{GainControl, WindowRange};
+    ///
+    /// let mut gain = GainControl::new();
+    /// let wide = WindowRange { min: 10, max: 250, clipped: 0 };
+    /// while !gain.update(&wide, 60) {}
+    /// assert_eq!(gain.level(), 1);
+    /// // The span at the weaker level is narrow, but was as wide as this just after stepping down
+    /// let narrow = WindowRange { min: 100, max: 160, clipped: 0 };
+    /// assert!((0..100).all(|_| !gain.update(&narrow, 40)));
+    /// // Once the span halves, it is expected to stay in range at full drive
+    /// let half = WindowRange { min: 100, max: 130, clipped: 0 };
+    /// assert!((0..100).any(|_| gain.update(&half, 20)));
+    /// assert_eq!(gain.level(), 0);
+    /// ```
+    pub fn update(&mut self, range: &WindowRange, delta: u8) -> bool {
+        self.record(range, delta);
+        let clipped = range.clipped > Self::MAX_CLIPPED;
+        if let Some(span) = self.stepped_from.take() {
+            self.step_spans[self.level] = Some((span, range.span().max(1)));
+        }
+        let weaker = (self.level + 1 < DRIVE_LEVELS.len()).then_some(self.level + 1);
+        let stronger = self.level.checked_sub(1).filter(|_| {
+            self.step_spans[self.level].is_none_or(|(stepped_span, first_span)| {
+                let predicted = stepped_span as u32 * range.span() as u32 / first_span as u32;
+                predicted <= Self::MAX_SPAN as u32
+            })
+        });
+        let wanted = if clipped || range.span() > Self::MAX_SPAN {
+            weaker
+        } else if range.span() < Self::MIN_SPAN {
+            stronger
+        } else {
+            None
+        };
+
+        match wanted {
+            Some(level) if self.pending == Some(level) => {
+                self.windows += 1;
+                if self.windows >= Self::ADJUST_WINDOWS {
+                    let stepped_down = level > self.level;
+                    let span = if clipped { u8::MAX } else { range.span() };
+                    self.set_level(level);
+                    if stepped_down {
+                        self.stepped_from = Some(span);
+                    }
+                    return true;
+                }
+            }
+            Some(level) => {
+                self.pending = Some(level);
+                self.windows = 1;
+            }
+            None => {
+                self.pending = None;
+                self.windows = 0;
+            }
+        }
+        false
+    }
+}
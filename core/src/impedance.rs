@@ -0,0 +1,310 @@
+//! Multi-frequency impedance features, for telling tissues apart by how their response changes
+//! with frequency.
+//!
+//! A single amplitude at one frequency cannot separate a blade in wet bone from a blade touching
+//! brain, as both load the electrodes similarly. Saline and bone are close to purely resistive, so
+//! their response is flat across frequency, while the cells of brain and dura block low
+//! frequencies more than high ones. A [`MultiFrequency`] alternates the excitation between two or
+//! three tones, one window each, and reduces every complete cycle of tones to
+//! [`ImpedanceFeatures`]: the amplitude and level at each tone, the phase where the sample rate
+//! allows it, and the amplitude of each tone relative to the first.
+//!
+//! The [`Detector`](crate::detect::Detector) runs its usual checks on the first tone, and only
+//! reports contact if the [dispersion](ImpedanceFeatures::dispersion_x100) has also changed from
+//! its calibrated value (see [`Thresholds::dispersion_delta`](crate::config::Thresholds)).
+//!
+//! ```
+//! use aps490_pfpu2_core::{
+//!     impedance::{MultiFrequency, ToneFeatures},
+//!     signal::WINDOW_SIZE,
+//!     spectrum::FrequencyList,
+//! };
+//!
+//! let list: FrequencyList = "20000 100000".parse().unwrap();
+//! let mut multi = MultiFrequency::new(list).unwrap();
+//! let quarter = (WINDOW_SIZE / 4) as i32;
+//! let mut features = None;
+//! while features.is_none() {
+//!     let excitation_hz = multi.frequency();
+//!     // Response falls from 40 to 30 counts at the higher frequency, sampled twice per cycle
+//!     let (high, low) = if excitation_hz == 20_000 { (160, 120) } else { (155, 125) };
+//!     let partial_sums = [high * quarter, low * quarter, high * quarter, low * quarter];
+//!     let tone = ToneFeatures::from_partial_sums(excitation_hz, &partial_sums, 2);
+//!     features = multi.record(tone);
+//! }
+//!
+//! let features = features.unwrap();
+//! assert_eq!(features.tones()[0].amplitude_x100, 4000);
+//! assert_eq!(features.tones()[1].amplitude_x100, 3000);
+//! assert_eq!(features.tones()[1].phase_mdeg, None);
+//! assert_eq!(features.dispersion_x100(), Some(75));
+//! assert_eq!(multi.latest(), Some(features));
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    signal::{AlignedAverages, WINDOW_SIZE},
+    spectrum::FrequencyList,
+};
+
+/// Most tones alternated between by a [`MultiFrequency`]
+pub const MAX_TONES: usize = 3;
+
+/// Response to a single tone, measured over one window
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ToneFeatures {
+    /// Excitation frequency, in hertz
+    pub excitation_hz: u32,
+    /// Amplitude of the response, in hundredths of an ADC count. Matches the difference between
+    /// the high and low averages for a response in phase with the excitation.
+    pub amplitude_x100: i32,
+    /// Mean level of the response, in hundredths of an ADC count
+    pub level_x100: i32,
+    /// Delay of the response behind the excitation, in millidegrees. Only available when sampling
+    /// four times per cycle.
+    pub phase_mdeg: Option<i32>,
+    /// Time taken to record the window, in microseconds
+    pub window_us: u32,
+}
+
+impl ToneFeatures {
+    /// Reduce the [`AlignedAverages::partial_sums`] of a phase-locked window, taken at
+    /// `samples_per_cycle` readings per cycle of `excitation_hz`.
+    ///
+    /// With two readings per cycle, the high half is at [`AlignedAverages::LOCKED_HIGH_IDX`] and
+    /// no phase is available. With four, the readings fall in the middle of each quarter cycle,
+    /// and the amplitude and phase are taken from the quadrature of the fundamental:
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{impedance::ToneFeatures, signal::WINDOW_SIZE};
+    ///
+    /// let quarter = (WINDOW_SIZE / 4) as i32;
+    /// // In phase with the excitation, which is high for the first half of each cycle
+    /// let tone = ToneFeatures::from_partial_sums(
+    ///     50_000,
+    ///     &[150 * quarter, 150 * quarter, 110 * quarter, 110 * quarter],
+    ///     4,
+    /// );
+    /// assert_eq!((tone.amplitude_x100, tone.level_x100), (4000, 13000));
+    /// assert_eq!(tone.phase_mdeg, Some(0));
+    ///
+    /// // A quarter cycle late
+    /// let tone = ToneFeatures::from_partial_sums(
+    ///     50_000,
+    ///     &[110 * quarter, 150 * quarter, 150 * quarter, 110 * quarter],
+    ///     4,
+    /// );
+    /// assert_eq!(tone.phase_mdeg, Some(90_000));
+    /// ```
+    pub fn from_partial_sums(
+        excitation_hz: u32,
+        partial_sums: &[i32; 4],
+        samples_per_cycle: u32,
+    ) -> Self {
+        let level_x100 = partial_sums.iter().sum::<i32>() * 100 / WINDOW_SIZE as i32;
+        let window_us = (WINDOW_SIZE as u64 * 1_000_000
+            / (excitation_hz as u64 * samples_per_cycle as u64).max(1))
+            as u32;
+        if samples_per_cycle != 4 {
+            let high_sum: i32 = AlignedAverages::LOCKED_HIGH_IDX
+                .iter()
+                .map(|idx| partial_sums[*idx])
+                .sum();
+            let low_sum = partial_sums.iter().sum::<i32>() - high_sum;
+            return Self {
+                excitation_hz,
+                amplitude_x100: (high_sum - low_sum) * 100 / (WINDOW_SIZE / 2) as i32,
+                level_x100,
+                phase_mdeg: None,
+                window_us,
+            };
+        }
+
+        let in_phase = (partial_sums[0] - partial_sums[2]) as i64;
+        let quadrature = (partial_sums[1] - partial_sums[3]) as i64;
+        // An in-phase response has equal components, so the magnitude is scaled by 1/sqrt(2)
+        let magnitude_x100 =
+            ((in_phase * in_phase + quadrature * quadrature) as u64 * 5000).isqrt();
+        let mut phase_mdeg = atan2_mdeg(quadrature, in_phase) - 45_000;
+        if phase_mdeg <= -180_000 {
+            phase_mdeg += 360_000;
+        }
+        Self {
+            excitation_hz,
+            amplitude_x100: (magnitude_x100 / (WINDOW_SIZE / 4) as u64) as i32,
+            level_x100,
+            phase_mdeg: Some(phase_mdeg),
+            window_us,
+        }
+    }
+
+    /// Amplitude in whole ADC counts, for the [`Detector`](crate::detect::Detector). A response
+    /// out of phase with the excitation has no amplitude.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::impedance::ToneFeatures;
+    ///
+    /// let tone = ToneFeatures {
+    ///     amplitude_x100: -4000,
+    ///     level_x100: 30_000,
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!((tone.delta(), tone.level()), (0, 255));
+    /// ```
+    pub fn delta(&self) -> u8 {
+        (self.amplitude_x100 / 100).clamp(0, 255) as u8
+    }
+
+    /// Level in whole ADC counts, for the [`Detector`](crate::detect::Detector)
+    pub fn level(&self) -> u8 {
+        (self.level_x100 / 100).clamp(0, 255) as u8
+    }
+}
+
+/// Angle of `(x, y)` in millidegrees, from -180° to 180°, within about 0.3°
+fn atan2_mdeg(y: i64, x: i64) -> i32 {
+    let (abs_x, abs_y) = (x.abs(), y.abs());
+    let (num, den) = if abs_y <= abs_x {
+        (abs_y, abs_x)
+    } else {
+        (abs_x, abs_y)
+    };
+    if den == 0 {
+        return 0;
+    }
+    // atan(r) ≈ πr/4 + 0.273r(1 - r) for 0 <= r <= 1
+    let mut angle = ((45_000 * num * den + 15_642 * num * (den - num)) / (den * den)) as i32;
+    if abs_y > abs_x {
+        angle = 90_000 - angle;
+    }
+    if x < 0 {
+        angle = 180_000 - angle;
+    }
+    if y < 0 {
+        angle = -angle;
+    }
+    angle
+}
+
+/// Response to every tone of a [`MultiFrequency`] cycle
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImpedanceFeatures {
+    /// Features of each tone, of which the first `len` are used
+    tones: [ToneFeatures; MAX_TONES],
+    /// Number of tones
+    len: usize,
+}
+
+impl ImpedanceFeatures {
+    /// Features of each tone, in the order they were excited
+    pub fn tones(&self) -> &[ToneFeatures] {
+        &self.tones[..self.len]
+    }
+
+    /// First tone, used for the usual contact and proximity checks
+    pub fn primary(&self) -> &ToneFeatures {
+        &self.tones[0]
+    }
+
+    /// Amplitude of tone `idx` relative to the first tone, in hundredths. `None` if there is no
+    /// such tone, or the first tone has no response.
+    pub fn ratio_x100(&self, idx: usize) -> Option<i32> {
+        let tone = self.tones().get(idx)?;
+        match self.primary().amplitude_x100 {
+            0 => None,
+            primary => Some(tone.amplitude_x100 * 100 / primary),
+        }
+    }
+
+    /// Amplitude of the last tone relative to the first, in hundredths
+    pub fn dispersion_x100(&self) -> Option<i32> {
+        self.ratio_x100(self.len - 1)
+    }
+
+    /// Time taken to record every tone of the cycle, in microseconds
+    pub fn cycle_us(&self) -> u32 {
+        self.tones().iter().map(|tone| tone.window_us).sum()
+    }
+}
+
+/// Alternates the excitation between tones window by window, collecting [`ImpedanceFeatures`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MultiFrequency {
+    /// Tones to alternate between
+    list: FrequencyList,
+    /// Features recorded so far in the current cycle
+    current: ImpedanceFeatures,
+    /// Index of the tone to excite next
+    step: usize,
+    /// Features of the last complete cycle
+    latest: Option<ImpedanceFeatures>,
+}
+
+impl MultiFrequency {
+    /// Alternate between the tones in `list`, starting from the first. Returns `None` unless
+    /// `list` has between two and [`MAX_TONES`] frequencies.
+    pub fn new(list: FrequencyList) -> Option<Self> {
+        if !(2..=MAX_TONES).contains(&list.as_slice().len()) {
+            return None;
+        }
+        Some(Self {
+            list,
+            current: ImpedanceFeatures {
+                tones: [ToneFeatures::default(); MAX_TONES],
+                len: list.as_slice().len(),
+            },
+            step: 0,
+            latest: None,
+        })
+    }
+
+    /// Tones alternated between, in hertz
+    pub fn frequencies(&self) -> &[u32] {
+        self.list.as_slice()
+    }
+
+    /// Frequency to excite at for the next window
+    pub fn frequency(&self) -> u32 {
+        self.list.as_slice()[self.step]
+    }
+
+    /// Record a window, returning the features once every tone in the cycle has been recorded.
+    ///
+    /// Windows which are not at [`MultiFrequency::frequency`], such as while the excitation has
+    /// not been changed yet, are ignored.
+    pub fn record(&mut self, tone: ToneFeatures) -> Option<ImpedanceFeatures> {
+        if tone.excitation_hz != self.frequency() {
+            return None;
+        }
+
+        self.current.tones[self.step] = tone;
+        self.step += 1;
+        if self.step < self.current.len {
+            return None;
+        }
+        self.step = 0;
+        self.latest = Some(self.current);
+        self.latest
+    }
+
+    /// Features of the last complete cycle, if any
+    pub fn latest(&self) -> Option<ImpedanceFeatures> {
+        self.latest
+    }
+}
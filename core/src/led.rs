@@ -0,0 +1,93 @@
+//! Output pins for status indicators, with configurable polarity.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use embedded_hal::digital::{OutputPin, PinState};
+
+/// Pin level which turns an LED on
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Polarity {
+    /// LED is on when the pin is high (ex. LED to ground)
+    #[default]
+    ActiveHigh,
+    /// LED is on when the pin is low (ex. common-anode RGB LEDs)
+    ActiveLow,
+}
+
+impl Polarity {
+    /// Pin level required for the LED to be `on`
+    pub fn pin_state(self, on: bool) -> PinState {
+        match self {
+            Polarity::ActiveHigh => PinState::from(on),
+            Polarity::ActiveLow => PinState::from(!on),
+        }
+    }
+}
+
+/// An LED driven by any [`OutputPin`].
+///
+/// ```
+/// use aps490_pfpu2_core::led::{LedPin, Polarity};
+/// use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction};
+///
+/// let mut pin = PinMock::new(&[
+///     Transaction::set(State::Low),
+///     Transaction::set(State::High),
+/// ]);
+/// let mut led = LedPin::new(pin.clone(), Polarity::ActiveLow);
+/// led.set(true).unwrap();
+/// led.set(false).unwrap();
+/// pin.done();
+/// ```
+#[derive(Debug)]
+pub struct LedPin<P: OutputPin> {
+    /// Pin driving the LED
+    pin: P,
+    /// Level which turns the LED on
+    polarity: Polarity,
+}
+
+impl<P: OutputPin> LedPin<P> {
+    /// Wrap `pin` with the given `polarity`
+    pub fn new(pin: P, polarity: Polarity) -> Self {
+        Self { pin, polarity }
+    }
+
+    /// Shortcut for [`Polarity::ActiveHigh`]
+    pub fn active_high(pin: P) -> Self {
+        Self::new(pin, Polarity::ActiveHigh)
+    }
+
+    /// Shortcut for [`Polarity::ActiveLow`]
+    pub fn active_low(pin: P) -> Self {
+        Self::new(pin, Polarity::ActiveLow)
+    }
+
+    /// Turn the LED on or off
+    pub fn set(&mut self, on: bool) -> Result<(), P::Error> {
+        self.pin.set_state(self.polarity.pin_state(on))
+    }
+
+    /// Level which turns the LED on
+    pub fn polarity(&self) -> Polarity {
+        self.polarity
+    }
+
+    /// Release the underlying pin
+    pub fn free(self) -> P {
+        self.pin
+    }
+}
@@ -0,0 +1,52 @@
+//! Hardware-independent logic shared by [`aps490_pfpu2_mini`](https://docs.rs/aps490_pfpu2_mini)
+//! and its host-side tools.
+//!
+//! Nothing in this crate touches RP2040 peripherals directly, so it can be built and tested on the
+//! host with:
+//!
+//! ```shell
+//! cargo test-host
+//! ```
+//!
+//! ## Crate features
+//!
+//! - `defmt`: Implements [`defmt::Format`](https://docs.rs/defmt/latest/defmt/trait.Format.html)
+//!   for shared types. Enabled by the firmware.
+//! - `telemetry`: Binary telemetry records and framing (see [`telemetry`]), shared by the firmware
+//!   and host tools.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+#![warn(missing_docs)]
+#![cfg_attr(docsrs, feature(doc_cfg), feature(doc_auto_cfg), feature(doc_cfg_hide))]
+
+pub mod alert;
+pub mod clock;
+pub mod command;
+pub mod config;
+pub mod debounce;
+pub mod demo;
+pub mod detect;
+pub mod gain;
+pub mod impedance;
+pub mod led;
+pub mod pattern;
+pub mod prbs;
+pub mod signal;
+pub mod spectrum;
+pub mod state;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
@@ -0,0 +1,158 @@
+//! Blink and brightness patterns for status indicators.
+//!
+//! Patterns are sampled once per [`TICK_MS`] by a hardware timer. Brightness is produced with
+//! software PWM over [`PWM_STEPS`] ticks, so any GPIO can show every pattern, including
+//! [`Pattern::Breathing`].
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Time between pattern updates
+pub const TICK_MS: u32 = 1;
+/// Number of ticks in one software PWM frame.
+///
+/// Currently set to 8 ticks (125 Hz with 1 ms ticks), which is fast enough to avoid visible
+/// flicker.
+pub const PWM_STEPS: u32 = 8;
+
+/// How a single LED is driven
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pattern {
+    /// Always off
+    #[default]
+    Off,
+    /// Always on
+    Steady,
+    /// On and off once every [`Pattern::SLOW_BLINK_MS`]
+    SlowBlink,
+    /// On and off once every [`Pattern::FAST_BLINK_MS`]
+    FastBlink,
+    /// Fades in and out once every [`Pattern::BREATHING_MS`]
+    Breathing,
+    /// Flashes `n` times, then pauses for [`Pattern::PULSE_GAP_MS`]. Used for [`FaultCode`]s.
+    Pulses(u8),
+}
+
+impl Pattern {
+    /// Period of [`Pattern::SlowBlink`]
+    pub const SLOW_BLINK_MS: u32 = 1000;
+    /// Period of [`Pattern::FastBlink`]
+    pub const FAST_BLINK_MS: u32 = 250;
+    /// Period of [`Pattern::Breathing`]
+    pub const BREATHING_MS: u32 = 2000;
+    /// Length of each flash in [`Pattern::Pulses`], and of the gap between flashes
+    pub const PULSE_MS: u32 = 200;
+    /// Pause after each group of [`Pattern::Pulses`]
+    pub const PULSE_GAP_MS: u32 = 1200;
+
+    /// Brightness from 0 (off) to 255 (fully on), `elapsed_ms` after the pattern started.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::pattern::Pattern;
+    ///
+    /// assert_eq!(Pattern::SlowBlink.brightness(0), 255);
+    /// assert_eq!(Pattern::SlowBlink.brightness(Pattern::SLOW_BLINK_MS / 2), 0);
+    /// assert_eq!(Pattern::Breathing.brightness(Pattern::BREATHING_MS / 2), 255);
+    ///
+    /// // Second flash of a 3-pulse code, then the pause
+    /// assert_eq!(Pattern::Pulses(3).brightness(2 * Pattern::PULSE_MS), 255);
+    /// assert_eq!(Pattern::Pulses(3).brightness(6 * Pattern::PULSE_MS), 0);
+    /// ```
+    pub fn brightness(&self, elapsed_ms: u32) -> u8 {
+        match *self {
+            Pattern::Off | Pattern::Pulses(0) => 0,
+            Pattern::Steady => u8::MAX,
+            Pattern::SlowBlink => Self::square(elapsed_ms, Self::SLOW_BLINK_MS),
+            Pattern::FastBlink => Self::square(elapsed_ms, Self::FAST_BLINK_MS),
+            Pattern::Breathing => {
+                let half = Self::BREATHING_MS / 2;
+                let phase = elapsed_ms % Self::BREATHING_MS;
+                let ramp = if phase < half {
+                    phase
+                } else {
+                    Self::BREATHING_MS - phase
+                };
+                // Squared for a more even perceived fade
+                let linear = ramp * u32::from(u8::MAX) / half;
+                (linear * linear / u32::from(u8::MAX)) as u8
+            }
+            Pattern::Pulses(n) => {
+                let flashes = 2 * Self::PULSE_MS * u32::from(n);
+                let phase = elapsed_ms % (flashes + Self::PULSE_GAP_MS);
+                if phase < flashes && (phase / Self::PULSE_MS).is_multiple_of(2) {
+                    u8::MAX
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    /// Whether the LED should be lit `elapsed_ms` after the pattern started, applying software PWM
+    /// to the [brightness](Pattern::brightness).
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::pattern::{Pattern, PWM_STEPS};
+    ///
+    /// // Breathing is dim near the start of each cycle
+    /// let lit = (0..PWM_STEPS)
+    ///     .filter(|tick| Pattern::Breathing.is_on(400 + tick))
+    ///     .count();
+    /// assert!(lit > 0 && lit < PWM_STEPS as usize / 2);
+    /// assert!((0..PWM_STEPS).all(|tick| Pattern::Steady.is_on(tick)));
+    /// ```
+    pub fn is_on(&self, elapsed_ms: u32) -> bool {
+        let step = (elapsed_ms / TICK_MS) % PWM_STEPS;
+        step * u32::from(u8::MAX) < u32::from(self.brightness(elapsed_ms)) * PWM_STEPS
+    }
+
+    /// Half on, half off over `period_ms`
+    fn square(elapsed_ms: u32, period_ms: u32) -> u8 {
+        if elapsed_ms % period_ms < period_ms / 2 {
+            u8::MAX
+        } else {
+            0
+        }
+    }
+}
+
+/// Reason for entering [`StatusLedStates::Fault`](crate::state::StatusLedStates::Fault). Each code
+/// is shown as a number of [`Pattern::Pulses`].
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum FaultCode {
+    /// The sensing chain did not respond during a self-test
+    SelfTestFailed = 1,
+    /// No ADC transfer was running when readings were expected
+    NoTransfer = 2,
+    /// A sample counter overflowed
+    CounterOverflow = 3,
+    /// Fault raised without a specific cause
+    #[default]
+    Unknown = 4,
+}
+
+impl FaultCode {
+    /// Number of pulses used to show this code
+    pub const fn pulses(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Pattern used to show this code
+    pub const fn pattern(&self) -> Pattern {
+        Pattern::Pulses(self.pulses())
+    }
+}
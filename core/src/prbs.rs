@@ -0,0 +1,188 @@
+//! Pseudo-random (PRBS) excitation, and correlation of the readings against it.
+//!
+//! A square wave puts all of its energy at the excitation frequency, so interference near that
+//! frequency, such as from the saw motor, passes straight into the high and low averages. In
+//! [`ExcitationMode::Prbs`], the excitation is held high or low for each whole cycle according to
+//! [`CHIPS`], a maximal-length sequence which spreads the energy across the band. Correlating the
+//! readings against the same sequence recovers the response of the channel, while interference,
+//! which does not follow the sequence, averages towards zero.
+//!
+//! The chips reach the output a fixed number of cycles after they are queued, so the response is
+//! estimated at each of the first [`TAPS`] delays and summed by
+//! [`ChannelResponse::amplitude_x100`].
+//!
+//! ```
+//! use aps490_pfpu2_core::{
+//!     prbs::{ChannelResponse, CHIPS},
+//!     signal::{AlignedAverages, WINDOW_SIZE},
+//! };
+//!
+//! // A response of 40 counts, two chips late, with strong interference at the chip rate
+//! let mut window = [0u8; WINDOW_SIZE];
+//! for (idx, reading) in window.iter_mut().enumerate() {
+//!     let chip = (idx / 2).checked_sub(2).is_some_and(|chip| CHIPS[chip]);
+//!     let interference = if idx % 2 == 0 { 12 } else { 0 };
+//!     *reading = if chip { 140 } else { 100 } + interference;
+//! }
+//!
+//! let response = ChannelResponse::from_window(&window);
+//! assert!((3950..=4050).contains(&response.amplitude_x100()));
+//! assert_eq!(response.averages().get_delta(), 40);
+//!
+//! // The interference shifts every high reading of a square wave instead
+//! let avgs = AlignedAverages::from_locked_window(&window);
+//! assert_eq!(avgs.get_delta(), 12);
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{fmt, str::FromStr};
+
+use crate::{
+    command::ParseError,
+    signal::{AlignedAverages, WINDOW_SIZE},
+};
+
+/// Number of chips in each window, with two readings per chip
+pub const CHIPS_PER_WINDOW: usize = WINDOW_SIZE / 2;
+/// Number of delays, in chips, at which the response is estimated
+pub const TAPS: usize = 4;
+/// Chips of every window, `true` where the excitation is high. The first [`CHIPS_PER_WINDOW`] of
+/// the 2047-chip sequence from the 11-bit LFSR <i>x</i><sup>11</sup> + <i>x</i><sup>9</sup> + 1.
+pub const CHIPS: [bool; CHIPS_PER_WINDOW] = prbs11();
+
+/// Generate [`CHIPS`], starting from all ones
+const fn prbs11() -> [bool; CHIPS_PER_WINDOW] {
+    let mut chips = [false; CHIPS_PER_WINDOW];
+    let mut state: u16 = 0x7ff;
+    let mut idx = 0;
+    while idx < CHIPS_PER_WINDOW {
+        let bit = ((state >> 10) ^ (state >> 8)) & 1;
+        state = ((state << 1) | bit) & 0x7ff;
+        chips[idx] = bit == 1;
+        idx += 1;
+    }
+    chips
+}
+
+/// Waveform used to excite the electrodes
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ExcitationMode {
+    /// Square wave at the excitation frequency, with a 50% duty cycle
+    #[default]
+    Square,
+    /// Each cycle held high or low according to [`CHIPS`]
+    Prbs,
+}
+
+impl ExcitationMode {
+    /// Every mode, in the order listed by the console
+    pub const ALL: [ExcitationMode; 2] = [ExcitationMode::Square, ExcitationMode::Prbs];
+
+    /// Name used by the console
+    pub const fn name(&self) -> &'static str {
+        match self {
+            ExcitationMode::Square => "square",
+            ExcitationMode::Prbs => "prbs",
+        }
+    }
+}
+
+impl FromStr for ExcitationMode {
+    type Err = ParseError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        ExcitationMode::ALL
+            .into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(name))
+            .ok_or(ParseError::InvalidValue)
+    }
+}
+
+impl fmt::Display for ExcitationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Response of the channel to [`CHIPS`], estimated from one window of readings
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelResponse {
+    /// Difference between high and low chips at each delay, in hundredths of an ADC count
+    pub taps_x100: [i32; TAPS],
+    /// Mean of every reading, in hundredths of an ADC count
+    pub level_x100: i32,
+}
+
+impl ChannelResponse {
+    /// Correlate a window taken with two readings per chip against [`CHIPS`].
+    ///
+    /// Each tap is the covariance of the readings with the chips at that delay, scaled so that a
+    /// response which moves between `low` and `high` with the chips gives `high - low`. Readings
+    /// from before the first chip at a delay are left out of that tap.
+    ///
+    /// Runs in `DMA_IRQ_0` for every window, so every tap is accumulated in a single pass over the
+    /// window. The sums of a window fit in `i32`, and only the covariance is widened.
+    pub fn from_window(window: &[u8; WINDOW_SIZE]) -> Self {
+        let (mut sums, mut chip_sums, mut product_sums) =
+            ([0i32; TAPS], [0i32; TAPS], [0i32; TAPS]);
+        let mut total = 0;
+        for (chip_idx, pair) in window.chunks_exact(2).enumerate() {
+            let pair = pair[0] as i32 + pair[1] as i32;
+            total += pair;
+            for delay in 0..TAPS.min(chip_idx + 1) {
+                sums[delay] += pair;
+                if CHIPS[chip_idx - delay] {
+                    chip_sums[delay] += 2;
+                    product_sums[delay] += pair;
+                } else {
+                    chip_sums[delay] -= 2;
+                    product_sums[delay] -= pair;
+                }
+            }
+        }
+
+        let mut taps_x100 = [0; TAPS];
+        for (delay, tap) in taps_x100.iter_mut().enumerate() {
+            let count = (WINDOW_SIZE - delay * 2) as i64;
+            let covariance =
+                count * product_sums[delay] as i64 - sums[delay] as i64 * chip_sums[delay] as i64;
+            // Chips are ±1, so the covariance is half of the difference between high and low
+            *tap = (200 * covariance / (count * count)) as i32;
+        }
+        Self {
+            taps_x100,
+            level_x100: total * 100 / WINDOW_SIZE as i32,
+        }
+    }
+
+    /// Total response across every delay, in hundredths of an ADC count
+    pub fn amplitude_x100(&self) -> i32 {
+        self.taps_x100.iter().sum()
+    }
+
+    /// High and low averages of a square wave with the same response and level, so the window
+    /// can be used for detection like any other
+    pub fn averages(&self) -> AlignedAverages {
+        let half_x100 = self.amplitude_x100() / 2;
+        AlignedAverages {
+            avg_high: (self.level_x100 + half_x100) / 100,
+            avg_low: (self.level_x100 - half_x100) / 100,
+            high_idx: AlignedAverages::LOCKED_HIGH_IDX,
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Reduces each window of ADC readings to the averages used for detection.
+//!
+//! The ADC samples at twice the excitation frequency, so readings alternate between the high and low
+//! halves of the signal. Live readings are phase-locked to the excitation (see
+//! [`clock`](crate::clock)), so every window starts on the high half, and
+//! [`AlignedAverages::from_locked_window`] takes the even readings as high.
+//!
+//! Where the phase is not known, such as recordings from older firmware, the window is split into
+//! four interleaved partial sums, and the two highest are taken as the high half by
+//! [`AlignedAverages::from_window`].
+//!
+//! ```
+//! use aps490_pfpu2_core::signal::{AlignedAverages, WINDOW_SIZE};
+//!
+//! let mut window = [0u8; WINDOW_SIZE];
+//! for (idx, reading) in window.iter_mut().enumerate() {
+//!     *reading = if idx % 2 == 1 { 140 } else { 95 };
+//! }
+//! let avgs = AlignedAverages::from_window(&window);
+//! assert_eq!((avgs.avg_high, avgs.avg_low), (140, 95));
+//! assert_eq!(avgs.high_idx, [3, 1]);
+//! assert_eq!(avgs.get_delta(), 45);
+//! assert_eq!(avgs.get_level(), 117);
+//!
+//! // Locked alignment always takes the even readings as high
+//! let avgs = AlignedAverages::from_locked_window(&window);
+//! assert_eq!((avgs.avg_high, avgs.avg_low), (95, 140));
+//! assert_eq!(avgs.high_idx, AlignedAverages::LOCKED_HIGH_IDX);
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cmp::Ordering;
+
+/// Number of ADC readings in each analysis window
+pub const WINDOW_SIZE: usize = 4000;
+
+/// Calculates proper averages aligned with signal timing
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AlignedAverages {
+    /// The average voltage from the "higher" 2000 measurements
+    pub avg_high: i32,
+    /// The average voltage from the "lower" 2000 measurements
+    pub avg_low: i32,
+    /// Partial sum indices (mod 4) used for the high average
+    pub high_idx: [usize; 2],
+}
+
+impl AlignedAverages {
+    /// Partial sum indices (mod 4) of the high half when sampling is phase-locked
+    pub const LOCKED_HIGH_IDX: [usize; 2] = [0, 2];
+
+    /// Sum every fourth reading of a window, starting from each of the first four
+    pub fn partial_sums(window: &[u8; WINDOW_SIZE]) -> [i32; 4] {
+        let mut partial_sums = [0i32; 4]; // 1000 samples each
+        for (idx, partial) in partial_sums.iter_mut().enumerate() {
+            *partial = window
+                .iter()
+                .skip(idx)
+                .step_by(4)
+                .map(|i| *i as i32)
+                .sum::<i32>();
+        }
+        partial_sums
+    }
+
+    /// Shortcut for [`AlignedAverages::align_signal_timing`] over the
+    /// [`AlignedAverages::partial_sums`] of a window
+    pub fn from_window(window: &[u8; WINDOW_SIZE]) -> Self {
+        Self::align_signal_timing(&Self::partial_sums(window))
+    }
+
+    /// Shortcut for [`AlignedAverages::locked_signal_timing`] over the
+    /// [`AlignedAverages::partial_sums`] of a window
+    pub fn from_locked_window(window: &[u8; WINDOW_SIZE]) -> Self {
+        Self::locked_signal_timing(&Self::partial_sums(window))
+    }
+
+    /// Takes the partial sums of phase-locked samples, where the high half is always at
+    /// [`AlignedAverages::LOCKED_HIGH_IDX`], to calculate the high and low averages
+    pub fn locked_signal_timing(partial_sums: &[i32; 4]) -> Self {
+        let high_idx = Self::LOCKED_HIGH_IDX;
+        let (high, low) =
+            partial_sums
+                .iter()
+                .enumerate()
+                .fold((0, 0), |(high, low), (idx, sum)| {
+                    if high_idx.contains(&idx) {
+                        (high + sum, low)
+                    } else {
+                        (high, low + sum)
+                    }
+                });
+        Self {
+            avg_high: high / (WINDOW_SIZE / 2) as i32,
+            avg_low: low / (WINDOW_SIZE / 2) as i32,
+            high_idx,
+        }
+    }
+
+    /// Takes the partial sums of the samples to calculate the high and low averages for contact
+    /// detection.
+    ///
+    /// Sorting requires an allocator, so this implementation identifies the highest partial sums.
+    /// Although this implementation technically allows for non-adjacent partial sums to be matched,
+    /// in effect this has little impact as those scenarios result in low overall deltas.
+    ///
+    /// This would be a good section to rewrite :)
+    pub fn align_signal_timing(partial_sums: &[i32; 4]) -> Self {
+        let mut avg_high_idx = [4usize; 2];
+        let mut avg_high = 0i32;
+
+        let match_sum = partial_sums
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.cmp(b.1))
+            .unwrap();
+        avg_high_idx[0] = match_sum.0;
+        avg_high += match_sum.1;
+
+        let match_sum = partial_sums
+            .iter()
+            .enumerate()
+            .max_by(|a, b| match a.1.cmp(b.1) {
+                Ordering::Less | Ordering::Equal => {
+                    if avg_high_idx.contains(&b.0) {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Less
+                    }
+                }
+                Ordering::Greater => {
+                    if avg_high_idx.contains(&a.0) {
+                        Ordering::Less
+                    } else {
+                        Ordering::Greater
+                    }
+                }
+            })
+            .unwrap();
+        avg_high_idx[1] = match_sum.0;
+        avg_high += match_sum.1;
+        avg_high /= (WINDOW_SIZE / 2) as i32;
+
+        let avg_low = partial_sums
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, sum)| {
+                if !avg_high_idx.contains(&idx) {
+                    Some(sum)
+                } else {
+                    None
+                }
+            })
+            .sum::<i32>()
+            / (WINDOW_SIZE / 2) as i32;
+
+        Self {
+            avg_low,
+            avg_high,
+            high_idx: avg_high_idx,
+        }
+    }
+
+    /// Calculates the average range of the sample interval
+    pub fn get_delta(&self) -> u8 {
+        u8::try_from(self.avg_high - self.avg_low).map_or(255, |avg| avg)
+    }
+
+    /// Calculates the average voltage of the sample interval, across both halves
+    pub fn get_level(&self) -> u8 {
+        u8::try_from((self.avg_high + self.avg_low) / 2).map_or(255, |avg| avg)
+    }
+}
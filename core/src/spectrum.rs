@@ -0,0 +1,226 @@
+//! Frequency sweep of the excitation, for a coarse impedance spectrum of the material at the blade.
+//!
+//! Tissue impedance depends on frequency, so the response amplitude (the difference between the
+//! high and low averages) is recorded at each frequency in a [`FrequencyList`]. A
+//! [`FrequencySweep`] reports the frequency to excite at, discards windows while the signal settles
+//! after each change, then averages the response over a fixed number of windows before moving to
+//! the next frequency.
+//!
+//! ```
+//! use aps490_pfpu2_core::{
+//!     signal::AlignedAverages,
+//!     spectrum::{FrequencyList, FrequencySweep},
+//! };
+//!
+//! let list: FrequencyList = "50000 100000".parse().unwrap();
+//! let mut sweep = FrequencySweep::new(list);
+//! while let Some(excitation_hz) = sweep.frequency() {
+//!     // Response halves at the higher frequency
+//!     let (avg_high, avg_low) = if excitation_hz == 50_000 { (160, 100) } else { (145, 115) };
+//!     let avgs = AlignedAverages {
+//!         avg_high,
+//!         avg_low,
+//!         high_idx: AlignedAverages::LOCKED_HIGH_IDX,
+//!     };
+//!     sweep.record(excitation_hz, &avgs);
+//! }
+//!
+//! let points = sweep.points();
+//! assert_eq!(points.len(), 2);
+//! assert_eq!((points[0].excitation_hz, points[0].amplitude_x100()), (50_000, 6000));
+//! assert_eq!((points[1].excitation_hz, points[1].amplitude_x100()), (100_000, 3000));
+//! assert_eq!(points[1].level_x100(), 13000);
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::str::FromStr;
+
+use crate::{command::ParseError, signal::AlignedAverages};
+
+/// Most frequencies in a single sweep
+pub const MAX_SWEEP_POINTS: usize = 8;
+
+/// Excitation frequencies to sweep through, in order
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrequencyList {
+    /// Frequencies in hertz, of which the first `len` are used
+    frequencies: [u32; MAX_SWEEP_POINTS],
+    /// Number of frequencies
+    len: usize,
+}
+
+impl FrequencyList {
+    /// Copy up to [`MAX_SWEEP_POINTS`] frequencies. Returns `None` if `frequencies` is empty or too
+    /// long.
+    pub fn new(frequencies: &[u32]) -> Option<Self> {
+        if frequencies.is_empty() || frequencies.len() > MAX_SWEEP_POINTS {
+            return None;
+        }
+        let mut list = Self {
+            frequencies: [0; MAX_SWEEP_POINTS],
+            len: frequencies.len(),
+        };
+        list.frequencies[..frequencies.len()].copy_from_slice(frequencies);
+        Some(list)
+    }
+
+    /// Parse every word as a frequency in hertz
+    pub fn from_words<'a>(words: impl Iterator<Item = &'a str>) -> Result<Self, ParseError> {
+        let mut frequencies = [0; MAX_SWEEP_POINTS];
+        let mut len = 0;
+        for word in words {
+            let slot = frequencies
+                .get_mut(len)
+                .ok_or(ParseError::TooManyArguments)?;
+            *slot = word.parse().or(Err(ParseError::InvalidValue))?;
+            len += 1;
+        }
+        Self::new(&frequencies[..len]).ok_or(ParseError::MissingArgument)
+    }
+
+    /// Frequencies in hertz
+    pub fn as_slice(&self) -> &[u32] {
+        &self.frequencies[..self.len]
+    }
+}
+
+impl FromStr for FrequencyList {
+    type Err = ParseError;
+
+    /// Parse frequencies in hertz, separated by whitespace
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{command::ParseError, spectrum::FrequencyList};
+    ///
+    /// let list: FrequencyList = "20000  40000 80000".parse().unwrap();
+    /// assert_eq!(list.as_slice(), [20_000, 40_000, 80_000]);
+    /// assert_eq!("".parse::<FrequencyList>(), Err(ParseError::MissingArgument));
+    /// assert_eq!("20k".parse::<FrequencyList>(), Err(ParseError::InvalidValue));
+    /// assert_eq!("1 2 3 4 5 6 7 8 9".parse::<FrequencyList>(), Err(ParseError::TooManyArguments));
+    /// ```
+    fn from_str(words: &str) -> Result<Self, Self::Err> {
+        Self::from_words(words.split_whitespace())
+    }
+}
+
+/// Response measured at a single frequency
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SweepPoint {
+    /// Excitation frequency, in hertz
+    pub excitation_hz: u32,
+    /// Number of windows measured
+    pub windows: u32,
+    /// Sum of [`AlignedAverages::avg_high`] over the measured windows
+    high_sum: i32,
+    /// Sum of [`AlignedAverages::avg_low`] over the measured windows
+    low_sum: i32,
+}
+
+impl SweepPoint {
+    /// Mean difference between the high and low averages, in hundredths of an ADC count
+    pub fn amplitude_x100(&self) -> i32 {
+        self.mean_x100(self.high_sum - self.low_sum)
+    }
+
+    /// Mean level across both halves, in hundredths of an ADC count
+    pub fn level_x100(&self) -> i32 {
+        self.mean_x100(self.high_sum + self.low_sum) / 2
+    }
+
+    /// Mean of `sum` over the measured windows, in hundredths
+    fn mean_x100(&self, sum: i32) -> i32 {
+        match self.windows {
+            0 => 0,
+            windows => sum * 100 / windows as i32,
+        }
+    }
+}
+
+/// Steps through a [`FrequencyList`], measuring the response at each frequency
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrequencySweep {
+    /// Frequencies to sweep through
+    list: FrequencyList,
+    /// Response at each frequency measured so far
+    points: [SweepPoint; MAX_SWEEP_POINTS],
+    /// Index of the current frequency
+    step: usize,
+    /// Windows recorded at the current frequency, including those discarded while settling
+    window: u32,
+}
+
+impl FrequencySweep {
+    /// Windows discarded after each change of frequency
+    pub const SETTLE_WINDOWS: u32 = 10;
+    /// Windows averaged at each frequency
+    pub const MEASURE_WINDOWS: u32 = 50;
+
+    /// Start a sweep at the first frequency in `list`
+    pub fn new(list: FrequencyList) -> Self {
+        Self {
+            list,
+            points: [SweepPoint::default(); MAX_SWEEP_POINTS],
+            step: 0,
+            window: 0,
+        }
+    }
+
+    /// Frequency to excite at, or `None` once the sweep is complete
+    pub fn frequency(&self) -> Option<u32> {
+        self.list.as_slice().get(self.step).copied()
+    }
+
+    /// Number of frequencies in the sweep
+    pub fn len(&self) -> usize {
+        self.list.as_slice().len()
+    }
+
+    /// The sweep has no frequencies. Never true, as a [`FrequencyList`] is never empty.
+    pub fn is_empty(&self) -> bool {
+        self.list.as_slice().is_empty()
+    }
+
+    /// Record a window taken while exciting at `excitation_hz`. Windows at any other frequency,
+    /// such as before the excitation has been changed, are ignored.
+    pub fn record(&mut self, excitation_hz: u32, avgs: &AlignedAverages) {
+        if self.frequency() != Some(excitation_hz) {
+            return;
+        }
+
+        self.window += 1;
+        if self.window <= Self::SETTLE_WINDOWS {
+            return;
+        }
+        let point = &mut self.points[self.step];
+        point.excitation_hz = excitation_hz;
+        point.windows += 1;
+        point.high_sum += avgs.avg_high;
+        point.low_sum += avgs.avg_low;
+
+        if point.windows >= Self::MEASURE_WINDOWS {
+            self.step += 1;
+            self.window = 0;
+        }
+    }
+
+    /// Response at each frequency which has been completely measured
+    pub fn points(&self) -> &[SweepPoint] {
+        &self.points[..self.step]
+    }
+}
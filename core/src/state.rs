@@ -0,0 +1,152 @@
+//! System state machine, with a validated transition table.
+//!
+//! Every change in system state goes through [`transition`], which rejects any change not listed
+//! in [`StatusLedStates::can_transition`].
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// System states, expressed by the status indicators
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "telemetry", derive(serde::Serialize, serde::Deserialize))]
+pub enum StatusLedStates {
+    /// Peripherals are being configured. Initial state after power-on.
+    Booting,
+    /// Confirming the sensing chain responds to a change in excitation
+    SelfTest,
+    /// Recording the idle signal level before detection begins
+    Calibrating,
+    /// Monitoring for contact
+    Armed,
+    /// Signal level indicates the blade is close to a conductive surface
+    Proximity,
+    /// Contact detected
+    Contact,
+    /// Detection cannot be trusted. Only cleared by a reset.
+    Fault,
+    /// Detection paused by the disable switch
+    Disabled,
+}
+
+/// A rejected change in [`StatusLedStates`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IllegalTransition {
+    /// State before the attempted change
+    pub from: StatusLedStates,
+    /// Requested state
+    pub to: StatusLedStates,
+}
+
+impl StatusLedStates {
+    /// Every state, in declaration order
+    pub const ALL: [StatusLedStates; 8] = [
+        StatusLedStates::Booting,
+        StatusLedStates::SelfTest,
+        StatusLedStates::Calibrating,
+        StatusLedStates::Armed,
+        StatusLedStates::Proximity,
+        StatusLedStates::Contact,
+        StatusLedStates::Fault,
+        StatusLedStates::Disabled,
+    ];
+
+    /// Transition table for the system.
+    ///
+    /// Staying in the same state is always allowed. [`StatusLedStates::Fault`] can only be left
+    /// through a reset ([`StatusLedStates::Booting`]), so a fault never silently returns to
+    /// [`StatusLedStates::Armed`]:
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::state::StatusLedStates;
+    ///
+    /// // Search every path out of Fault which does not pass through a reset
+    /// let mut reachable = [false; StatusLedStates::ALL.len()];
+    /// let mut queue = vec![StatusLedStates::Fault];
+    /// while let Some(from) = queue.pop() {
+    ///     for (idx, to) in StatusLedStates::ALL.into_iter().enumerate() {
+    ///         if to != StatusLedStates::Booting && from.can_transition(to) && !reachable[idx] {
+    ///             reachable[idx] = true;
+    ///             queue.push(to);
+    ///         }
+    ///     }
+    /// }
+    /// assert!(!reachable[StatusLedStates::Armed as usize]);
+    /// assert!(!reachable[StatusLedStates::Disabled as usize]);
+    ///
+    /// // Any other state can always raise a fault
+    /// for state in StatusLedStates::ALL {
+    ///     assert!(state.can_transition(StatusLedStates::Fault));
+    /// }
+    /// ```
+    pub const fn can_transition(self, to: StatusLedStates) -> bool {
+        use StatusLedStates::*;
+
+        if self as u8 == to as u8 {
+            return true;
+        }
+        match self {
+            Booting => matches!(to, SelfTest | Calibrating | Fault | Disabled),
+            SelfTest => matches!(to, Calibrating | Armed | Fault | Disabled),
+            Calibrating => matches!(to, Armed | Fault | Disabled),
+            Armed => matches!(
+                to,
+                SelfTest | Calibrating | Proximity | Contact | Fault | Disabled
+            ),
+            Proximity => matches!(to, Armed | Contact | Fault | Disabled),
+            Contact => matches!(to, Armed | Fault | Disabled),
+            Fault => matches!(to, Booting),
+            Disabled => matches!(to, Calibrating | Fault),
+        }
+    }
+
+    /// Signal generation and ADC readings are stopped in this state
+    pub const fn pauses_detection(self) -> bool {
+        matches!(self, StatusLedStates::Fault | StatusLedStates::Disabled)
+    }
+
+    /// Contact detection is active in this state
+    pub const fn is_detecting(self) -> bool {
+        matches!(self, StatusLedStates::Armed | StatusLedStates::Proximity)
+    }
+}
+
+/// Validate a change from `from` to `to`, returning the new state if permitted.
+///
+/// ```
+/// use aps490_pfpu2_core::state::{transition, IllegalTransition, StatusLedStates};
+///
+/// assert_eq!(
+///     transition(StatusLedStates::Armed, StatusLedStates::Contact),
+///     Ok(StatusLedStates::Contact)
+/// );
+/// assert_eq!(
+///     transition(StatusLedStates::Fault, StatusLedStates::Armed),
+///     Err(IllegalTransition {
+///         from: StatusLedStates::Fault,
+///         to: StatusLedStates::Armed
+///     })
+/// );
+/// ```
+pub fn transition(
+    from: StatusLedStates,
+    to: StatusLedStates,
+) -> Result<StatusLedStates, IllegalTransition> {
+    if from.can_transition(to) {
+        Ok(to)
+    } else {
+        Err(IllegalTransition { from, to })
+    }
+}
@@ -0,0 +1,354 @@
+//! Compact binary records of every analysis window, for live tuning on the host.
+//!
+//! Each [`Record`] is serialized with [postcard](https://docs.rs/postcard), followed by a
+//! little-endian [`CRC`] of the serialized bytes. The result is [COBS](https://docs.rs/cobs)
+//! encoded and terminated with a `0x00` byte, so a receiver can resynchronize on the next frame
+//! after any corruption. Every record carries a [`Record::sequence`] number, so frames dropped by
+//! the sender or lost in transit are counted by [`SequenceTracker`].
+//!
+//! ```
+//! use aps490_pfpu2_core::{
+//!     state::StatusLedStates,
+//!     telemetry::{FrameDecoder, Record, SequenceTracker, MAX_FRAME_SIZE},
+//! };
+//!
+//! let mut stream = Vec::new();
+//! for sequence in [0, 1, 4] {
+//!     let record = Record {
+//!         sequence,
+//!         sample: sequence,
+//!         avg_high: 140,
+//!         avg_low: 95,
+//!         delta: 45,
+//!         gain_percent: 100,
+//!         state: StatusLedStates::Armed,
+//!     };
+//!     let mut buf = [0; MAX_FRAME_SIZE];
+//!     stream.extend_from_slice(record.encode(&mut buf).unwrap());
+//! }
+//!
+//! let mut decoder = FrameDecoder::<MAX_FRAME_SIZE>::new();
+//! let mut tracker = SequenceTracker::new();
+//! for byte in stream {
+//!     if let Some(Ok(record)) = decoder.push(byte) {
+//!         tracker.update(record.sequence);
+//!     }
+//! }
+//! assert_eq!((tracker.received(), tracker.dropped()), (3, 2));
+//! ```
+//!
+//! ## Playback
+//!
+//! For hardware-in-the-loop playback, the host sends whole windows of ADC readings in the other
+//! direction. Each window is framed the same way, with the raw readings in place of a serialized
+//! record (see [`encode_window`] and [`decode_window`]).
+//!
+//! ```
+//! use aps490_pfpu2_core::{
+//!     signal::WINDOW_SIZE,
+//!     telemetry::{decode_window, encode_window, FrameDecoder, MAX_WINDOW_FRAME_SIZE},
+//! };
+//!
+//! let mut window = [0; WINDOW_SIZE];
+//! for (idx, reading) in window.iter_mut().enumerate() {
+//!     *reading = if idx % 2 == 0 { 140 } else { 0 };
+//! }
+//! let mut buf = [0; MAX_WINDOW_FRAME_SIZE];
+//! let frame = encode_window(&window, &mut buf).unwrap();
+//!
+//! let mut decoder = FrameDecoder::<MAX_WINDOW_FRAME_SIZE>::new();
+//! let (last, rest) = frame.split_last().unwrap();
+//! assert!(rest.iter().all(|byte| decoder.push_frame(*byte).is_none()));
+//! let received = decoder.push_frame(*last).unwrap().unwrap();
+//! assert_eq!(decode_window(received), Ok(&window));
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt;
+
+use crc::{Crc, CRC_16_IBM_SDLC};
+use serde::{Deserialize, Serialize};
+
+use crate::{signal::WINDOW_SIZE, state::StatusLedStates};
+
+/// Checksum appended to each serialized [`Record`], before COBS encoding
+pub const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+/// Largest serialized [`Record`], with every varint at its longest
+pub const MAX_RECORD_SIZE: usize = 5 + 5 + 5 + 5 + 1 + 1 + 1;
+/// Largest encoded frame, including the CRC, COBS overhead and `0x00` terminator
+pub const MAX_FRAME_SIZE: usize = MAX_RECORD_SIZE + 2 + 1 + 1;
+/// Encoded size of a window sent for playback, including the CRC, COBS overhead and `0x00`
+/// terminator
+pub const MAX_WINDOW_FRAME_SIZE: usize = cobs::max_encoding_length(WINDOW_SIZE + 2) + 1;
+
+/// Averages from a single analysis window
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record {
+    /// Incremented for every record produced, including any dropped before sending
+    pub sequence: u32,
+    /// Sample counter of the detection buffers when the window was analysed. Does not advance
+    /// during self-tests.
+    pub sample: u32,
+    /// Average voltage from the higher half of the window
+    pub avg_high: i32,
+    /// Average voltage from the lower half of the window
+    pub avg_low: i32,
+    /// Averaged difference used for detection
+    pub delta: u8,
+    /// [`DriveLevel::gain_percent`](crate::gain::DriveLevel) of the excitation for the window, so
+    /// windows taken at different drive levels can be compared
+    pub gain_percent: u8,
+    /// System state after the window was analysed
+    pub state: StatusLedStates,
+}
+
+impl Record {
+    /// Encode this record as a complete frame in `buf`, returning the bytes to send.
+    pub fn encode<'a>(&self, buf: &'a mut [u8; MAX_FRAME_SIZE]) -> Result<&'a [u8], FrameError> {
+        let mut raw = [0u8; MAX_RECORD_SIZE + 2];
+        let len = postcard::to_slice(self, &mut raw)
+            .or(Err(FrameError::Serialize))?
+            .len();
+        let crc = CRC.checksum(&raw[..len]);
+        raw[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+
+        let encoded = cobs::try_encode(&raw[..len + 2], buf).or(Err(FrameError::Serialize))?;
+        buf[encoded] = 0;
+        Ok(&buf[..=encoded])
+    }
+
+    /// Decode a single frame, without its `0x00` terminator. The frame is decoded in place.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{
+    ///     state::StatusLedStates,
+    ///     telemetry::{FrameError, Record, MAX_FRAME_SIZE},
+    /// };
+    ///
+    /// let record = Record {
+    ///     sequence: u32::MAX,
+    ///     sample: 45000,
+    ///     avg_high: -1,
+    ///     avg_low: i32::MIN,
+    ///     delta: 255,
+    ///     gain_percent: 22,
+    ///     state: StatusLedStates::Contact,
+    /// };
+    /// let mut buf = [0; MAX_FRAME_SIZE];
+    /// let len = record.encode(&mut buf).unwrap().len();
+    /// assert_eq!(Record::decode(&mut buf.clone()[..len - 1]), Ok(record));
+    ///
+    /// buf[3] ^= 0x10;
+    /// assert_eq!(Record::decode(&mut buf[..len - 1]), Err(FrameError::Checksum));
+    /// ```
+    pub fn decode(frame: &mut [u8]) -> Result<Record, FrameError> {
+        let len = cobs::decode_in_place(frame).or(Err(FrameError::Cobs))?;
+        if len < 2 {
+            return Err(FrameError::Cobs);
+        }
+        let (raw, crc) = frame[..len].split_at(len - 2);
+        if CRC.checksum(raw).to_le_bytes() != crc {
+            return Err(FrameError::Checksum);
+        }
+        postcard::from_bytes(raw).or(Err(FrameError::Deserialize))
+    }
+}
+
+/// Encode a window of ADC readings as a complete frame in `buf`, returning the bytes to send.
+pub fn encode_window<'a>(
+    window: &[u8; WINDOW_SIZE],
+    buf: &'a mut [u8; MAX_WINDOW_FRAME_SIZE],
+) -> Result<&'a [u8], FrameError> {
+    let crc = CRC.checksum(window).to_le_bytes();
+    let mut encoder = cobs::CobsEncoder::new(buf);
+    encoder
+        .push(window)
+        .and_then(|_| encoder.push(&crc))
+        .or(Err(FrameError::Serialize))?;
+    let encoded = encoder.finalize();
+    buf[encoded] = 0;
+    Ok(&buf[..=encoded])
+}
+
+/// Decode a single window frame, without its `0x00` terminator. The frame is decoded in place.
+///
+/// Frames of any length other than a full window are rejected with [`FrameError::Deserialize`].
+pub fn decode_window(frame: &mut [u8]) -> Result<&[u8; WINDOW_SIZE], FrameError> {
+    let len = cobs::decode_in_place(frame).or(Err(FrameError::Cobs))?;
+    if len < 2 {
+        return Err(FrameError::Cobs);
+    }
+    let (raw, crc) = frame[..len].split_at(len - 2);
+    if CRC.checksum(raw).to_le_bytes() != crc {
+        return Err(FrameError::Checksum);
+    }
+    raw.try_into().or(Err(FrameError::Deserialize))
+}
+
+/// Reasons a frame could not be encoded or decoded
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError {
+    /// The record did not fit in a frame
+    Serialize,
+    /// The frame is not valid COBS, or is too short to hold a CRC
+    Cobs,
+    /// The CRC does not match, so the frame was corrupted in transit
+    Checksum,
+    /// The CRC matches, but the contents are not a [`Record`] or window. Usually caused by
+    /// mismatched firmware and host versions.
+    Deserialize,
+    /// The frame was longer than the decoder's buffer, and has been discarded
+    TooLong,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FrameError::Serialize => "record too large for frame",
+            FrameError::Cobs => "invalid COBS frame",
+            FrameError::Checksum => "checksum mismatch",
+            FrameError::Deserialize => "invalid record",
+            FrameError::TooLong => "frame too long",
+        })
+    }
+}
+
+/// Collects received bytes until a frame terminator (`0x00`) is found, then decodes the frame.
+///
+/// Decoding resumes with the next frame after any error. Frames other than [`Record`]s, such as
+/// windows for playback, are collected with [`FrameDecoder::push_frame`].
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct FrameDecoder<const N: usize> {
+    /// Bytes received since the last terminator
+    buf: [u8; N],
+    /// Number of bytes in `buf`
+    len: usize,
+    /// More than `N` bytes have been received since the last terminator
+    overflow: bool,
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    /// Create an empty decoder
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Add a received byte. Returns the decoded record once a terminator is received.
+    ///
+    /// Empty frames are skipped, so a receiver which starts mid-stream only reports an error for
+    /// the first partial frame.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Record, FrameError>> {
+        self.push_frame(byte)
+            .map(|frame| frame.and_then(Record::decode))
+    }
+
+    /// Add a received byte. Returns the complete frame, still COBS encoded and without its
+    /// terminator, once a terminator is received.
+    pub fn push_frame(&mut self, byte: u8) -> Option<Result<&mut [u8], FrameError>> {
+        if byte != 0 {
+            match self.buf.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                }
+                None => self.overflow = true,
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return Some(Err(FrameError::TooLong));
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(Ok(&mut self.buf[..len]))
+    }
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts records lost between the sender and receiver, using [`Record::sequence`]
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SequenceTracker {
+    /// Sequence number of the last record received
+    last: Option<u32>,
+    /// Number of records received
+    received: u64,
+    /// Number of records missing between those received
+    dropped: u64,
+}
+
+impl SequenceTracker {
+    /// Create a tracker which has not received any records
+    pub const fn new() -> Self {
+        Self {
+            last: None,
+            received: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Record a received sequence number, returning the number of records dropped since the last
+    /// one. Sequence numbers wrap around at [`u32::MAX`], and a jump backwards (such as the sender
+    /// restarting) is not counted as dropped records.
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::telemetry::SequenceTracker;
+    ///
+    /// let mut tracker = SequenceTracker::new();
+    /// assert_eq!(tracker.update(u32::MAX - 1), 0);
+    /// assert_eq!(tracker.update(u32::MAX), 0);
+    /// assert_eq!(tracker.update(2), 2);
+    /// assert_eq!(tracker.update(0), 0);
+    /// ```
+    pub fn update(&mut self, sequence: u32) -> u32 {
+        let dropped = self.last.map_or(0, |last| {
+            let gap = sequence.wrapping_sub(last).wrapping_sub(1);
+            if gap < u32::MAX / 2 {
+                gap
+            } else {
+                0
+            }
+        });
+        self.last = Some(sequence);
+        self.received += 1;
+        self.dropped += u64::from(dropped);
+        dropped
+    }
+
+    /// Number of records received
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Number of records dropped since the first one received
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
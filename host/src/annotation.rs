@@ -0,0 +1,230 @@
+//! Ground truth for recordings: when the blade was really in contact.
+//!
+//! An annotation is a JSON file which points at a recording and lists the windows in each true
+//! contact. Recordings can be a defmt log (such as those in `logs/`), or CSV from `pfpu2_record`.
+//! Windows are numbered as in the converted data: [`Window::window`](crate::logs::Window::window)
+//! for logs, and [`Row::sequence`](crate::recorder::Row::sequence) for telemetry.
+//!
+//! ```json
+//! {
+//!   "recording": "../logs/all_up_knife_debug.log",
+//!   "window_ms": 20.0,
+//!   "contacts": [
+//!     { "first": 360, "last": 410, "note": "knife touches gel" }
+//!   ]
+//! }
+//! ```
+//!
+//! `recording` is relative to the annotation file. Annotate from the signal or an independent
+//! record of the session (video, notes), not from the states the firmware reported, or the
+//! evaluation will only measure agreement with the old algorithm.
+//!
+//! ```
+//! use aps490_pfpu2_host::annotation::Annotation;
+//!
+//! let annotation: Annotation = serde_json::from_str(
+//!     r#"{ "recording": "knife.log", "window_ms": 20, "contacts": [{ "first": 5, "last": 9 }] }"#,
+//! )
+//! .unwrap();
+//! annotation.validate().unwrap();
+//! assert!(annotation.contacts[0].contains(9));
+//! assert!(!annotation.contacts[0].contains(10));
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{logs::Dataset, recorder::Row};
+
+/// True contact intervals for one recording
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    /// Recording being annotated, relative to the annotation file
+    pub recording: PathBuf,
+    /// Length of one analysis window, used to convert windows to time
+    pub window_ms: f64,
+    /// Every true contact, in order
+    pub contacts: Vec<Interval>,
+}
+
+/// A range of windows, including both ends
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Interval {
+    /// First window in contact
+    pub first: usize,
+    /// Last window in contact
+    pub last: usize,
+    /// Free-form description
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub note: String,
+}
+
+impl Interval {
+    /// `true` if `window` is within the interval
+    pub fn contains(&self, window: usize) -> bool {
+        (self.first..=self.last).contains(&window)
+    }
+}
+
+/// One analysis window from a recording, as fed to the detector
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Sample {
+    /// Window number, matching [`Interval`]
+    pub window: usize,
+    /// Averaged difference between the high and low halves, limited to `u8` as in the firmware
+    pub delta: u8,
+    /// Average voltage across both halves, if it was recorded
+    pub level: Option<u8>,
+    /// [`DriveLevel::gain_percent`](aps490_pfpu2_core::gain::DriveLevel) of the excitation, or
+    /// 100 where it was not recorded
+    pub gain_percent: u8,
+}
+
+impl Sample {
+    /// Convert high and low averages as the firmware does, with out-of-range values becoming 255
+    pub fn from_averages(window: usize, avg_high: i32, avg_low: i32) -> Self {
+        Self {
+            window,
+            delta: u8::try_from(avg_high - avg_low).unwrap_or(255),
+            level: Some(u8::try_from((avg_high + avg_low) / 2).unwrap_or(255)),
+            gain_percent: 100,
+        }
+    }
+}
+
+/// Reasons an annotation or its recording could not be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    /// File could not be read
+    Io(PathBuf, io::Error),
+    /// Annotation is not valid JSON, or is missing fields
+    Json(PathBuf, serde_json::Error),
+    /// Telemetry CSV could not be parsed
+    Csv(PathBuf, csv::Error),
+    /// Annotation content is inconsistent
+    Invalid(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(path, err) => write!(f, "unable to read {}: {err}", path.display()),
+            LoadError::Json(path, err) => write!(f, "invalid annotation {}: {err}", path.display()),
+            LoadError::Csv(path, err) => write!(f, "invalid recording {}: {err}", path.display()),
+            LoadError::Invalid(reason) => write!(f, "invalid annotation: {reason}"),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(_, err) => Some(err),
+            LoadError::Json(_, err) => Some(err),
+            LoadError::Csv(_, err) => Some(err),
+            LoadError::Invalid(_) => None,
+        }
+    }
+}
+
+impl Annotation {
+    /// Read and validate an annotation file. [`Annotation::recording`] is resolved relative to the
+    /// file.
+    pub fn load(path: &Path) -> Result<Self, LoadError> {
+        let text = fs::read_to_string(path).map_err(|err| LoadError::Io(path.into(), err))?;
+        let mut annotation: Annotation =
+            serde_json::from_str(&text).map_err(|err| LoadError::Json(path.into(), err))?;
+        if let Some(dir) = path.parent() {
+            annotation.recording = dir.join(&annotation.recording);
+        }
+        annotation.validate()?;
+        Ok(annotation)
+    }
+
+    /// Check that the window length is positive, and intervals are in order without overlapping
+    pub fn validate(&self) -> Result<(), LoadError> {
+        if self.window_ms.is_nan() || self.window_ms <= 0.0 {
+            return Err(LoadError::Invalid(format!(
+                "window_ms must be positive, not {}",
+                self.window_ms
+            )));
+        }
+        for (idx, contact) in self.contacts.iter().enumerate() {
+            if contact.first > contact.last {
+                return Err(LoadError::Invalid(format!(
+                    "contact {idx} ends before it starts ({} > {})",
+                    contact.first, contact.last
+                )));
+            }
+            if idx > 0 && contact.first <= self.contacts[idx - 1].last {
+                return Err(LoadError::Invalid(format!(
+                    "contact {idx} overlaps or is before contact {}",
+                    idx - 1
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the windows of [`Annotation::recording`]. Files ending in `.csv` are read as
+    /// `pfpu2_record` output, and anything else as a defmt log.
+    ///
+    /// Log windows without averages or a backfilled difference are skipped, as the detector cannot
+    /// use them.
+    pub fn load_recording(&self) -> Result<Vec<Sample>, LoadError> {
+        let path = &self.recording;
+        if path.extension().is_some_and(|ext| ext == "csv") {
+            let mut reader =
+                csv::Reader::from_path(path).map_err(|err| LoadError::Csv(path.clone(), err))?;
+            reader
+                .deserialize::<Row>()
+                .map(|row| {
+                    row.map(|row| Sample {
+                        gain_percent: row.gain_percent,
+                        ..Sample::from_averages(row.sequence as usize, row.avg_high, row.avg_low)
+                    })
+                    .map_err(|err| LoadError::Csv(path.clone(), err))
+                })
+                .collect()
+        } else {
+            let text = fs::read_to_string(path).map_err(|err| LoadError::Io(path.clone(), err))?;
+            Ok(Dataset::parse(&text)
+                .windows
+                .iter()
+                .filter_map(
+                    |window| match (window.avg_high, window.avg_low, window.delta) {
+                        (Some(avg_high), Some(avg_low), _) => {
+                            Some(Sample::from_averages(window.window, avg_high, avg_low))
+                        }
+                        (_, _, Some(delta)) => Some(Sample {
+                            window: window.window,
+                            delta: u8::try_from(delta).unwrap_or(255),
+                            level: None,
+                            gain_percent: 100,
+                        }),
+                        _ => None,
+                    },
+                )
+                .collect())
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Evaluates detection accuracy against annotated recordings.
+//!
+//! Each annotation file (see [`aps490_pfpu2_host::annotation`]) names a recording, which is run
+//! through the detector with the given thresholds. Results are reported for every recording and
+//! for all of them combined.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{error::Error, path::PathBuf};
+
+use aps490_pfpu2_core::config::Thresholds;
+use aps490_pfpu2_host::{
+    annotation::Annotation,
+    eval::{evaluate, Options, Score},
+};
+use clap::Parser;
+
+/// Measure detection accuracy on annotated recordings
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Annotation files
+    #[arg(required = true)]
+    annotations: Vec<PathBuf>,
+    /// Trigger delta given to the detector
+    #[arg(long, default_value_t = Thresholds::new().trigger_delta)]
+    trigger_delta: u8,
+    /// Confirm delta given to the detector
+    #[arg(long, default_value_t = Thresholds::new().confirm_delta)]
+    confirm_delta: u8,
+    /// Restore delta given to the detector
+    #[arg(long, default_value_t = Thresholds::new().restore_delta)]
+    restore_delta: u8,
+    /// Proximity delta given to the detector, for recordings with levels
+    #[arg(long, default_value_t = Thresholds::new().proximity_delta)]
+    proximity_delta: u8,
+    /// Windows either side of a contact in which an alert still counts
+    #[arg(short, long, default_value_t = 0)]
+    tolerance: usize,
+    /// Print the combined results as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let options = Options {
+        thresholds: Thresholds {
+            trigger_delta: args.trigger_delta,
+            confirm_delta: args.confirm_delta,
+            restore_delta: args.restore_delta,
+            proximity_delta: args.proximity_delta,
+            ..Thresholds::new()
+        },
+        tolerance: args.tolerance,
+    };
+
+    let mut total = Score::default();
+    for path in &args.annotations {
+        let annotation = Annotation::load(path)?;
+        let samples = annotation.load_recording()?;
+        let score = evaluate(&samples, &annotation, &options);
+        if !args.json {
+            println!("== {}\n{}\n", path.display(), score.summary());
+        }
+        total.merge(&score);
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&total.summary())?);
+    } else if args.annotations.len() > 1 {
+        println!("== Total\n{}", total.summary());
+    }
+    Ok(())
+}
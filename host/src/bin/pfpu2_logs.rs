@@ -0,0 +1,90 @@
+//! Converts defmt logs into a time-ordered dataset of windows and state transitions.
+//!
+//! CSV output has one row per window, with state changes in the `events` column. JSON output
+//! contains both the windows and the full list of transitions.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use aps490_pfpu2_host::logs::Dataset;
+use clap::{Parser, ValueEnum};
+
+/// Convert detection system logs to CSV or JSON
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Log file, such as logs/all_up_knife_debug.log
+    input: PathBuf,
+    /// Output file. Defaults to stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+    format: Format,
+    /// Length of each analysis window in milliseconds, to add a `time_s` column
+    #[arg(short, long)]
+    window_ms: Option<f64>,
+}
+
+/// Output formats
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum Format {
+    /// One row per window
+    Csv,
+    /// Windows and transitions
+    Json,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let mut dataset = Dataset::parse(&fs::read_to_string(&args.input)?);
+    if let Some(window_ms) = args.window_ms {
+        dataset.set_window_length(window_ms / 1000.0);
+    }
+
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    match args.format {
+        Format::Csv => {
+            let mut csv = csv::Writer::from_writer(output);
+            for window in &dataset.windows {
+                csv.serialize(window)?;
+            }
+            csv.flush()?;
+        }
+        Format::Json => {
+            let mut output = output;
+            serde_json::to_writer_pretty(&mut output, &dataset)?;
+            writeln!(output)?;
+        }
+    }
+
+    eprintln!(
+        "Parsed {} windows and {} transitions from {}",
+        dataset.windows.len(),
+        dataset.transitions.len(),
+        args.input.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,159 @@
+//! Plays raw windows into the firmware over USB, and checks its decisions against the host.
+//!
+//! The firmware must be built with the `playback` feature, and reset before playback so that both
+//! sides start calibrating from the first window. Windows are sent on the telemetry port, a few at a
+//! time, and each one is matched with the next telemetry record. The firmware must use the same
+//! thresholds as given here, with the `AutoClear` alert policy.
+//!
+//! Exits with an error if any window differs, or the firmware stops responding.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Read, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use aps490_pfpu2_core::{
+    config::Thresholds,
+    telemetry::{encode_window, MAX_WINDOW_FRAME_SIZE},
+};
+use aps490_pfpu2_host::{
+    playback::{compare, read_windows, Decision, Reference},
+    recorder::{Row, Session},
+};
+use clap::Parser;
+use serialport::ClearBuffer;
+
+/// Play recorded windows through the detection firmware
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Raw windows, such as those written by `pfpu2_sim --raw`
+    input: PathBuf,
+    /// Telemetry serial port, such as /dev/ttyACM1 or COM4. This is the second port provided by the
+    /// firmware; the first is the console.
+    #[arg(short, long)]
+    port: String,
+    /// Write the firmware's decisions to CSV, in the same format as `pfpu2_record`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Trigger delta set on the firmware
+    #[arg(long, default_value_t = Thresholds::new().trigger_delta)]
+    trigger_delta: u8,
+    /// Confirm delta set on the firmware
+    #[arg(long, default_value_t = Thresholds::new().confirm_delta)]
+    confirm_delta: u8,
+    /// Restore delta set on the firmware
+    #[arg(long, default_value_t = Thresholds::new().restore_delta)]
+    restore_delta: u8,
+    /// Windows sent ahead of the firmware's decisions. The firmware holds one window while
+    /// receiving the next.
+    #[arg(long, default_value_t = 2)]
+    in_flight: usize,
+    /// Seconds to wait for each decision
+    #[arg(long, default_value_t = 2.0)]
+    timeout: f64,
+    /// Number of mismatched windows to print
+    #[arg(long, default_value_t = 10)]
+    show: usize,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let thresholds = Thresholds {
+        trigger_delta: args.trigger_delta,
+        confirm_delta: args.confirm_delta,
+        restore_delta: args.restore_delta,
+        ..Thresholds::new()
+    };
+    let windows = read_windows(File::open(&args.input)?)?;
+    let mut reference = Reference::new(thresholds);
+    let host: Vec<Decision> = windows
+        .iter()
+        .map(|window| reference.analyse(window))
+        .collect();
+
+    let mut port = serialport::new(&args.port, 115_200)
+        .timeout(Duration::from_secs_f64(args.timeout))
+        .open()?;
+    port.clear(ClearBuffer::All)?;
+
+    let mut session = Session::new();
+    let mut rows: Vec<Row> = Vec::with_capacity(windows.len());
+    let mut frame = [0u8; MAX_WINDOW_FRAME_SIZE];
+    let mut buf = [0u8; 1024];
+    let mut sent = 0;
+    let start = Instant::now();
+    eprintln!("Playing {} windows to {}", windows.len(), args.port);
+
+    while rows.len() < windows.len() {
+        while sent < windows.len() && sent - rows.len() < args.in_flight.max(1) {
+            let encoded =
+                encode_window(&windows[sent], &mut frame).map_err(|err| err.to_string())?;
+            port.write_all(encoded)?;
+            sent += 1;
+        }
+
+        let read = match port.read(&mut buf) {
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                return Err(format!(
+                    "no decision for window {} within {} s",
+                    rows.len(),
+                    args.timeout
+                )
+                .into())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        rows.extend(session.push(&buf[..read], start.elapsed()));
+
+        let (_, dropped, _) = session.stats();
+        if dropped > 0 {
+            return Err(format!("{dropped} decisions lost before window {}", rows.len()).into());
+        }
+    }
+    rows.truncate(windows.len());
+
+    if let Some(path) = &args.output {
+        let mut csv = csv::Writer::from_path(path)?;
+        for row in &rows {
+            csv.serialize(row)?;
+        }
+        csv.flush()?;
+    }
+
+    let target: Vec<Decision> = rows.iter().map(Decision::from).collect();
+    let mismatches = compare(&host, &target);
+    for mismatch in mismatches.iter().take(args.show) {
+        println!("{mismatch}");
+    }
+    eprintln!(
+        "Played {} windows in {:.1} s, {} mismatched",
+        windows.len(),
+        start.elapsed().as_secs_f64(),
+        mismatches.len()
+    );
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("host and target disagree on {} windows", mismatches.len()).into())
+    }
+}
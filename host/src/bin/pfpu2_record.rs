@@ -0,0 +1,148 @@
+//! Records the firmware's telemetry stream to CSV, and optionally Parquet.
+//!
+//! The firmware must be built with the `telemetry` feature, which adds a second USB serial port
+//! for the stream (telemetry is not sent over RTT, as `defmt-rtt` only provides one channel).
+//! Recording stops on Ctrl-C, at the end of `--input`, or after `--duration`.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Read},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "parquet")]
+use aps490_pfpu2_host::recorder::ParquetWriter;
+use aps490_pfpu2_host::recorder::{Row, Session};
+use clap::Parser;
+
+/// Record telemetry from the detection system
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Telemetry serial port, such as /dev/ttyACM1 or COM4. This is the second port provided by the
+    /// firmware; the first is the console.
+    #[arg(short, long, required_unless_present = "input")]
+    port: Option<String>,
+    /// Replay raw bytes previously captured from the telemetry port, instead of a live device
+    #[arg(short, long, conflicts_with = "port")]
+    input: Option<PathBuf>,
+    /// CSV output file
+    #[arg(short, long)]
+    output: PathBuf,
+    /// Parquet output file, written alongside the CSV
+    #[cfg(feature = "parquet")]
+    #[arg(long)]
+    parquet: Option<PathBuf>,
+    /// Stop after this many seconds
+    #[arg(short, long)]
+    duration: Option<f64>,
+}
+
+/// Rows are flushed to disk in batches of this size
+const BATCH_SIZE: usize = 500;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = stop.clone();
+    ctrlc::set_handler(move || handler_stop.store(true, Ordering::Relaxed))?;
+
+    let mut source: Box<dyn Read> = match (&args.port, &args.input) {
+        (_, Some(input)) => Box::new(File::open(input)?),
+        (Some(port), None) => Box::new(
+            serialport::new(port, 115_200)
+                .timeout(Duration::from_millis(100))
+                .open()?,
+        ),
+        (None, None) => unreachable!("clap requires --port or --input"),
+    };
+    let mut csv = csv::Writer::from_path(&args.output)?;
+    #[cfg(feature = "parquet")]
+    let mut parquet = args
+        .parquet
+        .as_ref()
+        .map(|path| File::create(path).map_err(Box::<dyn Error>::from))
+        .transpose()?
+        .map(ParquetWriter::new)
+        .transpose()?;
+
+    let limit = args.duration.map(Duration::from_secs_f64);
+    let mut session = Session::new();
+    let mut batch: Vec<Row> = Vec::with_capacity(BATCH_SIZE);
+    let mut buf = [0u8; 1024];
+    let start = Instant::now();
+    eprintln!(
+        "Recording to {}, press Ctrl-C to stop",
+        args.output.display()
+    );
+
+    while !stop.load(Ordering::Relaxed) && limit.is_none_or(|limit| start.elapsed() < limit) {
+        let read = match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let received = session.stats().0;
+        batch.extend(session.push(&buf[..read], start.elapsed()));
+        if let Some(err) = session.take_error() {
+            // The first frame is usually partial, as recording starts mid-stream
+            if session.stats().2 > 1 || received > 0 {
+                eprintln!("Discarded telemetry frame: {err}");
+            }
+        }
+
+        if batch.len() >= BATCH_SIZE {
+            for row in &batch {
+                csv.serialize(row)?;
+            }
+            csv.flush()?;
+            #[cfg(feature = "parquet")]
+            if let Some(parquet) = parquet.as_mut() {
+                parquet.write(&batch)?;
+            }
+            batch.clear();
+        }
+    }
+
+    for row in &batch {
+        csv.serialize(row)?;
+    }
+    csv.flush()?;
+    #[cfg(feature = "parquet")]
+    if let Some(mut parquet) = parquet {
+        if !batch.is_empty() {
+            parquet.write(&batch)?;
+        }
+        parquet.close()?;
+    }
+
+    let (received, dropped, errors) = session.stats();
+    eprintln!(
+        "Recorded {received} windows in {:.1} s ({dropped} dropped, {errors} corrupt frames)",
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
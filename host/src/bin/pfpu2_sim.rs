@@ -0,0 +1,153 @@
+//! Simulates a scenario, writing the windows in the same CSV format as `pfpu2_record`.
+//!
+//! Each window is analysed by a [`Reference`] copy of the firmware's detection, starting with
+//! calibration as after a reset. An annotation marking the scripted contacts can be written
+//! alongside, for `pfpu2_eval` and `pfpu2_sweep`, and the raw ADC readings saved for replay.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{self, PathBuf},
+    time::Duration,
+};
+
+use aps490_pfpu2_core::{config::Thresholds, telemetry::Record};
+use aps490_pfpu2_host::{
+    annotation::{Annotation, Interval},
+    playback::Reference,
+    recorder::Session,
+    sim::{run, Circuit, Scenario, Simulator},
+};
+use clap::Parser;
+
+/// Simulate the detection circuit for a scripted scenario
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Scenario to simulate
+    #[arg(value_enum)]
+    scenario: Scenario,
+    /// CSV output file
+    #[arg(short, long)]
+    output: PathBuf,
+    /// Write an annotation of the scripted contacts
+    #[arg(short, long)]
+    annotation: Option<PathBuf>,
+    /// Write the raw ADC readings, one window after another
+    #[arg(long)]
+    raw: Option<PathBuf>,
+    /// Seed for the noise and initial phase
+    #[arg(short, long, default_value_t = 0)]
+    seed: u64,
+    /// Standard deviation of the ADC noise, in 8-bit counts
+    #[arg(long, default_value_t = Circuit::default().noise_lsb)]
+    noise_lsb: f64,
+    /// Error in the ADC sample rate, in parts per million
+    #[arg(long, default_value_t = Circuit::default().adc_error_ppm)]
+    adc_error_ppm: f64,
+    /// Resistance between the PWM pin and the blade
+    #[arg(long, default_value_t = Circuit::default().series_ohms)]
+    series_ohms: f64,
+    /// Capacitance of the blade and wiring, in picofarads
+    #[arg(long, default_value_t = Circuit::default().stray_farads * 1e12)]
+    stray_pf: f64,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let circuit = Circuit {
+        noise_lsb: args.noise_lsb,
+        adc_error_ppm: args.adc_error_ppm,
+        series_ohms: args.series_ohms,
+        stray_farads: args.stray_pf * 1e-12,
+        ..Circuit::default()
+    };
+    let window_s = circuit.window_s();
+
+    let mut csv = csv::Writer::from_path(&args.output)?;
+    let mut raw = args
+        .raw
+        .as_ref()
+        .map(File::create)
+        .transpose()?
+        .map(BufWriter::new);
+    let mut session = Session::new();
+    let mut reference = Reference::new(Thresholds::new());
+    let mut contacts: Vec<Interval> = Vec::new();
+
+    let segments = args.scenario.segments();
+    let mut simulator = Simulator::new(circuit, args.seed);
+    for window in run(&mut simulator, &segments) {
+        let decision = reference.analyse(&window.readings);
+        let record = Record {
+            sequence: window.window as u32,
+            sample: window.window as u32,
+            avg_high: decision.avg_high,
+            avg_low: decision.avg_low,
+            delta: decision.delta,
+            gain_percent: decision.gain_percent,
+            state: decision.state,
+        };
+        let elapsed = Duration::from_secs_f64(window.window as f64 * window_s);
+        csv.serialize(session.row(record, elapsed))?;
+
+        if let Some(raw) = raw.as_mut() {
+            raw.write_all(&window.readings)?;
+        }
+        if window.contact {
+            match contacts.last_mut() {
+                Some(contact) if contact.last + 1 == window.window => contact.last = window.window,
+                _ => contacts.push(Interval {
+                    first: window.window,
+                    last: window.window,
+                    note: format!("{:?}", args.scenario),
+                }),
+            }
+        }
+    }
+    csv.flush()?;
+    if let Some(mut raw) = raw {
+        raw.flush()?;
+    }
+
+    if let Some(path) = &args.annotation {
+        // Refer to the recording relative to the annotation where possible
+        let recording = if path.parent() == args.output.parent() {
+            args.output
+                .file_name()
+                .map(PathBuf::from)
+                .unwrap_or_default()
+        } else {
+            path::absolute(&args.output)?
+        };
+        let annotation = Annotation {
+            recording,
+            window_ms: window_s * 1000.0,
+            contacts,
+        };
+        fs::write(path, serde_json::to_string_pretty(&annotation)?)?;
+    }
+
+    let (received, _, _) = session.stats();
+    eprintln!(
+        "Simulated {received} windows ({:.1} s) of {:?}",
+        received as f64 * window_s,
+        args.scenario
+    );
+    Ok(())
+}
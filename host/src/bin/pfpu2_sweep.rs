@@ -0,0 +1,133 @@
+//! Sweeps detection thresholds over annotated recordings, and recommends an operating point.
+//!
+//! Prints the ROC frontier as a table of false alerts against missed contacts. The full table can
+//! be written to CSV, the curve plotted to SVG, and the recommended thresholds exported as a script
+//! of console commands to send to the firmware's USB console. The firmware forgets them on reset,
+//! so the script must be sent again after every startup.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{error::Error, fs, ops::RangeInclusive, path::PathBuf};
+
+use aps490_pfpu2_host::{
+    annotation::Annotation,
+    sweep::{console_script, frontier, parse_range, recommend, roc_svg, sweep, Grid},
+};
+use clap::Parser;
+
+/// Sweep detection thresholds over annotated recordings
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Annotation files
+    #[arg(required = true)]
+    annotations: Vec<PathBuf>,
+    /// Trigger deltas to try, as `min-max` or a single value
+    #[arg(long, default_value = "1-10", value_parser = parse_range)]
+    trigger_delta: RangeInclusive<u8>,
+    /// Confirm deltas to try
+    #[arg(long, default_value = "0-4", value_parser = parse_range)]
+    confirm_delta: RangeInclusive<u8>,
+    /// Restore deltas to try
+    #[arg(long, default_value = "1-6", value_parser = parse_range)]
+    restore_delta: RangeInclusive<u8>,
+    /// Windows either side of a contact in which an alert still counts
+    #[arg(short, long, default_value_t = 0)]
+    tolerance: usize,
+    /// Highest false alert rate (per minute) allowed for the recommended operating point
+    #[arg(short, long, default_value_t = 0.5)]
+    max_false_per_min: f64,
+    /// Write every combination of thresholds to CSV
+    #[arg(long)]
+    table: Option<PathBuf>,
+    /// Plot the ROC curve to SVG
+    #[arg(long)]
+    roc: Option<PathBuf>,
+    /// Write a script of console commands applying the recommended thresholds until the next
+    /// reset
+    #[arg(short, long)]
+    script: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let grid = Grid {
+        trigger_delta: args.trigger_delta,
+        confirm_delta: args.confirm_delta,
+        restore_delta: args.restore_delta,
+    };
+
+    let recordings = args
+        .annotations
+        .iter()
+        .map(|path| {
+            let annotation = Annotation::load(path)?;
+            let samples = annotation.load_recording()?;
+            Ok((annotation, samples))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    let points = sweep(&recordings, &grid, args.tolerance);
+
+    if let Some(path) = &args.table {
+        let mut csv = csv::Writer::from_path(path)?;
+        for point in &points {
+            csv.serialize(point.row())?;
+        }
+        csv.flush()?;
+    }
+
+    println!(
+        "{:>7} {:>7} {:>7} {:>6} {:>8} {:>11} {:>10}",
+        "trigger", "confirm", "restore", "missed", "false", "false/min", "median ms"
+    );
+    for point in frontier(&points) {
+        let row = point.row();
+        println!(
+            "{:>7} {:>7} {:>7} {:>6} {:>8} {:>11.2} {:>10}",
+            row.trigger_delta,
+            row.confirm_delta,
+            row.restore_delta,
+            row.missed,
+            row.false_alerts,
+            row.false_alerts_per_min.unwrap_or_default(),
+            row.latency_median_ms
+                .map_or_else(|| "-".into(), |latency| format!("{latency:.0}"))
+        );
+    }
+
+    let recommended = recommend(&points, args.max_false_per_min);
+    if let Some(path) = &args.roc {
+        fs::write(path, roc_svg(&points, recommended))?;
+    }
+    match recommended {
+        Some(point) => {
+            println!(
+                "\nRecommended (at most {} false alerts/min):\n{}",
+                args.max_false_per_min,
+                point.score.summary()
+            );
+            let script = console_script(&point.thresholds);
+            match &args.script {
+                Some(path) => fs::write(path, script)?,
+                None => print!("\nConsole script (lost on reset):\n{script}"),
+            }
+        }
+        None => eprintln!(
+            "\nNo thresholds have at most {} false alerts/min",
+            args.max_false_per_min
+        ),
+    }
+    Ok(())
+}
@@ -0,0 +1,381 @@
+//! Measures detection accuracy against [annotated](crate::annotation) recordings.
+//!
+//! Every window of a recording is run through the same [`Detector`] as the firmware, starting
+//! [`Armed`](StatusLedStates::Armed) once its history is filled. Each entry into
+//! [`Contact`](StatusLedStates::Contact) is an alert, which is compared with the true contacts:
+//!
+//! - An alert within a contact (allowing [`Options::tolerance`] windows either side) detects it.
+//!   Further alerts during the same contact are counted as repeats, not false alerts.
+//! - Any other alert is a false alert.
+//! - Latency is the time from the start of a contact to the alert which detected it, and is
+//!   negative if the alert came early, within the tolerance.
+//!
+//! Alerts always clear as with
+//! [`AlertPolicy::AutoClear`](aps490_pfpu2_core::alert::AlertPolicy::AutoClear).
+//!
+//! Proximity needs the average voltage of every window. If the recording has it, the detector
+//! first [calibrates](StatusLedStates::Calibrating) on the opening
+//! [`Detector::CALIBRATION_US`] as the firmware does, taking each window as the
+//! [`Detector::DEFAULT_WINDOW_US`] recorded at the startup excitation, and entries into
+//! [`Proximity`](StatusLedStates::Proximity) are counted along with the contacts they gave warning
+//! of. Contacts cannot be detected during calibration. Without levels, calibration is skipped and
+//! proximity is reported as not measured.
+//!
+//! ```
+//! use aps490_pfpu2_host::{
+//!     annotation::{Annotation, Interval, Sample},
+//!     eval::{evaluate, Options},
+//! };
+//!
+//! let annotation = Annotation {
+//!     recording: "knife.log".into(),
+//!     window_ms: 20.0,
+//!     contacts: vec![Interval { first: 100, last: 200, note: String::new() }],
+//! };
+//! // Idle at 45, then contact reduces the difference to 39 from window 102
+//! let samples: Vec<Sample> = (0..3000)
+//!     .map(|window| Sample {
+//!         window,
+//!         delta: if (102..=200).contains(&window) { 39 } else { 45 },
+//!         level: None,
+//!         gain_percent: 100,
+//!     })
+//!     .collect();
+//!
+//! let score = evaluate(&samples, &annotation, &Options::default());
+//! let summary = score.summary();
+//! assert_eq!(summary.recall, Some(1.0));
+//! assert_eq!(summary.false_alerts, 0);
+//! assert_eq!(summary.latency_ms.median, Some(60.0));
+//! assert_eq!(summary.proximity, None);
+//! ```
+//!
+//! With levels recorded, the detector calibrates first and proximity is measured:
+//!
+//! ```
+//! use aps490_pfpu2_host::{
+//!     annotation::{Annotation, Interval, Sample},
+//!     eval::{evaluate, Options},
+//! };
+//!
+//! let annotation = Annotation {
+//!     recording: "knife.csv".into(),
+//!     window_ms: 20.0,
+//!     contacts: vec![Interval { first: 1000, last: 1100, note: String::new() }],
+//! };
+//! // The level rises as the blade approaches, before contact reduces the difference
+//! let samples: Vec<Sample> = (0..3000)
+//!     .map(|window| Sample {
+//!         window,
+//!         delta: if (1000..=1100).contains(&window) { 39 } else { 45 },
+//!         level: Some(if (900..=1100).contains(&window) { 140 } else { 100 }),
+//!         gain_percent: 100,
+//!     })
+//!     .collect();
+//!
+//! let summary = evaluate(&samples, &annotation, &Options::default()).summary();
+//! assert_eq!(summary.recall, Some(1.0));
+//! let proximity = summary.proximity.unwrap();
+//! assert_eq!((proximity.reports, proximity.warned), (1, 1));
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use aps490_pfpu2_core::{config::Thresholds, detect::Detector, state::StatusLedStates};
+use serde::Serialize;
+
+use crate::annotation::{Annotation, Sample};
+
+/// Settings for an evaluation
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Options {
+    /// Thresholds given to the detector
+    pub thresholds: Thresholds,
+    /// Windows either side of a contact in which an alert still counts as detecting it, to allow
+    /// for imprecise annotation
+    pub tolerance: usize,
+}
+
+/// Alerts and contacts counted over one or more recordings
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Score {
+    /// Recordings evaluated
+    pub recordings: usize,
+    /// Total length of the recordings
+    pub minutes: f64,
+    /// True contacts
+    pub contacts: usize,
+    /// True contacts with at least one alert
+    pub detected: usize,
+    /// Alerts which detected a contact
+    pub true_alerts: usize,
+    /// Alerts outside every contact
+    pub false_alerts: usize,
+    /// Further alerts during a contact which was already detected
+    pub repeat_alerts: usize,
+    /// Latency of each detected contact
+    pub latencies_ms: Vec<f64>,
+    /// Recordings with the levels needed to measure proximity
+    pub proximity_recordings: usize,
+    /// Entries into [`Proximity`](StatusLedStates::Proximity)
+    pub proximity_reports: usize,
+    /// Detected contacts which were alerted while proximity was reported
+    pub warned: usize,
+}
+
+/// Run the detector over a recording and score its alerts against the annotation
+pub fn evaluate(samples: &[Sample], annotation: &Annotation, options: &Options) -> Score {
+    // Proximity needs calibration, which needs the level of every window
+    let calibrate = !samples.is_empty() && samples.iter().all(|sample| sample.level.is_some());
+    let mut detector = Detector::new();
+    let mut state = if calibrate {
+        StatusLedStates::Calibrating
+    } else {
+        StatusLedStates::Armed
+    };
+    let mut alerts = Vec::new();
+    let mut proximity_reports = 0;
+    for (idx, sample) in samples.iter().enumerate() {
+        if !calibrate && idx + 1 < Detector::HISTORY {
+            detector.insert(sample.delta);
+            continue;
+        }
+        let level = sample.level.unwrap_or_default();
+        // Thresholds follow the drive level, as on the firmware
+        let thresholds = options.thresholds.at_gain(sample.gain_percent);
+        if let Some(next) = detector.update(state, sample.delta, level, &thresholds) {
+            match next {
+                StatusLedStates::Contact => {
+                    alerts.push((sample.window, state == StatusLedStates::Proximity))
+                }
+                StatusLedStates::Proximity => proximity_reports += 1,
+                _ => {}
+            }
+            state = next;
+        }
+    }
+
+    let mut score = Score {
+        recordings: 1,
+        minutes: samples.len() as f64 * annotation.window_ms / 60_000.0,
+        contacts: annotation.contacts.len(),
+        proximity_recordings: calibrate as usize,
+        proximity_reports,
+        ..Score::default()
+    };
+    let mut detected = vec![false; annotation.contacts.len()];
+    for (alert, in_proximity) in alerts {
+        let contact = annotation.contacts.iter().position(|contact| {
+            (contact.first.saturating_sub(options.tolerance)
+                ..=contact.last.saturating_add(options.tolerance))
+                .contains(&alert)
+        });
+        match contact {
+            Some(idx) if detected[idx] => score.repeat_alerts += 1,
+            Some(idx) => {
+                detected[idx] = true;
+                score.true_alerts += 1;
+                score.warned += in_proximity as usize;
+                let latency = alert as f64 - annotation.contacts[idx].first as f64;
+                score.latencies_ms.push(latency * annotation.window_ms);
+            }
+            None => score.false_alerts += 1,
+        }
+    }
+    score.detected = detected.iter().filter(|detected| **detected).count();
+    score
+}
+
+impl Score {
+    /// Add the results of another recording
+    pub fn merge(&mut self, other: &Score) {
+        self.recordings += other.recordings;
+        self.minutes += other.minutes;
+        self.contacts += other.contacts;
+        self.detected += other.detected;
+        self.true_alerts += other.true_alerts;
+        self.false_alerts += other.false_alerts;
+        self.repeat_alerts += other.repeat_alerts;
+        self.latencies_ms.extend_from_slice(&other.latencies_ms);
+        self.proximity_recordings += other.proximity_recordings;
+        self.proximity_reports += other.proximity_reports;
+        self.warned += other.warned;
+    }
+
+    /// Fraction of alerts which detected a contact, or `None` if there were no alerts
+    pub fn precision(&self) -> Option<f64> {
+        let alerts = self.true_alerts + self.false_alerts;
+        (alerts > 0).then(|| self.true_alerts as f64 / alerts as f64)
+    }
+
+    /// Fraction of contacts which were detected, or `None` if there were no contacts
+    pub fn recall(&self) -> Option<f64> {
+        (self.contacts > 0).then(|| self.detected as f64 / self.contacts as f64)
+    }
+
+    /// False alerts per minute of recording, or `None` if nothing was recorded
+    pub fn false_alerts_per_min(&self) -> Option<f64> {
+        (self.minutes > 0.0).then(|| self.false_alerts as f64 / self.minutes)
+    }
+
+    /// Derived metrics, for reporting
+    pub fn summary(&self) -> Summary {
+        Summary {
+            recordings: self.recordings,
+            minutes: self.minutes,
+            contacts: self.contacts,
+            detected: self.detected,
+            false_alerts: self.false_alerts,
+            repeat_alerts: self.repeat_alerts,
+            precision: self.precision(),
+            recall: self.recall(),
+            false_alerts_per_min: self.false_alerts_per_min(),
+            latency_ms: Latency::new(&self.latencies_ms),
+            proximity: (self.proximity_recordings > 0).then_some(Proximity {
+                recordings: self.proximity_recordings,
+                reports: self.proximity_reports,
+                warned: self.warned,
+            }),
+        }
+    }
+}
+
+/// Accuracy metrics from a [`Score`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    /// [`Score::recordings`]
+    pub recordings: usize,
+    /// [`Score::minutes`]
+    pub minutes: f64,
+    /// [`Score::contacts`]
+    pub contacts: usize,
+    /// [`Score::detected`]
+    pub detected: usize,
+    /// [`Score::false_alerts`]
+    pub false_alerts: usize,
+    /// [`Score::repeat_alerts`]
+    pub repeat_alerts: usize,
+    /// [`Score::precision`]
+    pub precision: Option<f64>,
+    /// [`Score::recall`]
+    pub recall: Option<f64>,
+    /// [`Score::false_alerts_per_min`]
+    pub false_alerts_per_min: Option<f64>,
+    /// Distribution of [`Score::latencies_ms`]
+    pub latency_ms: Latency,
+    /// Proximity results, or `None` if no recording had the levels to measure it
+    pub proximity: Option<Proximity>,
+}
+
+/// Proximity results from a [`Score`]
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Proximity {
+    /// [`Score::proximity_recordings`]
+    pub recordings: usize,
+    /// [`Score::proximity_reports`]
+    pub reports: usize,
+    /// [`Score::warned`]
+    pub warned: usize,
+}
+
+/// Distribution of detection latencies. Percentiles use the nearest rank.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Latency {
+    /// Fastest detection
+    pub min: Option<f64>,
+    /// 50th percentile
+    pub median: Option<f64>,
+    /// 90th percentile
+    pub p90: Option<f64>,
+    /// Slowest detection
+    pub max: Option<f64>,
+    /// Arithmetic mean
+    pub mean: Option<f64>,
+}
+
+impl Latency {
+    /// Summarize latencies, which may be in any order
+    pub fn new(latencies: &[f64]) -> Self {
+        if latencies.is_empty() {
+            return Self::default();
+        }
+        let mut sorted = latencies.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+        Self {
+            min: sorted.first().copied(),
+            median: Some(percentile(50.0)),
+            p90: Some(percentile(90.0)),
+            max: sorted.last().copied(),
+            mean: Some(sorted.iter().sum::<f64>() / sorted.len() as f64),
+        }
+    }
+}
+
+/// Format an optional value with a fixed number of decimals, or `-` if missing
+fn optional(value: Option<f64>, decimals: usize) -> String {
+    value.map_or_else(|| "-".into(), |value| format!("{value:.decimals$}"))
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} recordings, {:.1} min, {} contacts",
+            self.recordings, self.minutes, self.contacts
+        )?;
+        writeln!(
+            f,
+            "precision {}  recall {} ({}/{})",
+            optional(self.precision, 3),
+            optional(self.recall, 3),
+            self.detected,
+            self.contacts
+        )?;
+        writeln!(
+            f,
+            "false alerts {} ({}/min), repeat alerts {}",
+            self.false_alerts,
+            optional(self.false_alerts_per_min, 2),
+            self.repeat_alerts
+        )?;
+        writeln!(
+            f,
+            "latency ms: min {}  median {}  p90 {}  max {}  mean {}",
+            optional(self.latency_ms.min, 0),
+            optional(self.latency_ms.median, 0),
+            optional(self.latency_ms.p90, 0),
+            optional(self.latency_ms.max, 0),
+            optional(self.latency_ms.mean, 1)
+        )?;
+        match &self.proximity {
+            Some(proximity) => write!(
+                f,
+                "proximity reports {}, warned {}/{} detected ({}/{} recordings)",
+                proximity.reports,
+                proximity.warned,
+                self.detected,
+                proximity.recordings,
+                self.recordings
+            ),
+            None => write!(f, "proximity not measured (no levels recorded)"),
+        }
+    }
+}
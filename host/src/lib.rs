@@ -0,0 +1,50 @@
+//! Host-side tools for the [`aps490_pfpu2_mini`](https://docs.rs/aps490_pfpu2_mini) detection
+//! system, built on the shared logic in [`aps490_pfpu2_core`].
+//!
+//! Tools are run on the host rather than the RP2040, with:
+//!
+//! ```shell
+//! cargo host-run --bin <tool> -- --help
+//! ```
+//!
+//! ## Tools
+//!
+//! - `pfpu2_record`: Records the firmware's telemetry stream to CSV or Parquet (see [`recorder`]).
+//! - `pfpu2_logs`: Converts defmt logs, such as those in `logs/`, to CSV or JSON (see [`logs`]).
+//! - `pfpu2_eval`: Measures detection accuracy on recordings annotated with true contacts (see
+//!   [`annotation`] and [`eval`]).
+//! - `pfpu2_sweep`: Sweeps detection thresholds over annotated recordings, plots the ROC curve, and
+//!   recommends thresholds (see [`sweep`]).
+//! - `pfpu2_sim`: Simulates the excitation circuit for scripted scenarios, writing recordings and
+//!   annotations for the other tools (see [`sim`]).
+//! - `pfpu2_playback`: Plays raw windows through firmware built with the `playback` feature, and
+//!   checks that its decisions match the host bit-for-bit (see [`playback`]).
+//!
+//! ## Crate features
+//!
+//! - `parquet`: Adds Parquet output to the recorder (see [`recorder::ParquetWriter`]).
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![warn(missing_docs)]
+#![cfg_attr(docsrs, feature(doc_cfg), feature(doc_auto_cfg), feature(doc_cfg_hide))]
+
+pub mod annotation;
+pub mod eval;
+pub mod logs;
+pub mod playback;
+pub mod recorder;
+pub mod sim;
+pub mod sweep;
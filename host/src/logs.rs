@@ -0,0 +1,456 @@
+//! Parser for the defmt logs recorded during validation (see `logs/` in the repo).
+//!
+//! Logs were captured with `probe-rs` using the `{L} {f}:{l} => {s}` format from
+//! `.cargo/config.toml`, and different firmware versions logged different messages. This module
+//! understands:
+//!
+//! - `critical_section: DMA take readings`, which starts every analysis window
+//! - `high indices (mod 4): [a, b]` from `trace_indiv_samples`
+//! - `max: .. // min: .. // avg_high: .. // avg_low: ..` from `trace_indiv_samples`, including the
+//!   older `avg1: .. // avg2: ..` variant
+//! - `Here are the last 250 samples:` arrays from `trace_avg_samples`
+//! - `Checking for contact` and `Checking for end of contact` from the detection checks
+//! - State messages from `components.rs`, both the original `contact detected on sample N!` /
+//!   `State changed to normal` messages and the current `State changed from A to B`
+//!
+//! Each window becomes a [`Window`], and each state message a [`Transition`]:
+//!
+//! ```
+//! use aps490_pfpu2_core::state::StatusLedStates;
+//! use aps490_pfpu2_host::logs::Dataset;
+//!
+//! let log = "\
+//! [INFO ] components.rs:76   => Resuming normal detection: System initialization complete
+//! [DEBUG] interrupt.rs:115   => critical_section: DMA take readings
+//! [TRACE] interrupt.rs:85    => high indices (mod 4): [0, 3]
+//! [TRACE] interrupt.rs:140   => max: Some(81) // min: Some(16) // avg_high: 57 // avg_low: 55 // 20 samples: [16, 41]
+//! -> all_unique samples: 0
+//! [DEBUG] buffer.rs:143      => Checking for contact
+//! [DEBUG] interrupt.rs:37    => critical_section: DMA take readings
+//! [TRACE] interrupt.rs:59    => max: Some(255) // min: Some(0) // avg1: 127 // avg2: 120
+//! [INFO ] components.rs:97   => contact detected on sample 990! Adding to detection events
+//! ";
+//! let dataset = Dataset::parse(log);
+//! assert_eq!(dataset.windows.len(), 2);
+//! assert_eq!(dataset.windows[0].delta, Some(2));
+//! assert_eq!(dataset.windows[0].high_a, Some(0));
+//! assert_eq!(dataset.windows[1].avg_high, Some(127));
+//! assert!(dataset.windows[1].legacy);
+//! assert_eq!(dataset.windows[1].state, Some(StatusLedStates::Contact));
+//! assert_eq!(dataset.windows[1].events, "Contact");
+//! assert_eq!(dataset.transitions[1].sample, Some(990));
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_core::state::StatusLedStates;
+use serde::{Deserialize, Serialize};
+
+/// Log level of an [`Entry`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Level {
+    /// `[TRACE]`
+    Trace,
+    /// `[DEBUG]`
+    Debug,
+    /// `[INFO ]`
+    Info,
+    /// `[WARN ]`
+    Warn,
+    /// `[ERROR]`
+    Error,
+}
+
+impl Level {
+    /// Parse the level prefix at the start of a line, such as `[INFO ]`
+    fn from_prefix(line: &str) -> Option<(Level, &str)> {
+        let level = match line.get(..7)? {
+            "[TRACE]" => Level::Trace,
+            "[DEBUG]" => Level::Debug,
+            "[INFO ]" => Level::Info,
+            "[WARN ]" => Level::Warn,
+            "[ERROR]" => Level::Error,
+            _ => return None,
+        };
+        Some((level, &line[7..]))
+    }
+}
+
+/// A single log message, including any continuation lines
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Entry<'a> {
+    /// Line number of the message in the log, starting at 1
+    pub line: usize,
+    /// Log level
+    pub level: Level,
+    /// Source location, such as `interrupt.rs:140`
+    pub location: &'a str,
+    /// Message text. Continuation lines are joined with `\n`.
+    pub message: String,
+}
+
+/// Split a log into [`Entry`]s. Lines before the first message are skipped.
+///
+/// ```
+/// use aps490_pfpu2_host::logs::{entries, Level};
+///
+/// let log = "\
+/// [WARN ] main.rs:78         => Unable to downscale clock speed: CantIncreaseFreq
+/// Clocks will continue to run at 125000000
+/// [TRACE] buffer.rs:137      => Here are the last 250 samples:
+/// [0, 1, 2]
+/// ";
+/// let entries = entries(log);
+/// assert_eq!(entries.len(), 2);
+/// assert_eq!(entries[0].level, Level::Warn);
+/// assert_eq!(entries[0].location, "main.rs:78");
+/// assert!(entries[0].message.ends_with("run at 125000000"));
+/// assert_eq!(entries[1].message, "Here are the last 250 samples:\n[0, 1, 2]");
+/// ```
+pub fn entries(text: &str) -> Vec<Entry<'_>> {
+    let mut entries: Vec<Entry> = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let header = Level::from_prefix(line).and_then(|(level, rest)| {
+            let (location, message) = rest.split_once("=>")?;
+            Some((level, location.trim(), message.trim()))
+        });
+        match (header, entries.last_mut()) {
+            (Some((level, location, message)), _) => entries.push(Entry {
+                line: idx + 1,
+                level,
+                location,
+                message: message.to_owned(),
+            }),
+            (None, Some(entry)) => {
+                entry.message.push('\n');
+                entry.message.push_str(line.trim_end());
+            }
+            (None, None) => {}
+        }
+    }
+    entries
+}
+
+/// Meaning of a single [`Entry`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Start of an analysis window
+    WindowStart,
+    /// Indices (mod 4) of the partial sums used for the high average
+    HighIndices([u8; 2]),
+    /// Averages for the current window
+    Averages(Averages),
+    /// The most recent averaged differences, oldest first
+    Samples(Vec<u8>),
+    /// The system was armed while checking this window
+    CheckContact,
+    /// The system was in contact while checking this window
+    CheckEndContact,
+    /// The system changed state
+    State {
+        /// New state
+        state: StatusLedStates,
+        /// Sample counter of the detection, if reported
+        sample: Option<u32>,
+    },
+    /// Any other message
+    Other,
+}
+
+/// Averages logged by `trace_indiv_samples`
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Averages {
+    /// Highest reading in the window
+    pub max: Option<u8>,
+    /// Lowest reading in the window
+    pub min: Option<u8>,
+    /// Average of the higher half (`avg1` in older logs)
+    pub avg_high: i32,
+    /// Average of the lower half (`avg2` in older logs)
+    pub avg_low: i32,
+    /// Logged by older firmware, which used the names `avg1` and `avg2`
+    pub legacy: bool,
+}
+
+impl Message {
+    /// Interpret a message
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::state::StatusLedStates;
+    /// use aps490_pfpu2_host::logs::Message;
+    ///
+    /// assert_eq!(
+    ///     Message::parse("high indices (mod 4): [3, 0]"),
+    ///     Message::HighIndices([3, 0])
+    /// );
+    /// assert_eq!(
+    ///     Message::parse("State changed from Armed to Proximity"),
+    ///     Message::State { state: StatusLedStates::Proximity, sample: None }
+    /// );
+    /// assert_eq!(Message::parse("Checking for end of contact"), Message::CheckEndContact);
+    /// ```
+    pub fn parse(message: &str) -> Message {
+        let first_line = message.lines().next().unwrap_or_default();
+
+        if first_line == "critical_section: DMA take readings" {
+            Message::WindowStart
+        } else if let Some(indices) = first_line.strip_prefix("high indices (mod 4): ") {
+            match parse_list(indices).as_deref() {
+                Some(&[a, b]) => Message::HighIndices([a, b]),
+                _ => Message::Other,
+            }
+        } else if first_line.starts_with("max: ") {
+            parse_averages(first_line).map_or(Message::Other, Message::Averages)
+        } else if first_line.starts_with("Here are the last") {
+            message
+                .lines()
+                .nth(1)
+                .and_then(parse_list)
+                .map_or(Message::Other, Message::Samples)
+        } else if first_line == "Checking for contact" {
+            Message::CheckContact
+        } else if first_line == "Checking for end of contact" {
+            Message::CheckEndContact
+        } else if let Some(rest) = first_line.strip_prefix("contact detected on sample ") {
+            Message::State {
+                state: StatusLedStates::Contact,
+                sample: rest.split('!').next().and_then(|n| n.trim().parse().ok()),
+            }
+        } else if first_line == "State changed to normal"
+            || first_line == "Previous detection event cleared"
+            || first_line.starts_with("Resuming normal detection")
+        {
+            Message::State {
+                state: StatusLedStates::Armed,
+                sample: None,
+            }
+        } else if let Some(rest) = first_line.strip_prefix("State changed from ") {
+            rest.split_once(" to ")
+                .and_then(|(_, to)| parse_state(to.trim()))
+                .map_or(Message::Other, |state| Message::State {
+                    state,
+                    sample: None,
+                })
+        } else {
+            Message::Other
+        }
+    }
+}
+
+/// Parse a list such as `[1, 2, 3]`
+fn parse_list(list: &str) -> Option<Vec<u8>> {
+    let inner = list.trim().strip_prefix('[')?.strip_suffix(']')?;
+    inner
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().ok())
+        .collect()
+}
+
+/// Parse `max: Some(81) // min: Some(16) // avg_high: 57 // avg_low: 57 // ...`
+fn parse_averages(line: &str) -> Option<Averages> {
+    let mut max = None;
+    let mut min = None;
+    let mut avg_high = None;
+    let mut avg_low = None;
+    let mut legacy = false;
+    for field in line.split("//") {
+        let Some((key, value)) = field.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let option = || value.strip_prefix("Some(")?.strip_suffix(')')?.parse().ok();
+        match key.trim() {
+            "max" => max = option(),
+            "min" => min = option(),
+            "avg_high" => avg_high = value.parse().ok(),
+            "avg_low" => avg_low = value.parse().ok(),
+            "avg1" => {
+                avg_high = value.parse().ok();
+                legacy = true;
+            }
+            "avg2" => {
+                avg_low = value.parse().ok();
+                legacy = true;
+            }
+            _ => {}
+        }
+    }
+    Some(Averages {
+        max,
+        min,
+        avg_high: avg_high?,
+        avg_low: avg_low?,
+        legacy,
+    })
+}
+
+/// Find a state by its logged name
+fn parse_state(name: &str) -> Option<StatusLedStates> {
+    StatusLedStates::ALL
+        .into_iter()
+        .find(|state| format!("{state:?}") == name)
+}
+
+/// One analysis window, as written to CSV or JSON
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Window {
+    /// Number of windows before this one in the log
+    pub window: usize,
+    /// Log line which started the window
+    pub line: usize,
+    /// Seconds since the first window, if the window length is known
+    pub time_s: Option<f64>,
+    /// Average voltage from the higher half
+    pub avg_high: Option<i32>,
+    /// Average voltage from the lower half
+    pub avg_low: Option<i32>,
+    /// `avg_high - avg_low`, or the averaged difference from a `last 250 samples` dump if the
+    /// averages were not logged. Unlike the firmware, negative differences are kept.
+    pub delta: Option<i32>,
+    /// Highest reading
+    pub max: Option<u8>,
+    /// Lowest reading
+    pub min: Option<u8>,
+    /// First partial sum index (mod 4) used for the high average
+    pub high_a: Option<u8>,
+    /// Second partial sum index (mod 4) used for the high average
+    pub high_b: Option<u8>,
+    /// See [`Averages::legacy`]
+    pub legacy: bool,
+    /// System state at the end of the window, if known
+    pub state: Option<StatusLedStates>,
+    /// States entered during this window, separated by `;`
+    pub events: String,
+}
+
+/// A state change reported in the log
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    /// Window the change happened in, or `None` if before the first window
+    pub window: Option<usize>,
+    /// Log line of the message
+    pub line: usize,
+    /// New state
+    pub state: StatusLedStates,
+    /// Sample counter of the detection, if reported
+    pub sample: Option<u32>,
+    /// Original message
+    pub message: String,
+}
+
+/// Time-ordered windows and state changes reconstructed from a log
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Dataset {
+    /// Every analysis window
+    pub windows: Vec<Window>,
+    /// Every state change
+    pub transitions: Vec<Transition>,
+}
+
+impl Dataset {
+    /// Parse a complete log
+    pub fn parse(text: &str) -> Dataset {
+        let mut dataset = Dataset::default();
+        let mut state = None;
+
+        for entry in entries(text) {
+            let message = Message::parse(&entry.message);
+            if message == Message::WindowStart {
+                dataset.windows.push(Window {
+                    window: dataset.windows.len(),
+                    line: entry.line,
+                    state,
+                    ..Window::default()
+                });
+                continue;
+            }
+
+            if let Message::State {
+                state: new_state,
+                sample,
+            } = message
+            {
+                state = Some(new_state);
+                dataset.transitions.push(Transition {
+                    window: dataset.windows.len().checked_sub(1),
+                    line: entry.line,
+                    state: new_state,
+                    sample,
+                    message: entry.message.clone(),
+                });
+            }
+
+            if let Message::Samples(samples) = &message {
+                // Dumped at the end of a window, covering the windows up to and including it
+                let end = dataset.windows.len();
+                let start = end.saturating_sub(samples.len());
+                let skip = samples.len().saturating_sub(end);
+                for (window, sample) in dataset.windows[start..end]
+                    .iter_mut()
+                    .zip(samples.iter().skip(skip))
+                {
+                    window.delta.get_or_insert(i32::from(*sample));
+                }
+            }
+
+            let Some(window) = dataset.windows.last_mut() else {
+                continue;
+            };
+            match message {
+                Message::HighIndices([a, b]) => {
+                    window.high_a = Some(a);
+                    window.high_b = Some(b);
+                }
+                Message::Averages(averages) => {
+                    window.avg_high = Some(averages.avg_high);
+                    window.avg_low = Some(averages.avg_low);
+                    window.delta = Some(averages.avg_high - averages.avg_low);
+                    window.max = averages.max;
+                    window.min = averages.min;
+                    window.legacy = averages.legacy;
+                }
+                Message::CheckContact => {
+                    state.get_or_insert(StatusLedStates::Armed);
+                    window.state = state;
+                }
+                Message::CheckEndContact => {
+                    state.get_or_insert(StatusLedStates::Contact);
+                    window.state = state;
+                }
+                Message::State {
+                    state: new_state, ..
+                } => {
+                    window.state = Some(new_state);
+                    if !window.events.is_empty() {
+                        window.events.push(';');
+                    }
+                    window.events.push_str(&format!("{new_state:?}"));
+                }
+                Message::WindowStart | Message::Samples(_) | Message::Other => {}
+            }
+        }
+
+        dataset
+    }
+
+    /// Fill in [`Window::time_s`], given the length of each window
+    pub fn set_window_length(&mut self, window_s: f64) {
+        for window in &mut self.windows {
+            window.time_s = Some(window.window as f64 * window_s);
+        }
+    }
+}
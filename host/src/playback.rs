@@ -0,0 +1,213 @@
+//! Replays recorded windows through the firmware, and compares its decisions with the host.
+//!
+//! With the firmware's `playback` feature, windows of raw ADC readings sent on the telemetry port
+//! (framed with [`encode_window`](aps490_pfpu2_core::telemetry::encode_window)) are analysed in
+//! place of live readings, and each one is answered with a telemetry
+//! [`Record`](aps490_pfpu2_core::telemetry::Record). The same windows are run through a
+//! [`Reference`] on the host, which uses the shared [`AlignedAverages`] and [`Detector`], and any
+//! window where the two disagree is reported as a [`Mismatch`].
+//!
+//! The reference follows the firmware's single-tone path only: one tone with a square wave
+//! excitation at full drive, calibrated once on the first windows. Multi-frequency features,
+//! pseudo-random correlation and other drive levels are not modelled, so the firmware's console
+//! refuses `calibrate`, `freq`, `sweep`, `multi`, `excitation` and `gain` changes while `playback`
+//! is enabled. The drive of each record is still compared, so a window the firmware analysed with
+//! thresholds scaled to another drive level shows up as a mismatch.
+//!
+//! Raw windows are stored one after another, as written by `pfpu2_sim --raw`.
+//!
+//! ```
+//! use aps490_pfpu2_core::{config::Thresholds, signal::WINDOW_SIZE};
+//! use aps490_pfpu2_host::{
+//!     playback::{compare, read_windows, Reference},
+//!     sim::{Circuit, Load, Simulator},
+//! };
+//!
+//! let mut sim = Simulator::new(Circuit::default(), 1);
+//! let raw: Vec<u8> = (0..300).flat_map(|_| sim.window(&Load::AIR)).collect();
+//! let windows = read_windows(raw.as_slice()).unwrap();
+//! assert_eq!(windows.len(), 300);
+//! assert!(read_windows(&raw[..WINDOW_SIZE + 1]).is_err());
+//!
+//! let mut host = Reference::new(Thresholds::new());
+//! let host: Vec<_> = windows.iter().map(|window| host.analyse(window)).collect();
+//! let mut target = host.clone();
+//! target[280].delta += 1;
+//! // Windows the firmware analysed at a different drive level are not comparable
+//! target[290].gain_percent = 59;
+//! let mismatches = compare(&host, &target);
+//! assert_eq!(mismatches.len(), 2);
+//! assert_eq!(mismatches[0].window, 280);
+//! assert_eq!(mismatches[1].window, 290);
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    io::{self, Read},
+};
+
+use aps490_pfpu2_core::{
+    config::Thresholds,
+    detect::Detector,
+    signal::{AlignedAverages, WINDOW_SIZE},
+    state::StatusLedStates,
+};
+
+use crate::recorder::Row;
+
+/// Result of analysing a single window
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Decision {
+    /// [`AlignedAverages::avg_high`]
+    pub avg_high: i32,
+    /// [`AlignedAverages::avg_low`]
+    pub avg_low: i32,
+    /// [`AlignedAverages::get_delta`]
+    pub delta: u8,
+    /// [`DriveLevel::gain_percent`](aps490_pfpu2_core::gain::DriveLevel) of the excitation, which
+    /// the thresholds were scaled to
+    pub gain_percent: u8,
+    /// System state after the window was analysed
+    pub state: StatusLedStates,
+}
+
+impl From<&Row> for Decision {
+    fn from(row: &Row) -> Self {
+        Self {
+            avg_high: row.avg_high,
+            avg_low: row.avg_low,
+            delta: row.delta,
+            gain_percent: row.gain_percent,
+            state: row.state,
+        }
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "high={} low={} delta={} gain={} state={:?}",
+            self.avg_high, self.avg_low, self.delta, self.gain_percent, self.state
+        )
+    }
+}
+
+/// Host model of the firmware's single-tone analysis at full drive, starting with calibration as
+/// after a reset
+///
+/// The firmware never changes the drive during playback, so every window is analysed with the
+/// thresholds [scaled](Thresholds::at_gain) to [`Reference::GAIN_PERCENT`], and a firmware record
+/// at any other drive level is reported as a [`Mismatch`].
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Reference {
+    /// Shared detection logic
+    detector: Detector,
+    /// Thresholds given to the detector
+    thresholds: Thresholds,
+    /// State after the last window
+    state: StatusLedStates,
+}
+
+impl Reference {
+    /// [`DriveLevel::gain_percent`](aps490_pfpu2_core::gain::DriveLevel) of full drive, which
+    /// played windows are analysed at
+    pub const GAIN_PERCENT: u8 = 100;
+
+    /// Start calibrating with `thresholds`, which must match those on the firmware
+    pub fn new(thresholds: Thresholds) -> Self {
+        Self {
+            detector: Detector::new(),
+            thresholds,
+            state: StatusLedStates::Calibrating,
+        }
+    }
+
+    /// Analyse the next window
+    pub fn analyse(&mut self, window: &[u8; WINDOW_SIZE]) -> Decision {
+        let avgs = AlignedAverages::from_window(window);
+        let thresholds = self.thresholds.at_gain(Self::GAIN_PERCENT);
+        if let Some(next) =
+            self.detector
+                .update(self.state, avgs.get_delta(), avgs.get_level(), &thresholds)
+        {
+            self.state = next;
+        }
+        Decision {
+            avg_high: avgs.avg_high,
+            avg_low: avgs.avg_low,
+            delta: avgs.get_delta(),
+            gain_percent: Self::GAIN_PERCENT,
+            state: self.state,
+        }
+    }
+}
+
+/// Read raw windows stored one after another. Fails if the input ends partway through a window.
+pub fn read_windows(mut reader: impl Read) -> io::Result<Vec<[u8; WINDOW_SIZE]>> {
+    let mut raw = Vec::new();
+    reader.read_to_end(&mut raw)?;
+    if raw.len() % WINDOW_SIZE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} bytes is not a whole number of {WINDOW_SIZE}-byte windows",
+                raw.len()
+            ),
+        ));
+    }
+    Ok(raw
+        .chunks_exact(WINDOW_SIZE)
+        .map(|chunk| chunk.try_into().expect("chunks are exactly one window"))
+        .collect())
+}
+
+/// A window where the host and target decisions differ
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Mismatch {
+    /// Index of the window
+    pub window: usize,
+    /// Decision made by the [`Reference`]
+    pub host: Decision,
+    /// Decision reported by the firmware
+    pub target: Decision,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "window {}: host {}, target {}",
+            self.window, self.host, self.target
+        )
+    }
+}
+
+/// Every window where `host` and `target` differ. Windows beyond the end of the shorter list are
+/// not compared.
+pub fn compare(host: &[Decision], target: &[Decision]) -> Vec<Mismatch> {
+    host.iter()
+        .zip(target)
+        .enumerate()
+        .filter(|(_, (host, target))| host != target)
+        .map(|(window, (host, target))| Mismatch {
+            window,
+            host: *host,
+            target: *target,
+        })
+        .collect()
+}
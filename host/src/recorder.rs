@@ -0,0 +1,307 @@
+//! Converts the firmware's telemetry stream into table rows for analysis.
+//!
+//! A [`Session`] decodes frames from the [telemetry](aps490_pfpu2_core::telemetry) stream, and
+//! produces one [`Row`] per analysis window. Rows can be written to CSV with [`csv::Writer`], or to
+//! Parquet with [`ParquetWriter`] (requires the `parquet` feature).
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use aps490_pfpu2_core::{
+//!     state::StatusLedStates,
+//!     telemetry::{Record, MAX_FRAME_SIZE},
+//! };
+//! use aps490_pfpu2_host::recorder::Session;
+//!
+//! let mut stream = Vec::new();
+//! for (sequence, state) in [
+//!     (0, StatusLedStates::Armed),
+//!     (1, StatusLedStates::Armed),
+//!     (3, StatusLedStates::Contact),
+//! ] {
+//!     let record = Record {
+//!         sequence,
+//!         sample: sequence,
+//!         avg_high: 140,
+//!         avg_low: 95,
+//!         delta: 45,
+//!         gain_percent: 100,
+//!         state,
+//!     };
+//!     stream.extend_from_slice(record.encode(&mut [0; MAX_FRAME_SIZE]).unwrap());
+//! }
+//!
+//! let mut session = Session::new();
+//! let rows = session.push(&stream, Duration::from_millis(10));
+//! assert_eq!(rows.len(), 3);
+//! assert_eq!(rows[2].events, "dropped=1;Armed->Contact");
+//!
+//! let mut csv = csv::Writer::from_writer(Vec::new());
+//! for row in rows {
+//!     csv.serialize(row).unwrap();
+//! }
+//! let csv = String::from_utf8(csv.into_inner().unwrap()).unwrap();
+//! assert_eq!(
+//!     csv.lines().take(2).collect::<Vec<_>>(),
+//!     [
+//!         "time_s,sequence,sample,avg_high,avg_low,delta,gain_percent,state,events",
+//!         "0.01,0,0,140,95,45,100,Armed,"
+//!     ]
+//! );
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, time::Duration};
+
+use aps490_pfpu2_core::{
+    state::StatusLedStates,
+    telemetry::{FrameDecoder, FrameError, Record, SequenceTracker, MAX_FRAME_SIZE},
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "parquet")]
+pub use parquet_writer::ParquetWriter;
+
+/// One analysis window, as written to CSV or Parquet
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Row {
+    /// Seconds since the session started when the window was received. Windows arrive in bursts,
+    /// so use `sequence` for exact spacing.
+    pub time_s: f64,
+    /// [`Record::sequence`]
+    pub sequence: u32,
+    /// [`Record::sample`]
+    pub sample: u32,
+    /// [`Record::avg_high`]
+    pub avg_high: i32,
+    /// [`Record::avg_low`]
+    pub avg_low: i32,
+    /// [`Record::delta`]
+    pub delta: u8,
+    /// [`Record::gain_percent`]. Recordings from before the drive level was recorded were all
+    /// taken at full drive.
+    #[serde(default = "full_drive")]
+    pub gain_percent: u8,
+    /// [`Record::state`]
+    pub state: StatusLedStates,
+    /// [`Event`]s since the previous window, separated by `;`
+    pub events: String,
+}
+
+/// Gain of windows recorded without one
+fn full_drive() -> u8 {
+    100
+}
+
+/// Something notable between one window and the next
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub enum Event {
+    /// Records were lost before this one
+    Dropped(u32),
+    /// The system state changed
+    StateChange {
+        /// State in the previous window
+        from: StatusLedStates,
+        /// State in this window
+        to: StatusLedStates,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Dropped(count) => write!(f, "dropped={count}"),
+            Event::StateChange { from, to } => write!(f, "{from:?}->{to:?}"),
+        }
+    }
+}
+
+/// Decodes a telemetry stream into [`Row`]s
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    /// Partial frame received
+    decoder: FrameDecoder<MAX_FRAME_SIZE>,
+    /// Detects dropped records
+    tracker: SequenceTracker,
+    /// State in the last record received
+    last_state: Option<StatusLedStates>,
+    /// Frames which could not be decoded
+    errors: u64,
+    /// Latest frame which could not be decoded, until taken by [`Session::take_error`]
+    last_error: Option<FrameError>,
+}
+
+impl Session {
+    /// Start a session which has not received any data
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode received bytes, returning a row for every complete frame. `elapsed` is the time
+    /// since the session started. Frames which cannot be decoded are skipped, and reported by
+    /// [`Session::take_error`].
+    pub fn push(&mut self, bytes: &[u8], elapsed: Duration) -> Vec<Row> {
+        bytes
+            .iter()
+            .filter_map(|byte| match self.decoder.push(*byte)? {
+                Ok(record) => Some(self.row(record, elapsed)),
+                Err(err) => {
+                    self.frame_error(err);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Convert a decoded record into a row, noting any events since the last one
+    pub fn row(&mut self, record: Record, elapsed: Duration) -> Row {
+        let mut events = Vec::new();
+        let dropped = self.tracker.update(record.sequence);
+        if dropped > 0 {
+            events.push(Event::Dropped(dropped));
+        }
+        if let Some(from) = self.last_state.filter(|state| *state != record.state) {
+            events.push(Event::StateChange {
+                from,
+                to: record.state,
+            });
+        }
+        self.last_state = Some(record.state);
+
+        Row {
+            time_s: elapsed.as_secs_f64(),
+            sequence: record.sequence,
+            sample: record.sample,
+            avg_high: record.avg_high,
+            avg_low: record.avg_low,
+            delta: record.delta,
+            gain_percent: record.gain_percent,
+            state: record.state,
+            events: events
+                .iter()
+                .map(Event::to_string)
+                .collect::<Vec<_>>()
+                .join(";"),
+        }
+    }
+
+    /// Number of records received, dropped, and frames which could not be decoded
+    pub fn stats(&self) -> (u64, u64, u64) {
+        (self.tracker.received(), self.tracker.dropped(), self.errors)
+    }
+
+    /// Latest frame which could not be decoded since the last call, for the caller to report.
+    /// Every such frame is counted in [`Session::stats`]. The first frame is usually partial, as
+    /// recording starts mid-stream.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use aps490_pfpu2_core::telemetry::FrameError;
+    /// use aps490_pfpu2_host::recorder::Session;
+    ///
+    /// let mut session = Session::new();
+    /// assert!(session.push(&[0x12, 0x34, 0x00], Duration::ZERO).is_empty());
+    /// assert_eq!(session.take_error(), Some(FrameError::Cobs));
+    /// assert_eq!(session.take_error(), None);
+    /// assert_eq!(session.stats(), (0, 0, 1));
+    /// ```
+    pub fn take_error(&mut self) -> Option<FrameError> {
+        self.last_error.take()
+    }
+
+    /// Count a frame which could not be decoded
+    fn frame_error(&mut self, err: FrameError) {
+        self.errors += 1;
+        self.last_error = Some(err);
+    }
+}
+
+/// Parquet output, kept separate as it requires the `parquet` feature
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use std::{fs::File, sync::Arc};
+
+    use arrow_array::{
+        ArrayRef, Float64Array, Int32Array, RecordBatch, StringArray, UInt32Array, UInt8Array,
+    };
+    use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+    use parquet::{arrow::ArrowWriter, errors::ParquetError};
+
+    use super::Row;
+
+    /// Writes [`Row`]s to a Parquet file, with the same columns as the CSV output
+    pub struct ParquetWriter {
+        /// Column layout of every batch
+        schema: SchemaRef,
+        /// Open file
+        writer: ArrowWriter<File>,
+    }
+
+    impl ParquetWriter {
+        /// Create a writer. [`ParquetWriter::close`] must be called to produce a valid file.
+        pub fn new(file: File) -> Result<Self, ParquetError> {
+            let schema = Arc::new(Schema::new(vec![
+                Field::new("time_s", DataType::Float64, false),
+                Field::new("sequence", DataType::UInt32, false),
+                Field::new("sample", DataType::UInt32, false),
+                Field::new("avg_high", DataType::Int32, false),
+                Field::new("avg_low", DataType::Int32, false),
+                Field::new("delta", DataType::UInt8, false),
+                Field::new("gain_percent", DataType::UInt8, false),
+                Field::new("state", DataType::Utf8, false),
+                Field::new("events", DataType::Utf8, false),
+            ]));
+            let writer = ArrowWriter::try_new(file, schema.clone(), None)?;
+            Ok(Self { schema, writer })
+        }
+
+        /// Write a batch of rows
+        pub fn write(&mut self, rows: &[Row]) -> Result<(), ParquetError> {
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(Float64Array::from_iter_values(
+                    rows.iter().map(|r| r.time_s),
+                )),
+                Arc::new(UInt32Array::from_iter_values(
+                    rows.iter().map(|r| r.sequence),
+                )),
+                Arc::new(UInt32Array::from_iter_values(rows.iter().map(|r| r.sample))),
+                Arc::new(Int32Array::from_iter_values(
+                    rows.iter().map(|r| r.avg_high),
+                )),
+                Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.avg_low))),
+                Arc::new(UInt8Array::from_iter_values(rows.iter().map(|r| r.delta))),
+                Arc::new(UInt8Array::from_iter_values(
+                    rows.iter().map(|r| r.gain_percent),
+                )),
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|r| format!("{:?}", r.state)),
+                )),
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|r| r.events.as_str()),
+                )),
+            ];
+            let batch = RecordBatch::try_new(self.schema.clone(), columns)
+                .map_err(|err: ArrowError| ParquetError::ArrowError(err.to_string()))?;
+            self.writer.write(&batch)
+        }
+
+        /// Write the file footer
+        pub fn close(self) -> Result<(), ParquetError> {
+            self.writer.close().map(|_| ())
+        }
+    }
+}
@@ -0,0 +1,429 @@
+//! Simulates the excitation and sensing circuit, producing ADC windows for scripted scenarios.
+//!
+//! The model follows the hardware:
+//!
+//! - The PWM drives a square wave of [`Circuit::supply_volts`] at [`Circuit::pwm_hz`] through
+//!   [`Circuit::series_ohms`] into the blade.
+//! - The blade has [`Circuit::stray_farads`] to ground, and is loaded by the ADC input
+//!   ([`Circuit::adc_input_ohms`]) and whatever it touches, modelled as a resistance in parallel
+//!   with a capacitance ([`Load`]).
+//! - The ADC samples the blade voltage at [`Circuit::adc_ratio`] times the PWM frequency, with an
+//!   optional clock error, adds Gaussian noise, and quantizes to 8 bits.
+//!
+//! Between PWM edges the drive is constant, so the blade voltage is solved exactly as a first-order
+//! RC response. Runs are deterministic for a given seed, and the phase between the PWM and the ADC
+//! is chosen from the seed. As on the hardware, the averaged difference depends heavily on this
+//! phase: readings taken close to the PWM edges see little of the swing.
+//!
+//! Component values for each [`Load`] are rough estimates, intended to give plausible changes
+//! rather than match any specific tissue.
+//!
+//! ```
+//! use aps490_pfpu2_core::signal::AlignedAverages;
+//! use aps490_pfpu2_host::sim::{Circuit, Load, Simulator};
+//!
+//! let mut sim = Simulator::new(Circuit::default(), 1);
+//! let air = AlignedAverages::from_window(&sim.window(&Load::AIR));
+//! let gel = AlignedAverages::from_window(&sim.window(&Load::GEL));
+//! // Touching a conductive load reduces both the signal range and level
+//! assert!(gel.get_delta() + 2 <= air.get_delta());
+//! assert!(gel.get_level() < air.get_level());
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(doc)]
+use aps490_pfpu2_core::gain::GainControl;
+use aps490_pfpu2_core::signal::WINDOW_SIZE;
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Normal};
+
+/// Fixed properties of the excitation and sensing circuit
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Circuit {
+    /// PWM high level, which is also the ADC reference
+    pub supply_volts: f64,
+    /// Excitation frequency, `SIGNAL_GEN_FREQ_HZ` in the firmware
+    pub pwm_hz: f64,
+    /// Fraction of each PWM period spent high
+    pub duty: f64,
+    /// Resistance between the PWM pin and the blade
+    pub series_ohms: f64,
+    /// Capacitance of the blade and wiring to ground
+    pub stray_farads: f64,
+    /// Resistance of the ADC input to ground
+    pub adc_input_ohms: f64,
+    /// ADC sample rate as a multiple of [`Circuit::pwm_hz`]
+    pub adc_ratio: f64,
+    /// Error in the ADC sample rate, in parts per million. A non-zero error makes the sampling
+    /// phase drift through the PWM period.
+    pub adc_error_ppm: f64,
+    /// Standard deviation of the ADC noise, in 8-bit counts
+    pub noise_lsb: f64,
+}
+
+impl Circuit {
+    /// Length of one window of [`WINDOW_SIZE`] readings, in seconds
+    pub fn window_s(&self) -> f64 {
+        WINDOW_SIZE as f64 / (self.adc_ratio * self.pwm_hz)
+    }
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Self {
+            supply_volts: 3.3,
+            pwm_hz: 100_000.0,
+            duty: 0.5,
+            series_ohms: 10_000.0,
+            stray_farads: 1e-9,
+            adc_input_ohms: 1e6,
+            adc_ratio: 2.0,
+            adc_error_ppm: 0.0,
+            noise_lsb: 0.8,
+        }
+    }
+}
+
+/// What the blade is touching
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Load {
+    /// Resistance to ground, or [`f64::INFINITY`] if none
+    pub ohms: f64,
+    /// Capacitance to ground, in addition to [`Circuit::stray_farads`]
+    pub farads: f64,
+    /// `false` if the excitation wire is disconnected from the blade
+    pub connected: bool,
+}
+
+impl Load {
+    /// Blade in air
+    pub const AIR: Load = Load {
+        ohms: f64::INFINITY,
+        farads: 0.0,
+        connected: true,
+    };
+    /// Cutting through bone, which conducts poorly
+    pub const BONE: Load = Load {
+        ohms: 200e3,
+        farads: 100e-12,
+        connected: true,
+    };
+    /// Thin layer of bone left over the brain, coupling capacitively
+    pub const THIN_BONE: Load = Load {
+        ohms: 20e3,
+        farads: 1e-9,
+        connected: true,
+    };
+    /// Conductive gel, used in place of tissue during validation
+    pub const GEL: Load = Load {
+        ohms: 1e3,
+        farads: 0.0,
+        connected: true,
+    };
+    /// Brain tissue
+    pub const BRAIN: Load = Load {
+        ohms: 2e3,
+        farads: 0.0,
+        connected: true,
+    };
+    /// Excitation wire pulled out, with the blade in air
+    pub const DISCONNECTED: Load = Load {
+        connected: false,
+        ..Load::AIR
+    };
+
+    /// Blend towards `to`, with `fraction` from 0 to 1. Conductance and capacitance change
+    /// linearly, as with the contact area.
+    pub fn interpolate(&self, to: &Load, fraction: f64) -> Load {
+        let siemens = (1.0 - fraction) / self.ohms + fraction / to.ohms;
+        Load {
+            ohms: 1.0 / siemens,
+            farads: self.farads + (to.farads - self.farads) * fraction,
+            connected: if fraction < 1.0 {
+                self.connected
+            } else {
+                to.connected
+            },
+        }
+    }
+}
+
+/// Part of a scenario, during which the load changes steadily
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Segment {
+    /// Length of the segment
+    pub windows: usize,
+    /// Load in the first window
+    pub from: Load,
+    /// Load in the last window
+    pub to: Load,
+    /// `true` if this is a contact which should be detected
+    pub contact: bool,
+}
+
+impl Segment {
+    /// Segment with a constant load
+    pub const fn hold(windows: usize, load: Load, contact: bool) -> Self {
+        Self {
+            windows,
+            from: load,
+            to: load,
+            contact,
+        }
+    }
+
+    /// Segment which moves from one load to another, without contact
+    pub const fn ramp(windows: usize, from: Load, to: Load) -> Self {
+        Self {
+            windows,
+            from,
+            to,
+            contact: false,
+        }
+    }
+
+    /// Load in window `idx` of the segment
+    pub fn load(&self, idx: usize) -> Load {
+        let fraction = if self.windows > 1 {
+            idx as f64 / (self.windows - 1) as f64
+        } else {
+            1.0
+        };
+        self.from.interpolate(&self.to, fraction)
+    }
+}
+
+/// Windows in air at the start of every scenario, so the firmware can calibrate
+pub const SETTLE_WINDOWS: usize = 300;
+
+/// Scripted sequences of loads
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, ValueEnum)]
+pub enum Scenario {
+    /// Blade in air throughout
+    Air,
+    /// Cutting bone, without reaching the brain
+    Bone,
+    /// Repeated touches of conductive gel
+    Gel,
+    /// Cutting bone until it thins over the brain, then touching the brain
+    BrainApproach,
+    /// Excitation wire briefly pulled out, then reconnected
+    WirePull,
+}
+
+impl Scenario {
+    /// Every segment of the scenario, in order
+    pub fn segments(&self) -> Vec<Segment> {
+        let settle = Segment::hold(SETTLE_WINDOWS, Load::AIR, false);
+        match self {
+            Scenario::Air => vec![settle, Segment::hold(1500, Load::AIR, false)],
+            Scenario::Bone => vec![
+                settle,
+                Segment::ramp(25, Load::AIR, Load::BONE),
+                Segment::hold(1000, Load::BONE, false),
+                Segment::ramp(25, Load::BONE, Load::AIR),
+                Segment::hold(300, Load::AIR, false),
+            ],
+            Scenario::Gel => {
+                let mut segments = vec![settle];
+                for _ in 0..3 {
+                    segments.push(Segment::hold(100, Load::GEL, true));
+                    segments.push(Segment::hold(300, Load::AIR, false));
+                }
+                segments
+            }
+            Scenario::BrainApproach => vec![
+                settle,
+                Segment::ramp(25, Load::AIR, Load::BONE),
+                Segment::hold(500, Load::BONE, false),
+                Segment::ramp(500, Load::BONE, Load::THIN_BONE),
+                Segment::hold(150, Load::BRAIN, true),
+                Segment::ramp(25, Load::BRAIN, Load::AIR),
+                Segment::hold(300, Load::AIR, false),
+            ],
+            Scenario::WirePull => vec![
+                settle,
+                Segment::hold(10, Load::DISCONNECTED, false),
+                Segment::hold(300, Load::AIR, false),
+                Segment::hold(100, Load::DISCONNECTED, false),
+                Segment::hold(300, Load::AIR, false),
+            ],
+        }
+    }
+}
+
+/// Steps the circuit through time, one window at a time
+#[derive(Clone, Debug)]
+pub struct Simulator {
+    /// Circuit being simulated
+    circuit: Circuit,
+    /// Source of noise
+    rng: ChaCha8Rng,
+    /// ADC noise distribution
+    noise: Normal<f64>,
+    /// Time of the last update, in seconds
+    time: f64,
+    /// Blade voltage at `time`
+    volts: f64,
+    /// `true` while the PWM output is high
+    pwm_high: bool,
+    /// Time of the next PWM edge
+    next_edge: f64,
+    /// Time of the next ADC reading
+    next_sample: f64,
+}
+
+impl Simulator {
+    /// Start a simulation with the blade discharged and the PWM rising. The ADC starts at a random
+    /// point in the first PWM period.
+    pub fn new(circuit: Circuit, seed: u64) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let next_sample = rng.gen_range(0.0..1.0) / circuit.pwm_hz;
+        Self {
+            circuit,
+            rng,
+            noise: Normal::new(0.0, circuit.noise_lsb.max(0.0)).expect("noise must be finite"),
+            time: 0.0,
+            volts: 0.0,
+            pwm_high: true,
+            next_edge: circuit.duty / circuit.pwm_hz,
+            next_sample,
+        }
+    }
+
+    /// Start a simulation like [`Simulator::new`], but with the ADC locked to the PWM as in the
+    /// firmware: readings fall a quarter of a period after each rising edge and midway between
+    /// the following ones, whatever the duty cycle.
+    ///
+    /// Narrow pulses often end before the high reading, so the delta at each drive level does not
+    /// follow the nominal gain. The gains measured by a [`GainControl`] as the drive steps down
+    /// follow the simulated deltas instead:
+    ///
+    /// ```
+    /// use aps490_pfpu2_core::{
+    ///     gain::{GainControl, WindowRange, DRIVE_LEVELS},
+    ///     signal::AlignedAverages,
+    /// };
+    /// use aps490_pfpu2_host::sim::{Circuit, Load, Simulator};
+    ///
+    /// let mut gain = GainControl::new();
+    /// let mut deltas = Vec::new();
+    /// for (level, drive) in DRIVE_LEVELS.iter().enumerate() {
+    ///     let duty = drive.duty_percent as f64 / 100.0;
+    ///     let mut sim = Simulator::phase_locked(Circuit { duty, ..Default::default() }, 1);
+    ///     gain.set_level(level);
+    ///     let mut sum = 0;
+    ///     for _ in 0..GainControl::MEASURE_WINDOWS {
+    ///         let window = sim.window(&Load::AIR);
+    ///         let delta = AlignedAverages::from_locked_window(&window).get_delta();
+    ///         gain.record(&WindowRange::from_window(&window), delta);
+    ///         sum += delta as u32;
+    ///     }
+    ///     deltas.push(sum);
+    ///     let simulated = (sum * 100 / deltas[0]).min(255) as u8;
+    ///     assert!(gain.drive().gain_percent.abs_diff(simulated) <= 5);
+    /// }
+    /// // The narrowest pulse gives a larger delta in air than full drive, against a nominal 22%
+    /// assert!(gain.drive().gain_percent > 100);
+    /// ```
+    pub fn phase_locked(circuit: Circuit, seed: u64) -> Self {
+        Self {
+            next_sample: 0.25 / circuit.pwm_hz,
+            ..Self::new(circuit, seed)
+        }
+    }
+
+    /// Circuit being simulated
+    pub fn circuit(&self) -> &Circuit {
+        &self.circuit
+    }
+
+    /// Simulate one window of ADC readings with the blade touching `load`
+    pub fn window(&mut self, load: &Load) -> [u8; WINDOW_SIZE] {
+        let circuit = self.circuit;
+        let period = 1.0 / circuit.pwm_hz;
+        let sample_period =
+            1.0 / (circuit.adc_ratio * circuit.pwm_hz) * (1.0 + circuit.adc_error_ppm * 1e-6);
+        let source_siemens = if load.connected {
+            1.0 / circuit.series_ohms
+        } else {
+            0.0
+        };
+        let total_siemens = source_siemens + 1.0 / circuit.adc_input_ohms + 1.0 / load.ohms;
+        let tau = (circuit.stray_farads + load.farads) / total_siemens;
+
+        let mut readings = [0u8; WINDOW_SIZE];
+        for reading in readings.iter_mut() {
+            while self.next_edge <= self.next_sample {
+                self.advance(self.next_edge, source_siemens / total_siemens, tau);
+                self.pwm_high = !self.pwm_high;
+                self.next_edge += if self.pwm_high {
+                    circuit.duty * period
+                } else {
+                    (1.0 - circuit.duty) * period
+                };
+            }
+            self.advance(self.next_sample, source_siemens / total_siemens, tau);
+            self.next_sample += sample_period;
+
+            let counts =
+                self.volts / circuit.supply_volts * 256.0 + self.noise.sample(&mut self.rng);
+            *reading = counts.floor().clamp(0.0, 255.0) as u8;
+        }
+        readings
+    }
+
+    /// Move to `time`, with the blade settling towards `gain` times the PWM output
+    fn advance(&mut self, time: f64, gain: f64, tau: f64) {
+        let drive = if self.pwm_high {
+            self.circuit.supply_volts
+        } else {
+            0.0
+        };
+        let target = drive * gain;
+        self.volts = target + (self.volts - target) * (-(time - self.time) / tau).exp();
+        self.time = time;
+    }
+}
+
+/// One simulated window
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimWindow {
+    /// Number of windows before this one
+    pub window: usize,
+    /// ADC readings
+    pub readings: [u8; WINDOW_SIZE],
+    /// `true` if the window is part of a contact which should be detected
+    pub contact: bool,
+}
+
+/// Simulate every window of a scenario's segments
+pub fn run<'a>(
+    simulator: &'a mut Simulator,
+    segments: &'a [Segment],
+) -> impl Iterator<Item = SimWindow> + 'a {
+    segments
+        .iter()
+        .flat_map(|segment| (0..segment.windows).map(move |idx| (segment, idx)))
+        .enumerate()
+        .map(move |(window, (segment, idx))| SimWindow {
+            window,
+            readings: simulator.window(&segment.load(idx)),
+            contact: segment.contact,
+        })
+}
@@ -0,0 +1,361 @@
+//! Searches detection thresholds for the best trade-off between missed contacts and false alerts.
+//!
+//! Every combination of thresholds in a [`Grid`] is [evaluated](crate::eval) over a set of
+//! annotated recordings. As recordings have no natural negative windows, the ROC curve plots recall
+//! against false alerts per minute, and only the [frontier](frontier) of points which no other point
+//! beats on both is kept.
+//!
+//! ```
+//! use aps490_pfpu2_host::{
+//!     annotation::{Annotation, Interval, Sample},
+//!     sweep::{console_script, frontier, recommend, sweep, Grid},
+//! };
+//!
+//! let annotation = Annotation {
+//!     recording: "knife.log".into(),
+//!     window_ms: 20.0,
+//!     contacts: vec![Interval { first: 100, last: 200, note: String::new() }],
+//! };
+//! // Contact reduces the difference by 6, and noise reduces it by 3 for two windows
+//! let samples: Vec<Sample> = (0..3000)
+//!     .map(|window| Sample {
+//!         window,
+//!         delta: match window {
+//!             102..=200 => 39,
+//!             1000..=1001 => 42,
+//!             _ => 45,
+//!         },
+//!         level: None,
+//!         gain_percent: 100,
+//!     })
+//!     .collect();
+//!
+//! let grid = Grid {
+//!     trigger_delta: 1..=8,
+//!     confirm_delta: 1..=1,
+//!     restore_delta: 2..=2,
+//! };
+//! let points = sweep(&[(annotation, samples)], &grid, 0);
+//! assert_eq!(points.len(), 8);
+//! assert_eq!(frontier(&points).len(), 1);
+//!
+//! let best = recommend(&points, 0.0).unwrap();
+//! assert_eq!(best.thresholds.trigger_delta, 4);
+//! let script = console_script(&best.thresholds);
+//! assert!(script.starts_with("set trigger_delta 4\n"));
+//! // Proximity and dispersion are not swept, so the firmware keeps its own values
+//! assert!(!script.contains("proximity_delta"));
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp::Ordering, fmt::Write, ops::RangeInclusive};
+
+use aps490_pfpu2_core::{command::Threshold, config::Thresholds};
+use serde::Serialize;
+
+use crate::{
+    annotation::{Annotation, Sample},
+    eval::{evaluate, Options, Score},
+};
+
+/// Values tried for each threshold. Thresholds not listed keep their defaults.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Grid {
+    /// [`Thresholds::trigger_delta`]
+    pub trigger_delta: RangeInclusive<u8>,
+    /// [`Thresholds::confirm_delta`]
+    pub confirm_delta: RangeInclusive<u8>,
+    /// [`Thresholds::restore_delta`]
+    pub restore_delta: RangeInclusive<u8>,
+}
+
+impl Grid {
+    /// Thresholds which the grid sweeps. The rest are not tuned, and are left out of
+    /// [`console_script`].
+    pub const SWEPT: [Threshold; 3] = [
+        Threshold::TriggerDelta,
+        Threshold::ConfirmDelta,
+        Threshold::RestoreDelta,
+    ];
+
+    /// Every combination of thresholds in the grid
+    pub fn thresholds(&self) -> impl Iterator<Item = Thresholds> + '_ {
+        self.trigger_delta.clone().flat_map(move |trigger_delta| {
+            self.confirm_delta.clone().flat_map(move |confirm_delta| {
+                self.restore_delta
+                    .clone()
+                    .map(move |restore_delta| Thresholds {
+                        trigger_delta,
+                        confirm_delta,
+                        restore_delta,
+                        ..Thresholds::new()
+                    })
+            })
+        })
+    }
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Self {
+            trigger_delta: 1..=10,
+            confirm_delta: 0..=4,
+            restore_delta: 1..=6,
+        }
+    }
+}
+
+/// Parse a range of values as `min-max`, or a single value
+pub fn parse_range(text: &str) -> Result<RangeInclusive<u8>, String> {
+    let (min, max) = text.split_once('-').unwrap_or((text, text));
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<u8>()
+            .map_err(|err| format!("invalid value {value:?}: {err}"))
+    };
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(format!("range {min}-{max} is empty"));
+    }
+    Ok(min..=max)
+}
+
+/// Results for one combination of thresholds, over every recording
+#[derive(Clone, Debug, PartialEq)]
+pub struct Point {
+    /// Thresholds given to the detector
+    pub thresholds: Thresholds,
+    /// Combined score
+    pub score: Score,
+}
+
+impl Point {
+    /// False alerts per minute, treating an empty recording as no alerts
+    fn false_rate(&self) -> f64 {
+        self.score.false_alerts_per_min().unwrap_or_default()
+    }
+
+    /// Recall, treating recordings without contacts as fully detected
+    fn recall(&self) -> f64 {
+        self.score.recall().unwrap_or(1.0)
+    }
+
+    /// Row for the sweep table
+    pub fn row(&self) -> TableRow {
+        let summary = self.score.summary();
+        TableRow {
+            trigger_delta: self.thresholds.trigger_delta,
+            confirm_delta: self.thresholds.confirm_delta,
+            restore_delta: self.thresholds.restore_delta,
+            contacts: summary.contacts,
+            missed: summary.contacts - summary.detected,
+            false_alerts: summary.false_alerts,
+            false_alerts_per_min: summary.false_alerts_per_min,
+            recall: summary.recall,
+            precision: summary.precision,
+            latency_median_ms: summary.latency_ms.median,
+            latency_p90_ms: summary.latency_ms.p90,
+        }
+    }
+}
+
+/// One line of the sweep table, as written to CSV
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TableRow {
+    /// [`Thresholds::trigger_delta`]
+    pub trigger_delta: u8,
+    /// [`Thresholds::confirm_delta`]
+    pub confirm_delta: u8,
+    /// [`Thresholds::restore_delta`]
+    pub restore_delta: u8,
+    /// True contacts
+    pub contacts: usize,
+    /// True contacts without an alert
+    pub missed: usize,
+    /// [`Score::false_alerts`]
+    pub false_alerts: usize,
+    /// [`Score::false_alerts_per_min`]
+    pub false_alerts_per_min: Option<f64>,
+    /// [`Score::recall`]
+    pub recall: Option<f64>,
+    /// [`Score::precision`]
+    pub precision: Option<f64>,
+    /// Median latency
+    pub latency_median_ms: Option<f64>,
+    /// 90th percentile latency
+    pub latency_p90_ms: Option<f64>,
+}
+
+/// Evaluate every combination of thresholds in `grid` over the recordings
+pub fn sweep(
+    recordings: &[(Annotation, Vec<Sample>)],
+    grid: &Grid,
+    tolerance: usize,
+) -> Vec<Point> {
+    grid.thresholds()
+        .map(|thresholds| {
+            let options = Options {
+                thresholds,
+                tolerance,
+            };
+            let mut score = Score::default();
+            for (annotation, samples) in recordings {
+                score.merge(&evaluate(samples, annotation, &options));
+            }
+            Point { thresholds, score }
+        })
+        .collect()
+}
+
+/// Distance from the default thresholds, so that parameters which make no difference are left alone
+fn distance_from_default(thresholds: &Thresholds) -> u32 {
+    let default = Thresholds::new();
+    Grid::SWEPT
+        .iter()
+        .map(|threshold| threshold.get(thresholds).abs_diff(threshold.get(&default)) as u32)
+        .sum()
+}
+
+/// Order points from best to worst: highest recall, then fewest false alerts, then lowest median
+/// latency, then closest to the defaults
+fn compare(a: &Point, b: &Point) -> Ordering {
+    let latency = |point: &Point| {
+        point
+            .score
+            .summary()
+            .latency_ms
+            .median
+            .unwrap_or(f64::INFINITY)
+    };
+    b.recall()
+        .total_cmp(&a.recall())
+        .then(a.false_rate().total_cmp(&b.false_rate()))
+        .then(latency(a).total_cmp(&latency(b)))
+        .then(distance_from_default(&a.thresholds).cmp(&distance_from_default(&b.thresholds)))
+}
+
+/// Points where recall cannot be improved without more false alerts, in order of increasing false
+/// alerts. Points with identical results are represented by the one closest to the defaults.
+pub fn frontier(points: &[Point]) -> Vec<&Point> {
+    let mut sorted: Vec<&Point> = points.iter().collect();
+    sorted.sort_by(|a, b| {
+        a.false_rate()
+            .total_cmp(&b.false_rate())
+            .then(compare(a, b))
+    });
+
+    let mut best_recall = f64::NEG_INFINITY;
+    sorted
+        .into_iter()
+        .filter(|point| {
+            let improves = point.recall() > best_recall;
+            best_recall = best_recall.max(point.recall());
+            improves
+        })
+        .collect()
+}
+
+/// Best point with at most `max_false_per_min` false alerts per minute, or `None` if every point has
+/// more
+pub fn recommend(points: &[Point], max_false_per_min: f64) -> Option<&Point> {
+    points
+        .iter()
+        .filter(|point| point.false_rate() <= max_false_per_min)
+        .min_by(|a, b| compare(a, b))
+}
+
+/// Script of console commands which apply the [swept](Grid::SWEPT) `thresholds` to the running
+/// firmware, one [`Command::Set`](aps490_pfpu2_core::command::Command::Set) per line. The other
+/// thresholds were not tuned, so they are left out and keep their current values.
+///
+/// The firmware has no persistent storage, so the thresholds only last until it is reset. To keep
+/// them, send the script after every startup, or change
+/// [`Thresholds::new`](aps490_pfpu2_core::config::Thresholds::new) and rebuild.
+pub fn console_script(thresholds: &Thresholds) -> String {
+    Grid::SWEPT
+        .iter()
+        .fold(String::new(), |mut config, threshold| {
+            let _ = writeln!(config, "set {} {}", threshold, threshold.get(thresholds));
+            config
+        })
+}
+
+/// Plot the ROC curve as an SVG image: every point in grey, the frontier in blue, and the
+/// `recommended` point in red
+pub fn roc_svg(points: &[Point], recommended: Option<&Point>) -> String {
+    const WIDTH: f64 = 640.0;
+    const HEIGHT: f64 = 480.0;
+    const MARGIN: f64 = 50.0;
+
+    let max_rate = points
+        .iter()
+        .map(Point::false_rate)
+        .fold(0.0, f64::max)
+        .max(0.1);
+    let x = |point: &Point| MARGIN + point.false_rate() / max_rate * (WIDTH - 2.0 * MARGIN);
+    let y = |point: &Point| HEIGHT - MARGIN - point.recall() * (HEIGHT - 2.0 * MARGIN);
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" font-family="sans-serif" font-size="12">"#
+    );
+    let _ = writeln!(
+        svg,
+        r#"<rect width="100%" height="100%" fill="white"/><path d="M{MARGIN} {MARGIN}V{bottom}H{right}" fill="none" stroke="black"/>"#,
+        bottom = HEIGHT - MARGIN,
+        right = WIDTH - MARGIN
+    );
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="{}" text-anchor="middle">False alerts per minute (0 to {max_rate:.2})</text>"#,
+        WIDTH / 2.0,
+        HEIGHT - 15.0
+    );
+    let _ = writeln!(
+        svg,
+        r#"<text transform="translate(15 {}) rotate(-90)" text-anchor="middle">Recall (0 to 1)</text>"#,
+        HEIGHT / 2.0
+    );
+    for point in points {
+        let _ = writeln!(
+            svg,
+            r#"<circle cx="{:.1}" cy="{:.1}" r="2" fill="grey"/>"#,
+            x(point),
+            y(point)
+        );
+    }
+    let line: Vec<String> = frontier(points)
+        .into_iter()
+        .map(|point| format!("{:.1},{:.1}", x(point), y(point)))
+        .collect();
+    let _ = writeln!(
+        svg,
+        r#"<polyline points="{}" fill="none" stroke="blue" stroke-width="2"/>"#,
+        line.join(" ")
+    );
+    if let Some(point) = recommended {
+        let _ = writeln!(
+            svg,
+            r#"<circle cx="{:.1}" cy="{:.1}" r="5" fill="none" stroke="red" stroke-width="2"/>"#,
+            x(point),
+            y(point)
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
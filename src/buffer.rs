@@ -1,28 +1,28 @@
 //! Buffers for recording data from the ADC, and tracking long-term averages from the detection system.
 
 // Copyright 2024 Jessica Rodriguez
-// 
+//
 // Licensed under the Apache License, Version 2.0 (the "License");
 // you may not use this file except in compliance with the License.
 // You may obtain a copy of the License at
-// 
+//
 // http://www.apache.org/licenses/LICENSE-2.0
-// 
+//
 // Unless required by applicable law or agreed to in writing, software
 // distributed under the License is distributed on an "AS IS" BASIS,
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use aps490_pfpu2_core::{
+    config::Thresholds, detect::Detector, impedance::ImpedanceFeatures, pattern::FaultCode,
+    signal::WINDOW_SIZE, state::StatusLedStates,
+};
 use cortex_m::singleton;
 #[allow(unused_imports)]
 use defmt::trace;
 use defmt::{debug, warn, Format, Formatter};
 
-#[cfg(feature = "rgba_status")]
-use crate::components::Rgba;
-#[cfg(feature = "triple_status")]
-use crate::components::Triple;
 use crate::{
     components::{StatusLed, StatusLedBase},
     interrupt::BUFFERS,
@@ -30,12 +30,18 @@ use crate::{
 
 /// Number of samples stored in the long-term buffer. Should be a multiple of 250 for tracing purposes
 ///
-/// Currently set to 45k averaged samples (90 s with 2 ms averaging)
+/// Currently set to 45k averaged samples (15 minutes of 20 ms windows at 100 kHz)
 pub const LONGTERM_SIZE: usize = 45000;
 
+/// Number of changes of the excitation gain remembered for [`Buffers::recent_gain`]
+pub const GAIN_CHANGES: usize = 32;
+
 /// Index of a detection event, combined with voltage difference
 pub type DetectionEvent = (SampleCounter, u8);
 
+/// Index of the first sample taken at a new excitation gain, combined with the gain
+pub type GainChange = (SampleCounter, u8);
+
 /// Monotonic counter indicating the position of averaged samples in the buffer
 #[derive(Copy, Clone, Default, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
 pub struct SampleCounter(pub usize);
@@ -51,16 +57,11 @@ impl SampleCounter {
     pub fn increment(&mut self) {
         match self.0.checked_add(1) {
             None => critical_section::with(|cs| {
-                debug!("critical_section: counter set_error overflow");
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_error(
-                    cs,
-                    Some("No ADC transfer in progress! Unable to collect latest readings"),
-                );
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_error(
+                debug!("critical_section: counter set_fault overflow");
+                StatusLedBase::set_fault(
                     cs,
-                    Some("No ADC transfer in progress! Unable to collect latest readings"),
+                    FaultCode::CounterOverflow,
+                    Some("Sample counter overflowed! Unable to index latest readings"),
                 );
             }),
             Some(new_counter) => self.0 = new_counter,
@@ -100,24 +101,15 @@ pub struct Buffers {
     /// Rotates position time stamps for up to 10 recent detection events, comparable with `current_sample`.
     /// Most recent event is stored at index 0
     detection_events: [Option<DetectionEvent>; 10],
-    /// A potential detection event or event clear has been recorded, and the system is awaiting a
-    /// second sample
-    await_confirm: bool,
+    /// Rotates the last [`GAIN_CHANGES`] changes of the
+    /// [`DriveLevel::gain_percent`](aps490_pfpu2_core::gain::DriveLevel) of the excitation, which
+    /// changes far less often than samples are taken. Most recent change is stored at index 0
+    gain_changes: [Option<GainChange>; GAIN_CHANGES],
+    /// Contact, proximity, and calibration logic, shared with the host tools
+    detector: Detector,
 }
 
 impl Buffers {
-    /// Initial averaged difference used for detecting contact.
-    ///
-    /// Ex. a trigger delta of 128 on a 3.3V signal requires that the average voltage range has
-    /// decreased by approximately 1.65V. Current values are based on experimental data and account
-    /// for signal drift.
-    const INIT_TRIGGER_DELTA: i16 = 2;
-    /// Initial averaged difference to restore
-    /// [`StatusLedStates::Normal`](crate::components::StatusLedStates::Normal).
-    ///
-    /// This is the increase in voltage relative to the last detection event. Current values are
-    /// based on experimental data and account for signal drift.
-    const INIT_RESTORE_DELTA: i16 = 2;
     /// Panic message raised if buffers are not available
     pub const NO_BUFFER_PANIC_MSG: &'static str =
         "Buffers have not been initialized or are not currently available in mutex";
@@ -128,7 +120,8 @@ impl Buffers {
             longterm_buffer: [0u8; LONGTERM_SIZE],
             current_sample: SampleCounter::default(),
             detection_events: [None; 10],
-            await_confirm: false
+            gain_changes: [None; GAIN_CHANGES],
+            detector: Detector::new()
         }) {
             Some(init_buffers) => {
                 debug!("critical_section: init buffers");
@@ -143,13 +136,59 @@ impl Buffers {
         SampleCounter(self.current_sample.get_counter() % LONGTERM_SIZE)
     }
 
-    /// Insert a new sample at the head
-    pub fn insert(&mut self, sample: u8) {
+    /// Counter for the most recent sample
+    pub fn current_sample(&self) -> SampleCounter {
+        self.current_sample
+    }
+
+    /// Sample recorded `age` samples before the most recent one, or `None` if it has been
+    /// overwritten or never recorded
+    pub fn recent_sample(&self, age: usize) -> Option<u8> {
+        if age >= LONGTERM_SIZE || age >= self.current_sample.get_counter() {
+            return None;
+        }
+        let idx = self
+            .current_wrapped()
+            .wrapping_counter_add(LONGTERM_SIZE - age, LONGTERM_SIZE);
+        Some(self.longterm_buffer[idx])
+    }
+
+    /// Gain of the excitation when the sample `age` samples before the most recent one was taken,
+    /// or `None` if it has been overwritten or never recorded, or the gain has changed more than
+    /// [`GAIN_CHANGES`] times since
+    pub fn recent_gain(&self, age: usize) -> Option<u8> {
+        if age >= LONGTERM_SIZE || age >= self.current_sample.get_counter() {
+            return None;
+        }
+        let sample = self.current_sample.get_counter() - age;
+        self.gain_changes
+            .iter()
+            .flatten()
+            .find(|(first, _)| first.get_counter() <= sample)
+            .map(|(_, gain_percent)| *gain_percent)
+    }
+
+    /// Recent detection events, with the most recent first
+    pub fn detection_events(&self) -> impl Iterator<Item = &DetectionEvent> {
+        self.detection_events.iter().flatten()
+    }
+
+    /// Idle average voltage from the last calibration, if complete
+    pub fn baseline_level(&self) -> Option<u8> {
+        self.detector.baseline_level()
+    }
+
+    /// Insert a new sample at the head, taken with the excitation at `gain_percent`
+    pub fn insert(&mut self, sample: u8, gain_percent: u8) {
         let new_head = self
             .current_wrapped()
             .wrapping_counter_add(1, LONGTERM_SIZE);
         self.longterm_buffer[new_head] = sample;
         self.current_sample.increment();
+        if self.gain_changes[0].map(|(_, gain)| gain) != Some(gain_percent) {
+            self.gain_changes.rotate_right(1);
+            self.gain_changes[0] = Some((self.current_sample, gain_percent));
+        }
 
         #[cfg(feature = "trace_avg_samples")]
         if self.current_sample.get_counter() % 250 == 0 {
@@ -170,80 +209,78 @@ impl Buffers {
         trace!("Here are the last 250 samples:\n{=[u8]}", new_samples)
     }
 
-    /// Analyze the most recent data to determine if a contact event has occurred.
+    /// Insert a new sample and run the [`Detector`] check for the current `state`, returning the
+    /// next state if it should change. Contacts are added to the record of recent detection events.
     ///
-    /// Also updates the record of recent detection events
-    pub fn detect_contact(&mut self) -> bool {
-        debug!("Checking for contact");
-        if !self.await_confirm {
-            // First contact check
-            let prev_sample = self.current_sample.wrapping_counter_sub(1, LONGTERM_SIZE);
-            if i16::abs(
-                self.longterm_buffer[prev_sample] as i16
-                    - self.longterm_buffer[self.current_sample.get_counter()] as i16,
-            ) >= Self::INIT_TRIGGER_DELTA
-            {
-                self.await_confirm = true;
-            }
-            return false;
-        } else {
-            // Validation contact check
-            self.await_confirm = false; // Always reset on validation check
-            let prev_high_sample = self.current_sample.wrapping_counter_sub(2, LONGTERM_SIZE);
-            if i16::abs(
-                self.longterm_buffer[prev_high_sample] as i16
-                    - self.longterm_buffer[self.current_sample.get_counter()] as i16,
-            ) >= 1
-            {
-                // Contact detected!
-                self.add_detection_event();
-                return true;
-            }
-        }
-        false
+    /// See [`Detector::update`] for the meaning of `delta` and `level`, and
+    /// [`Detector::set_window_us`] for `window_us`. The window was taken with the excitation at
+    /// `gain_percent`, so `thresholds` are scaled to match with [`Thresholds::at_gain`].
+    pub fn update(
+        &mut self,
+        state: StatusLedStates,
+        delta: u8,
+        level: u8,
+        window_us: u32,
+        gain_percent: u8,
+        thresholds: &Thresholds,
+    ) -> Option<StatusLedStates> {
+        self.insert(delta, gain_percent);
+        self.detector.set_window_us(window_us);
+        Self::trace_check(state);
+        let thresholds = thresholds.at_gain(gain_percent);
+        let next_state = self.detector.update(state, delta, level, &thresholds);
+        self.record_state(state, next_state);
+        next_state
     }
 
-    /// Analyze the most recent data and contact events to determine when contact ends
+    /// Insert the first tone of a multi-frequency cycle as a new sample, and run the [`Detector`]
+    /// check on the whole cycle, returning the next state if it should change.
     ///
-    /// A detection [`StatusLedStates::Alert`](crate::components::StatusLedStates::Alert) will not clear until at least 150 samples (300 milliseconds with 2 ms
-    /// averaging) have been recorded. This ensures the operator will see the LED light up.
-    pub fn detect_end_contact(&mut self) -> bool {
-        debug!("Checking for end of contact");
-        if let Some(last_detection) = self.detection_events[0] {
-            if self
-                .current_sample
-                .wrapping_counter_sub(last_detection.0.get_counter(), LONGTERM_SIZE)
-                >= 150
-            {
-                self.await_confirm = false;
-                return true;
-            } else if !self.await_confirm
-                && i16::abs(
-                    self.longterm_buffer[self.current_sample.get_counter()] as i16
-                        - last_detection.1 as i16,
-                ) >= Self::INIT_RESTORE_DELTA
-            {
-                // First clear check
-                self.await_confirm = true;
-            }
-            return false;
-        } else if self.await_confirm {
-            // Validation clear check
-            self.await_confirm = false;
-            if let Some(last_detection) = self.detection_events[0] {
-                if i16::abs(
-                    self.longterm_buffer[self.current_sample.get_counter()] as i16
-                        - last_detection.1 as i16,
-                ) >= 1
-                {
-                    // Contact cleared!
-                    return true;
-                }
-            }
-        } else {
-            warn!("End contact detection was called before any detection events have occurred.");
+    /// See [`Detector::update_features`], and [`Buffers::update`] for `gain_percent`.
+    pub fn update_features(
+        &mut self,
+        state: StatusLedStates,
+        features: &ImpedanceFeatures,
+        gain_percent: u8,
+        thresholds: &Thresholds,
+    ) -> Option<StatusLedStates> {
+        self.insert(features.primary().delta(), gain_percent);
+        Self::trace_check(state);
+        let thresholds = thresholds.at_gain(gain_percent);
+        let next_state = self.detector.update_features(state, features, &thresholds);
+        self.record_state(state, next_state);
+        next_state
+    }
+
+    /// Log which check is about to run for `state`
+    fn trace_check(state: StatusLedStates) {
+        match state {
+            StatusLedStates::Armed | StatusLedStates::Proximity => debug!("Checking for contact"),
+            StatusLedStates::Contact => debug!("Checking for end of contact"),
+            _ => {}
         }
-        false
+    }
+
+    /// Record the outcome of a [`Detector`] check
+    fn record_state(&mut self, state: StatusLedStates, next_state: Option<StatusLedStates>) {
+        match next_state {
+            Some(StatusLedStates::Contact) => self.add_detection_event(),
+            Some(StatusLedStates::Armed) if state == StatusLedStates::Calibrating => debug!(
+                "Calibrated idle signal level: {=u8}",
+                self.detector.baseline_level().unwrap_or_default()
+            ),
+            _ => {}
+        }
+    }
+
+    /// Discard the current idle level and begin a new calibration
+    pub fn start_calibration(&mut self) {
+        self.detector.start_calibration();
+    }
+
+    /// `true` if an idle level has been recorded since the last [`Buffers::start_calibration`]
+    pub fn is_calibrated(&self) -> bool {
+        self.detector.is_calibrated()
     }
 
     /// Shortcut to return index of a successful detection sample.
@@ -252,11 +289,11 @@ impl Buffers {
     /// use std::cell::RefCell;
     /// use critical_section::Mutex;
     /// use aps490_pfpu2_mini::buffer::Buffers;
-    /// 
+    ///
     /// pub static BUFFERS: Mutex<RefCell<Option<&'static mut Buffers>>> = Mutex::new(RefCell::new(None));
     ///
     /// Buffers::init();
-    /// critical_section::with(|cs| BUFFERS.borrow_ref_mut(cs).as_mut().unwrap().insert(12));
+    /// critical_section::with(|cs| BUFFERS.borrow_ref_mut(cs).as_mut().unwrap().insert(12, 100));
     /// critical_section::with(|cs| {
     ///    let buf = BUFFERS.borrow_ref_mut(cs).as_mut().unwrap();
     ///    assert_eq!(buf.detection_idx(), buf.current_wrapped().get_counter() - 1)
@@ -266,17 +303,21 @@ impl Buffers {
         self.current_sample.get_counter() - 1
     }
 
-    /// Add an entry to the `detection_events` array, based on the penultimate sample.
+    /// Add an entry to the `detection_events` array, based on the most recent sample.
+    ///
+    /// The sample is read at the wrapped index. The checks replaced by [`Detector`] used the
+    /// unwrapped counter, which read the same sample until the buffer first wrapped (see
+    /// [`Detector`] for a comparison).
     fn add_detection_event(&mut self) {
         self.detection_events.rotate_right(1);
         self.detection_events[0] = Some((
             self.current_sample,
-            self.longterm_buffer[self.current_sample.get_counter()],
+            self.longterm_buffer[self.current_wrapped().get_counter()],
         ));
     }
 }
 
-/// Newtype to send formatted error messages when [`Buffers::detect_contact`] is successful.
+/// Newtype to send formatted error messages when [`Buffers::update`] detects contact.
 #[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub struct DetectionMsg(pub SampleCounter);
 
@@ -300,6 +341,6 @@ impl Format for DetectionMsg {
 }
 
 /// Creates a [`singleton`] buffer for ADC DMA transfers
-pub fn create_avg_buffer() -> Option<&'static mut [u8; 4000]> {
-    singleton!(: [u8; 4000] = [0u8; 4000])
+pub fn create_avg_buffer() -> Option<&'static mut [u8; WINDOW_SIZE]> {
+    singleton!(: [u8; WINDOW_SIZE] = [0u8; WINDOW_SIZE])
 }
@@ -0,0 +1,181 @@
+//! Audible alerts from a buzzer or piezo, for operators who are watching the cut rather than the
+//! status LEDs.
+//!
+//! [`Buzzer`] is attached to [`STATUS_LEDS`](crate::interrupt::STATUS_LEDS) like any other
+//! indicator, so it follows every state change made through [`StatusLed`](crate::components::StatusLed).
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_core::{
+    debounce::{DebouncedInput, Edge},
+    pattern::{FaultCode, Pattern, TICK_MS},
+};
+use cortex_m::singleton;
+use defmt::{info, warn};
+use embedded_hal::{digital::InputPin, pwm::SetDutyCycle};
+use rp2040_hal::{
+    gpio::{bank0::Gpio15, FunctionSio, Pin, PullDown, SioInput},
+    pwm,
+    pwm::{FreeRunning, Pwm7, Slice},
+};
+
+use crate::{
+    components::{LedControl, StatusLedStates},
+    interrupt::DEBOUNCE_SAMPLES,
+};
+
+/// Wrapper for the buzzer output, on GPIO 14 (PWM slice 7, channel A)
+pub type BuzzerPwm = pwm::Channel<Slice<Pwm7, FreeRunning>, pwm::A>;
+/// Wrapper for the mute switch, on GPIO 15
+pub type MuteInput = Pin<Gpio15, FunctionSio<SioInput>, PullDown>;
+/// [`Buzzer`] on the board's spare PWM slice
+pub type Pwm7Buzzer = Buzzer<BuzzerPwm, MuteInput>;
+
+/// Plays a tone pattern for states which need the operator's attention:
+///
+/// | State     | Tone                                 |
+/// |-----------|--------------------------------------|
+/// | Proximity | Slow beeps                           |
+/// | Contact   | Continuous                           |
+/// | Fault     | Pulsed [`FaultCode`]                 |
+/// | Others    | Silent                               |
+///
+/// The pitch is set by the PWM slice driving `channel`, which should be configured near the
+/// resonant frequency of the buzzer. While the optional mute input is high, the buzzer is silent.
+/// The mute input is sampled on every [`TICK_MS`], and debounced over [`DEBOUNCE_SAMPLES`] ticks.
+#[derive(Debug)]
+pub struct Buzzer<C, M>
+where
+    C: SetDutyCycle,
+    M: InputPin,
+{
+    /// PWM channel driving the buzzer
+    channel: C,
+    /// Silences the buzzer while high
+    mute: Option<DebouncedInput<M>>,
+    /// Current tone pattern
+    pattern: Pattern,
+    /// Time since the current pattern started
+    elapsed_ms: u32,
+    /// Played in [`StatusLedStates::Fault`]
+    fault_code: FaultCode,
+    /// Whether the buzzer is currently sounding
+    sounding: bool,
+}
+
+impl<C: SetDutyCycle, M: InputPin> Buzzer<C, M> {
+    /// Duty cycle while sounding. A square wave gives the loudest tone from a piezo.
+    pub const DUTY_PERCENT: u8 = 50;
+
+    /// Create a buzzer, with an optional mute input. Fails if the mute input cannot be read.
+    pub fn new(channel: C, mute: Option<M>) -> Result<Self, M::Error> {
+        let mute = mute
+            .map(|pin| DebouncedInput::new(pin, DEBOUNCE_SAMPLES))
+            .transpose()?;
+        let mut buzzer = Self {
+            channel,
+            mute,
+            pattern: Pattern::Off,
+            elapsed_ms: 0,
+            fault_code: FaultCode::Unknown,
+            sounding: true,
+        };
+        if buzzer.is_muted() {
+            info!("Buzzer muted at startup");
+        }
+        buzzer.apply();
+        Ok(buzzer)
+    }
+
+    /// Tone pattern for each state
+    pub const fn pattern(state: StatusLedStates, fault_code: FaultCode) -> Pattern {
+        match state {
+            StatusLedStates::Proximity => Pattern::SlowBlink,
+            StatusLedStates::Contact => Pattern::Steady,
+            StatusLedStates::Fault => fault_code.pattern(),
+            StatusLedStates::Booting
+            | StatusLedStates::SelfTest
+            | StatusLedStates::Calibrating
+            | StatusLedStates::Armed
+            | StatusLedStates::Disabled => Pattern::Off,
+        }
+    }
+
+    /// Whether the debounced mute input is high
+    pub fn is_muted(&self) -> bool {
+        self.mute.as_ref().is_some_and(DebouncedInput::is_high)
+    }
+
+    /// Sample the mute input, keeping the last debounced level if it cannot be read
+    fn poll_mute(&mut self) {
+        let Some(mute) = self.mute.as_mut() else {
+            return;
+        };
+        match mute.poll() {
+            Ok(Some(edge)) => info!("Buzzer muted: {=bool}", edge == Edge::Rising),
+            Ok(None) => {}
+            Err(_) => warn!("Unable to read mute input"),
+        }
+    }
+
+    /// Start or stop the tone to match the pattern and the mute input
+    fn apply(&mut self) {
+        let sounding = !self.is_muted() && self.pattern.brightness(self.elapsed_ms) > 0;
+        if sounding != self.sounding {
+            let duty = if sounding { Self::DUTY_PERCENT } else { 0 };
+            self.channel
+                .set_duty_cycle_percent(duty)
+                .expect("Unable to set buzzer duty cycle");
+            self.sounding = sounding;
+        }
+    }
+}
+
+impl Pwm7Buzzer {
+    /// Move the buzzer into a [`singleton`], to be attached to
+    /// [`STATUS_LEDS`](crate::interrupt::STATUS_LEDS) with
+    /// [`StatusLedBase::add_indicator`](crate::components::StatusLedBase::add_indicator).
+    pub fn init(self) -> Option<&'static mut Pwm7Buzzer> {
+        singleton!(: Pwm7Buzzer = self)
+    }
+}
+
+impl<C, M> LedControl for Buzzer<C, M>
+where
+    C: SetDutyCycle + Send,
+    M: InputPin + Send,
+{
+    fn set_led(
+        &mut self,
+        _old_state: &StatusLedStates,
+        new_state: StatusLedStates,
+    ) -> StatusLedStates {
+        self.pattern = Self::pattern(new_state, self.fault_code);
+        self.elapsed_ms = 0;
+        self.apply();
+
+        new_state
+    }
+
+    fn show_fault(&mut self, code: FaultCode) {
+        self.fault_code = code;
+    }
+
+    fn tick(&mut self) {
+        self.elapsed_ms = self.elapsed_ms.wrapping_add(TICK_MS);
+        self.poll_mute();
+        self.apply();
+    }
+}
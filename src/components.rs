@@ -14,103 +14,87 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use aps490_pfpu2_core::{
+    led::LedPin,
+    pattern::{FaultCode, Pattern, TICK_MS},
+    state::{transition, IllegalTransition},
+};
 use cortex_m::{prelude::_embedded_hal_PwmPin, singleton};
 use critical_section::CriticalSection;
 use defmt::{debug, error, info, warn, Format, Formatter};
-use embedded_hal::digital::{OutputPin, PinState};
+use embedded_hal::digital::OutputPin;
 use rp2040_hal::{
-    dma::{single_buffer, SingleChannel},
-    gpio::{
-        bank0::{Gpio6, Gpio7, Gpio8},
-        FunctionNull, FunctionSio, Pin, PullDown, SioOutput,
-    },
+    dma::SingleChannel,
+    gpio::{DynPinId, FunctionSio, Pin, PullDown, SioOutput},
+};
+#[cfg(feature = "ws2812_status")]
+use rp2040_hal::{
+    gpio::{bank0::Gpio16, FunctionPio0},
+    pac::PIO0,
+    pio::SM0,
 };
+#[cfg(feature = "ws2812_status")]
+use smart_leds_trait::{SmartLedsWrite, RGB8};
+#[cfg(feature = "ws2812_status")]
+use ws2812_pio::Ws2812Direct;
 
 use crate::{
-    buffer::DetectionMsg,
+    buffer::{DetectionMsg, SampleCounter},
+    events::{self, Event},
     interrupt::{READINGS_FIFO, SIGNAL_CONF, SIGNAL_GEN, STATUS_LEDS},
+    sampling::start_readings,
 };
 
-/// System states, expressed by LEDs colours
-#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
-pub enum StatusLedStates {
-    /// Green
-    Normal,
-    /// Yellow
-    Alert,
-    /// Red
-    Error,
-    /// None illuminated
-    Disabled,
-}
-
-impl Format for StatusLedStates {
-    fn format(&self, fmt: Formatter) {
-        defmt::write!(
-            fmt,
-            "{}",
-            match self {
-                StatusLedStates::Normal => "Normal",
-                StatusLedStates::Alert => "Alert",
-                StatusLedStates::Error => "Error",
-                StatusLedStates::Disabled => "Disabled",
-            }
-        );
-    }
-}
+pub use aps490_pfpu2_core::state::StatusLedStates;
 
 /// System status is communicated via a trio of LED colours (see [`StatusLedStates`]).
+///
+/// All changes in state go through [`StatusLed::set_state`], which enforces the transition table in
+/// [`StatusLedStates::can_transition`].
 pub trait StatusLed {
     /// Panic message if no LEDs have been configured.
     const NO_LED_PANIC_MSG: &'static str =
         "Unable to display state due to non-configured LEDs, or not available in mutex";
-    /// Message displayed if system enters [`StatusLedStates::Error`]
+    /// Message displayed if system enters [`StatusLedStates::Fault`]
     const RESET_MSG: &'static str = "\nSystem must be power cycled to restore normal operation.";
     /// Message displayed if system enters [`StatusLedStates::Disabled`]
     const DISABLE_MSG: &'static str = "\nToggle the disable switch to resume normal operation.";
 
-    /// Set [`StatusLedStates::Normal`] within a [`CriticalSection`]
-    fn set_normal(cs: CriticalSection, message: Option<&str>);
-    /// Set [`StatusLedStates::Alert`] within a [`CriticalSection`]
-    fn set_alert(cs: CriticalSection, message: Option<DetectionMsg>);
-    /// Set [`StatusLedStates::Error`] within a [`CriticalSection`]
-    fn set_error(cs: CriticalSection, message: Option<&str>);
+    /// Move to `new_state` within a [`CriticalSection`], pausing or resuming detection as needed.
+    ///
+    /// Illegal transitions are logged and leave the state unchanged.
+    fn set_state(
+        cs: CriticalSection,
+        new_state: StatusLedStates,
+    ) -> Result<StatusLedStates, IllegalTransition>;
+    /// Set [`StatusLedStates::Armed`] within a [`CriticalSection`]
+    fn set_armed(cs: CriticalSection, message: Option<&str>);
+    /// Set [`StatusLedStates::Contact`] within a [`CriticalSection`]
+    fn set_contact(cs: CriticalSection, message: Option<DetectionMsg>);
+    /// Set [`StatusLedStates::Fault`] within a [`CriticalSection`], showing `code` on the
+    /// indicators
+    fn set_fault(cs: CriticalSection, code: FaultCode, message: Option<&str>);
     /// Set [`StatusLedStates::Disabled`] within a [`CriticalSection`]
     fn set_disabled(cs: CriticalSection, message: Option<&str>);
-    /// Pause signal generation, readings, and interrupts when disabled or error raised
+    /// Pause signal generation, readings, and interrupts when disabled or fault raised
     fn pause_detection(cs: CriticalSection);
     /// Resume components with normal operation
     fn resume_detection(cs: CriticalSection);
 }
 
+/// Default pin type for [`Rgba`] and [`Triple`]. Any GPIO can be used by converting it with
+/// [`Pin::into_dyn_pin`].
+pub type DynLedPin = Pin<DynPinId, FunctionSio<SioOutput>, PullDown>;
+
+/// Maximum number of indicators driven by [`StatusLedBase`]
+pub const MAX_INDICATORS: usize = 4;
+
 /// Directly controls the LEDs.
-pub trait LedControl {
-    /// Initialize LEDs.
-    ///
-    /// Example:
-    ///
-    /// ```no_run
-    /// # use defmt::debug;
-    /// # use rp2040_hal::{pac, Sio};
-    /// # use rp2040_hal::gpio::Pins;
-    /// #
-    /// # let mut pac = pac::Peripherals::take().unwrap();
-    /// # let sio = Sio::new(pac.SIO);
-    /// # let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
-    /// #
-    /// debug!("critical_section: init status LEDs");
-    /// critical_section::with(|cs| {
-    ///     #[cfg(feature = "rgba_status")]
-    ///     STATUS_LEDS.replace(cs, Rgba::init(pins.gpio6, pins.gpio7, pins.gpio8));
-    ///     #[cfg(feature = "triple_status")]
-    ///     STATUS_LEDS.replace(cs, Triple::init(pins.gpio6, pins.gpio7, pins.gpio8));
-    /// });
-    /// ```
-    fn init(
-        gpio6: Pin<Gpio6, FunctionNull, PullDown>,
-        gpio7: Pin<Gpio7, FunctionNull, PullDown>,
-        gpio8: Pin<Gpio8, FunctionNull, PullDown>,
-    ) -> Option<&'static mut impl StatusLed>;
+///
+/// Any number of controllers (up to [`MAX_INDICATORS`]) can be attached to [`STATUS_LEDS`] with
+/// [`StatusLedBase::add_indicator`], so front-panel LEDs and remote indicators all show the same
+/// state.
+pub trait LedControl: Send {
     /// Set the LEDs to match the current state. Any internal state should also be set.
     ///
     /// Returns `new_state` for convenience.
@@ -119,78 +103,227 @@ pub trait LedControl {
         old_state: &StatusLedStates,
         new_state: StatusLedStates,
     ) -> StatusLedStates;
+
+    /// Record the reason for the next [`StatusLedStates::Fault`]. Called before
+    /// [`LedControl::set_led`].
+    fn show_fault(&mut self, _code: FaultCode) {}
+
+    /// Advance any blink or brightness pattern by [`TICK_MS`]
+    fn tick(&mut self) {}
+
+    /// Write any output which is slow to send, such as a serial LED strip. Called every
+    /// [`TICK_MS`] outside of a critical section, after [`LedControl::tick`].
+    fn flush(&mut self) {}
+
+    /// Latest voltage difference from the detection system, reported once per averaging window
+    fn show_delta(&mut self, _delta: u8) {}
 }
 
-/// Controls the status LEDs on separate pins
+/// Returned by [`StatusLedBase::add_indicator`] when [`MAX_INDICATORS`] are already attached
 #[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
-pub struct StatusLedBase<C>
-where
-    C: LedControl,
-{
+pub struct IndicatorsFull;
+
+/// Tracks the system state, and shows it on every attached [`LedControl`]
+pub struct StatusLedBase {
     /// Current LED state
     pub state: StatusLedStates,
-    /// Controller for lights
-    pub ctrl: C,
+    /// Reason for the most recent [`StatusLedStates::Fault`]
+    pub fault_code: FaultCode,
+    /// Controllers for lights, updated in order of attachment
+    indicators: [Option<&'static mut dyn LedControl>; MAX_INDICATORS],
+}
+
+impl StatusLedBase {
+    /// Start in [`StatusLedStates::Booting`], with no indicators attached
+    pub const fn new() -> Self {
+        Self {
+            state: StatusLedStates::Booting,
+            fault_code: FaultCode::Unknown,
+            indicators: [const { None }; MAX_INDICATORS],
+        }
+    }
+
+    /// Move a new [`StatusLedBase`] into a [`singleton`] for [`STATUS_LEDS`]. Returns `None` if
+    /// called more than once.
+    pub fn init() -> Option<&'static mut StatusLedBase> {
+        singleton!(: StatusLedBase = StatusLedBase::new())
+    }
+
+    /// Attach another indicator, which immediately shows the current state
+    pub fn add_indicator(
+        &mut self,
+        ctrl: &'static mut dyn LedControl,
+    ) -> Result<(), IndicatorsFull> {
+        let slot = self
+            .indicators
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IndicatorsFull)?;
+        ctrl.show_fault(self.fault_code);
+        ctrl.set_led(&self.state, self.state);
+        *slot = Some(ctrl);
+        Ok(())
+    }
+
+    /// Number of attached indicators
+    pub fn indicator_count(&self) -> usize {
+        self.indicators.iter().flatten().count()
+    }
+
+    /// Advance the patterns on every attached indicator. Called every [`TICK_MS`] by
+    /// [`PATTERN_ALARM`](crate::interrupt::PATTERN_ALARM).
+    pub fn tick(&mut self) {
+        for ctrl in self.indicators.iter_mut().flatten() {
+            ctrl.tick();
+        }
+    }
+
+    /// Send pending output on every attached indicator. Must be called outside of a critical
+    /// section, as writes may block for some time.
+    pub fn flush(&mut self) {
+        for ctrl in self.indicators.iter_mut().flatten() {
+            ctrl.flush();
+        }
+    }
+
+    /// Report the latest voltage difference to every attached indicator
+    pub fn show_delta(&mut self, delta: u8) {
+        for ctrl in self.indicators.iter_mut().flatten() {
+            ctrl.show_delta(delta);
+        }
+    }
+
+    /// Show `new_state` on every attached indicator
+    fn set_leds(&mut self, old_state: &StatusLedStates, new_state: StatusLedStates) {
+        for ctrl in self.indicators.iter_mut().flatten() {
+            if new_state == StatusLedStates::Fault {
+                ctrl.show_fault(self.fault_code);
+            }
+            ctrl.set_led(old_state, new_state);
+        }
+        self.state = new_state;
+    }
+}
+
+impl Default for StatusLedBase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Format for StatusLedBase {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(
+            fmt,
+            "StatusLedBase {{ state: {}, indicators: {=usize} }}",
+            self.state,
+            self.indicator_count()
+        )
+    }
 }
 
-impl<C: LedControl> StatusLed for StatusLedBase<C> {
-    fn set_normal(cs: CriticalSection, message: Option<&str>) {
+impl StatusLed for StatusLedBase {
+    fn set_state(
+        cs: CriticalSection,
+        new_state: StatusLedStates,
+    ) -> Result<StatusLedStates, IllegalTransition> {
         let status = STATUS_LEDS.take(cs).expect(Self::NO_LED_PANIC_MSG);
-        if let Some(detection_msg) = message {
-            info!("Resuming normal detection: {}", detection_msg);
-        } else {
-            warn!("State changed to normal");
+        let old_state = status.state;
+        if let Err(illegal) = transition(old_state, new_state) {
+            error!(
+                "Rejected illegal state transition from {} to {}",
+                illegal.from, illegal.to
+            );
+            STATUS_LEDS.replace(cs, Some(status));
+            return Err(illegal);
+        }
+        if old_state == new_state {
+            STATUS_LEDS.replace(cs, Some(status));
+            return Ok(new_state);
         }
 
-        match status.state {
-            StatusLedStates::Error | StatusLedStates::Disabled => Self::resume_detection(cs),
-            StatusLedStates::Normal | StatusLedStates::Alert => {}
+        debug!("State changed from {} to {}", old_state, new_state);
+        if !old_state.pauses_detection() && new_state.pauses_detection() {
+            Self::pause_detection(cs);
+        } else if old_state.pauses_detection() && !new_state.pauses_detection() {
+            Self::resume_detection(cs);
         }
-        status.state = status.ctrl.set_led(&status.state, StatusLedStates::Normal);
+        status.set_leds(&old_state, new_state);
         STATUS_LEDS.replace(cs, Some(status));
+
+        if old_state == StatusLedStates::Contact && new_state == StatusLedStates::Armed {
+            events::dispatch(cs, Event::Clear);
+        } else if new_state == StatusLedStates::Fault {
+            let code = STATUS_LEDS
+                .borrow_ref(cs)
+                .as_ref()
+                .map_or(FaultCode::Unknown, |status| status.fault_code);
+            events::dispatch(cs, Event::Fault(code));
+        }
+        events::dispatch(
+            cs,
+            Event::StateChange {
+                from: old_state,
+                to: new_state,
+            },
+        );
+        Ok(new_state)
     }
 
-    fn set_alert(cs: CriticalSection, message: Option<DetectionMsg>) {
-        let status = STATUS_LEDS.take(cs).expect(Self::NO_LED_PANIC_MSG);
+    fn set_armed(cs: CriticalSection, message: Option<&str>) {
+        if let Some(msg_text) = message {
+            info!("Detection armed: {=str}", msg_text);
+        } else {
+            warn!("State changed to armed");
+        }
+        let _ = Self::set_state(cs, StatusLedStates::Armed);
+    }
+
+    fn set_contact(cs: CriticalSection, message: Option<DetectionMsg>) {
         if let Some(detection_msg) = message {
             info!("{}", detection_msg);
         } else {
-            warn!("Unknown alert raised!");
+            warn!("Unknown contact raised!");
         }
-
-        match status.state {
-            StatusLedStates::Error | StatusLedStates::Disabled => Self::resume_detection(cs),
-            StatusLedStates::Normal | StatusLedStates::Alert => {}
-        };
-        status.state = status.ctrl.set_led(&status.state, StatusLedStates::Alert);
-        STATUS_LEDS.replace(cs, Some(status));
+        let old_state = STATUS_LEDS
+            .borrow_ref(cs)
+            .as_ref()
+            .expect(Self::NO_LED_PANIC_MSG)
+            .state;
+        if old_state != StatusLedStates::Contact
+            && old_state.can_transition(StatusLedStates::Contact)
+        {
+            let sample = message.map_or(SampleCounter::default(), |msg| msg.0);
+            events::dispatch(cs, Event::Contact(sample));
+        }
+        let _ = Self::set_state(cs, StatusLedStates::Contact);
     }
 
-    fn set_error(cs: CriticalSection, message: Option<&str>) {
-        let status = STATUS_LEDS.take(cs).expect(Self::NO_LED_PANIC_MSG);
+    fn set_fault(cs: CriticalSection, code: FaultCode, message: Option<&str>) {
         if let Some(msg_text) = message {
             error!(
-                "Error encountered during operation:\n{=str}{=str}",
+                "Fault {} encountered during operation:\n{=str}{=str}",
+                code,
                 msg_text,
                 Self::RESET_MSG
             );
         } else {
             error!(
-                "Unknown error encountered during operation.{=str}",
+                "Fault {} encountered during operation.{=str}",
+                code,
                 Self::RESET_MSG
             );
         }
-
-        match status.state {
-            StatusLedStates::Normal | StatusLedStates::Alert => Self::pause_detection(cs),
-            StatusLedStates::Error | StatusLedStates::Disabled => {}
-        };
-        status.state = status.ctrl.set_led(&status.state, StatusLedStates::Error);
-        STATUS_LEDS.replace(cs, Some(status));
+        if let Some(status) = STATUS_LEDS.borrow_ref_mut(cs).as_mut() {
+            // Keep the first fault, which is most likely to be the cause
+            if status.state != StatusLedStates::Fault {
+                status.fault_code = code;
+            }
+        }
+        let _ = Self::set_state(cs, StatusLedStates::Fault);
     }
 
     fn set_disabled(cs: CriticalSection, message: Option<&str>) {
-        let status = STATUS_LEDS.take(cs).expect(Self::NO_LED_PANIC_MSG);
         if let Some(msg_text) = message {
             info!(
                 "System has been disabled:\n{=str}{=str}",
@@ -200,15 +333,7 @@ impl<C: LedControl> StatusLed for StatusLedBase<C> {
         } else {
             info!("System has been disabled.{=str}", Self::DISABLE_MSG);
         }
-
-        match status.state {
-            StatusLedStates::Normal | StatusLedStates::Alert => Self::pause_detection(cs),
-            StatusLedStates::Error | StatusLedStates::Disabled => {}
-        };
-        status.state = status
-            .ctrl
-            .set_led(&status.state, StatusLedStates::Disabled);
-        STATUS_LEDS.replace(cs, Some(status));
+        let _ = Self::set_state(cs, StatusLedStates::Disabled);
     }
 
     fn pause_detection(cs: CriticalSection) {
@@ -218,8 +343,13 @@ impl<C: LedControl> StatusLed for StatusLedBase<C> {
         SIGNAL_GEN.replace(cs, Some(signal_pwm));
 
         debug!("Disabling FIFO readings/interrupts");
-        let fifo_transfer = READINGS_FIFO.take(cs).expect("Unable to access ADC FIFO");
-        SIGNAL_CONF.replace(cs, Some(fifo_transfer.wait()));
+        if let Some(fifo_transfer) = READINGS_FIFO.take(cs) {
+            let mut config = fifo_transfer.wait();
+            config.0.check_irq0(); // Don't run DMA_IRQ_0 for the final transfer
+            SIGNAL_CONF.replace(cs, Some(config));
+        } else {
+            warn!("No ADC transfer in progress to pause");
+        }
     }
 
     fn resume_detection(cs: CriticalSection) {
@@ -232,8 +362,7 @@ impl<C: LedControl> StatusLed for StatusLedBase<C> {
         let config = SIGNAL_CONF.replace(cs, None);
         if let Some(mut inner) = config {
             inner.0.enable_irq0();
-            let new_transfer = single_buffer::Config::new(inner.0, inner.1, inner.2);
-            READINGS_FIFO.replace(cs, Some(new_transfer.start()));
+            start_readings(cs, inner);
         } else {
             warn!("Failed to restore FIFO config");
             READINGS_FIFO.replace(cs, None);
@@ -241,118 +370,519 @@ impl<C: LedControl> StatusLed for StatusLedBase<C> {
     }
 }
 
-/// Common anode RGB, mapped as follows:
-/// - [`Gpio6`] is the red control
-/// - [`Gpio7`] is the green control
-/// - [`Gpio8`] is the blue control (initialized high but otherwise unused)
-#[cfg(any(doc, feature = "rgba_status"))]
-pub struct Rgba {
-    /// Used in [`StatusLedStates::Alert`] and [`StatusLedStates::Error`]
-    red_led: Pin<Gpio6, FunctionSio<SioOutput>, PullDown>,
-    /// Used in [`StatusLedStates::Normal`] and [`StatusLedStates::Error`]
-    green_led: Pin<Gpio7, FunctionSio<SioOutput>, PullDown>,
-    /// Initialized but unused
-    #[allow(dead_code)]
-    blue_led: Pin<Gpio8, FunctionSio<SioOutput>, PullDown>,
+/// RGB LED, mapped as follows:
+/// - `red` is the red control
+/// - `green` is the green control
+/// - `blue` is the blue control
+///
+/// The LED in our schematic is common anode, so each pin should be
+/// [active low](aps490_pfpu2_core::led::Polarity::ActiveLow). States are shown with the
+/// following [`Pattern`]s:
+///
+/// | State       | Colour                                   |
+/// |-------------|------------------------------------------|
+/// | Booting     | White                                    |
+/// | SelfTest    | Blue, fast blink                         |
+/// | Calibrating | Blue, breathing                          |
+/// | Armed       | Green                                    |
+/// | Proximity   | Green, with blue slow blink (cyan)       |
+/// | Contact     | Yellow, fast blink                       |
+/// | Fault       | Red, pulsed [`FaultCode`]                |
+/// | Disabled    | Blue                                     |
+pub struct Rgba<R = DynLedPin, G = DynLedPin, B = DynLedPin>
+where
+    R: OutputPin,
+    G: OutputPin,
+    B: OutputPin,
+{
+    /// Used in [`StatusLedStates::Contact`] and [`StatusLedStates::Fault`]
+    red_led: LedPin<R>,
+    /// Used in [`StatusLedStates::Armed`] and [`StatusLedStates::Contact`]
+    green_led: LedPin<G>,
+    /// Used in [`StatusLedStates::SelfTest`], [`StatusLedStates::Calibrating`],
+    /// [`StatusLedStates::Proximity`] and [`StatusLedStates::Disabled`]
+    blue_led: LedPin<B>,
+    /// Current (red, green, blue) patterns
+    patterns: [Pattern; 3],
+    /// Time since the current patterns started
+    elapsed_ms: u32,
+    /// Shown in [`StatusLedStates::Fault`]
+    fault_code: FaultCode,
 }
 
-#[cfg(any(doc, feature = "rgba_status"))]
-impl LedControl for Rgba {
-    #[allow(refining_impl_trait)]
-    fn init(
-        gpio6: Pin<Gpio6, FunctionNull, PullDown>,
-        gpio7: Pin<Gpio7, FunctionNull, PullDown>,
-        gpio8: Pin<Gpio8, FunctionNull, PullDown>,
-    ) -> Option<&'static mut StatusLedBase<Rgba>> {
-        singleton!(: StatusLedBase<Rgba> = StatusLedBase {
-            state: StatusLedStates::Alert,
-            ctrl: Rgba {
-                red_led: gpio6.into_push_pull_output_in_state(PinState::Low),
-                green_led: gpio7.into_push_pull_output_in_state(PinState::Low),
-                blue_led: gpio8.into_push_pull_output_in_state(PinState::High),
-            }
-        })
+impl<R: OutputPin, G: OutputPin, B: OutputPin> Rgba<R, G, B> {
+    /// Create a controller from three LED pins
+    pub fn new(red_led: LedPin<R>, green_led: LedPin<G>, blue_led: LedPin<B>) -> Self {
+        Self {
+            red_led,
+            green_led,
+            blue_led,
+            patterns: [Pattern::Off; 3],
+            elapsed_ms: 0,
+            fault_code: FaultCode::Unknown,
+        }
     }
 
+    /// (red, green, blue) patterns for each state
+    pub const fn patterns(state: StatusLedStates, fault_code: FaultCode) -> [Pattern; 3] {
+        match state {
+            StatusLedStates::Booting => [Pattern::Steady, Pattern::Steady, Pattern::Steady],
+            StatusLedStates::SelfTest => [Pattern::Off, Pattern::Off, Pattern::FastBlink],
+            StatusLedStates::Calibrating => [Pattern::Off, Pattern::Off, Pattern::Breathing],
+            StatusLedStates::Armed => [Pattern::Off, Pattern::Steady, Pattern::Off],
+            StatusLedStates::Proximity => [Pattern::Off, Pattern::Steady, Pattern::SlowBlink],
+            StatusLedStates::Contact => [Pattern::FastBlink, Pattern::FastBlink, Pattern::Off],
+            StatusLedStates::Fault => [fault_code.pattern(), Pattern::Off, Pattern::Off],
+            StatusLedStates::Disabled => [Pattern::Off, Pattern::Off, Pattern::Steady],
+        }
+    }
+
+    /// Set each LED according to its pattern
+    fn apply(&mut self) {
+        let [red, green, blue] = self.patterns;
+        self.red_led.set(red.is_on(self.elapsed_ms)).unwrap();
+        self.green_led.set(green.is_on(self.elapsed_ms)).unwrap();
+        self.blue_led.set(blue.is_on(self.elapsed_ms)).unwrap();
+    }
+}
+
+impl Rgba {
+    /// Move the LEDs into a [`singleton`], to be attached to [`STATUS_LEDS`] with
+    /// [`StatusLedBase::add_indicator`].
+    ///
+    /// ```no_run
+    /// # use defmt::debug;
+    /// # use rp2040_hal::{pac, Sio};
+    /// # use rp2040_hal::gpio::Pins;
+    /// # use aps490_pfpu2_core::led::LedPin;
+    /// # use aps490_pfpu2_mini::{components::{Rgba, StatusLedBase}, interrupt::STATUS_LEDS};
+    /// #
+    /// # let mut pac = pac::Peripherals::take().unwrap();
+    /// # let sio = Sio::new(pac.SIO);
+    /// # let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+    /// #
+    /// let leds = Rgba::new(
+    ///     LedPin::active_low(pins.gpio6.into_push_pull_output().into_dyn_pin()),
+    ///     LedPin::active_low(pins.gpio7.into_push_pull_output().into_dyn_pin()),
+    ///     LedPin::active_low(pins.gpio8.into_push_pull_output().into_dyn_pin()),
+    /// );
+    /// let status = StatusLedBase::init().unwrap();
+    /// status.add_indicator(leds.init().unwrap()).unwrap();
+    ///
+    /// debug!("critical_section: init status LEDs");
+    /// critical_section::with(|cs| STATUS_LEDS.replace(cs, Some(status)));
+    /// ```
+    pub fn init(self) -> Option<&'static mut Rgba> {
+        singleton!(: Rgba = self)
+    }
+}
+
+impl<R, G, B> LedControl for Rgba<R, G, B>
+where
+    R: OutputPin + Send,
+    G: OutputPin + Send,
+    B: OutputPin + Send,
+{
     fn set_led(
         &mut self,
-        old_state: &StatusLedStates,
+        _old_state: &StatusLedStates,
         new_state: StatusLedStates,
     ) -> StatusLedStates {
-        match old_state {
-            StatusLedStates::Normal => self.green_led.set_high().unwrap(),
-            StatusLedStates::Alert => {
-                self.red_led.set_high().unwrap();
-                self.green_led.set_high().unwrap();
-            }
-            StatusLedStates::Error => self.red_led.set_high().unwrap(),
-            StatusLedStates::Disabled => {}
-        }
-
-        match new_state {
-            StatusLedStates::Normal => self.green_led.set_low().unwrap(),
-            StatusLedStates::Alert => {
-                self.red_led.set_low().unwrap();
-                self.green_led.set_low().unwrap();
-            }
-            StatusLedStates::Error => self.green_led.set_low().unwrap(),
-            StatusLedStates::Disabled => {}
-        }
+        self.patterns = Self::patterns(new_state, self.fault_code);
+        self.elapsed_ms = 0;
+        self.apply();
 
         new_state
     }
+
+    fn show_fault(&mut self, code: FaultCode) {
+        self.fault_code = code;
+    }
+
+    fn tick(&mut self) {
+        self.elapsed_ms = self.elapsed_ms.wrapping_add(TICK_MS);
+        self.apply();
+    }
 }
 
 /// Triple LED status, mapped as follows:
-/// - [`Gpio6`] is a green LED
-/// - [`Gpio7`] is a yellow LED
-/// - [`Gpio8`] is a red LED
-#[cfg(any(doc, feature = "triple_status"))]
-pub struct Triple {
+/// - `normal` is a green LED
+/// - `alert` is a yellow LED
+/// - `error` is a red LED
+///
+/// States are shown with the following [`Pattern`]s:
+///
+/// | State       | Green      | Yellow     | Red                   |
+/// |-------------|------------|------------|-----------------------|
+/// | Booting     | On         | On         | On                    |
+/// | SelfTest    | Fast blink |            | Fast blink            |
+/// | Calibrating |            | Breathing  |                       |
+/// | Armed       | On         |            |                       |
+/// | Proximity   | On         | Slow blink |                       |
+/// | Contact     |            | Fast blink |                       |
+/// | Fault       |            |            | Pulsed [`FaultCode`]  |
+/// | Disabled    |            |            |                       |
+pub struct Triple<G = DynLedPin, Y = DynLedPin, R = DynLedPin>
+where
+    G: OutputPin,
+    Y: OutputPin,
+    R: OutputPin,
+{
     /// Green
-    normal_led: Pin<Gpio6, FunctionSio<SioOutput>, PullDown>,
+    normal_led: LedPin<G>,
     /// Yellow
-    alert_led: Pin<Gpio7, FunctionSio<SioOutput>, PullDown>,
+    alert_led: LedPin<Y>,
     /// Red
-    error_led: Pin<Gpio8, FunctionSio<SioOutput>, PullDown>,
+    error_led: LedPin<R>,
+    /// Current (green, yellow, red) patterns
+    patterns: [Pattern; 3],
+    /// Time since the current patterns started
+    elapsed_ms: u32,
+    /// Shown in [`StatusLedStates::Fault`]
+    fault_code: FaultCode,
 }
 
-#[cfg(any(doc, feature = "triple_status"))]
-impl LedControl for Triple {
-    #[allow(refining_impl_trait)]
-    fn init(
-        gpio6: Pin<Gpio6, FunctionNull, PullDown>,
-        gpio7: Pin<Gpio7, FunctionNull, PullDown>,
-        gpio8: Pin<Gpio8, FunctionNull, PullDown>,
-    ) -> Option<&'static mut StatusLedBase<Self>> {
-        singleton!(: StatusLedBase<Triple> = StatusLedBase {
-            state: StatusLedStates::Alert,
-            ctrl: Triple {
-                normal_led: gpio6.into_push_pull_output_in_state(PinState::Low),
-                alert_led: gpio7.into_push_pull_output_in_state(PinState::High),
-                error_led: gpio8.into_push_pull_output_in_state(PinState::Low),
-            }
-        })
+impl<G: OutputPin, Y: OutputPin, R: OutputPin> Triple<G, Y, R> {
+    /// Create a controller from three LED pins
+    pub fn new(normal_led: LedPin<G>, alert_led: LedPin<Y>, error_led: LedPin<R>) -> Self {
+        Self {
+            normal_led,
+            alert_led,
+            error_led,
+            patterns: [Pattern::Off; 3],
+            elapsed_ms: 0,
+            fault_code: FaultCode::Unknown,
+        }
+    }
+
+    /// (green, yellow, red) patterns for each state
+    pub const fn patterns(state: StatusLedStates, fault_code: FaultCode) -> [Pattern; 3] {
+        match state {
+            StatusLedStates::Booting => [Pattern::Steady, Pattern::Steady, Pattern::Steady],
+            StatusLedStates::SelfTest => [Pattern::FastBlink, Pattern::Off, Pattern::FastBlink],
+            StatusLedStates::Calibrating => [Pattern::Off, Pattern::Breathing, Pattern::Off],
+            StatusLedStates::Armed => [Pattern::Steady, Pattern::Off, Pattern::Off],
+            StatusLedStates::Proximity => [Pattern::Steady, Pattern::SlowBlink, Pattern::Off],
+            StatusLedStates::Contact => [Pattern::Off, Pattern::FastBlink, Pattern::Off],
+            StatusLedStates::Fault => [Pattern::Off, Pattern::Off, fault_code.pattern()],
+            StatusLedStates::Disabled => [Pattern::Off, Pattern::Off, Pattern::Off],
+        }
+    }
+
+    /// Set each LED according to its pattern
+    fn apply(&mut self) {
+        let [normal, alert, error] = self.patterns;
+        self.normal_led.set(normal.is_on(self.elapsed_ms)).unwrap();
+        self.alert_led.set(alert.is_on(self.elapsed_ms)).unwrap();
+        self.error_led.set(error.is_on(self.elapsed_ms)).unwrap();
+    }
+}
+
+impl Triple {
+    /// Move the LEDs into a [`singleton`], to be attached to [`STATUS_LEDS`] with
+    /// [`StatusLedBase::add_indicator`].
+    ///
+    /// ```no_run
+    /// # use defmt::debug;
+    /// # use rp2040_hal::{pac, Sio};
+    /// # use rp2040_hal::gpio::Pins;
+    /// # use aps490_pfpu2_core::led::LedPin;
+    /// # use aps490_pfpu2_mini::{components::{Triple, StatusLedBase}, interrupt::STATUS_LEDS};
+    /// #
+    /// # let mut pac = pac::Peripherals::take().unwrap();
+    /// # let sio = Sio::new(pac.SIO);
+    /// # let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+    /// #
+    /// let leds = Triple::new(
+    ///     LedPin::active_high(pins.gpio6.into_push_pull_output().into_dyn_pin()),
+    ///     LedPin::active_high(pins.gpio7.into_push_pull_output().into_dyn_pin()),
+    ///     LedPin::active_high(pins.gpio8.into_push_pull_output().into_dyn_pin()),
+    /// );
+    /// let status = StatusLedBase::init().unwrap();
+    /// status.add_indicator(leds.init().unwrap()).unwrap();
+    ///
+    /// debug!("critical_section: init status LEDs");
+    /// critical_section::with(|cs| STATUS_LEDS.replace(cs, Some(status)));
+    /// ```
+    pub fn init(self) -> Option<&'static mut Triple> {
+        singleton!(: Triple = self)
     }
+}
 
+impl<G, Y, R> LedControl for Triple<G, Y, R>
+where
+    G: OutputPin + Send,
+    Y: OutputPin + Send,
+    R: OutputPin + Send,
+{
     fn set_led(
         &mut self,
-        old_state: &StatusLedStates,
+        _old_state: &StatusLedStates,
         new_state: StatusLedStates,
     ) -> StatusLedStates {
-        match old_state {
-            StatusLedStates::Normal => self.normal_led.set_low().unwrap(),
-            StatusLedStates::Alert => self.alert_led.set_low().unwrap(),
-            StatusLedStates::Error => self.error_led.set_low().unwrap(),
-            StatusLedStates::Disabled => {}
+        self.patterns = Self::patterns(new_state, self.fault_code);
+        self.elapsed_ms = 0;
+        self.apply();
+
+        new_state
+    }
+
+    fn show_fault(&mut self, code: FaultCode) {
+        self.fault_code = code;
+    }
+
+    fn tick(&mut self) {
+        self.elapsed_ms = self.elapsed_ms.wrapping_add(TICK_MS);
+        self.apply();
+    }
+}
+
+/// Number of LEDs on the WS2812 strip driven by [`Ws2812`]
+#[cfg(feature = "ws2812_status")]
+pub const WS2812_LEDS: usize = 8;
+
+/// Pin type for the WS2812 data line, on GPIO 16
+#[cfg(feature = "ws2812_status")]
+pub type Ws2812Pin = Pin<Gpio16, FunctionPio0, PullDown>;
+
+/// [`NeoPixel`] strip driven by state machine 0 of PIO0
+#[cfg(feature = "ws2812_status")]
+pub type Ws2812 = NeoPixel<Ws2812Direct<PIO0, SM0, Ws2812Pin>, WS2812_LEDS>;
+
+/// What is shown on a [`NeoPixel`] strip
+#[cfg(feature = "ws2812_status")]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum NeoPixelMode {
+    /// Every LED shows the system state
+    Status,
+    /// The first LED shows the system state, and the rest show the live voltage difference as a
+    /// bar graph. The bar is full when the difference reaches `full_scale`.
+    BarGraph {
+        /// Voltage difference which lights the whole bar
+        full_scale: u8,
+    },
+}
+
+/// Addressable RGB LEDs (WS2812/NeoPixel), driven through any [`SmartLedsWrite`] implementation
+/// such as the PIO driver in [`ws2812_pio`].
+///
+/// States are shown with the following colours and [`Pattern`]s:
+///
+/// | State       | Colour                       |
+/// |-------------|------------------------------|
+/// | Booting     | White                        |
+/// | SelfTest    | Magenta, fast blink          |
+/// | Calibrating | Blue, breathing              |
+/// | Armed       | Green                        |
+/// | Proximity   | Cyan, slow blink             |
+/// | Contact     | Yellow, fast blink           |
+/// | Fault       | Red, pulsed [`FaultCode`]    |
+/// | Disabled    | Blue                         |
+#[cfg(feature = "ws2812_status")]
+pub struct NeoPixel<W, const N: usize>
+where
+    W: SmartLedsWrite<Color = RGB8>,
+{
+    /// Driver for the LED data line
+    writer: W,
+    /// What is shown on the strip
+    mode: NeoPixelMode,
+    /// Overall brightness from 0 to 255, as WS2812s are very bright at full power
+    brightness: u8,
+    /// Colour of the current state
+    colour: RGB8,
+    /// Pattern of the current state
+    pattern: Pattern,
+    /// Time since the current pattern started
+    elapsed_ms: u32,
+    /// Shown in [`StatusLedStates::Fault`]
+    fault_code: FaultCode,
+    /// Latest voltage difference, shown in [`NeoPixelMode::BarGraph`]
+    delta: u8,
+    /// Latest colours for the strip
+    frame: [RGB8; N],
+    /// `frame` has changed since it was last written to the strip
+    pending: bool,
+}
+
+#[cfg(feature = "ws2812_status")]
+impl<W, const N: usize> NeoPixel<W, N>
+where
+    W: SmartLedsWrite<Color = RGB8>,
+{
+    /// Brightness used by [`NeoPixel::new`]
+    pub const DEFAULT_BRIGHTNESS: u8 = 64;
+    /// Time between updates of the strip. Each update blocks while the colours are shifted out
+    /// (about 30 µs per LED), so the strip is refreshed less often than the patterns are advanced.
+    /// Writes are left to [`LedControl::flush`], outside of any critical section.
+    pub const REFRESH_MS: u32 = 20;
+
+    /// Create a controller for `N` LEDs
+    pub fn new(writer: W, mode: NeoPixelMode) -> Self {
+        Self {
+            writer,
+            mode,
+            brightness: Self::DEFAULT_BRIGHTNESS,
+            colour: RGB8::default(),
+            pattern: Pattern::Off,
+            elapsed_ms: 0,
+            fault_code: FaultCode::Unknown,
+            delta: 0,
+            frame: [RGB8::default(); N],
+            pending: false,
         }
-        match new_state {
-            StatusLedStates::Normal => self.normal_led.set_high().unwrap(),
-            StatusLedStates::Alert => self.alert_led.set_high().unwrap(),
-            StatusLedStates::Error => self.error_led.set_high().unwrap(),
-            StatusLedStates::Disabled => {}
+    }
+
+    /// Set the overall brightness, from 0 to 255
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+
+    /// Change what is shown on the strip
+    pub fn set_mode(&mut self, mode: NeoPixelMode) {
+        self.mode = mode;
+    }
+
+    /// Colour and pattern for each state
+    pub const fn colours(state: StatusLedStates, fault_code: FaultCode) -> (RGB8, Pattern) {
+        match state {
+            StatusLedStates::Booting => (rgb(255, 255, 255), Pattern::Steady),
+            StatusLedStates::SelfTest => (rgb(255, 0, 255), Pattern::FastBlink),
+            StatusLedStates::Calibrating => (rgb(0, 0, 255), Pattern::Breathing),
+            StatusLedStates::Armed => (rgb(0, 255, 0), Pattern::Steady),
+            StatusLedStates::Proximity => (rgb(0, 255, 255), Pattern::SlowBlink),
+            StatusLedStates::Contact => (rgb(255, 255, 0), Pattern::FastBlink),
+            StatusLedStates::Fault => (rgb(255, 0, 0), fault_code.pattern()),
+            StatusLedStates::Disabled => (rgb(0, 0, 255), Pattern::Steady),
         }
+    }
+
+    /// Colour of segment `index` in a bar of `segments`: green for the lower half, yellow for the
+    /// next quarter, and red at the top
+    fn bar_colour(index: usize, segments: usize) -> RGB8 {
+        match index * 4 / segments {
+            0 | 1 => rgb(0, 255, 0),
+            2 => rgb(255, 255, 0),
+            _ => rgb(255, 0, 0),
+        }
+    }
+
+    /// Scale `colour` by `level` and the overall brightness
+    fn scale(&self, colour: RGB8, level: u8) -> RGB8 {
+        let channel = |value: u8| {
+            (u32::from(value) * u32::from(level) * u32::from(self.brightness) / (255 * 255)) as u8
+        };
+        RGB8 {
+            r: channel(colour.r),
+            g: channel(colour.g),
+            b: channel(colour.b),
+        }
+    }
+
+    /// Build the next frame, and mark it to be written if anything has changed
+    fn refresh(&mut self) {
+        let status = self.scale(self.colour, self.pattern.brightness(self.elapsed_ms));
+        let mut frame = [RGB8::default(); N];
+        match self.mode {
+            NeoPixelMode::Status => frame.fill(status),
+            NeoPixelMode::BarGraph { full_scale } => {
+                if let Some((first, bar)) = frame.split_first_mut() {
+                    *first = status;
+                    let segments = bar.len();
+                    let lit = (usize::from(self.delta) * segments)
+                        .div_ceil(usize::from(full_scale.max(1)))
+                        .min(segments);
+                    for (index, led) in bar.iter_mut().enumerate().take(lit) {
+                        *led = self.scale(Self::bar_colour(index, segments), u8::MAX);
+                    }
+                }
+            }
+        }
+
+        if frame != self.frame {
+            self.frame = frame;
+            self.pending = true;
+        }
+    }
+}
+
+#[cfg(feature = "ws2812_status")]
+impl Ws2812 {
+    /// Move the strip into a [`singleton`], to be attached to [`STATUS_LEDS`] with
+    /// [`StatusLedBase::add_indicator`].
+    ///
+    /// ```no_run
+    /// # use rp2040_hal::{clocks::init_clocks_and_plls, pac, pio::PIOExt, Clock, Sio, Watchdog};
+    /// # use rp2040_hal::gpio::Pins;
+    /// # use aps490_pfpu2_mini::components::{NeoPixelMode, StatusLedBase, Ws2812};
+    /// # use ws2812_pio::Ws2812Direct;
+    /// #
+    /// # let mut pac = pac::Peripherals::take().unwrap();
+    /// # let mut watchdog = Watchdog::new(pac.WATCHDOG);
+    /// # let sio = Sio::new(pac.SIO);
+    /// # let clocks = init_clocks_and_plls(12_000_000, pac.XOSC, pac.CLOCKS, pac.PLL_SYS, pac.PLL_USB, &mut pac.RESETS, &mut watchdog).ok().unwrap();
+    /// # let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+    /// #
+    /// let (mut pio0, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+    /// let writer = Ws2812Direct::new(
+    ///     pins.gpio16.into_function(),
+    ///     &mut pio0,
+    ///     sm0,
+    ///     clocks.peripheral_clock.freq(),
+    /// );
+    /// let strip = Ws2812::new(writer, NeoPixelMode::BarGraph { full_scale: 8 });
+    ///
+    /// let status = StatusLedBase::init().unwrap();
+    /// status.add_indicator(strip.init().unwrap()).unwrap();
+    /// ```
+    pub fn init(self) -> Option<&'static mut Ws2812> {
+        singleton!(: Ws2812 = self)
+    }
+}
+
+#[cfg(feature = "ws2812_status")]
+impl<W, const N: usize> LedControl for NeoPixel<W, N>
+where
+    W: SmartLedsWrite<Color = RGB8> + Send,
+{
+    fn set_led(
+        &mut self,
+        _old_state: &StatusLedStates,
+        new_state: StatusLedStates,
+    ) -> StatusLedStates {
+        (self.colour, self.pattern) = Self::colours(new_state, self.fault_code);
+        self.elapsed_ms = 0;
+        self.refresh();
 
         new_state
     }
+
+    fn show_fault(&mut self, code: FaultCode) {
+        self.fault_code = code;
+    }
+
+    fn tick(&mut self) {
+        self.elapsed_ms = self.elapsed_ms.wrapping_add(TICK_MS);
+        if self.elapsed_ms.is_multiple_of(Self::REFRESH_MS) {
+            self.refresh();
+        }
+    }
+
+    fn flush(&mut self) {
+        if core::mem::take(&mut self.pending)
+            && self.writer.write(self.frame.iter().copied()).is_err()
+        {
+            warn!("Unable to update NeoPixel strip");
+        }
+    }
+
+    fn show_delta(&mut self, delta: u8) {
+        self.delta = delta;
+    }
+}
+
+/// Shorthand for [`RGB8`] constants
+#[cfg(feature = "ws2812_status")]
+const fn rgb(r: u8, g: u8, b: u8) -> RGB8 {
+    RGB8 { r, g, b }
 }
@@ -0,0 +1,734 @@
+//! Serial console over USB (CDC-ACM), so the system can be inspected and adjusted without a debug
+//! probe.
+//!
+//! Commands are parsed by [`aps490_pfpu2_core::command`], and each one is answered with any
+//! requested data followed by a line starting with `ok` or `error:`. Run `help` to list the
+//! available commands.
+//!
+//! With the `telemetry` feature, the device also provides a second serial port which streams
+//! telemetry records (see `telemetry`).
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt::{self, Write};
+
+use aps490_pfpu2_core::{
+    clock::ClockPlan,
+    command::{Command, LineBuffer, Threshold},
+    impedance::{MultiFrequency, MAX_TONES},
+    pattern::FaultCode,
+    prbs::ExcitationMode,
+    spectrum::FrequencySweep,
+};
+use cortex_m::{peripheral::SCB, singleton};
+use critical_section::CriticalSection;
+use defmt::{info, warn};
+use heapless::Deque;
+use rp2040_hal::{
+    clocks::UsbClock,
+    pac::{RESETS, USBCTRL_DPRAM, USBCTRL_REGS},
+    usb::UsbBus,
+};
+use usb_device::{
+    bus::UsbBusAllocator,
+    device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid},
+};
+use usbd_serial::SerialPort;
+#[cfg(not(feature = "telemetry"))]
+use usbd_serial::USB_CLASS_CDC;
+
+#[cfg(feature = "telemetry")]
+use crate::telemetry::TelemetryPort;
+
+use crate::{
+    buffer::{SampleCounter, LONGTERM_SIZE},
+    components::{StatusLed, StatusLedBase, StatusLedStates},
+    interrupt::{
+        ADC_TRIGGER, ALERT_LATCH, BUFFERS, CONFIG, DMA_ISR_TIME, MULTI_FREQUENCY, STATUS_LEDS,
+        SWEEP, TICK_ISR_TIME, USB_ISR_TIME,
+    },
+};
+
+/// Longest command accepted, in bytes
+pub const LINE_LENGTH: usize = 64;
+/// Size of the queue of bytes waiting to be sent to the host
+pub const OUTPUT_SIZE: usize = 1024;
+/// Free space in the output queue required before another command is read. Every response except
+/// `dump` fits within this space.
+const RESPONSE_SPACE: usize = 256;
+/// Free space in the output queue required before another chunk of a `dump` is queued
+const DUMP_CHUNK_SPACE: usize = DUMP_CHUNK * 20;
+/// Number of samples copied from [`BUFFERS`] at a time during a `dump`
+const DUMP_CHUNK: usize = 16;
+
+/// Progress through a `dump` command
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+struct Dump {
+    /// Counter of the first sample sent
+    first: usize,
+    /// Counter of the next sample to send
+    next: usize,
+    /// Counter of the last sample to send
+    last: usize,
+}
+
+/// USB serial console, polled from `USBCTRL_IRQ`. Also owns the telemetry port, as both share
+/// the USB device.
+pub struct Console {
+    /// USB device state
+    usb_dev: UsbDevice<'static, UsbBus>,
+    /// CDC-ACM serial port
+    serial: SerialPort<'static, UsbBus>,
+    /// Serial port for the telemetry stream
+    #[cfg(feature = "telemetry")]
+    telemetry: TelemetryPort,
+    /// Partial command received from the host
+    line: LineBuffer<LINE_LENGTH>,
+    /// Bytes waiting to be sent to the host
+    out: Deque<u8, OUTPUT_SIZE>,
+    /// `dump` in progress
+    dump: Option<Dump>,
+    /// Reset once all output has been sent
+    reset_pending: bool,
+}
+
+impl Console {
+    /// USB vendor and product ID, from the [pid.codes](https://pid.codes) test range
+    pub const VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x27dd);
+
+    /// Bring up the USB peripheral and move the console into a [`singleton`]. Returns `None` if
+    /// called more than once.
+    pub fn init(
+        ctrl_reg: USBCTRL_REGS,
+        ctrl_dpram: USBCTRL_DPRAM,
+        usb_clock: UsbClock,
+        resets: &mut RESETS,
+    ) -> Option<&'static mut Console> {
+        let bus = singleton!(: UsbBusAllocator<UsbBus> = UsbBusAllocator::new(
+            UsbBus::new(ctrl_reg, ctrl_dpram, usb_clock, true, resets)
+        ))?;
+        let serial = SerialPort::new(bus);
+        #[cfg(feature = "telemetry")]
+        let telemetry = TelemetryPort::new(bus);
+        let builder = UsbDeviceBuilder::new(bus, Self::VID_PID)
+            .strings(&[StringDescriptors::default()
+                .manufacturer("PFPU2")
+                .product("PFPU2 contact detection")
+                .serial_number(env!("CARGO_PKG_VERSION"))])
+            .expect("Unable to set USB string descriptors");
+        #[cfg(not(feature = "telemetry"))]
+        let builder = builder.device_class(USB_CLASS_CDC);
+        // Two serial ports on one device need interface association descriptors
+        #[cfg(feature = "telemetry")]
+        let builder = builder.composite_with_iads();
+        let usb_dev = builder.build();
+
+        singleton!(: Console = Console {
+            usb_dev,
+            serial,
+            #[cfg(feature = "telemetry")]
+            telemetry,
+            line: LineBuffer::new(),
+            out: Deque::new(),
+            dump: None,
+            reset_pending: false,
+        })
+    }
+
+    /// Service the USB device, run any received commands, and send queued output
+    pub fn poll(&mut self) {
+        #[cfg(not(feature = "telemetry"))]
+        let received = self.usb_dev.poll(&mut [&mut self.serial]);
+        #[cfg(feature = "telemetry")]
+        let received = self
+            .usb_dev
+            .poll(&mut [&mut self.serial, self.telemetry.serial()]);
+        if received {
+            self.receive();
+        }
+        self.continue_dump();
+        self.flush();
+        #[cfg(feature = "playback")]
+        self.telemetry.receive();
+        #[cfg(feature = "telemetry")]
+        self.telemetry.send();
+
+        if self.reset_pending && self.out.is_empty() {
+            info!("Resetting system from console");
+            SCB::sys_reset();
+        }
+    }
+
+    /// Read commands until the output queue is too full to hold another response
+    fn receive(&mut self) {
+        let mut byte = [0u8];
+        while self.dump.is_none()
+            && !self.reset_pending
+            && OUTPUT_SIZE - self.out.len() >= RESPONSE_SPACE
+        {
+            match self.serial.read(&mut byte) {
+                Ok(1) => {}
+                _ => break,
+            }
+            let parsed = self.line.push(byte[0]).map(|line| {
+                line.map_err(|err| {
+                    warn!("Discarded console input: {}", err);
+                    ConsoleError::Line(err)
+                })
+                .and_then(|text| text.parse::<Command>().map_err(ConsoleError::Parse))
+            });
+            match parsed {
+                Some(Ok(command)) => self.execute(command),
+                Some(Err(err)) => self.respond(format_args!("error: {}\n", err)),
+                None => {}
+            }
+        }
+    }
+
+    /// Run a single command, and queue the response
+    fn execute(&mut self, command: Command) {
+        info!("Console command: {}", command);
+        // The host reference only models a single tone at full drive, calibrated once
+        #[cfg(feature = "playback")]
+        if matches!(
+            command,
+            Command::Calibrate
+                | Command::Frequency(Some(_))
+                | Command::Sweep(Some(_))
+                | Command::Multi(Some(_))
+                | Command::Excitation(Some(_))
+                | Command::Gain(Some(_))
+        ) {
+            self.respond(format_args!("error: not available during playback\n"));
+            return;
+        }
+        match command {
+            Command::Status => {
+                let (state, fault_code, sample, baseline, config) = critical_section::with(|cs| {
+                    let (state, fault_code) = STATUS_LEDS
+                        .borrow_ref(cs)
+                        .as_ref()
+                        .map_or((StatusLedStates::Booting, FaultCode::Unknown), |status| {
+                            (status.state, status.fault_code)
+                        });
+                    let (sample, baseline) = BUFFERS
+                        .borrow_ref(cs)
+                        .as_ref()
+                        .map_or((SampleCounter::default(), None), |buffers| {
+                            (buffers.current_sample(), buffers.baseline_level())
+                        });
+                    (state, fault_code, sample, baseline, CONFIG.borrow(cs).get())
+                });
+                self.respond(format_args!(
+                    "state={:?} sample={} policy={}\n",
+                    state,
+                    sample.get_counter(),
+                    config.alert_policy
+                ));
+                if state == StatusLedStates::Fault {
+                    self.respond(format_args!("fault={:?}\n", fault_code));
+                }
+                match baseline {
+                    Some(level) => self.respond(format_args!("baseline={}\n", level)),
+                    None => self.respond(format_args!("baseline=none\n")),
+                }
+                let (dma, tick, usb) = critical_section::with(|cs| {
+                    (
+                        DMA_ISR_TIME.borrow(cs).get(),
+                        TICK_ISR_TIME.borrow(cs).get(),
+                        USB_ISR_TIME.borrow(cs).get(),
+                    )
+                });
+                self.respond(format_args!(
+                    "isr_us dma={}/{} tick={}/{} usb={}/{}\n",
+                    dma.max_us,
+                    dma.budget_us,
+                    tick.max_us,
+                    tick.budget_us,
+                    usb.max_us,
+                    usb.budget_us
+                ));
+                self.respond(format_args!("ok\n"));
+            }
+            Command::Get(threshold) => {
+                let config = critical_section::with(|cs| CONFIG.borrow(cs).get());
+                for listed in Threshold::ALL {
+                    if threshold.is_none_or(|requested| requested == listed) {
+                        let value = listed.get(&config.thresholds);
+                        self.respond(format_args!("{}={}\n", listed, value));
+                    }
+                }
+                if threshold.is_none() {
+                    self.respond(format_args!(
+                        "{}={}\n",
+                        Command::ALERT_POLICY,
+                        config.alert_policy
+                    ));
+                }
+                self.respond(format_args!("ok\n"));
+            }
+            Command::Set(threshold, value) => {
+                critical_section::with(|cs| {
+                    let mut config = CONFIG.borrow(cs).get();
+                    threshold.set(&mut config.thresholds, value);
+                    CONFIG.borrow(cs).set(config);
+                });
+                info!("Threshold {} set to {=u8}", threshold, value);
+                self.respond(format_args!("ok {}={}\n", threshold, value));
+            }
+            Command::AlertPolicy(None) => {
+                let policy = critical_section::with(|cs| CONFIG.borrow(cs).get().alert_policy);
+                self.respond(format_args!("{}={}\nok\n", Command::ALERT_POLICY, policy));
+            }
+            Command::AlertPolicy(Some(policy)) => {
+                // Without the acknowledge button, a latched alert could never be cleared
+                if policy.latches() && !cfg!(feature = "ack_button") {
+                    self.respond(format_args!("error: {} needs feature ack_button\n", policy));
+                    return;
+                }
+                critical_section::with(|cs| {
+                    let mut config = CONFIG.borrow(cs).get();
+                    config.alert_policy = policy;
+                    CONFIG.borrow(cs).set(config);
+
+                    // A current alert may already meet the conditions of the new policy
+                    let alert = STATUS_LEDS
+                        .borrow_ref(cs)
+                        .as_ref()
+                        .is_some_and(|status| status.state == StatusLedStates::Contact);
+                    if alert && ALERT_LATCH.borrow(cs).get().should_clear(policy) {
+                        StatusLedBase::set_armed(cs, Some("Alert cleared by new alert policy"));
+                    }
+                });
+                info!("Alert policy set to {}", policy);
+                self.respond(format_args!("ok {}={}\n", Command::ALERT_POLICY, policy));
+            }
+            Command::Events => {
+                let mut events = [None; 10];
+                critical_section::with(|cs| {
+                    if let Some(buffers) = BUFFERS.borrow_ref(cs).as_ref() {
+                        for (slot, event) in events.iter_mut().zip(buffers.detection_events()) {
+                            *slot = Some(*event);
+                        }
+                    }
+                });
+                let mut count = 0;
+                for (sample, value) in events.iter().flatten() {
+                    self.respond(format_args!("{},{}\n", sample.get_counter(), value));
+                    count += 1;
+                }
+                self.respond(format_args!("ok {} events\n", count));
+            }
+            Command::Dump(count) => {
+                let current = critical_section::with(|cs| {
+                    BUFFERS
+                        .borrow_ref(cs)
+                        .as_ref()
+                        .map_or(0, |buffers| buffers.current_sample().get_counter())
+                });
+                let count = count.min(current).min(LONGTERM_SIZE);
+                if count == 0 {
+                    self.respond(format_args!("ok 0 samples\n"));
+                } else {
+                    self.dump = Some(Dump {
+                        first: current + 1 - count,
+                        next: current + 1 - count,
+                        last: current,
+                    });
+                }
+            }
+            Command::Calibrate => match critical_section::with(recalibrate) {
+                Ok(()) => self.respond(format_args!("ok calibrating\n")),
+                Err(state) => {
+                    self.respond(format_args!("error: cannot calibrate while {:?}\n", state))
+                }
+            },
+            Command::Frequency(None) => {
+                let (requested, plan) = critical_section::with(|cs| {
+                    (
+                        CONFIG.borrow(cs).get().excitation_hz,
+                        ADC_TRIGGER
+                            .borrow_ref(cs)
+                            .as_ref()
+                            .map(|trigger| trigger.plan()),
+                    )
+                });
+                self.respond(format_args!("excitation={}\n", requested));
+                if let Some(plan) = plan {
+                    self.respond(format_args!(
+                        "achieved={} mHz sampling={} mHz\n",
+                        plan.excitation_millihz(),
+                        plan.sample_millihz()
+                    ));
+                }
+                self.respond(format_args!("ok\n"));
+            }
+            Command::Frequency(Some(excitation_hz)) => {
+                let Some(plan) = self.check_excitation(excitation_hz) else {
+                    return;
+                };
+                critical_section::with(|cs| {
+                    let mut config = CONFIG.borrow(cs).get();
+                    config.excitation_hz = excitation_hz;
+                    CONFIG.borrow(cs).set(config);
+                    // The baseline was taken on the first tone, which may not be this frequency
+                    if MULTI_FREQUENCY.take(cs).is_some() {
+                        let _ = recalibrate(cs);
+                    }
+                });
+                info!("Excitation frequency set to {=u32} Hz", excitation_hz);
+                self.respond(format_args!(
+                    "ok excitation={} achieved={} mHz\n",
+                    excitation_hz,
+                    plan.excitation_millihz()
+                ));
+            }
+            Command::Sweep(None) => {
+                let Some(sweep) = critical_section::with(|cs| *SWEEP.borrow_ref(cs)) else {
+                    self.respond(format_args!("ok no sweep\n"));
+                    return;
+                };
+                for point in sweep.points() {
+                    self.respond(format_args!(
+                        "{},{},{}\n",
+                        point.excitation_hz,
+                        Hundredths(point.amplitude_x100()),
+                        Hundredths(point.level_x100())
+                    ));
+                }
+                match sweep.frequency() {
+                    Some(excitation_hz) => self.respond(format_args!(
+                        "ok {}/{} frequencies, measuring {}\n",
+                        sweep.points().len(),
+                        sweep.len(),
+                        excitation_hz
+                    )),
+                    None => self.respond(format_args!("ok {} frequencies\n", sweep.len())),
+                }
+            }
+            Command::Sweep(Some(list)) => {
+                if !self.check_square() {
+                    return;
+                }
+                for excitation_hz in list.as_slice() {
+                    if self.check_excitation(*excitation_hz).is_none() {
+                        return;
+                    }
+                }
+                critical_section::with(|cs| {
+                    SWEEP.replace(cs, Some(FrequencySweep::new(list)));
+                    MULTI_FREQUENCY.replace(cs, None);
+                });
+                info!("Sweeping {=usize} frequencies", list.as_slice().len());
+                self.respond(format_args!(
+                    "ok sweeping {} frequencies, calibrating until done\n",
+                    list.as_slice().len()
+                ));
+            }
+            Command::Multi(None) => {
+                let Some(multi) = critical_section::with(|cs| *MULTI_FREQUENCY.borrow_ref(cs))
+                else {
+                    self.respond(format_args!("ok single frequency\n"));
+                    return;
+                };
+                if let Some(features) = multi.latest() {
+                    for (idx, tone) in features.tones().iter().enumerate() {
+                        self.respond(format_args!(
+                            "{},{},{},{},{}\n",
+                            tone.excitation_hz,
+                            Hundredths(tone.amplitude_x100),
+                            Hundredths(tone.level_x100),
+                            OptionalHundredths(tone.phase_mdeg.map(|phase| phase / 10)),
+                            OptionalHundredths(features.ratio_x100(idx))
+                        ));
+                    }
+                }
+                self.respond(format_args!(
+                    "ok alternating {} frequencies\n",
+                    multi.frequencies().len()
+                ));
+            }
+            Command::Multi(Some(list)) => {
+                if !self.check_square() {
+                    return;
+                }
+                for excitation_hz in list.as_slice() {
+                    if self.check_excitation(*excitation_hz).is_none() {
+                        return;
+                    }
+                }
+                let Some(multi) = MultiFrequency::new(list) else {
+                    self.respond(format_args!(
+                        "error: between 2 and {} frequencies\n",
+                        MAX_TONES
+                    ));
+                    return;
+                };
+                // Detection now runs on whole cycles, so it needs a baseline for them
+                critical_section::with(|cs| {
+                    SWEEP.replace(cs, None);
+                    MULTI_FREQUENCY.replace(cs, Some(multi));
+                    let _ = recalibrate(cs);
+                });
+                info!("Alternating {=usize} frequencies", list.as_slice().len());
+                self.respond(format_args!(
+                    "ok alternating {} frequencies\n",
+                    list.as_slice().len()
+                ));
+            }
+            Command::Excitation(None) => {
+                let (requested, applied) = critical_section::with(|cs| {
+                    (
+                        CONFIG.borrow(cs).get().excitation_mode,
+                        ADC_TRIGGER
+                            .borrow_ref(cs)
+                            .as_ref()
+                            .map(|trigger| trigger.mode()),
+                    )
+                });
+                match applied {
+                    Some(applied) if applied != requested => self.respond(format_args!(
+                        "ok excitation={} pending={}\n",
+                        applied, requested
+                    )),
+                    _ => self.respond(format_args!("ok excitation={}\n", requested)),
+                }
+            }
+            Command::Excitation(Some(mode)) => {
+                critical_section::with(|cs| {
+                    let mut config = CONFIG.borrow(cs).get();
+                    config.excitation_mode = mode;
+                    CONFIG.borrow(cs).set(config);
+                    // Tone measurements rely on the square wave
+                    if mode == ExcitationMode::Prbs {
+                        SWEEP.replace(cs, None);
+                        MULTI_FREQUENCY.replace(cs, None);
+                    }
+                });
+                info!("Excitation waveform set to {}", mode);
+                self.respond(format_args!("ok excitation={}\n", mode));
+            }
+            Command::Gain(None) => {
+                let (setting, drive) = critical_section::with(|cs| {
+                    (
+                        CONFIG.borrow(cs).get().gain,
+                        ADC_TRIGGER
+                            .borrow_ref(cs)
+                            .as_ref()
+                            .map(|trigger| (trigger.gain_level(), trigger.drive())),
+                    )
+                });
+                self.respond(format_args!("gain={}\n", setting));
+                if let Some((level, drive)) = drive {
+                    self.respond(format_args!(
+                        "level={} duty={} gain_percent={}\n",
+                        level, drive.duty_percent, drive.gain_percent
+                    ));
+                }
+                self.respond(format_args!("ok\n"));
+            }
+            Command::Gain(Some(setting)) => {
+                critical_section::with(|cs| {
+                    let mut config = CONFIG.borrow(cs).get();
+                    config.gain = setting;
+                    CONFIG.borrow(cs).set(config);
+                });
+                info!("Excitation gain set to {}", setting);
+                self.respond(format_args!("ok gain={}\n", setting));
+            }
+            Command::Reset => {
+                self.respond(format_args!("ok resetting\n"));
+                self.reset_pending = true;
+            }
+            Command::Help => {
+                self.respond(format_args!("{}\nok\n", Command::HELP));
+            }
+        }
+    }
+
+    /// Plan the clocks for an excitation of `excitation_hz`. Responds with an error if it cannot
+    /// be generated.
+    fn check_excitation(&mut self, excitation_hz: u32) -> Option<ClockPlan> {
+        let plan = critical_section::with(|cs| {
+            ADC_TRIGGER
+                .borrow_ref(cs)
+                .as_ref()
+                .map(|trigger| trigger.plan())
+        });
+        match plan.map(|plan| plan.with_excitation(excitation_hz)) {
+            Some(Ok(plan)) => Some(plan),
+            Some(Err(err)) => {
+                self.respond(format_args!("error: {}\n", err));
+                None
+            }
+            None => {
+                self.respond(format_args!("error: sampling not started\n"));
+                None
+            }
+        }
+    }
+
+    /// Check that the excitation is a square wave, as needed to measure individual tones. Responds
+    /// with an error otherwise.
+    fn check_square(&mut self) -> bool {
+        let mode = critical_section::with(|cs| CONFIG.borrow(cs).get().excitation_mode);
+        if mode != ExcitationMode::Square {
+            self.respond(format_args!(
+                "error: not available with {} excitation\n",
+                mode
+            ));
+        }
+        mode == ExcitationMode::Square
+    }
+
+    /// Queue the next samples of a `dump`, oldest first
+    fn continue_dump(&mut self) {
+        while let Some(mut dump) = self.dump {
+            if OUTPUT_SIZE - self.out.len() < DUMP_CHUNK_SPACE {
+                return;
+            }
+
+            // Copy a chunk out of the buffers, as new samples keep arriving during the dump
+            let mut chunk = [None; DUMP_CHUNK];
+            let chunk_len = (dump.last + 1 - dump.next).min(DUMP_CHUNK);
+            critical_section::with(|cs| {
+                if let Some(buffers) = BUFFERS.borrow_ref(cs).as_ref() {
+                    let current = buffers.current_sample().get_counter();
+                    for (offset, slot) in chunk.iter_mut().enumerate().take(chunk_len) {
+                        let age = current - (dump.next + offset);
+                        *slot = buffers.recent_sample(age).zip(buffers.recent_gain(age));
+                    }
+                }
+            });
+
+            for sample in chunk.iter().take(chunk_len) {
+                match sample {
+                    Some((value, gain)) => {
+                        self.respond(format_args!("{},{},{}\n", dump.next, value, gain))
+                    }
+                    None => {
+                        self.respond(format_args!("error: sample {} overwritten\n", dump.next));
+                        self.dump = None;
+                        return;
+                    }
+                }
+                dump.next += 1;
+            }
+
+            if dump.next > dump.last {
+                self.respond(format_args!("ok {} samples\n", dump.next - dump.first));
+                self.dump = None;
+            } else {
+                self.dump = Some(dump);
+            }
+        }
+    }
+
+    /// Send as much queued output as the serial port will accept
+    fn flush(&mut self) {
+        while !self.out.is_empty() {
+            let (front, _) = self.out.as_slices();
+            match self.serial.write(front) {
+                Ok(written) if written > 0 => {
+                    for _ in 0..written {
+                        self.out.pop_front();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Queue a formatted response. Output which does not fit is dropped.
+    fn respond(&mut self, args: fmt::Arguments) {
+        if Output(&mut self.out).write_fmt(args).is_err() {
+            warn!("Console output queue full, response truncated");
+        }
+    }
+}
+
+/// Reasons a received line could not be run
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+enum ConsoleError {
+    /// See [`aps490_pfpu2_core::command::LineError`]
+    Line(aps490_pfpu2_core::command::LineError),
+    /// See [`aps490_pfpu2_core::command::ParseError`]
+    Parse(aps490_pfpu2_core::command::ParseError),
+}
+
+impl fmt::Display for ConsoleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleError::Line(err) => err.fmt(f),
+            ConsoleError::Parse(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Value in hundredths, displayed with two decimal places
+struct Hundredths(i32);
+
+impl fmt::Display for Hundredths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{}{}.{:02}", sign, abs / 100, abs % 100)
+    }
+}
+
+/// Formats a number of hundredths like [`Hundredths`], or `-` if unavailable
+struct OptionalHundredths(Option<i32>);
+
+impl fmt::Display for OptionalHundredths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(value) => Hundredths(value).fmt(f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+/// Discard the idle level and start calibrating, unless the system is alerting, paused or faulted.
+/// Returns the current state if calibration cannot start.
+fn recalibrate(cs: CriticalSection) -> Result<(), StatusLedStates> {
+    let state = STATUS_LEDS
+        .borrow_ref(cs)
+        .as_ref()
+        .map_or(StatusLedStates::Booting, |status| status.state);
+    // Calibrating from the console must not override the disable switch or a fault
+    if !matches!(
+        state,
+        StatusLedStates::Armed | StatusLedStates::Proximity | StatusLedStates::Calibrating
+    ) {
+        return Err(state);
+    }
+    if let Some(buffers) = BUFFERS.borrow_ref_mut(cs).as_mut() {
+        buffers.start_calibration();
+    }
+    let _ = StatusLedBase::set_state(cs, StatusLedStates::Calibrating);
+    Ok(())
+}
+
+/// Writes formatted text into the console output queue
+struct Output<'a>(&'a mut Deque<u8, OUTPUT_SIZE>);
+
+impl Write for Output<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.0.push_back(byte).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
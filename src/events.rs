@@ -0,0 +1,107 @@
+//! Event hooks, so library users can attach their own outputs (ex. retraction, telemetry, or a
+//! buzzer) without editing the interrupt handlers.
+//!
+//! Handlers are registered once with [`register_handler`], and are called from interrupt context
+//! within a [`CriticalSection`]:
+//!
+//! ```no_run
+//! use aps490_pfpu2_mini::events::{register_handler, Event, EventHandler};
+//! use critical_section::CriticalSection;
+//!
+//! struct Retraction;
+//!
+//! impl EventHandler for Retraction {
+//!     fn handle(&self, _cs: CriticalSection, event: Event) {
+//!         if let Event::Contact(_) = event {
+//!             // Drive the retraction solenoid
+//!         }
+//!     }
+//! }
+//!
+//! static RETRACTION: Retraction = Retraction;
+//! register_handler(&RETRACTION).unwrap();
+//! ```
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::RefCell;
+
+use aps490_pfpu2_core::pattern::FaultCode;
+use critical_section::{CriticalSection, Mutex};
+use defmt::{debug, Format};
+
+use crate::{buffer::SampleCounter, components::StatusLedStates};
+
+/// Maximum number of handlers which can be registered
+pub const MAX_HANDLERS: usize = 4;
+
+/// Registered handlers, called in order of registration
+static HANDLERS: Mutex<RefCell<[Option<&'static dyn EventHandler>; MAX_HANDLERS]>> =
+    Mutex::new(RefCell::new([None; MAX_HANDLERS]));
+
+/// Events reported to each [`EventHandler`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Event {
+    /// Contact detected on the given sample
+    Contact(SampleCounter),
+    /// A contact alert has been cleared, and detection is armed again
+    Clear,
+    /// A fault has been raised, and detection has stopped
+    Fault(FaultCode),
+    /// The system state has changed. Reported after [`Event::Contact`], [`Event::Clear`] or
+    /// [`Event::Fault`].
+    StateChange {
+        /// Previous state
+        from: StatusLedStates,
+        /// New state
+        to: StatusLedStates,
+    },
+}
+
+/// Receives [`Event`]s from the detection system.
+///
+/// Handlers run in interrupt context while the status LEDs are being updated, so they should
+/// return quickly and must not change the system state themselves.
+pub trait EventHandler: Sync {
+    /// Called for every [`Event`]
+    fn handle(&self, cs: CriticalSection, event: Event);
+}
+
+/// Returned by [`register_handler`] when [`MAX_HANDLERS`] are already registered
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct HandlersFull;
+
+/// Register a new [`EventHandler`]
+pub fn register_handler(handler: &'static dyn EventHandler) -> Result<(), HandlersFull> {
+    critical_section::with(|cs| {
+        debug!("critical_section: register event handler");
+        let mut handlers = HANDLERS.borrow_ref_mut(cs);
+        let slot = handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(HandlersFull)?;
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+/// Send `event` to every registered [`EventHandler`]
+pub fn dispatch(cs: CriticalSection, event: Event) {
+    // Copy the handlers out, so a handler may register another without a borrow conflict
+    let handlers = *HANDLERS.borrow_ref(cs);
+    for handler in handlers.iter().flatten() {
+        handler.handle(cs, event);
+    }
+}
@@ -14,54 +14,100 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::{cell::RefCell, cmp::Ordering};
+use core::cell::{Cell, RefCell};
 
+#[cfg(feature = "demo_mode")]
+use aps490_pfpu2_core::demo::{DemoSignal, DEMO_SCRIPT};
+#[cfg(not(feature = "playback"))]
+use aps490_pfpu2_core::prbs::ChannelResponse;
+use aps490_pfpu2_core::{
+    alert::AlertLatch,
+    config::Config,
+    debounce::{DebouncedInput, Edge},
+    gain::WindowRange,
+    impedance::MultiFrequency,
+    pattern::{FaultCode, TICK_MS},
+    signal::{AlignedAverages, WINDOW_SIZE},
+    spectrum::FrequencySweep,
+};
+use cortex_m::peripheral::{scb::SystemHandler, SCB, SYST};
 use cortex_m_rt::exception;
 use critical_section::Mutex;
 #[allow(unused_imports)]
 use defmt::trace;
-use defmt::{debug, Format};
-use embedded_hal::digital::InputPin;
+use defmt::{debug, info, warn};
 use rp2040_hal::{
     adc::DmaReadTarget,
-    dma::{single_buffer, single_buffer::Transfer, Channel, CH0},
-    gpio::{bank0::Gpio9, FunctionSio, Pin, PullDown, SioInput},
-    pac::interrupt,
+    dma::{single_buffer::Transfer, Channel, SingleChannel, CH0},
+    fugit::MicrosDurationU32,
+    gpio::{
+        bank0::{Gpio10, Gpio9},
+        FunctionSio, Interrupt as GpioInterrupt, Pin, PullDown, SioInput,
+    },
+    pac::{self, interrupt},
     pwm,
     pwm::{FreeRunning, Pwm3, Slice},
+    timer::{Alarm, Alarm0},
 };
 
-#[cfg(feature = "rgba_status")]
-use crate::components::Rgba;
-#[cfg(feature = "triple_status")]
-use crate::components::Triple;
+#[cfg(feature = "usb_console")]
+use crate::console::Console;
+#[cfg(feature = "playback")]
+use crate::playback::PlaybackSlot;
+#[cfg(feature = "demo_mode")]
+use crate::selftest::SelfTestPhase;
+#[cfg(feature = "telemetry")]
+use crate::telemetry::TelemetryQueue;
 use crate::{
     buffer::{Buffers, DetectionMsg},
     components::{StatusLed, StatusLedBase, StatusLedStates},
+    sampling::{
+        gain_percent, record_tone, start_readings, update_excitation, window_us, AdcTrigger,
+        ToneWindow,
+    },
+    selftest::SelfTestResult,
 };
+#[cfg(not(feature = "playback"))]
+use crate::{sampling::chips_running, selftest::SelfTest};
 
 /// Wrapper for [DMA `Transfer`](Transfer)
-pub type ReadingsDma = Transfer<Channel<CH0>, DmaReadTarget<u8>, &'static mut [u8; 4000]>;
+pub type ReadingsDma = Transfer<Channel<CH0>, DmaReadTarget<u8>, &'static mut [u8; WINDOW_SIZE]>;
 /// Wrapper for [`DISABLE_SWITCH`]
-pub type DisableSwitch = Pin<Gpio9, FunctionSio<SioInput>, PullDown>;
+pub type DisableSwitch = DebouncedInput<Pin<Gpio9, FunctionSio<SioInput>, PullDown>>;
+/// Wrapper for [`ACK_BUTTON`]
+pub type AckButton = DebouncedInput<Pin<Gpio10, FunctionSio<SioInput>, PullDown>>;
 /// Wrapper for [`SIGNAL_GEN`]
 pub type SignalPwm = pwm::Channel<Slice<Pwm3, FreeRunning>, pwm::A>;
 /// Wrapper for [`SIGNAL_CONF`]
-pub type SignalGenConfig = (Channel<CH0>, DmaReadTarget<u8>, &'static mut [u8; 4000]);
+pub type SignalGenConfig = (
+    Channel<CH0>,
+    DmaReadTarget<u8>,
+    &'static mut [u8; WINDOW_SIZE],
+);
 
-/// Status LEDs for access in interrupts. Implementation for feature `rgba_status`.
-#[cfg(feature = "rgba_status")]
-pub static STATUS_LEDS: Mutex<RefCell<Option<&'static mut StatusLedBase<Rgba>>>> =
-    Mutex::new(RefCell::new(None));
-/// Status LEDs for access in interrupts. There is a nearly identical implementation for feature
-/// `rgba_status`, which does not appear here.
-#[cfg(any(doc, feature = "triple_status"))]
-pub static STATUS_LEDS: Mutex<RefCell<Option<&'static mut StatusLedBase<Triple>>>> =
+/// Status LEDs for access in interrupts. Every indicator attached with
+/// [`StatusLedBase::add_indicator`] shows the same state.
+///
+/// The [`PATTERN_ALARM`] interrupt takes the indicators out while writing them, and
+/// [`StatusLed::set_state`] panics if they are missing. Every handler which changes the state must
+/// therefore keep the same NVIC priority as that interrupt, which is checked in debug builds.
+pub static STATUS_LEDS: Mutex<RefCell<Option<&'static mut StatusLedBase>>> =
     Mutex::new(RefCell::new(None));
 
 ///  access in interrupts
 pub static READINGS_FIFO: Mutex<RefCell<Option<ReadingsDma>>> = Mutex::new(RefCell::new(None));
 
+/// Starts each ADC conversion in phase with the excitation, one window at a time
+pub static ADC_TRIGGER: Mutex<RefCell<Option<AdcTrigger>>> = Mutex::new(RefCell::new(None));
+
+/// Frequency sweep in progress, or the results of the last one. Overrides
+/// [`Config::excitation_hz`] until complete.
+pub static SWEEP: Mutex<RefCell<Option<FrequencySweep>>> = Mutex::new(RefCell::new(None));
+
+/// Tones the excitation alternates between, one window each, and the features of the last complete
+/// cycle. Only used while there is no [`SWEEP`] in progress.
+pub static MULTI_FREQUENCY: Mutex<RefCell<Option<MultiFrequency>>> = Mutex::new(RefCell::new(None));
+
 /// access when disabling system/ in error state
 pub static SIGNAL_GEN: Mutex<RefCell<Option<SignalPwm>>> = Mutex::new(RefCell::new(None));
 
@@ -74,93 +120,168 @@ pub static BUFFERS: Mutex<RefCell<Option<&'static mut Buffers>>> = Mutex::new(Re
 /// Global disable switch
 pub static DISABLE_SWITCH: Mutex<RefCell<Option<DisableSwitch>>> = Mutex::new(RefCell::new(None));
 
-/// Calculates proper averages aligned with signal timing
-#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
-pub struct AlignedAverages {
-    /// The average voltage from the "higher" 2000 measurements
-    avg_high: i32,
-    /// The average voltage from the "lower" 2000 measurements
-    avg_low: i32,
+/// Operator acknowledge button, used to clear a latched alert
+pub static ACK_BUTTON: Mutex<RefCell<Option<AckButton>>> = Mutex::new(RefCell::new(None));
+
+/// SysTick, which samples [`DISABLE_SWITCH`] and [`ACK_BUTTON`] every 1 ms while debouncing
+pub static DEBOUNCE_TIMER: Mutex<RefCell<Option<SYST>>> = Mutex::new(RefCell::new(None));
+
+/// Number of consecutive 1 ms samples required to accept a new input position
+pub const DEBOUNCE_SAMPLES: u8 = 10;
+
+/// Timer alarm which advances the status LED patterns every [`TICK_MS`]
+pub static PATTERN_ALARM: Mutex<RefCell<Option<Alarm0>>> = Mutex::new(RefCell::new(None));
+
+/// Runtime configuration
+pub static CONFIG: Mutex<Cell<Config>> = Mutex::new(Cell::new(Config::new()));
+
+/// Conditions for clearing the current alert, according to [`Config::alert_policy`]
+pub static ALERT_LATCH: Mutex<Cell<AlertLatch>> = Mutex::new(Cell::new(AlertLatch::new()));
+
+/// Serial console, serviced by `USBCTRL_IRQ`
+#[cfg(feature = "usb_console")]
+pub static USB_CONSOLE: Mutex<RefCell<Option<&'static mut Console>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Averages from every analysis window, waiting to be streamed by [`USB_CONSOLE`]
+#[cfg(feature = "telemetry")]
+pub static TELEMETRY: Mutex<RefCell<TelemetryQueue>> =
+    Mutex::new(RefCell::new(TelemetryQueue::new()));
+
+/// Window received from the host, waiting to replace the next ADC readings
+#[cfg(feature = "playback")]
+pub static PLAYBACK: Mutex<RefCell<PlaybackSlot>> = Mutex::new(RefCell::new(PlaybackSlot::new()));
+
+/// Synthetic signal which replaces every window of ADC readings
+#[cfg(feature = "demo_mode")]
+pub static DEMO: Mutex<Cell<DemoSignal>> = Mutex::new(Cell::new(DemoSignal::new(&DEMO_SCRIPT)));
+
+/// Periodic check of the sensing chain, advanced by every window in `DMA_IRQ_0`
+#[cfg(not(feature = "playback"))]
+pub static SELF_TEST: Mutex<Cell<SelfTest>> = Mutex::new(Cell::new(SelfTest::new()));
+
+/// Longest time spent in an interrupt handler since startup, and the time it has before it is
+/// next due
+#[derive(Clone, Copy)]
+pub struct IsrTime {
+    /// Longest time taken, in microseconds
+    pub max_us: u32,
+    /// Time available when the longest time was taken, in microseconds
+    pub budget_us: u32,
 }
 
-impl AlignedAverages {
-    /// Takes the partial sums of the samples to calculate the high and low averages for contact
-    /// detection.
-    ///
-    /// Sorting requires an allocator, so this implementation identifies the highest partial sums.
-    /// Although this implementation technically allows for non-adjacent partial sums to be matched,
-    /// in effect this has little impact as those scenarios result in low overall deltas.
-    ///
-    /// This would be a good section to rewrite :)
-    pub fn align_signal_timing(partial_sums: &[i32; 4]) -> Self {
-        let mut avg_high_idx = [4usize; 2];
-        let mut avg_high = 0i32;
-
-        let match_sum = partial_sums
-            .iter()
-            .enumerate()
-            .max_by(|a, b| a.1.cmp(b.1))
-            .unwrap();
-        avg_high_idx[0] = match_sum.0;
-        avg_high += match_sum.1;
-
-        let match_sum = partial_sums
-            .iter()
-            .enumerate()
-            .max_by(|a, b| match a.1.cmp(b.1) {
-                Ordering::Less | Ordering::Equal => {
-                    if avg_high_idx.contains(&b.0) {
-                        Ordering::Greater
-                    } else {
-                        Ordering::Less
-                    }
-                }
-                Ordering::Greater => {
-                    if avg_high_idx.contains(&a.0) {
-                        Ordering::Less
-                    } else {
-                        Ordering::Greater
-                    }
-                }
-            })
-            .unwrap();
-        avg_high_idx[1] = match_sum.0;
-        avg_high += match_sum.1;
-        avg_high /= 2000;
-        #[cfg(feature = "trace_indiv_samples")]
-        Self::trace_high_index(&avg_high_idx);
-
-        let avg_low = partial_sums
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, sum)| {
-                if !avg_high_idx.contains(&idx) {
-                    Some(sum)
-                } else {
-                    None
-                }
-            })
-            .sum::<i32>()
-            / 2000;
+impl IsrTime {
+    /// No runs recorded yet
+    pub const fn new() -> Self {
+        Self {
+            max_us: 0,
+            budget_us: 0,
+        }
+    }
 
-        Self { avg_low, avg_high }
+    /// Records the time taken by one run, returning `true` if it is the longest so far
+    pub fn record(&mut self, elapsed_us: u32, budget_us: u32) -> bool {
+        if elapsed_us <= self.max_us {
+            return false;
+        }
+        self.max_us = elapsed_us;
+        self.budget_us = budget_us;
+        true
     }
+}
 
-    /// Records the two highest measurements from the first four of a 2 ms sample.
-    #[cfg(any(doc, feature = "trace_indiv_samples"))]
-    pub fn trace_high_index(avg_high_idx: &[usize; 2]) {
-        trace!("high indices (mod 4): {}", avg_high_idx);
+impl Default for IsrTime {
+    fn default() -> Self {
+        Self::new()
     }
+}
+
+/// Time spent processing each window in `DMA_IRQ_0`, against the time taken to record the next
+pub static DMA_ISR_TIME: Mutex<Cell<IsrTime>> = Mutex::new(Cell::new(IsrTime::new()));
+
+/// Time spent in `TIMER_IRQ_0`, against the pattern tick
+pub static TICK_ISR_TIME: Mutex<Cell<IsrTime>> = Mutex::new(Cell::new(IsrTime::new()));
+
+/// Time spent in `USBCTRL_IRQ`, against a 1 ms USB frame
+#[cfg(feature = "usb_console")]
+pub static USB_ISR_TIME: Mutex<Cell<IsrTime>> = Mutex::new(Cell::new(IsrTime::new()));
+
+/// USB frame period, which bounds how long the console may hold up other interrupts
+#[cfg(feature = "usb_console")]
+const USB_FRAME_US: u32 = 1_000;
 
-    /// Calculates the average range of the sample interval
-    fn get_delta(&self) -> u8 {
-        u8::try_from(self.avg_high - self.avg_low).map_or(255, |avg| avg)
+/// Lower 32 bits of the free-running microsecond timer
+///
+/// Reading `TIMERAWL` does not latch the upper half, so this can be read from any interrupt
+/// without disturbing [`Timer`](rp2040_hal::Timer) readings in progress.
+pub fn now_us() -> u32 {
+    // SAFETY: Atomic read of a register without side effects
+    unsafe { (*pac::TIMER::ptr()).timerawl().read().bits() }
+}
+
+/// Records the time since `start_us` against `budget_us` in `time`, warning when a new longest
+/// time exceeds the budget. Returns the time taken, in microseconds.
+fn record_isr_time(time: &Mutex<Cell<IsrTime>>, name: &str, start_us: u32, budget_us: u32) -> u32 {
+    let elapsed_us = now_us().wrapping_sub(start_us);
+    let longest = critical_section::with(|cs| {
+        let mut isr_time = time.borrow(cs).get();
+        let longest = isr_time.record(elapsed_us, budget_us);
+        time.borrow(cs).set(isr_time);
+        longest
+    });
+    if longest && elapsed_us > budget_us {
+        warn!(
+            "{=str} took {=u32} us, over its {=u32} us budget",
+            name, elapsed_us, budget_us
+        );
     }
+    elapsed_us
+}
+
+/// Records the two highest measurements from the first four of a window.
+#[cfg(any(doc, feature = "trace_indiv_samples"))]
+pub fn trace_high_index(avg_high_idx: &[usize; 2]) {
+    trace!("high indices (mod 4): {}", avg_high_idx);
 }
 
 /// ISR for reading ADC values and calculating averages
+///
+/// Also runs the periodic [`SelfTest`](crate::selftest::SelfTest) while the system is idle.
+///
+/// Each window must be processed before the next finishes recording, so the time taken is recorded
+/// in [`DMA_ISR_TIME`] against the window length at the current excitation.
 #[interrupt]
 fn DMA_IRQ_0() {
+    let start_us = now_us();
+    process_window();
+
+    let Some((plan, excitation_hz)) = critical_section::with(|cs| {
+        ADC_TRIGGER
+            .borrow_ref(cs)
+            .as_ref()
+            .map(|trigger| (trigger.plan(), trigger.excitation_hz()))
+    }) else {
+        return;
+    };
+    let elapsed_us = record_isr_time(
+        &DMA_ISR_TIME,
+        "DMA_IRQ_0",
+        start_us,
+        plan.window_us(WINDOW_SIZE),
+    );
+    #[cfg(feature = "trace_clock")]
+    trace!(
+        "excitation {=u64} mHz, drift {=i64} mdeg per window, processed in {=u32} us",
+        plan.excitation_millihz(),
+        plan.drift_millidegrees(excitation_hz, WINDOW_SIZE),
+        elapsed_us
+    );
+    #[cfg(not(feature = "trace_clock"))]
+    let _ = (excitation_hz, elapsed_us);
+}
+
+/// Analyses the window just recorded and starts the next transfer
+fn process_window() {
     let mut readings_isr: Option<ReadingsDma> = None;
     if readings_isr.is_none() {
         debug!("critical_section: DMA take readings");
@@ -168,93 +289,218 @@ fn DMA_IRQ_0() {
     }
 
     if let Some(adc_dma_transfer) = readings_isr {
-        let (dma_ch, dma_from, avg_buffer) = adc_dma_transfer.wait();
-
-        // Align averages with incoming signals
-        let mut partial_sums = [0i32; 4]; // 1000 samples each
-        for (idx, partial) in partial_sums.iter_mut().enumerate() {
-            *partial = avg_buffer
-                .iter()
-                .skip(idx)
-                .step_by(4)
-                .map(|i| *i as i32)
-                .sum::<i32>();
+        let (mut dma_ch, dma_from, avg_buffer) = adc_dma_transfer.wait();
+        dma_ch.check_irq0(); // Clear interrupt so other handlers can run until the next transfer
+
+        // Excitation is switched off while the self-test settles and measures
+        #[cfg(feature = "demo_mode")]
+        {
+            let (mut demo, phase) = critical_section::with(|cs| {
+                (DEMO.borrow(cs).get(), SELF_TEST.borrow(cs).get().phase())
+            });
+            demo.fill(
+                avg_buffer,
+                !matches!(phase, SelfTestPhase::Settling | SelfTestPhase::Measuring),
+            );
+            critical_section::with(|cs| DEMO.borrow(cs).set(demo));
         }
+
+        // Only played windows are analysed, so each one produces exactly one telemetry record
+        #[cfg(feature = "playback")]
+        if !critical_section::with(|cs| PLAYBACK.borrow_ref_mut(cs).take_into(avg_buffer)) {
+            critical_section::with(|cs| start_readings(cs, (dma_ch, dma_from, avg_buffer)));
+            return;
+        }
+
+        // Live readings start on the high half, but played windows may have any alignment
+        let partial_sums = AlignedAverages::partial_sums(avg_buffer);
+        // Synthetic demo windows always follow a square wave
+        #[cfg(not(feature = "playback"))]
+        let avgs = if !cfg!(feature = "demo_mode") && critical_section::with(chips_running) {
+            ChannelResponse::from_window(avg_buffer).averages()
+        } else {
+            AlignedAverages::locked_signal_timing(&partial_sums)
+        };
+        #[cfg(feature = "playback")]
         let avgs = AlignedAverages::align_signal_timing(&partial_sums);
+        let range = WindowRange::from_window(avg_buffer);
+        let (gain_percent, window_us) =
+            critical_section::with(|cs| (gain_percent(cs), window_us(cs)));
+        #[cfg(feature = "trace_indiv_samples")]
+        trace_high_index(&avgs.high_idx);
 
         #[cfg(feature = "trace_indiv_samples")]
         trace_indiv_samples(avg_buffer, &avgs);
 
+        // Self-test runs at startup, and periodically while armed
+        let state = critical_section::with(|cs| {
+            STATUS_LEDS
+                .borrow_ref(cs)
+                .as_ref()
+                .map_or(StatusLedStates::Booting, |status| status.state)
+        });
+        #[cfg(not(feature = "playback"))]
+        let self_test = {
+            let mut self_test = critical_section::with(|cs| SELF_TEST.borrow(cs).get());
+            let result = self_test.process(&avgs, state, window_us);
+            critical_section::with(|cs| SELF_TEST.borrow(cs).set(self_test));
+            result
+        };
+        // Played windows do not respond to the excitation
+        #[cfg(feature = "playback")]
+        let self_test = SelfTestResult::Inactive;
+
         // Determine if enough low sample events have occurred
         let sample_avg = avgs.get_delta();
-        let mut contact_detected = false;
-        let mut reset_detected = false;
+        let level = avgs.get_level();
+        let mut next_state = None;
+        if self_test == SelfTestResult::Inactive {
+            critical_section::with(|cs| {
+                debug!("critical_section: dma update and check longterm buffers");
+                let buffers = BUFFERS.take(cs).expect(Buffers::NO_BUFFER_PANIC_MSG);
+                let thresholds = CONFIG.borrow(cs).get().thresholds;
+
+                // Each tone of a multi-frequency cycle responds differently, so they are only
+                // checked together
+                next_state = match record_tone(cs, &partial_sums) {
+                    ToneWindow::Single => buffers.update(
+                        state,
+                        sample_avg,
+                        level,
+                        window_us,
+                        gain_percent,
+                        &thresholds,
+                    ),
+                    ToneWindow::Partial => None,
+                    ToneWindow::Complete(features) => {
+                        buffers.update_features(state, &features, gain_percent, &thresholds)
+                    }
+                };
+
+                BUFFERS.replace(cs, Some(buffers));
+                debug!("exit buffer critical section");
+            });
+        }
+
+        debug!("critical_section: start new DMA transfer");
+        critical_section::with(|cs| {
+            // Windows used by the self-test, or which change state, never change the excitation
+            if self_test == SelfTestResult::Inactive && next_state.is_none() {
+                update_excitation(cs, &avgs, &range, state);
+            }
+            start_readings(cs, (dma_ch, dma_from, avg_buffer));
+        });
+
+        // Change state once the next transfer is running, so detection can be paused if needed
         critical_section::with(|cs| {
-            debug!("critical_section: dma update and check longterm buffers");
-            let buffers = BUFFERS.take(cs).expect(Buffers::NO_BUFFER_PANIC_MSG);
-            buffers.insert(sample_avg);
-
-            debug!("critical_section: match status for correct buffer logic");
-            let status_leds = STATUS_LEDS.borrow_ref(cs);
-            if status_leds.is_some() {
-                match status_leds.as_ref().unwrap().state {
-                    StatusLedStates::Normal => {
-                        if buffers.detect_contact() {
-                            contact_detected = true
-                        }
+            debug!("critical_section: dma update state");
+            if let Some(status) = STATUS_LEDS.borrow_ref_mut(cs).as_mut() {
+                status.show_delta(sample_avg);
+            }
+            match self_test {
+                SelfTestResult::Started => {
+                    let _ = StatusLedBase::set_state(cs, StatusLedStates::SelfTest);
+                }
+                SelfTestResult::Passed => {
+                    let calibrated = BUFFERS
+                        .borrow_ref(cs)
+                        .as_ref()
+                        .is_some_and(|buffers| buffers.is_calibrated());
+                    if calibrated {
+                        StatusLedBase::set_armed(cs, Some("Self-test passed"));
+                    } else {
+                        let _ = StatusLedBase::set_state(cs, StatusLedStates::Calibrating);
                     }
-                    StatusLedStates::Alert => {
-                        if buffers.detect_end_contact() {
-                            reset_detected = true
-                        }
+                }
+                SelfTestResult::Failed { baseline, measured } => {
+                    warn!(
+                        "Average voltage {=u8} changed to {=u8} during self-test",
+                        baseline, measured
+                    );
+                    StatusLedBase::set_fault(
+                        cs,
+                        FaultCode::SelfTestFailed,
+                        Some("Self-test failed! Sensing chain did not respond to test signal"),
+                    );
+                }
+                SelfTestResult::Inactive | SelfTestResult::InProgress => {}
+            }
+
+            match (state, next_state) {
+                (_, Some(StatusLedStates::Contact)) => {
+                    let mut latch = ALERT_LATCH.borrow(cs).get();
+                    latch.raise();
+                    ALERT_LATCH.borrow(cs).set(latch);
+
+                    let buffers = BUFFERS.take(cs).unwrap();
+                    StatusLedBase::set_contact(cs, Some(DetectionMsg::create(buffers)));
+                    BUFFERS.replace(cs, Some(buffers));
+                }
+                (StatusLedStates::Contact, Some(StatusLedStates::Armed)) => {
+                    let policy = CONFIG.borrow(cs).get().alert_policy;
+                    let mut latch = ALERT_LATCH.borrow(cs).get();
+                    let clear = latch.signal_clear(policy);
+                    ALERT_LATCH.borrow(cs).set(latch);
+
+                    if clear {
+                        StatusLedBase::set_armed(cs, Some("Previous detection event cleared"));
                     }
-                    StatusLedStates::Error | StatusLedStates::Disabled => {}
                 }
+                (StatusLedStates::Calibrating, Some(StatusLedStates::Armed)) => {
+                    StatusLedBase::set_armed(cs, Some("Calibration complete"));
+                }
+                (_, Some(new_state)) => {
+                    let _ = StatusLedBase::set_state(cs, new_state);
+                }
+                (_, None) => {}
             }
 
-            BUFFERS.replace(cs, Some(buffers));
-            debug!("exit buffer critical section");
+            #[cfg(feature = "telemetry")]
+            {
+                let state = STATUS_LEDS
+                    .borrow_ref(cs)
+                    .as_ref()
+                    .map_or(StatusLedStates::Booting, |status| status.state);
+                let sample = BUFFERS
+                    .borrow_ref(cs)
+                    .as_ref()
+                    .map_or(0, |buffers| buffers.current_sample().get_counter());
+                TELEMETRY.borrow_ref_mut(cs).push(
+                    sample,
+                    avgs.avg_high,
+                    avgs.avg_low,
+                    sample_avg,
+                    gain_percent,
+                    state,
+                );
+            }
         });
-        if contact_detected {
-            critical_section::with(|cs| {
-                let buffers = BUFFERS.take(cs).unwrap();
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_alert(cs, Some(DetectionMsg::create(buffers)));
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_alert(cs, Some(DetectionMsg::create(buffers)));
-                BUFFERS.replace(cs, Some(buffers));
-            });
-        } else if reset_detected {
-            critical_section::with(|cs| {
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_normal(cs, None);
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_normal(cs, None);
-            })
-        }
 
-        let new_dma_transfer = single_buffer::Config::new(dma_ch, dma_from, avg_buffer);
-        debug!("critical_section: start new DMA transfer");
-        critical_section::with(|cs| READINGS_FIFO.replace(cs, Some(new_dma_transfer.start())));
+        // USB is otherwise only serviced on bus events, so wake it to send the new record
+        #[cfg(feature = "telemetry")]
+        rp2040_hal::pac::NVIC::pend(rp2040_hal::pac::Interrupt::USBCTRL_IRQ);
     } else {
-        // Report error if FIFO is not active
+        // Report error if FIFO is not active, unless detection has been paused
         critical_section::with(|cs| {
-            debug!("critical_section: dma set_error for no active FIFO");
-            #[cfg(feature = "rgba_status")]
-            StatusLedBase::<Rgba>::set_error(
-                cs,
-                Some("No ADC transfer in progress! Unable to collect latest readings"),
-            );
-            #[cfg(feature = "triple_status")]
-            StatusLedBase::<Triple>::set_error(
+            let paused = STATUS_LEDS
+                .borrow_ref(cs)
+                .as_ref()
+                .is_some_and(|status| status.state.pauses_detection());
+            if paused {
+                return;
+            }
+
+            debug!("critical_section: dma set_fault for no active FIFO");
+            StatusLedBase::set_fault(
                 cs,
+                FaultCode::NoTransfer,
                 Some("No ADC transfer in progress! Unable to collect latest readings"),
             );
         });
     }
 }
 
-/// Records the following information about a 2 ms sample (note all measurements are 8 bits on a
+/// Records the following information about a window (note all measurements are 8 bits on a
 /// <span style="white-space:nowrap;">3.3 V</span> signal):
 /// - Maximum voltage recorded
 /// - Minimum voltage recorded
@@ -270,7 +516,7 @@ fn DMA_IRQ_0() {
 /// -> all_unique samples: [Some(0), Some(1), Some(2), Some(3), None, None, None, None, None, None, None, None, None, None, None, None, Some(16), Some(17), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(95), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(140), Some(141), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(231), Some(232), Some(233), Some(234), Some(235), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(253), Some(254), Some(255)]
 /// ```
 #[cfg(any(doc, feature = "trace_indiv_samples"))]
-pub fn trace_indiv_samples(avg_buffer: &[u8; 4000], avgs: &AlignedAverages) {
+pub fn trace_indiv_samples(avg_buffer: &[u8; WINDOW_SIZE], avgs: &AlignedAverages) {
     let unique_samples = avg_buffer.iter().fold([None; 256], |mut acc, s| {
         acc[*s as usize] = Some(s);
         acc
@@ -286,40 +532,188 @@ pub fn trace_indiv_samples(avg_buffer: &[u8; 4000], avgs: &AlignedAverages) {
             );
 }
 
-/// ISR for SysTick, used for checking [`DisableSwitch`]
+/// ISR for [`PATTERN_ALARM`], which advances the blink and brightness patterns on every status
+/// indicator
 ///
-/// Lazily takes ownership of [`DISABLE_SWITCH`] as it will not be used again in the main runtime
-/// again.
-#[exception]
-fn SysTick() {
-    static mut DISABLE_SWITCH_ISR: Option<DisableSwitch> = None;
+/// Patterns are advanced in a critical section, but the indicators are taken out of
+/// [`STATUS_LEDS`] to be written, so slow outputs such as a WS2812 strip do not hold up other
+/// interrupts. Every interrupt runs at the same priority, so no other handler can find
+/// [`STATUS_LEDS`] empty in the meantime. Raising the priority of any handler in
+/// [`STATUS_HANDLERS`] above this one breaks that, and fails [`debug_assert_equal_priority`].
+#[interrupt]
+fn TIMER_IRQ_0() {
+    let start_us = now_us();
+    tick_patterns();
+    record_isr_time(&TICK_ISR_TIME, "TIMER_IRQ_0", start_us, TICK_MS * 1000);
+}
 
-    if DISABLE_SWITCH_ISR.is_none() {
-        critical_section::with(|cs| {
-            *DISABLE_SWITCH_ISR = DISABLE_SWITCH.take(cs);
-        })
+/// Interrupts which may change the state of [`STATUS_LEDS`], alongside [`SysTick`]
+const STATUS_HANDLERS: [pac::Interrupt; 3] = [
+    pac::Interrupt::DMA_IRQ_0,
+    pac::Interrupt::IO_IRQ_BANK0,
+    pac::Interrupt::USBCTRL_IRQ,
+];
+
+/// Checks that no handler which changes the state can preempt `TIMER_IRQ_0` while it holds the
+/// indicators taken out of [`STATUS_LEDS`]
+fn debug_assert_equal_priority() {
+    if !cfg!(debug_assertions) {
+        return;
     }
+    let tick = pac::NVIC::get_priority(pac::Interrupt::TIMER_IRQ_0);
+    for irq in STATUS_HANDLERS {
+        debug_assert_eq!(
+            pac::NVIC::get_priority(irq),
+            tick,
+            "{:?} preempts pattern ticks",
+            irq
+        );
+    }
+    debug_assert_eq!(
+        SCB::get_priority(SystemHandler::SysTick),
+        tick,
+        "SysTick preempts pattern ticks"
+    );
+}
 
-    if let Some(switch) = DISABLE_SWITCH_ISR {
-        if switch
-            .is_high()
-            .expect("Unable to check disable switch state")
-        {
-            critical_section::with(|cs| {
-                debug!("critical_section: system disabled by switch");
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_disabled(cs, Some("System disabled by switch."));
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_disabled(cs, Some("System disabled by switch."));
-            });
-        } else if switch.is_low().unwrap() {
-            critical_section::with(|cs| {
-                debug!("critical_section: system disabled by switch");
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_normal(cs, Some("System disabled by switch."));
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_normal(cs, Some("System disabled by switch."));
+/// Advances the status LED patterns and writes the indicators
+fn tick_patterns() {
+    debug_assert_equal_priority();
+    let status = critical_section::with(|cs| {
+        if let Some(alarm) = PATTERN_ALARM.borrow_ref_mut(cs).as_mut() {
+            alarm.clear_interrupt();
+            alarm
+                .schedule(MicrosDurationU32::millis(TICK_MS))
+                .expect("Unable to schedule next pattern tick");
+        }
+        let status = STATUS_LEDS.take(cs)?;
+        status.tick();
+        Some(status)
+    });
+    let Some(status) = status else {
+        return;
+    };
+    status.flush();
+    critical_section::with(|cs| STATUS_LEDS.replace(cs, Some(status)));
+}
+
+/// ISR for the USB controller, which services [`USB_CONSOLE`]
+///
+/// The console is taken out of its [`Mutex`] while polling, so commands only hold a critical
+/// section while reading or changing shared state.
+#[cfg(feature = "usb_console")]
+#[interrupt]
+fn USBCTRL_IRQ() {
+    let start_us = now_us();
+    let Some(console) = critical_section::with(|cs| USB_CONSOLE.take(cs)) else {
+        return;
+    };
+    console.poll();
+    critical_section::with(|cs| USB_CONSOLE.replace(cs, Some(console)));
+    record_isr_time(&USB_ISR_TIME, "USBCTRL_IRQ", start_us, USB_FRAME_US);
+}
+
+/// ISR for GPIO edges on [`DisableSwitch`] and [`AckButton`]
+///
+/// Starts [`DEBOUNCE_TIMER`], which samples the inputs until their positions have settled.
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    critical_section::with(|cs| {
+        debug!("critical_section: input edge");
+        if let Some(switch) = DISABLE_SWITCH.borrow_ref_mut(cs).as_mut() {
+            let pin = switch.pin_mut();
+            pin.clear_interrupt(GpioInterrupt::EdgeHigh);
+            pin.clear_interrupt(GpioInterrupt::EdgeLow);
+        }
+        if let Some(button) = ACK_BUTTON.borrow_ref_mut(cs).as_mut() {
+            let pin = button.pin_mut();
+            pin.clear_interrupt(GpioInterrupt::EdgeHigh);
+            pin.clear_interrupt(GpioInterrupt::EdgeLow);
+        }
+        if let Some(syst) = DEBOUNCE_TIMER.borrow_ref_mut(cs).as_mut() {
+            syst.clear_current();
+            syst.enable_counter();
+            syst.enable_interrupt();
+        }
+    });
+}
+
+/// ISR for SysTick, used for debouncing [`DisableSwitch`] and [`AckButton`]
+///
+/// SysTick only runs after [`IO_IRQ_BANK0`] reports an edge, and stops itself once both inputs have
+/// settled. The system state is only changed when the debounced position of an input changes.
+#[exception]
+fn SysTick() {
+    let (switch_edge, button_edge) = critical_section::with(|cs| {
+        let mut settled = true;
+        let switch_edge = DISABLE_SWITCH
+            .borrow_ref_mut(cs)
+            .as_mut()
+            .and_then(|switch| {
+                let edge = switch.poll().expect("Unable to check disable switch state");
+                settled &= switch.is_settled();
+                edge
             });
+        let button_edge = ACK_BUTTON.borrow_ref_mut(cs).as_mut().and_then(|button| {
+            let edge = button
+                .poll()
+                .expect("Unable to check acknowledge button state");
+            settled &= button.is_settled();
+            edge
+        });
+
+        if settled {
+            if let Some(syst) = DEBOUNCE_TIMER.borrow_ref_mut(cs).as_mut() {
+                syst.disable_interrupt();
+                syst.disable_counter();
+            }
         }
+        (switch_edge, button_edge)
+    });
+
+    match switch_edge {
+        Some(Edge::Rising) => critical_section::with(|cs| {
+            debug!("critical_section: system disabled by switch");
+            StatusLedBase::set_disabled(cs, Some("System disabled by switch."));
+        }),
+        Some(Edge::Falling) => critical_section::with(|cs| {
+            debug!("critical_section: system re-enabled by switch");
+            let disabled = STATUS_LEDS
+                .borrow_ref(cs)
+                .as_ref()
+                .is_some_and(|status| status.state == StatusLedStates::Disabled);
+            // Faults still require a power cycle
+            if disabled {
+                info!("System re-enabled by switch.");
+                if let Some(buffers) = BUFFERS.borrow_ref_mut(cs).as_mut() {
+                    buffers.start_calibration();
+                }
+                let _ = StatusLedBase::set_state(cs, StatusLedStates::Calibrating);
+            }
+        }),
+        None => {}
+    }
+
+    if button_edge == Some(Edge::Rising) {
+        critical_section::with(|cs| {
+            debug!("critical_section: alert acknowledged");
+            let alert = STATUS_LEDS
+                .borrow_ref(cs)
+                .as_ref()
+                .is_some_and(|status| status.state == StatusLedStates::Contact);
+            if !alert {
+                return;
+            }
+
+            let policy = CONFIG.borrow(cs).get().alert_policy;
+            let mut latch = ALERT_LATCH.borrow(cs).get();
+            let clear = latch.acknowledge(policy);
+            ALERT_LATCH.borrow(cs).set(latch);
+            if clear {
+                StatusLedBase::set_armed(cs, Some("Alert acknowledged by operator."));
+            } else {
+                info!("Alert acknowledged, waiting for contact to end");
+            }
+        });
     }
 }
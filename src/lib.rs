@@ -6,18 +6,78 @@
 //!
 //! - `triple_status`: Enables the use of 3 LEDs to provide system status. This is the main user
 //!   interface for the tool, and is enabled by default.
-//! - `rgba_status`: Alternate configuration which uses a single common-anode RGB LED. This is the design which appears in
+//! - `rgba_status`: Alternate configuration which uses a single common-anode RGB LED. This is the
+//!   design which appears in
 //!   [our schematic](https://github.com/jessicarod7/aps490_retraction_fsm/blob/hardware/aps490_detection/aps490_detection-schematic.pdf).
+//!   Takes the place of `triple_status` on GPIO 6-8 whenever it is enabled.
+//! - `panel_strap`: Reads a strap on GPIO 5 at startup, so one image runs on either board. When
+//!   the strap is tied high the RGB LED is used, and when it is left open the front panel is
+//!   chosen by the features above. Ignored with `rgba_status`.
+//! - `ws2812_status`: Drives a strip of addressable WS2812 (NeoPixel) LEDs on GPIO 16 through PIO0.
+//!   The first LED shows the system status, and the rest show the live voltage difference as a bar
+//!   graph (see `components::NeoPixelMode`).
+//! - `buzzer`: Drives a buzzer or piezo from PWM slice 7 on GPIO 14, with a mute switch on GPIO 15.
+//!   Tone patterns are played for proximity, contact and faults (see `buzzer::Buzzer`).
+//! - `usb_console`: Serial console over the USB port, for reading the system status, changing
+//!   detection thresholds or the excitation frequency, sweeping the excitation through a list of
+//!   frequencies (see [`aps490_pfpu2_core::spectrum`]), alternating between frequencies to tell
+//!   tissues apart (see [`aps490_pfpu2_core::impedance`]), selecting a pseudo-random excitation
+//!   (see [`aps490_pfpu2_core::prbs`]), holding the drive level of the excitation (see
+//!   [`aps490_pfpu2_core::gain`]) and dumping recent samples without a debug probe (see
+//!   [`aps490_pfpu2_core::command`]).
+//! - `telemetry`: Streams the averages, drive gain and state from every analysis window as framed
+//!   binary on a second USB serial port (see `telemetry`). Enables `usb_console`.
 //! - `trace_avg_samples`: Logs the average voltage difference measured, 250 samples at a time. See
 //!   [`buffer::Buffers::trace_avg_samples`].
 //! - `trace_indiv_samples` Logs information on every sample recorded. Very noisy! See
-//!   [`interrupt::AlignedAverages::trace_high_index`] and [`interrupt::trace_indiv_samples`]
-//! - `disable_switch`: Starts the SysTick timer to check the disable switch status. Never tested
-//!   this feature, and I'm pretty sure my implementation will cause the system to panic due to poor
-//!   synchronization. This functionality should be redesigned before enabling the feature.
+//!   [`interrupt::trace_high_index`] and [`interrupt::trace_indiv_samples`]
+//! - `trace_clock` Logs the achieved excitation, its drift from the requested frequency and the
+//!   time taken to process every window. See [`interrupt::DMA_ISR_TIME`]
+//! - `disable_switch`: Enables GPIO edge interrupts for the disable switch. Each edge starts SysTick
+//!   to debounce the switch (see [`aps490_pfpu2_core::debounce`]), and the system is only disabled
+//!   or re-enabled when the debounced position changes.
+//! - `ack_button`: Enables the operator acknowledge button on GPIO 10, debounced alongside the
+//!   disable switch. Required for latching alerts (see [`aps490_pfpu2_core::alert::AlertPolicy`]),
+//!   which are selected with [`interrupt::CONFIG`].
+//! - `demo_mode`: Replaces every window of ADC readings with a synthetic signal which loops through
+//!   idle, proximity, contact and withdrawal (see [`aps490_pfpu2_core::demo`]). The rest of
+//!   the system runs unchanged, so the indicators and outputs can be shown without a saw or tissue.
+//! - `playback`: Analyses windows of ADC readings sent by the host on the telemetry port in place
+//!   of live readings, reporting each decision in the telemetry stream (see `playback`). Used to
+//!   replay recordings through the on-target code. Enables `telemetry`, and cannot be combined
+//!   with `demo_mode`. The console refuses `calibrate` and changes to the excitation during
+//!   playback, which the host reference does not model.
 //!
-//! <div class="warning">Features <code>triple_status</code> and <code>rgba_status</code> are
-//! mutually exclusive.</div>
+//! Any number of status indicators (up to [`components::MAX_INDICATORS`]) can be attached to
+//! [`interrupt::STATUS_LEDS`], such as a remote indicator alongside the front-panel LEDs. Both
+//! front panels ([`components::Triple`] and [`components::Rgba`]) are always built, and the one
+//! on GPIO 6-8 is picked at startup from the features above.
+//! States are told apart with blink and brightness patterns (see [`aps490_pfpu2_core::pattern`]),
+//! which are advanced by [`interrupt::PATTERN_ALARM`]. Faults are shown as a number of pulses.
+//!
+//! ## Sampling
+//!
+//! ADC conversions are started by a second PWM slice which is locked to the excitation (see
+//! [`sampling`]), so every window starts on the high half of the signal and is aligned without
+//! searching.
+//!
+//! In multi-frequency mode, the excitation moves to the next of two or three tones after every
+//! window, and contacts are decided on each complete cycle of tones (see
+//! [`interrupt::MULTI_FREQUENCY`]).
+//!
+//! The excitation can also follow a pseudo-random sequence of whole cycles, written to the PWM by
+//! DMA, with each window correlated against the sequence to reject narrowband interference.
+//!
+//! The drive level of the excitation can be adjusted automatically with `gain auto`, so the
+//! response stays within the ADC range without clipping (see [`aps490_pfpu2_core::gain`]). It is
+//! held at full drive by default. Every sample is recorded with the gain it was taken at, and
+//! thresholds are scaled to match.
+//!
+//! ## Event hooks
+//!
+//! Contact, clear, fault and state change events are reported to any handlers registered with
+//! [`events::register_handler`], so additional outputs can be attached without changing the
+//! interrupt handlers.
 //!
 //! ## Demo
 //!
@@ -28,13 +88,21 @@
 //! #![no_std]
 //! #![no_main]
 //!
+//! use aps490_pfpu2_core::{
+//!     alert::AlertPolicy, clock::ClockPlan, config::Config, debounce::DebouncedInput, led::LedPin,
+//!     pattern::TICK_MS,
+//! };
 //! use aps490_pfpu2_mini::{
 //!     buffer::{create_avg_buffer, Buffers},
-//!     components::{LedControl, Rgba, StatusLed, StatusLedBase},
-//!     interrupt::{DISABLE_SWITCH, READINGS_FIFO, SIGNAL_GEN, STATUS_LEDS},
+//!     components::{Rgba, StatusLed, StatusLedBase, StatusLedStates},
+//!     interrupt::{
+//!         ADC_TRIGGER, CONFIG, DEBOUNCE_SAMPLES, DEBOUNCE_TIMER, DISABLE_SWITCH, PATTERN_ALARM,
+//!         SIGNAL_GEN, STATUS_LEDS,
+//!     },
+//!     sampling::{start_readings, AdcTrigger, SLICE_MASK},
 //! };
 //! use cortex_m::peripheral::syst::SystClkSource;
-//! use defmt::{debug, warn};
+//! use defmt::{debug, info, warn};
 //! #[allow(unused_imports)]
 //! use defmt_rtt as _;
 //! use embedded_hal::pwm::SetDutyCycle;
@@ -42,22 +110,28 @@
 //! use panic_probe as _;
 //! use rp2040_hal::{
 //!     adc::{Adc, AdcPin},
-//!     clocks::init_clocks_and_plls,
-//!     dma::{single_buffer, DMAExt, SingleChannel},
+//!     clocks::ClocksManager,
+//!     dma::{DMAExt, SingleChannel},
 //!     entry,
-//!     gpio::Pins,
+//!     fugit::MicrosDurationU32,
+//!     gpio::{Interrupt as GpioInterrupt, Pins},
 //!     pac,
+//!     pll::{common_configs::PLL_USB_48MHZ, setup_pll_blocking, PLLConfig},
 //!     prelude::*,
 //!     pwm::Slices,
-//!     Sio, Watchdog,
+//!     xosc::setup_xosc_blocking,
+//!     timer::Alarm,
+//!     Sio, Timer, Watchdog,
 //! };
 //!
 //! #[link_section = ".boot2"]
 //! #[used]
 //! pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
 //! pub const XOSC_FREQ_HZ: u32 = 12_000_000;
-//! const SYS_CLOCK_FREQ: u32 = 24_000_000;
-//! pub static SIGNAL_GEN_FREQ_HZ: f32 = 100_000.0;
+//! const SYS_CLOCK_FREQ: u32 = 125_000_000;
+//! pub const SIGNAL_GEN_FREQ_HZ: u32 = 100_000;
+//! /// Policy for clearing contact alerts at startup, changed with `set alert_policy`
+//! const ALERT_POLICY: AlertPolicy = AlertPolicy::AutoClear;
 //!
 //! #[entry]
 //! fn main() -> ! {
@@ -66,18 +140,38 @@
 //!     let mut watchdog = Watchdog::new(pac.WATCHDOG);
 //!     let sio = Sio::new(pac.SIO);
 //!
-//!     let mut clocks = init_clocks_and_plls(
-//!         XOSC_FREQ_HZ,
-//!         pac.XOSC,
-//!         pac.CLOCKS,
+//!     // 100 kHz excitation, sampled twice per cycle by the 48 MHz ADC clock
+//!     let plan =
+//!         ClockPlan::new(XOSC_FREQ_HZ, SYS_CLOCK_FREQ, 48_000_000, SIGNAL_GEN_FREQ_HZ, 2).unwrap();
+//!     let xosc = setup_xosc_blocking(pac.XOSC, XOSC_FREQ_HZ.Hz())
+//!         .ok()
+//!         .unwrap();
+//!     watchdog.enable_tick_generation((XOSC_FREQ_HZ / 1_000_000) as u8);
+//!     let mut clocks = ClocksManager::new(pac.CLOCKS);
+//!     let pll_sys = setup_pll_blocking(
 //!         pac.PLL_SYS,
+//!         xosc.operating_frequency(),
+//!         PLLConfig {
+//!             vco_freq: plan.pll_sys.vco_hz(XOSC_FREQ_HZ).Hz(),
+//!             refdiv: 1,
+//!             post_div1: plan.pll_sys.post_div1,
+//!             post_div2: plan.pll_sys.post_div2,
+//!         },
+//!         &mut clocks,
+//!         &mut pac.RESETS,
+//!     )
+//!     .ok()
+//!     .unwrap();
+//!     let pll_usb = setup_pll_blocking(
 //!         pac.PLL_USB,
+//!         xosc.operating_frequency(),
+//!         PLL_USB_48MHZ,
+//!         &mut clocks,
 //!         &mut pac.RESETS,
-//!         &mut watchdog,
 //!     )
 //!     .ok()
 //!     .unwrap();
-//!     let mut sysclk_rescale = clocks.system_clock.freq().to_Hz() as f32 / SYS_CLOCK_FREQ as f32;
+//!     clocks.init_default(&xosc, &pll_sys, &pll_usb).ok().unwrap();
 //!
 //!     // Setup status LEDs
 //!     let pins = Pins::new(
@@ -86,17 +180,25 @@
 //!         sio.gpio_bank0,
 //!         &mut pac.RESETS,
 //!     );
-//!     critical_section::with(|cs| {
-//!         STATUS_LEDS.replace(cs, Rgba::init(pins.gpio6, pins.gpio7, pins.gpio8));
-//!     });
+//!     let leds = Rgba::new(
+//!         LedPin::active_low(pins.gpio6.into_push_pull_output().into_dyn_pin()),
+//!         LedPin::active_low(pins.gpio7.into_push_pull_output().into_dyn_pin()),
+//!         LedPin::active_low(pins.gpio8.into_push_pull_output().into_dyn_pin()),
+//!     );
+//!     let status = StatusLedBase::init().unwrap();
+//!     status.add_indicator(leds.init().unwrap()).unwrap();
+//!     critical_section::with(|cs| STATUS_LEDS.replace(cs, Some(status)));
 //!
 //!     // Initialize and start signal generator
 //!     let mut pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
-//!     pwm_slices.pwm3.set_top(
-//!         ((clocks.system_clock.freq().to_Hz() as f32 / (SIGNAL_GEN_FREQ_HZ * sysclk_rescale))
-//!             - 1.0) as u16,
-//!     );
-//!     pwm_slices.pwm3.enable();
+//!     pwm_slices.pwm3.set_div_int(plan.pwm.div_int);
+//!     pwm_slices.pwm3.set_div_frac(plan.pwm.div_frac);
+//!     pwm_slices.pwm3.set_top(plan.pwm.top);
+//!     pwm_slices.pwm4.set_div_int(plan.pacing.pwm.div_int);
+//!     pwm_slices.pwm4.set_div_frac(plan.pacing.pwm.div_frac);
+//!     pwm_slices.pwm4.set_top(plan.pacing.pwm.top);
+//!     pwm_slices.pwm4.set_counter(plan.pacing.counter);
+//!     pwm_slices.enable_simultaneous(SLICE_MASK);
 //!     let mut signal_gen = pwm_slices.pwm3.channel_a;
 //!     signal_gen.output_to(pins.gpio22);
 //!     signal_gen.set_duty_cycle_percent(50).unwrap();
@@ -110,46 +212,71 @@
 //!
 //!     // Setup first transfer
 //!     let avg_buffer = create_avg_buffer().unwrap();
-//!     let mut readings_fifo = adc
+//!     let readings_fifo = adc
 //!         .build_fifo()
 //!         .set_channel(&mut adc_pin0)
-//!         .clock_divider(
-//!             ((clocks.system_clock.freq().to_Hz() as f32
-//!                 / (2.0 * (SIGNAL_GEN_FREQ_HZ * sysclk_rescale)))
-//!                 - 1.0) as u16,
-//!             0,
-//!         )
 //!         .shift_8bit()
 //!         .enable_dma()
 //!         .start_paused();
+//!     let adc_trigger = AdcTrigger::init(dma.ch1, dma.ch2, plan, SIGNAL_GEN_FREQ_HZ).unwrap();
 //!     dma.ch0.enable_irq0();
-//!     let adc_dma_transfer =
-//!         single_buffer::Config::new(dma.ch0, readings_fifo.dma_read_target(), avg_buffer);
-//!     debug!("critical_section: transfer readings FIFO to mutex");
-//!     critical_section::with(|cs| READINGS_FIFO.replace(cs, Some(adc_dma_transfer.start())));
-//!     readings_fifo.resume();
+//!     debug!("critical_section: transfer readings FIFO and ADC trigger to mutex");
+//!     critical_section::with(|cs| {
+//!         ADC_TRIGGER.replace(cs, Some(adc_trigger));
+//!         start_readings(cs, (dma.ch0, readings_fifo.dma_read_target(), avg_buffer));
+//!     });
 //!
-//!     // Configure and enable SysTick for disable switch
+//!     // Configure disable switch, which is debounced by SysTick after each edge
 //!     let disable_switch = pins.gpio9.into_pull_down_input();
-//!     disable_switch.set_schmitt_enabled(true); // Debouncing
+//!     disable_switch.set_schmitt_enabled(true);
+//!     disable_switch.set_interrupt_enabled(GpioInterrupt::EdgeHigh, true);
+//!     disable_switch.set_interrupt_enabled(GpioInterrupt::EdgeLow, true);
+//!     let disable_switch = DebouncedInput::new(disable_switch, DEBOUNCE_SAMPLES).unwrap();
+//!     #[allow(unused_variables)]
+//!     let start_disabled = disable_switch.is_high();
 //!     debug!("critical_section: init disable switch");
 //!     critical_section::with(|cs| DISABLE_SWITCH.replace(cs, Some(disable_switch)));
+//!     critical_section::with(|cs| {
+//!         CONFIG.borrow(cs).set(Config {
+//!             alert_policy: ALERT_POLICY,
+//!             excitation_hz: SIGNAL_GEN_FREQ_HZ,
+//!             ..Config::new()
+//!         })
+//!     });
 //!
 //!     let mut syst = core.SYST;
-//!     syst.set_clock_source(SystClkSource::Core); // 1 us per tick
-//!     syst.set_reload(20_000);
+//!     syst.set_clock_source(SystClkSource::Core);
+//!     syst.set_reload(clocks.system_clock.freq().to_kHz() - 1); // 1 ms per tick
 //!     syst.clear_current();
-//!     #[cfg(feature = "disable_switch")]
-//!     syst.enable_interrupt();
+//!     critical_section::with(|cs| DEBOUNCE_TIMER.replace(cs, Some(syst)));
 //!
-//!     // Begin normal system operation
+//!     // Drive status LED patterns from a timer alarm
+//!     let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+//!     let mut pattern_alarm = timer.alarm_0().unwrap();
+//!     pattern_alarm
+//!         .schedule(MicrosDurationU32::millis(TICK_MS))
+//!         .unwrap();
+//!     pattern_alarm.enable_interrupt();
+//!     critical_section::with(|cs| PATTERN_ALARM.replace(cs, Some(pattern_alarm)));
+//!
+//!     // Begin operation with a self-test, followed by calibration
+//!     info!("System initialization complete");
 //!     critical_section::with(|cs| {
-//!         #[cfg(feature = "rgba_status")]
-//!         StatusLedBase::<Rgba>::set_normal(cs, Some("System initialization complete"));
-//!         #[cfg(feature = "triple_status")]
-//!         StatusLedBase::<Triple>::set_normal(cs, Some("System initialization complete"));
+//!         let _ = StatusLedBase::set_state(cs, StatusLedStates::SelfTest);
 //!     });
-//!     unsafe { pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0) }
+//!     #[cfg(feature = "disable_switch")]
+//!     {
+//!         if start_disabled {
+//!             critical_section::with(|cs| {
+//!                 StatusLedBase::set_disabled(cs, Some("Disable switch was on at startup."));
+//!             });
+//!         }
+//!         unsafe { pac::NVIC::unmask(pac::Interrupt::IO_IRQ_BANK0) }
+//!     }
+//!     unsafe {
+//!         pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+//!         pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0);
+//!     }
 //!     loop {
 //!         // All functionality in interrupts
 //!         cortex_m::asm::wfi();
@@ -176,9 +303,20 @@
 #![warn(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_cfg), feature(doc_auto_cfg), feature(doc_cfg_hide))]
 
+#[cfg(all(feature = "demo_mode", feature = "playback"))]
+compile_error!("Features `demo_mode` and `playback` both replace the ADC readings");
+
 pub mod buffer;
+#[cfg(feature = "buzzer")]
+pub mod buzzer;
 pub mod components;
+#[cfg(feature = "usb_console")]
+pub mod console;
+pub mod events;
 pub mod interrupt;
-
-#[cfg(all(feature = "triple_status", feature = "rgba_status"))]
-compile_error!("Features `triple_status` and `rgba_status` cannot be enabled at the same time in crate aps490_pfpu2_mini");
+#[cfg(feature = "playback")]
+pub mod playback;
+pub mod sampling;
+pub mod selftest;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
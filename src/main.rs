@@ -19,34 +19,60 @@
 #![warn(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_cfg), feature(doc_auto_cfg), feature(doc_cfg_hide))]
 
-#[cfg(feature = "rgba_status")]
-use aps490_pfpu2_mini::components::Rgba;
-#[cfg(feature = "triple_status")]
-use aps490_pfpu2_mini::components::Triple;
+#[cfg(any(feature = "disable_switch", feature = "ack_button"))]
+use aps490_pfpu2_core::debounce::DebouncedInput;
+use aps490_pfpu2_core::{
+    alert::AlertPolicy, clock::ClockPlan, config::Config, led::LedPin, pattern::TICK_MS,
+    signal::WINDOW_SIZE,
+};
+#[cfg(feature = "buzzer")]
+use aps490_pfpu2_mini::buzzer::Buzzer;
+#[cfg(feature = "ws2812_status")]
+use aps490_pfpu2_mini::components::{NeoPixelMode, Ws2812};
+#[cfg(feature = "ack_button")]
+use aps490_pfpu2_mini::interrupt::ACK_BUTTON;
+#[cfg(any(feature = "disable_switch", feature = "ack_button"))]
+use aps490_pfpu2_mini::interrupt::DEBOUNCE_SAMPLES;
+#[cfg(feature = "disable_switch")]
+use aps490_pfpu2_mini::interrupt::DISABLE_SWITCH;
 use aps490_pfpu2_mini::{
     buffer::{create_avg_buffer, Buffers},
-    components::{LedControl, StatusLed, StatusLedBase},
-    interrupt::{DISABLE_SWITCH, READINGS_FIFO, SIGNAL_GEN, STATUS_LEDS},
+    components::{LedControl, Rgba, StatusLed, StatusLedBase, StatusLedStates, Triple},
+    interrupt::{ADC_TRIGGER, CONFIG, DEBOUNCE_TIMER, PATTERN_ALARM, SIGNAL_GEN, STATUS_LEDS},
+    sampling::{start_readings, AdcTrigger, SLICE_MASK},
 };
+#[cfg(feature = "usb_console")]
+use aps490_pfpu2_mini::{console::Console, interrupt::USB_CONSOLE};
 use cortex_m::peripheral::syst::SystClkSource;
 use defmt::{debug, info, warn};
 #[allow(unused_imports)]
 use defmt_rtt as _;
+#[cfg(all(feature = "panel_strap", not(feature = "rgba_status")))]
+use embedded_hal::digital::InputPin;
 use embedded_hal::pwm::SetDutyCycle;
 #[allow(unused_imports)]
 use panic_probe as _;
+#[cfg(any(feature = "disable_switch", feature = "ack_button"))]
+use rp2040_hal::gpio::Interrupt as GpioInterrupt;
+#[cfg(feature = "ws2812_status")]
+use rp2040_hal::pio::PIOExt;
 use rp2040_hal::{
     adc::{Adc, AdcPin},
-    clocks::init_clocks_and_plls,
-    dma::{single_buffer, DMAExt, SingleChannel},
+    clocks::ClocksManager,
+    dma::{DMAExt, SingleChannel},
     entry,
-    fugit::RateExtU32,
+    fugit::{MicrosDurationU32, RateExtU32},
     gpio::Pins,
     pac,
+    pll::{common_configs::PLL_USB_48MHZ, setup_pll_blocking, PLLConfig},
     prelude::*,
     pwm::Slices,
-    Sio, Watchdog,
+    timer::Alarm,
+    xosc::setup_xosc_blocking,
+    Sio, Timer, Watchdog,
 };
+#[cfg(feature = "ws2812_status")]
+use ws2812_pio::Ws2812Direct;
 
 /// Second-stage bootloader, from [rp2040-boot2](https://docs.rs/rp2040-boot2)
 #[link_section = ".boot2"]
@@ -54,48 +80,97 @@ use rp2040_hal::{
 pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
 /// External high-speed crystal on the pico board is 12Mhz
 pub const XOSC_FREQ_HZ: u32 = 12_000_000;
-/// Attempt to run system clock at 24 MHz
-const SYS_CLOCK_FREQ: u32 = 24_000_000;
-/// Frequency of detection signal is 100 kHz
-pub static SIGNAL_GEN_FREQ_HZ: f32 = 100_000.0;
+/// Run system clock at 125 MHz as before exact clock planning. Each window must be processed in
+/// `DMA_IRQ_0` before the next fills, and slower clocks leave little headroom alongside USB and the
+/// pattern tick; interrupt times are checked against their budgets (see `DMA_ISR_TIME`).
+const SYS_CLOCK_FREQ: u32 = 125_000_000;
+/// ADC clock, generated by `PLL_USB` alongside the USB clock
+const ADC_CLOCK_FREQ: u32 = 48_000_000;
+/// Frequency of detection signal is 100 kHz at startup, and can be changed from the console
+pub const SIGNAL_GEN_FREQ_HZ: u32 = 100_000;
+/// ADC samples per cycle of the detection signal, one in each half
+const SAMPLES_PER_CYCLE: u32 = 2;
+/// Tone of the buzzer, near the resonant frequency of a typical piezo
+#[cfg(feature = "buzzer")]
+const BUZZER_FREQ_HZ: u32 = 4_000;
+/// Voltage difference which fills the WS2812 bar graph
+#[cfg(feature = "ws2812_status")]
+const WS2812_FULL_SCALE: u8 = 8;
+/// Policy for clearing contact alerts at startup, which can be changed with `set alert_policy` on
+/// the console. Latching policies require feature `ack_button`.
+const ALERT_POLICY: AlertPolicy = AlertPolicy::AutoClear;
 
 /// Main operation loop
 #[entry]
 fn main() -> ! {
-    #[cfg(all(feature = "triple_status", feature = "rgba_status"))]
-    compile_error!("Features `triple_status` and `rgba_status` cannot be enabled at the same time in crate aps490_pfpu2_mini");
-
     info!("Detection system startup");
     let mut pac = pac::Peripherals::take().unwrap();
     let core = pac::CorePeripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
     let sio = Sio::new(pac.SIO);
 
-    // Rescale other calculations based on system clock
-    let mut sysclk_rescale = 1f32;
-    let mut clocks = init_clocks_and_plls(
+    // Plan exact PLL and PWM dividers before touching the clocks
+    let plan = ClockPlan::new(
         XOSC_FREQ_HZ,
-        pac.XOSC,
-        pac.CLOCKS,
+        SYS_CLOCK_FREQ,
+        ADC_CLOCK_FREQ,
+        SIGNAL_GEN_FREQ_HZ,
+        SAMPLES_PER_CYCLE,
+    )
+    .expect("Unable to plan clocks");
+    let xosc = setup_xosc_blocking(pac.XOSC, XOSC_FREQ_HZ.Hz())
+        .ok()
+        .unwrap();
+    watchdog.enable_tick_generation((XOSC_FREQ_HZ / 1_000_000) as u8);
+    let mut clocks = ClocksManager::new(pac.CLOCKS);
+    let pll_sys = setup_pll_blocking(
         pac.PLL_SYS,
+        xosc.operating_frequency(),
+        PLLConfig {
+            vco_freq: plan.pll_sys.vco_hz(XOSC_FREQ_HZ).Hz(),
+            refdiv: 1,
+            post_div1: plan.pll_sys.post_div1,
+            post_div2: plan.pll_sys.post_div2,
+        },
+        &mut clocks,
+        &mut pac.RESETS,
+    )
+    .ok()
+    .unwrap();
+    let pll_usb = setup_pll_blocking(
         pac.PLL_USB,
+        xosc.operating_frequency(),
+        PLL_USB_48MHZ,
+        &mut clocks,
         &mut pac.RESETS,
-        &mut watchdog,
     )
     .ok()
     .unwrap();
-    // Attempt to switch system to 24 MHz for efficiency
-    clocks
-        .system_clock
-        .configure_clock(&clocks.reference_clock, SYS_CLOCK_FREQ.Hz())
-        .unwrap_or_else(|err| {
-            warn!(
-                "Unable to downscale clock speed: {}\nClocks will continue to run at {=u32}",
-                err,
-                clocks.system_clock.freq().to_Hz()
-            );
-            sysclk_rescale = clocks.system_clock.freq().to_Hz() as f32 / SYS_CLOCK_FREQ as f32;
-        });
+    // System clock runs from PLL_SYS undivided, and the ADC and USB from PLL_USB
+    clocks.init_default(&xosc, &pll_sys, &pll_usb).ok().unwrap();
+    info!(
+        "System clock {=u32} Hz (VCO {=u32} Hz / {=u8} / {=u8}), ADC clock {=u32} Hz",
+        clocks.system_clock.freq().to_Hz(),
+        plan.pll_sys.vco_hz(XOSC_FREQ_HZ),
+        plan.pll_sys.post_div1,
+        plan.pll_sys.post_div2,
+        clocks.adc_clock.freq().to_Hz()
+    );
+    info!(
+        "Excitation {=u64} mHz (divider {=u8} + {=u8}/16, top {=u16}), sampling {=u64} mHz phase-locked (top {=u16})",
+        plan.excitation_millihz(),
+        plan.pwm.div_int,
+        plan.pwm.div_frac,
+        plan.pwm.top,
+        plan.sample_millihz(),
+        plan.pacing.pwm.top
+    );
+    info!(
+        "Drift {=i64} mdeg per window from {=u32} Hz, window recorded in {=u32} us",
+        plan.drift_millidegrees(SIGNAL_GEN_FREQ_HZ, WINDOW_SIZE),
+        SIGNAL_GEN_FREQ_HZ,
+        plan.window_us(WINDOW_SIZE)
+    );
     let pins = Pins::new(
         pac.IO_BANK0,
         pac.PADS_BANK0,
@@ -103,32 +178,103 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    // Setup status LEDs
+    // Setup status LEDs. `rgba_status` forces the common-anode RGB LED, which would be inverted
+    // if driven as three active-high LEDs, so the strap can only select it and never clear it.
+    let status = StatusLedBase::init().unwrap();
+    #[cfg(feature = "rgba_status")]
+    let rgba_panel = true;
+    #[cfg(all(feature = "panel_strap", not(feature = "rgba_status")))]
+    let rgba_panel = pins.gpio5.into_pull_down_input().is_high().unwrap();
+    #[cfg(not(any(feature = "rgba_status", feature = "panel_strap")))]
+    let rgba_panel = false;
+    let front_panel: Option<&'static mut dyn LedControl> = if rgba_panel {
+        // Common anode, so each pin sinks current to turn on
+        info!("Front panel: common-anode RGB LED");
+        let leds = Rgba::new(
+            LedPin::active_low(pins.gpio6.into_push_pull_output().into_dyn_pin()),
+            LedPin::active_low(pins.gpio7.into_push_pull_output().into_dyn_pin()),
+            LedPin::active_low(pins.gpio8.into_push_pull_output().into_dyn_pin()),
+        );
+        Some(leds.init().unwrap())
+    } else if cfg!(feature = "triple_status") {
+        info!("Front panel: three separate LEDs");
+        let leds = Triple::new(
+            LedPin::active_high(pins.gpio6.into_push_pull_output().into_dyn_pin()),
+            LedPin::active_high(pins.gpio7.into_push_pull_output().into_dyn_pin()),
+            LedPin::active_high(pins.gpio8.into_push_pull_output().into_dyn_pin()),
+        );
+        Some(leds.init().unwrap())
+    } else {
+        None
+    };
+    if let Some(front_panel) = front_panel {
+        status.add_indicator(front_panel).unwrap();
+    }
+    #[cfg(feature = "ws2812_status")]
+    {
+        let (mut pio0, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+        let writer = Ws2812Direct::new(
+            pins.gpio16.into_function(),
+            &mut pio0,
+            sm0,
+            clocks.peripheral_clock.freq(),
+        );
+        let strip = Ws2812::new(
+            writer,
+            NeoPixelMode::BarGraph {
+                full_scale: WS2812_FULL_SCALE,
+            },
+        );
+        status.add_indicator(strip.init().unwrap()).unwrap();
+    }
+    if status.indicator_count() == 0 {
+        warn!("No status indicators enabled");
+    }
     debug!("critical_section: init status LEDs");
-    critical_section::with(|cs| {
-        #[cfg(feature = "rgba_status")]
-        STATUS_LEDS.replace(cs, Rgba::init(pins.gpio6, pins.gpio7, pins.gpio8));
-        #[cfg(feature = "triple_status")]
-        STATUS_LEDS.replace(cs, Triple::init(pins.gpio6, pins.gpio7, pins.gpio8));
-    });
+    critical_section::with(|cs| STATUS_LEDS.replace(cs, Some(status)));
 
     // Initialize and start signal generator
     let mut pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
-    pwm_slices
-        .pwm3
-        // Ex. 24 MHz clock generates 100 kHz signal ->  240 clk cycles per PWM cycle (`top`)
-        // with 50% duty cycle
-        .set_top(
-            ((clocks.system_clock.freq().to_Hz() as f32 / (SIGNAL_GEN_FREQ_HZ * sysclk_rescale))
-                - 1.0) as u16,
-        );
-    pwm_slices.pwm3.enable();
+    // Ex. 125 MHz clock generates 100 kHz signal -> 1250 clk cycles per PWM cycle (`top` of 1249)
+    // with 50% duty cycle
+    pwm_slices.pwm3.set_div_int(plan.pwm.div_int);
+    pwm_slices.pwm3.set_div_frac(plan.pwm.div_frac);
+    pwm_slices.pwm3.set_top(plan.pwm.top);
+    // Pacing slice wraps every 625 clk cycles, midway through each half of the signal, to start
+    // each ADC conversion. Both slices are enabled together to keep them in phase.
+    pwm_slices.pwm4.set_div_int(plan.pacing.pwm.div_int);
+    pwm_slices.pwm4.set_div_frac(plan.pacing.pwm.div_frac);
+    pwm_slices.pwm4.set_top(plan.pacing.pwm.top);
+    pwm_slices.pwm4.set_counter(plan.pacing.counter);
+    pwm_slices.enable_simultaneous(SLICE_MASK);
     let mut signal_gen = pwm_slices.pwm3.channel_a;
     signal_gen.output_to(pins.gpio22);
     signal_gen.set_duty_cycle_percent(50).unwrap();
     debug!("critical_section: transfer PWM control to mutex");
     critical_section::with(|cs| SIGNAL_GEN.replace(cs, Some(signal_gen)));
 
+    // Buzzer follows the status LEDs, using a spare PWM slice for the tone
+    #[cfg(feature = "buzzer")]
+    {
+        pwm_slices
+            .pwm7
+            .set_top((clocks.system_clock.freq().to_Hz() / BUZZER_FREQ_HZ - 1) as u16);
+        pwm_slices.pwm7.enable();
+        let mut buzzer_pwm = pwm_slices.pwm7.channel_a;
+        buzzer_pwm.output_to(pins.gpio14);
+        let mute = pins.gpio15.into_pull_down_input();
+        let buzzer = Buzzer::new(buzzer_pwm, Some(mute)).unwrap().init().unwrap();
+        debug!("critical_section: attach buzzer");
+        critical_section::with(|cs| {
+            STATUS_LEDS
+                .borrow_ref_mut(cs)
+                .as_mut()
+                .expect("Status LEDs must be configured before the buzzer")
+                .add_indicator(buzzer)
+                .unwrap()
+        });
+    }
+
     // Setup ADC pins, DMA, buffers
     let mut adc = Adc::new(pac.ADC, &mut pac.RESETS);
     let mut adc_pin0 = AdcPin::new(pins.gpio26.into_floating_input()).unwrap();
@@ -137,47 +283,124 @@ fn main() -> ! {
 
     // Setup first transfer
     let avg_buffer = create_avg_buffer().unwrap();
-    let mut readings_fifo = adc
+    // Conversions are only started by the pacing slice, through DMA channel 1. Channel 2 writes
+    // pseudo-random chips to the excitation when selected.
+    let readings_fifo = adc
         .build_fifo()
         .set_channel(&mut adc_pin0)
-        // Ex. 24 MHz clock at 200 ksamples/s (2x SIGNAL_FREQ_KHZ) -> sample every 120 clk cycles
-        .clock_divider(
-            ((clocks.system_clock.freq().to_Hz() as f32
-                / (2.0 * (SIGNAL_GEN_FREQ_HZ * sysclk_rescale)))
-                - 1.0) as u16,
-            0,
-        )
         .shift_8bit()
         .enable_dma()
         .start_paused();
+    let adc_trigger = AdcTrigger::init(dma.ch1, dma.ch2, plan, SIGNAL_GEN_FREQ_HZ).unwrap();
     dma.ch0.enable_irq0();
-    let adc_dma_transfer =
-        single_buffer::Config::new(dma.ch0, readings_fifo.dma_read_target(), avg_buffer);
-    debug!("critical_section: transfer readings FIFO to mutex");
-    critical_section::with(|cs| READINGS_FIFO.replace(cs, Some(adc_dma_transfer.start())));
-    readings_fifo.resume();
-
-    // Configure and enable SysTick for disable switch
-    let disable_switch = pins.gpio9.into_pull_down_input();
-    disable_switch.set_schmitt_enabled(true); // Debouncing
-    debug!("critical_section: init disable switch");
-    critical_section::with(|cs| DISABLE_SWITCH.replace(cs, Some(disable_switch)));
+    debug!("critical_section: transfer readings FIFO and ADC trigger to mutex");
+    critical_section::with(|cs| {
+        ADC_TRIGGER.replace(cs, Some(adc_trigger));
+        start_readings(cs, (dma.ch0, readings_fifo.dma_read_target(), avg_buffer));
+    });
+
+    // Configure disable switch, which is debounced by SysTick after each edge
+    #[cfg(feature = "disable_switch")]
+    let start_disabled = {
+        let disable_switch = pins.gpio9.into_pull_down_input();
+        disable_switch.set_schmitt_enabled(true);
+        disable_switch.set_interrupt_enabled(GpioInterrupt::EdgeHigh, true);
+        disable_switch.set_interrupt_enabled(GpioInterrupt::EdgeLow, true);
+        let disable_switch = DebouncedInput::new(disable_switch, DEBOUNCE_SAMPLES).unwrap();
+        let start_disabled = disable_switch.is_high();
+        debug!("critical_section: init disable switch");
+        critical_section::with(|cs| DISABLE_SWITCH.replace(cs, Some(disable_switch)));
+        start_disabled
+    };
+
+    // Configure acknowledge button for latched alerts, debounced alongside the disable switch
+    #[cfg(feature = "ack_button")]
+    {
+        let ack_button = pins.gpio10.into_pull_down_input();
+        ack_button.set_schmitt_enabled(true);
+        ack_button.set_interrupt_enabled(GpioInterrupt::EdgeHigh, true);
+        ack_button.set_interrupt_enabled(GpioInterrupt::EdgeLow, true);
+        let ack_button = DebouncedInput::new(ack_button, DEBOUNCE_SAMPLES).unwrap();
+        debug!("critical_section: init acknowledge button");
+        critical_section::with(|cs| ACK_BUTTON.replace(cs, Some(ack_button)));
+    }
+    #[cfg(not(feature = "ack_button"))]
+    if ALERT_POLICY != AlertPolicy::AutoClear {
+        warn!("Alerts will not clear without feature `ack_button`");
+    }
+    #[cfg(feature = "demo_mode")]
+    warn!("Demo mode: ADC readings are replaced by a scripted signal");
+    debug!("critical_section: init config");
+    critical_section::with(|cs| {
+        CONFIG.borrow(cs).set(Config {
+            alert_policy: ALERT_POLICY,
+            excitation_hz: SIGNAL_GEN_FREQ_HZ,
+            ..Config::new()
+        })
+    });
 
     let mut syst = core.SYST;
-    syst.set_clock_source(SystClkSource::Core); // 1 us per tick
-    syst.set_reload(20_000);
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(clocks.system_clock.freq().to_kHz() - 1); // 1 ms per tick
     syst.clear_current();
-    #[cfg(feature = "disable_switch")]
-    syst.enable_interrupt();
+    critical_section::with(|cs| DEBOUNCE_TIMER.replace(cs, Some(syst)));
+
+    // Drive status LED patterns from a timer alarm
+    let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+    let mut pattern_alarm = timer.alarm_0().unwrap();
+    pattern_alarm
+        .schedule(MicrosDurationU32::millis(TICK_MS))
+        .unwrap();
+    pattern_alarm.enable_interrupt();
+    debug!("critical_section: init pattern timer");
+    critical_section::with(|cs| PATTERN_ALARM.replace(cs, Some(pattern_alarm)));
 
-    // Begin normal system operation
+    // Serial console takes the USB clock, so must be set up after everything else using `clocks`
+    #[cfg(feature = "usb_console")]
+    {
+        let console = Console::init(
+            pac.USBCTRL_REGS,
+            pac.USBCTRL_DPRAM,
+            clocks.usb_clock,
+            &mut pac.RESETS,
+        )
+        .unwrap();
+        debug!("critical_section: init usb console");
+        critical_section::with(|cs| USB_CONSOLE.replace(cs, Some(console)));
+    }
+
+    // Begin operation with a self-test, followed by calibration
+    info!("System initialization complete");
+    #[cfg(not(feature = "playback"))]
     critical_section::with(|cs| {
-        #[cfg(feature = "rgba_status")]
-        StatusLedBase::<Rgba>::set_normal(cs, Some("System initialization complete"));
-        #[cfg(feature = "triple_status")]
-        StatusLedBase::<Triple>::set_normal(cs, Some("System initialization complete"));
+        let _ = StatusLedBase::set_state(cs, StatusLedStates::SelfTest);
     });
-    unsafe { pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0) }
+    // Played windows cannot pass the self-test, so calibrate on the first played windows
+    #[cfg(feature = "playback")]
+    {
+        warn!("Playback: analysing windows sent by the host instead of ADC readings");
+        critical_section::with(|cs| {
+            let _ = StatusLedBase::set_state(cs, StatusLedStates::Calibrating);
+        });
+    }
+    #[cfg(feature = "disable_switch")]
+    if start_disabled {
+        critical_section::with(|cs| {
+            StatusLedBase::set_disabled(cs, Some("Disable switch was on at startup."));
+        });
+    }
+    #[cfg(any(feature = "disable_switch", feature = "ack_button"))]
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::IO_IRQ_BANK0)
+    }
+    #[cfg(feature = "usb_console")]
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ)
+    }
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+        pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0);
+    }
     loop {
         // All functionality in interrupts
         cortex_m::asm::wfi();
@@ -0,0 +1,107 @@
+//! Hardware-in-the-loop playback of recorded windows.
+//!
+//! The host sends windows of ADC readings as framed binary (see
+//! [`aps490_pfpu2_core::telemetry::encode_window`]) on the telemetry port, and `USBCTRL_IRQ` stores
+//! each one in [`PLAYBACK`](crate::interrupt::PLAYBACK). When the next ADC transfer completes,
+//! `DMA_IRQ_0` replaces the readings with the played window and analyses it as normal, so the
+//! resulting [`Record`](aps490_pfpu2_core::telemetry::Record) on the telemetry port reports the
+//! decision made by the on-target code. Transfers which complete without a played window waiting
+//! are discarded, so every record corresponds to exactly one played window, in order.
+//!
+//! Only one window is held at a time. The telemetry port stops reading while it is full, which
+//! holds off the host through USB flow control.
+//!
+//! Recorded windows do not respond to the excitation, so the [self-test](crate::selftest) is
+//! skipped, and the system starts in [`StatusLedStates::Calibrating`](crate::components::StatusLedStates::Calibrating).
+//!
+//! The host compares each decision with a reference which models a single tone at full drive,
+//! calibrated once. The console refuses `calibrate`, and any change to the frequency, tones,
+//! waveform or drive level, so the firmware stays on that path for the whole playback.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_core::signal::WINDOW_SIZE;
+use defmt::Format;
+
+/// Holds the next played window until `DMA_IRQ_0` is ready for it
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct PlaybackSlot {
+    /// Readings of the waiting window
+    window: [u8; WINDOW_SIZE],
+    /// `window` holds a window which has not been analysed yet
+    full: bool,
+    /// Windows analysed since startup
+    played: u32,
+}
+
+impl PlaybackSlot {
+    /// Create an empty slot. Usable in `static` initializers.
+    pub const fn new() -> Self {
+        Self {
+            window: [0; WINDOW_SIZE],
+            full: false,
+            played: 0,
+        }
+    }
+
+    /// A window is waiting to be analysed
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Store a received window. Returns `false` without changing the slot if it is already full.
+    pub fn put(&mut self, window: &[u8; WINDOW_SIZE]) -> bool {
+        if self.full {
+            return false;
+        }
+        self.window.copy_from_slice(window);
+        self.full = true;
+        true
+    }
+
+    /// Copy the waiting window into `readings`, emptying the slot. Returns `false` if no window was
+    /// waiting, leaving `readings` unchanged.
+    pub fn take_into(&mut self, readings: &mut [u8; WINDOW_SIZE]) -> bool {
+        if !self.full {
+            return false;
+        }
+        readings.copy_from_slice(&self.window);
+        self.full = false;
+        self.played = self.played.wrapping_add(1);
+        true
+    }
+
+    /// Number of windows analysed since startup
+    pub fn played(&self) -> u32 {
+        self.played
+    }
+}
+
+impl Default for PlaybackSlot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Format for PlaybackSlot {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "PlaybackSlot {{ full: {=bool}, played: {=u32} }}",
+            self.full,
+            self.played
+        )
+    }
+}
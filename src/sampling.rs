@@ -0,0 +1,670 @@
+//! Phase-locked ADC sampling, paced from the excitation PWM.
+//!
+//! The ADC runs in single-conversion mode. PWM slice 4 is enabled together with the excitation on
+//! slice 3, with the same divider and half its `top`, so it wraps in the middle of each half of the
+//! excitation (see [`PacingPlan`](aps490_pfpu2_core::clock::PacingPlan)). Every wrap raises a DMA
+//! request, and [`AdcTrigger`] answers each one by writing `START_ONCE` to the ADC, so conversions
+//! happen in lockstep with the excitation while `DMA_IRQ_0` collects the readings as before.
+//!
+//! Each window of triggers is only armed at the start of an excitation cycle, so the first reading
+//! of every window is from the high half, and
+//! [`AlignedAverages::from_locked_window`] does not need to search for the alignment.
+//!
+//! The excitation frequency follows [`Config::excitation_hz`](aps490_pfpu2_core::config::Config),
+//! or the current step of a [`SWEEP`]. Both slices are retuned together between windows, with the
+//! sample rate re-derived from the new [`ClockPlan`], and detection recalibrates at the new
+//! frequency. Each window still holds [`WINDOW_SIZE`] readings, so its duration scales with the
+//! excitation period. Detection and the self-test are timed by [`window_us`] rather than by
+//! counting windows, so calibration and contacts last as long at any frequency.
+//!
+//! While a [`MULTI_FREQUENCY`] cycle is running, the excitation moves to the next tone after every
+//! window without recalibrating, and [`record_tone`] collects each window into the cycle.
+//!
+//! With [`ExcitationMode::Prbs`], a third DMA channel is armed alongside each window of triggers.
+//! It writes a compare value for every chip of [`CHIPS`](aps490_pfpu2_core::prbs::CHIPS) to the
+//! excitation slice as it wraps, holding the output high or low for a whole cycle. The compare
+//! register only takes a new value at the following wrap, so each chip reaches the output two
+//! cycles after it is queued, well within the delays covered by
+//! [`ChannelResponse`](aps490_pfpu2_core::prbs::ChannelResponse). Chips are held off while the
+//! [`SelfTest`](crate::selftest::SelfTest) changes the duty cycle. Correlating a window takes
+//! longer than averaging it, so the time spent in `DMA_IRQ_0` is checked against the window length
+//! in [`DMA_ISR_TIME`](crate::interrupt::DMA_ISR_TIME), and reported by the console `status`.
+//!
+//! The drive of the excitation follows the [`DriveLevel`] of a [`GainControl`], which narrows the
+//! high half of the square wave, or the high chips, so the response stays within the ADC range.
+//! With [`GainSetting::Auto`] in [`CONFIG`], [`update_excitation`] steps the drive down while
+//! windows clip and back up while they are small, but only once armed: detection recalibrates
+//! whenever the drive level changes, and every window is recorded with its
+//! [`DriveLevel::gain_percent`]. The gain of each
+//! level is measured from the delta of the windows on either side of a change, including a fixed
+//! level set from the console, as it does not follow the duty cycle alone.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_core::{
+    clock::{ClockError, ClockPlan},
+    detect::Detector,
+    gain::{DriveLevel, GainControl, GainSetting, WindowRange, DRIVE_LEVELS},
+    impedance::{ImpedanceFeatures, MultiFrequency, ToneFeatures},
+    prbs::{ExcitationMode, CHIPS, CHIPS_PER_WINDOW},
+    signal::{AlignedAverages, WINDOW_SIZE},
+    spectrum::FrequencySweep,
+};
+use cortex_m::singleton;
+use critical_section::CriticalSection;
+use defmt::{debug, info, warn, Format, Formatter};
+use embedded_hal::pwm::SetDutyCycle;
+use rp2040_hal::{
+    dma::{single_buffer, single_buffer::Transfer, Channel, ReadTarget, WriteTarget, CH1, CH2},
+    pac,
+};
+
+use crate::{
+    components::{StatusLed, StatusLedBase, StatusLedStates},
+    interrupt::{
+        SignalGenConfig, ADC_TRIGGER, BUFFERS, CONFIG, MULTI_FREQUENCY, READINGS_FIFO, SIGNAL_GEN,
+        SWEEP,
+    },
+};
+
+/// PWM slice which generates the excitation
+pub const EXCITATION_SLICE: usize = 3;
+/// PWM slice which paces the ADC
+pub const PACING_SLICE: usize = 4;
+/// Bits of [`EXCITATION_SLICE`] and [`PACING_SLICE`] in the PWM `EN` register
+pub const SLICE_MASK: u8 = (1 << EXCITATION_SLICE) | (1 << PACING_SLICE);
+/// DMA request raised when [`PACING_SLICE`] wraps (`DREQ_PWM_WRAP0` is 24)
+pub const DREQ_PACING: u8 = 24 + PACING_SLICE as u8;
+/// DMA request raised when [`EXCITATION_SLICE`] wraps
+pub const DREQ_EXCITATION: u8 = 24 + EXCITATION_SLICE as u8;
+/// `START_ONCE` bit of the ADC `CS` register
+const ADC_CS_START_ONCE: u32 = 1 << 2;
+/// `ERR_STICKY` bit of the ADC `CS` register, which is cleared by writing 1
+const ADC_CS_ERR_STICKY: u32 = 1 << 10;
+
+/// Control word for the ADC which starts a single conversion, read repeatedly by the DMA
+pub struct StartConversion(&'static u32);
+
+// Safety: the word is never written after `AdcTrigger::init`
+unsafe impl ReadTarget for StartConversion {
+    type ReceivedWord = u32;
+
+    fn rx_treq() -> Option<u8> {
+        None
+    }
+
+    fn rx_address_count(&self) -> (u32, u32) {
+        (self.0 as *const u32 as u32, u32::MAX)
+    }
+
+    fn rx_increment(&self) -> bool {
+        false
+    }
+}
+
+/// ADC `CS` register, written once for every wrap of [`PACING_SLICE`]
+pub struct AdcControl;
+
+// Safety: the DMA only writes the same configuration back, with `START_ONCE` set
+unsafe impl WriteTarget for AdcControl {
+    type TransmittedWord = u32;
+
+    fn tx_treq() -> Option<u8> {
+        Some(DREQ_PACING)
+    }
+
+    fn tx_address_count(&mut self) -> (u32, u32) {
+        // `CS` is the first register of the ADC
+        (pac::ADC::ptr() as u32, WINDOW_SIZE as u32)
+    }
+
+    fn tx_increment(&self) -> bool {
+        false
+    }
+}
+
+/// Compare register of [`EXCITATION_SLICE`], written once for every excitation cycle
+pub struct ExcitationCompare;
+
+// Safety: the DMA only writes compare values for the excitation, which is owned by this module
+unsafe impl WriteTarget for ExcitationCompare {
+    type TransmittedWord = u32;
+
+    fn tx_treq() -> Option<u8> {
+        Some(DREQ_EXCITATION)
+    }
+
+    fn tx_address_count(&mut self) -> (u32, u32) {
+        // Safety: only the address is taken
+        let cc = unsafe { (*pac::PWM::ptr()).ch(EXCITATION_SLICE).cc().as_ptr() };
+        (cc as u32, CHIPS_PER_WINDOW as u32)
+    }
+
+    fn tx_increment(&self) -> bool {
+        false
+    }
+}
+
+/// Wrapper for a window of conversion triggers
+pub type TriggerDma = Transfer<Channel<CH1>, StartConversion, AdcControl>;
+/// Wrapper for the trigger DMA channel between windows
+pub type TriggerConfig = (Channel<CH1>, StartConversion, AdcControl);
+/// Compare values for every chip of a window, with the excitation on channel A
+pub type ChipTable = &'static mut [u32; CHIPS_PER_WINDOW];
+/// Wrapper for a window of chips
+pub type ChipDma = Transfer<Channel<CH2>, ChipTable, ExcitationCompare>;
+/// Wrapper for the chip DMA channel between windows
+pub type ChipConfig = (Channel<CH2>, ChipTable, ExcitationCompare);
+
+/// Writes [`CHIPS`] to the excitation, one window at a time
+struct ChipSequence {
+    /// Chips for the current window, if armed
+    transfer: Option<ChipDma>,
+    /// Channel and targets while not armed
+    config: Option<ChipConfig>,
+    /// Compare value of high chips which the table was last built for
+    high: Option<u32>,
+}
+
+impl ChipSequence {
+    /// Wait for any chips still to be written. Chips run alongside the window of triggers, so
+    /// once the window is complete this waits for at most one excitation cycle.
+    fn stop(&mut self) {
+        if let Some(transfer) = self.transfer.take() {
+            self.config = Some(transfer.wait());
+        }
+    }
+
+    /// Reclaim the channel if every chip has been written, without waiting. Returns `false` if
+    /// chips are still being written.
+    fn try_stop(&mut self) -> bool {
+        match self.transfer.take() {
+            Some(transfer) if !transfer.is_done() => {
+                self.transfer = Some(transfer);
+                false
+            }
+            Some(transfer) => {
+                self.config = Some(transfer.wait());
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Wait for any chips still to be written, and rebuild the table if the compare value of high
+    /// chips has changed
+    fn prepare(&mut self, high: u32) {
+        self.stop();
+        if self.high == Some(high) {
+            return;
+        }
+        if let Some((_, table, _)) = self.config.as_mut() {
+            for (value, chip) in table.iter_mut().zip(CHIPS) {
+                *value = if chip { high } else { 0 };
+            }
+            self.high = Some(high);
+        }
+    }
+
+    /// Start writing a window of chips, after [`ChipSequence::prepare`]
+    fn start(&mut self) {
+        if let Some((channel, from, to)) = self.config.take() {
+            self.transfer = Some(single_buffer::Config::new(channel, from, to).start());
+        }
+    }
+}
+
+/// Starts one ADC conversion for every wrap of [`PACING_SLICE`], one window at a time
+pub struct AdcTrigger {
+    /// Triggers for the current window, if armed
+    transfer: Option<TriggerDma>,
+    /// Channel and targets while not armed
+    config: Option<TriggerConfig>,
+    /// Clocks and dividers currently applied to the PWM slices
+    plan: ClockPlan,
+    /// Excitation frequency requested for `plan`, in hertz
+    excitation_hz: u32,
+    /// Pseudo-random excitation, used in [`ExcitationMode::Prbs`]
+    chips: ChipSequence,
+    /// Waveform currently applied
+    mode: ExcitationMode,
+    /// Duty cycle held by a self-test, with chips held off until normal excitation is restored
+    test_duty: Option<u8>,
+    /// `test_duty` is waiting for chips still being written, and is applied when the next window
+    /// is armed
+    duty_pending: bool,
+    /// Drive level of the excitation
+    gain: GainControl,
+}
+
+impl AdcTrigger {
+    /// Create triggers on `channel` for an ADC which has already been configured for a single
+    /// channel with the FIFO and DMA enabled, and PWM slices already running with `plan` for a
+    /// square wave of `excitation_hz` at full drive. Chips are written by `chip_channel`. Returns
+    /// `None` if called more than once.
+    pub fn init(
+        channel: Channel<CH1>,
+        chip_channel: Channel<CH2>,
+        plan: ClockPlan,
+        excitation_hz: u32,
+    ) -> Option<Self> {
+        // Safety: read-only access, and the ADC is owned by the FIFO which will take the readings
+        let cs = unsafe { (*pac::ADC::ptr()).cs().read().bits() };
+        let start = singleton!(: u32 = (cs & !ADC_CS_ERR_STICKY) | ADC_CS_START_ONCE)?;
+        let chip_table = singleton!(: [u32; CHIPS_PER_WINDOW] = [0; CHIPS_PER_WINDOW])?;
+        Some(Self {
+            transfer: None,
+            config: Some((channel, StartConversion(start), AdcControl)),
+            plan,
+            excitation_hz,
+            chips: ChipSequence {
+                transfer: None,
+                config: Some((chip_channel, chip_table, ExcitationCompare)),
+                high: None,
+            },
+            mode: ExcitationMode::Square,
+            test_duty: None,
+            duty_pending: false,
+            gain: GainControl::new(),
+        })
+    }
+
+    /// Clocks and dividers currently applied
+    pub fn plan(&self) -> ClockPlan {
+        self.plan
+    }
+
+    /// Excitation frequency currently applied, as requested in hertz
+    pub fn excitation_hz(&self) -> u32 {
+        self.excitation_hz
+    }
+
+    /// Waveform currently applied
+    pub fn mode(&self) -> ExcitationMode {
+        self.mode
+    }
+
+    /// Drive level currently applied
+    pub fn drive(&self) -> DriveLevel {
+        self.gain.drive()
+    }
+
+    /// Index of the drive level currently applied in
+    /// [`DRIVE_LEVELS`]
+    pub fn gain_level(&self) -> usize {
+        self.gain.level()
+    }
+
+    /// Record the range and `delta` of the latest window with the [`GainControl`], applying any
+    /// change of drive level from the next window. Returns `true` if the level changed. Must only
+    /// be called between windows.
+    pub fn update_gain(&mut self, cs: CriticalSection, range: &WindowRange, delta: u8) -> bool {
+        let changed = self.gain.update(range, delta);
+        if changed {
+            self.apply_drive(cs);
+        }
+        changed
+    }
+
+    /// Record the range and `delta` of the latest window with the [`GainControl`] to measure the
+    /// gain of the current level, without changing level
+    pub fn record_gain(&mut self, range: &WindowRange, delta: u8) {
+        self.gain.record(range, delta);
+    }
+
+    /// Hold the drive at `level`, from the next window. Returns `true` if the level changed. Must
+    /// only be called between windows.
+    pub fn set_gain_level(&mut self, cs: CriticalSection, level: usize) -> bool {
+        if level == self.gain.level() {
+            return false;
+        }
+        self.gain.set_level(level);
+        self.apply_drive(cs);
+        true
+    }
+
+    /// Apply the duty cycle of the current drive level, or any held by
+    /// [`AdcTrigger::hold_duty`], to [`SIGNAL_GEN`]. The duty cycle is a count, so it must also
+    /// follow any change of `top`. Chips pick up the new level when the next window is armed.
+    pub fn apply_drive(&self, cs: CriticalSection) {
+        let duty_percent = self.test_duty.unwrap_or(self.drive().duty_percent);
+        if let Some(signal_pwm) = SIGNAL_GEN.borrow_ref_mut(cs).as_mut() {
+            signal_pwm
+                .set_duty_cycle_percent(duty_percent)
+                .expect("Unable to set signal duty cycle");
+        }
+    }
+
+    /// Compare value for high chips. Full drive needs a value past `top` to stay high for the
+    /// whole cycle, and weaker levels are high for the nominal [`DriveLevel::gain_percent`] of it.
+    /// Their response is measured by the [`GainControl`] like that of a square wave.
+    fn chip_high(&self) -> u32 {
+        let nominal = DRIVE_LEVELS[self.gain.level()].gain_percent;
+        (self.plan.pwm.top as u32 + 1) * nominal as u32 / 100
+    }
+
+    /// `true` if chips were armed for the latest window, so it should be correlated against them
+    pub fn chips_running(&self) -> bool {
+        self.chips.transfer.is_some()
+    }
+
+    /// Change the waveform, from the next window. Must only be called between windows.
+    pub fn set_mode(&mut self, cs: CriticalSection, mode: ExcitationMode) {
+        self.chips.stop();
+        self.mode = mode;
+        if mode == ExcitationMode::Square {
+            self.apply_drive(cs);
+        }
+    }
+
+    /// Hold the excitation at `duty_percent` with chips held off, such as for a self-test, or
+    /// return to the current drive level with `None`.
+    ///
+    /// Chips still being written from the latest window would overwrite the duty cycle. Rather
+    /// than waiting for them here, the duty cycle is then applied once they finish, when the next
+    /// window is armed.
+    pub fn hold_duty(&mut self, cs: CriticalSection, duty_percent: Option<u8>) {
+        self.test_duty = duty_percent;
+        self.duty_pending = !self.chips.try_stop();
+        if !self.duty_pending {
+            self.apply_drive(cs);
+        }
+    }
+
+    /// Change the excitation to `excitation_hz`, along with the sample rate. Must only be called
+    /// between windows, while no conversions are armed.
+    pub fn retune(
+        &mut self,
+        cs: CriticalSection,
+        excitation_hz: u32,
+    ) -> Result<ClockPlan, ClockError> {
+        let plan = self.plan.with_excitation(excitation_hz)?;
+        self.chips.stop();
+        // Safety: both slices are owned by this module after startup, and stopped while changed
+        unsafe {
+            let pwm = &*pac::PWM::ptr();
+            pwm.en()
+                .modify(|r, w| w.bits(r.bits() & !(SLICE_MASK as u32)));
+            for (slice, settings) in [
+                (EXCITATION_SLICE, plan.pwm),
+                (PACING_SLICE, plan.pacing.pwm),
+            ] {
+                pwm.ch(slice).div().write(|w| {
+                    w.int().bits(settings.div_int);
+                    w.frac().bits(settings.div_frac)
+                });
+                pwm.ch(slice).top().write(|w| w.top().bits(settings.top));
+            }
+            pwm.ch(EXCITATION_SLICE).ctr().write(|w| w.ctr().bits(0));
+            pwm.ch(PACING_SLICE)
+                .ctr()
+                .write(|w| w.ctr().bits(plan.pacing.counter));
+            pwm.en().modify(|r, w| w.bits(r.bits() | SLICE_MASK as u32));
+        }
+
+        self.apply_drive(cs);
+        self.plan = plan;
+        self.excitation_hz = excitation_hz;
+        Ok(plan)
+    }
+
+    /// Trigger the next window of conversions, starting at the next excitation cycle, along with
+    /// its chips in [`ExcitationMode::Prbs`]. Waits for the previous window and its chips to
+    /// finish, and up to one excitation cycle for the start of a cycle. Applies any duty cycle
+    /// left pending by [`AdcTrigger::hold_duty`].
+    pub fn arm(&mut self, cs: CriticalSection) {
+        let (channel, from, to) = match (self.transfer.take(), self.config.take()) {
+            (Some(transfer), _) => transfer.wait(),
+            (None, Some(config)) => config,
+            (None, None) => unreachable!("ADC trigger channel is always stored"),
+        };
+        let spread = self.mode == ExcitationMode::Prbs && self.test_duty.is_none();
+        if spread {
+            self.chips.prepare(self.chip_high());
+        } else {
+            self.chips.stop();
+        }
+        if core::mem::take(&mut self.duty_pending) {
+            self.apply_drive(cs);
+        }
+
+        while excitation_counter() >= self.plan.pacing.arm_before {}
+        if spread {
+            self.chips.start();
+        }
+        self.transfer = Some(single_buffer::Config::new(channel, from, to).start());
+    }
+}
+
+impl Format for AdcTrigger {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(
+            fmt,
+            "AdcTrigger {{ armed: {=bool}, chips: {=bool}, ",
+            self.transfer.is_some(),
+            self.chips_running()
+        );
+        defmt::write!(
+            fmt,
+            "mode: {}, drive: {}, excitation_hz: {=u32}, plan: {} }}",
+            self.mode,
+            self.drive(),
+            self.excitation_hz,
+            self.plan
+        )
+    }
+}
+
+/// Current count of [`EXCITATION_SLICE`]
+fn excitation_counter() -> u16 {
+    // Safety: read-only access to a counter which is never written after startup
+    unsafe {
+        (*pac::PWM::ptr())
+            .ch(EXCITATION_SLICE)
+            .ctr()
+            .read()
+            .ctr()
+            .bits()
+    }
+}
+
+/// `true` if the latest window was taken with pseudo-random chips, so its readings must be
+/// correlated with [`ChannelResponse`](aps490_pfpu2_core::prbs::ChannelResponse). Must be called
+/// before [`start_readings`] arms the next window.
+pub fn chips_running(cs: CriticalSection) -> bool {
+    ADC_TRIGGER
+        .borrow_ref(cs)
+        .as_ref()
+        .is_some_and(AdcTrigger::chips_running)
+}
+
+/// [`DriveLevel::gain_percent`] of the excitation for the latest window. Must be called before
+/// [`update_excitation`] changes the drive level for the next window.
+pub fn gain_percent(cs: CriticalSection) -> u8 {
+    ADC_TRIGGER
+        .borrow_ref(cs)
+        .as_ref()
+        .map_or(100, |trigger| trigger.drive().gain_percent)
+}
+
+/// Time taken to record the latest window at the achieved sample rate, in microseconds. Must be
+/// called before [`update_excitation`] retunes the excitation for the next window.
+pub fn window_us(cs: CriticalSection) -> u32 {
+    ADC_TRIGGER
+        .borrow_ref(cs)
+        .as_ref()
+        .map_or(Detector::DEFAULT_WINDOW_US, |trigger| {
+            trigger.plan().window_us(WINDOW_SIZE)
+        })
+}
+
+/// Start a window of readings into `config`, and arm [`ADC_TRIGGER`] so the readings are taken in
+/// phase with the excitation
+pub fn start_readings(cs: CriticalSection, config: SignalGenConfig) {
+    let (channel, from, to) = config;
+    READINGS_FIFO.replace(
+        cs,
+        Some(single_buffer::Config::new(channel, from, to).start()),
+    );
+    if let Some(trigger) = ADC_TRIGGER.borrow_ref_mut(cs).as_mut() {
+        trigger.arm(cs);
+    }
+}
+
+/// How a window is checked for detection
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum ToneWindow {
+    /// The excitation is at a single frequency, so the window is checked on its own
+    Single,
+    /// The window is part of a [`MULTI_FREQUENCY`] cycle which is not complete yet
+    Partial,
+    /// The window completed a [`MULTI_FREQUENCY`] cycle, which is checked as a whole
+    Complete(ImpedanceFeatures),
+}
+
+/// Record the latest window in any [`MULTI_FREQUENCY`] cycle, from the
+/// [`partial_sums`](AlignedAverages::partial_sums) of its readings
+pub fn record_tone(cs: CriticalSection, partial_sums: &[i32; 4]) -> ToneWindow {
+    let mut multi = MULTI_FREQUENCY.borrow_ref_mut(cs);
+    let trigger = ADC_TRIGGER.borrow_ref(cs);
+    let (Some(multi), Some(trigger)) = (multi.as_mut(), trigger.as_ref()) else {
+        return ToneWindow::Single;
+    };
+    let tone = ToneFeatures::from_partial_sums(
+        trigger.excitation_hz(),
+        partial_sums,
+        trigger.plan().samples_per_cycle,
+    );
+    match multi.record(tone) {
+        Some(features) => ToneWindow::Complete(features),
+        None => ToneWindow::Partial,
+    }
+}
+
+/// Record the latest window in any [`SWEEP`], then apply any change of waveform in [`CONFIG`],
+/// adjust the drive level from the `range` of the window, and retune the excitation if the sweep,
+/// [`MULTI_FREQUENCY`] or [`CONFIG`] asks for a different frequency. Must be called before
+/// [`start_readings`], with the system `state` after the window was analysed.
+///
+/// Retuning, or changing the waveform or drive level, restarts calibration, so it waits while the
+/// system is alerting, paused or testing. Automatic gain control also waits during proximity, as
+/// the response is expected to move while the blade approaches.
+/// Moving between the tones of a [`MULTI_FREQUENCY`] cycle does not, so it also continues during a
+/// contact, where every cycle is needed to detect the end of the contact. Only whole cycles reach
+/// the detector, so the other tones cannot raise or clear a contact early; see
+/// [`Detector::update_features`].
+pub fn update_excitation(
+    cs: CriticalSection,
+    avgs: &AlignedAverages,
+    range: &WindowRange,
+    state: StatusLedStates,
+) {
+    let mut trigger = ADC_TRIGGER.borrow_ref_mut(cs);
+    let Some(trigger) = trigger.as_mut() else {
+        return;
+    };
+    let mut sweep = SWEEP.borrow_ref_mut(cs);
+    if let Some(sweep) = sweep.as_mut() {
+        sweep.record(trigger.excitation_hz(), avgs);
+    }
+
+    let recalibrate = matches!(
+        state,
+        StatusLedStates::Armed | StatusLedStates::Proximity | StatusLedStates::Calibrating
+    );
+    let mode = CONFIG.borrow(cs).get().excitation_mode;
+    if mode != trigger.mode() && recalibrate {
+        trigger.set_mode(cs, mode);
+        info!("Excitation waveform set to {}", mode);
+        restart_calibration(cs);
+    }
+
+    // Played and synthetic windows do not respond to the drive, and a sweep compares raw averages
+    // across every step, so the drive is held while one runs
+    let live = !cfg!(any(feature = "demo_mode", feature = "playback"));
+    let measure = live
+        && matches!(state, StatusLedStates::Armed | StatusLedStates::Calibrating)
+        && sweep.is_none();
+    // Every change restarts calibration, so one which keeps being asked for while calibrating
+    // would stop the system from ever arming
+    let adjust = measure && state == StatusLedStates::Armed;
+    let delta = avgs.get_delta();
+    let gain_changed = match CONFIG.borrow(cs).get().gain {
+        GainSetting::Auto if adjust => trigger.update_gain(cs, range, delta),
+        GainSetting::Auto => {
+            if measure {
+                trigger.record_gain(range, delta);
+            }
+            false
+        }
+        GainSetting::Fixed(level) => {
+            if measure {
+                trigger.record_gain(range, delta);
+            }
+            recalibrate && trigger.set_gain_level(cs, level as usize)
+        }
+    };
+    if gain_changed {
+        let drive = trigger.drive();
+        info!(
+            "Excitation drive set to {=u8}% duty, {=u8}% gain (window range {=u8} to {=u8})",
+            drive.duty_percent, drive.gain_percent, range.min, range.max
+        );
+        restart_calibration(cs);
+    }
+
+    let sweep_hz = sweep.as_ref().and_then(FrequencySweep::frequency);
+    let tone_hz = MULTI_FREQUENCY
+        .borrow_ref(cs)
+        .as_ref()
+        .map(MultiFrequency::frequency)
+        .filter(|_| sweep_hz.is_none());
+    let target_hz = sweep_hz
+        .or(tone_hz)
+        .unwrap_or_else(|| CONFIG.borrow(cs).get().excitation_hz);
+    let permitted = recalibrate || (state == StatusLedStates::Contact && tone_hz.is_some());
+    if target_hz == trigger.excitation_hz() || !permitted {
+        return;
+    }
+
+    match trigger.retune(cs, target_hz) {
+        Ok(_) if tone_hz.is_some() => debug!("Excitation moved to {=u32} Hz", target_hz),
+        Ok(plan) => {
+            info!(
+                "Excitation set to {=u64} mHz, sampling at {=u64} mHz",
+                plan.excitation_millihz(),
+                plan.sample_millihz()
+            );
+            restart_calibration(cs);
+        }
+        Err(err) => {
+            // Requests are checked by the console, so fall back rather than retry every window
+            warn!("Unable to set excitation to {=u32} Hz: {}", target_hz, err);
+            let mut config = CONFIG.borrow(cs).get();
+            config.excitation_hz = trigger.excitation_hz();
+            CONFIG.borrow(cs).set(config);
+            *sweep = None;
+            MULTI_FREQUENCY.replace(cs, None);
+        }
+    }
+}
+
+/// Discard the idle level, which no longer matches the excitation, and calibrate again
+fn restart_calibration(cs: CriticalSection) {
+    if let Some(buffers) = BUFFERS.borrow_ref_mut(cs).as_mut() {
+        buffers.start_calibration();
+    }
+    let _ = StatusLedBase::set_state(cs, StatusLedStates::Calibrating);
+}
@@ -0,0 +1,224 @@
+//! Periodic in-service self-test of the sensing chain.
+//!
+//! The test runs once at startup, and then periodically while the system is
+//! [armed](StatusLedStates::Armed). The excitation signal is briefly raised to full drive and then
+//! switched off to confirm that the measured voltage responds. If the chain from
+//! [`SignalPwm`](crate::interrupt::SignalPwm) to [`AlignedAverages`] no longer reacts, the system
+//! cannot be trusted to see a contact either.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_core::signal::AlignedAverages;
+use defmt::{debug, info, warn, Format};
+use embedded_hal::pwm::SetDutyCycle;
+
+use crate::{
+    components::StatusLedStates,
+    interrupt::{ADC_TRIGGER, SIGNAL_GEN},
+};
+
+/// Progress through a single self-test
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum SelfTestPhase {
+    /// Waiting for the next test
+    #[default]
+    Idle,
+    /// Excitation has been raised to full drive, and the window is discarded while the signal
+    /// settles
+    Raising,
+    /// Recording the response at full drive
+    Baseline,
+    /// Excitation has been switched off, and the window is discarded while the signal settles
+    Settling,
+    /// Recording the response to the changed excitation
+    Measuring,
+    /// Excitation has been restored to the drive level before the test, and the window is
+    /// discarded while the signal settles
+    Restoring,
+}
+
+/// Result of passing a window through [`SelfTest::process`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum SelfTestResult {
+    /// The window is not part of a test, and should be analyzed for contact as normal
+    Inactive,
+    /// A test has started, and the system should move to [`StatusLedStates::SelfTest`]. The
+    /// excitation is raised to full drive from the next window.
+    Started,
+    /// The window was used by the self-test and must not be used for contact detection
+    InProgress,
+    /// The measured voltage responded to the change in excitation
+    Passed,
+    /// The measured voltage did not respond to the change in excitation
+    Failed {
+        /// Average voltage at full drive
+        baseline: u8,
+        /// Average voltage with the test excitation
+        measured: u8,
+    },
+}
+
+/// Periodically changes the [`SIGNAL_GEN`] duty cycle and confirms that the averaged voltage
+/// follows.
+///
+/// A test is started immediately in [`StatusLedStates::SelfTest`], or after
+/// [`SelfTest::INTERVAL_US`] in [`StatusLedStates::Armed`]. The test is aborted if the system
+/// leaves [`StatusLedStates::SelfTest`] partway through.
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct SelfTest {
+    /// Time since the last test completed, in microseconds
+    us_since_test: u32,
+    /// Current progress
+    phase: SelfTestPhase,
+    /// Average voltage recorded at full drive
+    baseline: u8,
+    /// Average voltage recorded with the test excitation
+    measured: u8,
+}
+
+impl SelfTest {
+    /// Time between tests, in microseconds.
+    ///
+    /// Currently set to 100 s (5000 windows at 100 kHz, 1000 windows at 20 kHz)
+    pub const INTERVAL_US: u32 = 100_000_000;
+    /// Duty cycle applied during the test. Switching the excitation off entirely gives the
+    /// largest expected response.
+    pub const TEST_DUTY_PERCENT: u8 = 0;
+    /// Duty cycle of full drive, which the test runs at whatever the current drive level
+    pub const NORMAL_DUTY_PERCENT: u8 = 50;
+    /// Minimum change in average voltage for the test to pass. The test always runs at full
+    /// drive, so the response does not shrink with the weaker drive levels used by automatic gain
+    /// control.
+    ///
+    /// Ex. a response of 16 on a 3.3V signal requires the average voltage to change by
+    /// approximately 0.2V. Recorded idle signals average around 56, and fall close to 0 with no
+    /// excitation.
+    pub const MIN_RESPONSE: u8 = 16;
+
+    /// Create a self-test which waits a full [`SelfTest::INTERVAL_US`] before the first test
+    pub const fn new() -> Self {
+        Self {
+            us_since_test: 0,
+            phase: SelfTestPhase::Idle,
+            baseline: 0,
+            measured: 0,
+        }
+    }
+
+    /// Current progress of the test
+    pub fn phase(&self) -> SelfTestPhase {
+        self.phase
+    }
+
+    /// Advance the self-test with the latest window, given the current system `state` and the
+    /// time taken to record the window in `window_us`.
+    ///
+    /// The window which starts a test and the four after it are reserved for the test: the drive
+    /// is raised to full, a baseline is recorded, the excitation is switched off and measured, and
+    /// then the drive level from before the test is restored. None of them should be used for
+    /// contact detection.
+    pub fn process(
+        &mut self,
+        avgs: &AlignedAverages,
+        state: StatusLedStates,
+        window_us: u32,
+    ) -> SelfTestResult {
+        let testing = state == StatusLedStates::SelfTest;
+        if self.phase != SelfTestPhase::Idle && !testing {
+            warn!("Self-test aborted as system left self-test state");
+            self.abort();
+            return SelfTestResult::Inactive;
+        }
+
+        match self.phase {
+            SelfTestPhase::Idle => {
+                self.us_since_test = self.us_since_test.saturating_add(window_us);
+                let due =
+                    state == StatusLedStates::Armed && self.us_since_test >= Self::INTERVAL_US;
+                if testing || due {
+                    debug!("Starting sensing chain self-test");
+                    Self::set_duty(Some(Self::NORMAL_DUTY_PERCENT));
+                    self.phase = SelfTestPhase::Raising;
+                    SelfTestResult::Started
+                } else {
+                    SelfTestResult::Inactive
+                }
+            }
+            SelfTestPhase::Raising => {
+                self.phase = SelfTestPhase::Baseline;
+                SelfTestResult::InProgress
+            }
+            SelfTestPhase::Baseline => {
+                self.baseline = avgs.get_level();
+                Self::set_duty(Some(Self::TEST_DUTY_PERCENT));
+                self.phase = SelfTestPhase::Settling;
+                SelfTestResult::InProgress
+            }
+            SelfTestPhase::Settling => {
+                self.phase = SelfTestPhase::Measuring;
+                SelfTestResult::InProgress
+            }
+            SelfTestPhase::Measuring => {
+                self.measured = avgs.get_level();
+                Self::set_duty(None);
+                self.phase = SelfTestPhase::Restoring;
+                SelfTestResult::InProgress
+            }
+            SelfTestPhase::Restoring => {
+                self.phase = SelfTestPhase::Idle;
+                self.us_since_test = 0;
+                if self.baseline.abs_diff(self.measured) >= Self::MIN_RESPONSE {
+                    info!(
+                        "Self-test passed: average voltage moved from {=u8} to {=u8}",
+                        self.baseline, self.measured
+                    );
+                    SelfTestResult::Passed
+                } else {
+                    SelfTestResult::Failed {
+                        baseline: self.baseline,
+                        measured: self.measured,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Restore normal excitation and wait for the next interval
+    pub fn abort(&mut self) {
+        if !matches!(self.phase, SelfTestPhase::Idle | SelfTestPhase::Restoring) {
+            Self::set_duty(None);
+        }
+        self.phase = SelfTestPhase::Idle;
+        self.us_since_test = 0;
+    }
+
+    /// Hold the duty cycle of [`SIGNAL_GEN`] at `percent`, holding off any pseudo-random chips, or
+    /// return to the current drive level of the [`AdcTrigger`](crate::sampling::AdcTrigger) with
+    /// `None`
+    fn set_duty(percent: Option<u8>) {
+        critical_section::with(|cs| {
+            debug!("critical_section: self-test set signal duty cycle");
+            if let Some(trigger) = ADC_TRIGGER.borrow_ref_mut(cs).as_mut() {
+                trigger.hold_duty(cs, percent);
+                return;
+            }
+            let mut signal_pwm = SIGNAL_GEN.take(cs).expect("Unable to access PWM controls");
+            signal_pwm
+                .set_duty_cycle_percent(percent.unwrap_or(Self::NORMAL_DUTY_PERCENT))
+                .expect("Unable to set signal duty cycle");
+            SIGNAL_GEN.replace(cs, Some(signal_pwm));
+        });
+    }
+}
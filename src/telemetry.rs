@@ -0,0 +1,215 @@
+//! Live stream of every analysis window, for tuning detection on the host.
+//!
+//! `DMA_IRQ_0` pushes a [`Record`] into [`TELEMETRY`](crate::interrupt::TELEMETRY) for every
+//! window, and `USBCTRL_IRQ` sends them as framed binary (see [`aps490_pfpu2_core::telemetry`]) on
+//! a second USB serial port, alongside the [console](crate::console). Records are numbered when
+//! they are produced, so any dropped because the host is not keeping up show up as a gap in
+//! [`Record::sequence`].
+//!
+//! With the `playback` feature, the host also sends windows to analyse on the same port (see
+//! `playback`).
+//!
+//! A second RTT up channel is not used, as `defmt-rtt` owns the RTT control block and only provides
+//! a single channel.
+
+// Copyright 2024 Jessica Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ops::Range;
+
+#[cfg(feature = "playback")]
+use aps490_pfpu2_core::telemetry::{decode_window, FrameDecoder, MAX_WINDOW_FRAME_SIZE};
+use aps490_pfpu2_core::{
+    state::StatusLedStates,
+    telemetry::{Record, MAX_FRAME_SIZE},
+};
+use defmt::{warn, Format};
+use heapless::Deque;
+use rp2040_hal::usb::UsbBus;
+use usb_device::bus::UsbBusAllocator;
+use usbd_serial::SerialPort;
+
+#[cfg(feature = "playback")]
+use crate::interrupt::PLAYBACK;
+use crate::interrupt::TELEMETRY;
+
+/// Number of records held while waiting for the host. Covers about 640 ms of 20 ms windows.
+pub const QUEUE_SIZE: usize = 32;
+
+/// Records waiting to be sent
+#[derive(Debug)]
+pub struct TelemetryQueue {
+    /// Records waiting to be sent, oldest first
+    records: Deque<Record, QUEUE_SIZE>,
+    /// Sequence number for the next record
+    sequence: u32,
+    /// Records dropped because the queue was full
+    dropped: u32,
+}
+
+impl TelemetryQueue {
+    /// Create an empty queue
+    pub const fn new() -> Self {
+        Self {
+            records: Deque::new(),
+            sequence: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Add a record for the latest window. If the queue is full, the record is dropped, but its
+    /// sequence number is still used so the host can detect the gap.
+    pub fn push(
+        &mut self,
+        sample: usize,
+        avg_high: i32,
+        avg_low: i32,
+        delta: u8,
+        gain_percent: u8,
+        state: StatusLedStates,
+    ) {
+        let record = Record {
+            sequence: self.sequence,
+            sample: sample as u32,
+            avg_high,
+            avg_low,
+            delta,
+            gain_percent,
+            state,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        if self.records.push_back(record).is_err() {
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+    }
+
+    /// Take the oldest record waiting to be sent
+    pub fn pop(&mut self) -> Option<Record> {
+        self.records.pop_front()
+    }
+
+    /// Discard all waiting records, such as while the host is not listening
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Number of records dropped because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+impl Default for TelemetryQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Format for TelemetryQueue {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "TelemetryQueue {{ waiting: {=usize}, sequence: {=u32}, dropped: {=u32} }}",
+            self.records.len(),
+            self.sequence,
+            self.dropped
+        )
+    }
+}
+
+/// Second USB serial port, which sends records from [`TELEMETRY`] while the host has it open
+pub struct TelemetryPort {
+    /// CDC-ACM serial port
+    serial: SerialPort<'static, UsbBus>,
+    /// Frame currently being sent
+    frame: [u8; MAX_FRAME_SIZE],
+    /// Bytes of `frame` not yet accepted by the serial port
+    pending: Range<usize>,
+    /// Partial window received from the host
+    #[cfg(feature = "playback")]
+    window: FrameDecoder<MAX_WINDOW_FRAME_SIZE>,
+}
+
+impl TelemetryPort {
+    /// Allocate the serial port. Must be called before the USB device is built.
+    pub fn new(bus: &'static UsbBusAllocator<UsbBus>) -> Self {
+        Self {
+            serial: SerialPort::new(bus),
+            frame: [0; MAX_FRAME_SIZE],
+            pending: 0..0,
+            #[cfg(feature = "playback")]
+            window: FrameDecoder::new(),
+        }
+    }
+
+    /// Serial port, to be polled by the USB device
+    pub fn serial(&mut self) -> &mut SerialPort<'static, UsbBus> {
+        &mut self.serial
+    }
+
+    /// Read windows sent by the host until one is waiting in [`PLAYBACK`]. Bytes are read one at a
+    /// time, so nothing past the end of a window is read while the slot is full.
+    #[cfg(feature = "playback")]
+    pub fn receive(&mut self) {
+        let mut byte = [0u8];
+        while !critical_section::with(|cs| PLAYBACK.borrow_ref(cs).is_full()) {
+            match self.serial.read(&mut byte) {
+                Ok(1) => {}
+                _ => return,
+            }
+            match self
+                .window
+                .push_frame(byte[0])
+                .map(|frame| frame.and_then(decode_window))
+            {
+                Some(Ok(window)) => {
+                    critical_section::with(|cs| PLAYBACK.borrow_ref_mut(cs).put(window));
+                }
+                Some(Err(err)) => warn!("Discarded played window: {}", err),
+                None => {}
+            }
+        }
+    }
+
+    /// Send as many waiting records as the serial port will accept. Records are discarded while
+    /// the host does not have the port open, so a new connection starts with live data.
+    pub fn send(&mut self) {
+        if !self.serial.dtr() {
+            self.pending = 0..0;
+            critical_section::with(|cs| TELEMETRY.borrow_ref_mut(cs).clear());
+            return;
+        }
+
+        loop {
+            if self.pending.is_empty() {
+                let Some(record) = critical_section::with(|cs| TELEMETRY.borrow_ref_mut(cs).pop())
+                else {
+                    return;
+                };
+                match record.encode(&mut self.frame) {
+                    Ok(frame) => self.pending = 0..frame.len(),
+                    Err(err) => {
+                        warn!("Unable to encode telemetry record {}: {}", record, err);
+                        continue;
+                    }
+                }
+            }
+
+            match self.serial.write(&self.frame[self.pending.clone()]) {
+                Ok(written) if written > 0 => self.pending.start += written,
+                _ => return,
+            }
+        }
+    }
+}